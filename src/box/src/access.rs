@@ -0,0 +1,296 @@
+use std::io;
+
+use ic_cdk::export::candid::{CandidType, Deserialize, Principal};
+
+use crate::directory::{Acl, EntryKind};
+use crate::error::Error;
+use crate::file_system::FileSystem;
+use crate::memory::Memory;
+
+/// What a caller wants to do with an entry.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+    /// Change the ACL, which only owners may, along with admins.
+    Own,
+}
+
+/// The ACLs along `path`: that of the root directory, then those of the
+/// entries on the way which exist. `None` if the root directory has none.
+fn acls<M: Memory>(fs: &FileSystem<M>, path: &[String]) -> io::Result<Option<Vec<Acl>>> {
+    let mut dir = fs.read_root_directory()?;
+    let mut acls = match dir.acl.take() {
+        Some(acl) => vec![acl],
+        None => return Ok(None),
+    };
+    for name in path {
+        let entry = match dir.entry_with_name(name) {
+            Some(entry) => entry,
+            None => break,
+        };
+        acls.extend(entry.acl.clone());
+        if entry.kind != EntryKind::Directory {
+            break;
+        }
+        dir = fs.read_directory(entry)?;
+    }
+    Ok(Some(acls))
+}
+
+/// Fails unless `principal` may have `access` to `path`, whether or not it
+/// exists. Admins, who may write the root directory, may do anything, and
+/// so may owners of entries along the path. Otherwise the nearest ACL on
+/// the path which lists any writers or readers decides. Without an ACL on
+/// the root directory everyone may do anything.
+pub fn check<M: Memory>(
+    fs: &FileSystem<M>,
+    path: &[String],
+    principal: &str,
+    access: Access,
+) -> io::Result<()> {
+    let acls = match acls(fs, path)? {
+        Some(acls) => acls,
+        None => return Ok(()),
+    };
+    let nearest = acls
+        .iter()
+        .rev()
+        .find(|acl| !acl.writers.is_empty() || !acl.readers.is_empty());
+    let allowed = acls[0].can_write(principal)
+        || acls.iter().any(|acl| acl.is_owner(principal))
+        || nearest.is_some_and(|acl| match access {
+            Access::Read => acl.can_read(principal),
+            Access::Write => acl.can_write(principal),
+            Access::Own => false,
+        });
+    if !allowed {
+        return Err(Error::AccessDenied.into());
+    }
+    Ok(())
+}
+
+/// Whether `principal` may do anything, as it may write the root
+/// directory.
+pub fn is_admin<M: Memory>(fs: &FileSystem<M>, principal: &str) -> io::Result<bool> {
+    match check(fs, &[], principal, Access::Write) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// How many names along `path` lead to entries which exist.
+pub fn existing_len<M: Memory>(fs: &FileSystem<M>, path: &[String]) -> usize {
+    (1..=path.len())
+        .take_while(|&len| fs.exists(&path[..len]))
+        .count()
+}
+
+/// Makes `principal` the owner of the first entry along `path` past the
+/// `existing` ones, if there is one now. Owning that entry, it owns
+/// whatever else was created below it.
+pub fn record_owner<M: Memory>(
+    fs: &mut FileSystem<M>,
+    path: &[String],
+    existing: usize,
+    principal: &str,
+) -> io::Result<()> {
+    let created = &path[..path.len().min(existing + 1)];
+    if created.len() > existing && fs.exists(created) {
+        fs.set_acl(created.to_vec(), Some(Acl::owned_by(principal)))?;
+    }
+    Ok(())
+}
+
+/// Who may call the endpoints which change anything, on top of ACLs.
+/// Controllers always may, and only they may change the mode.
+#[derive(Debug, PartialEq, Clone, CandidType, Deserialize)]
+pub enum WriteMode {
+    #[serde(rename = "controllers")]
+    Controllers,
+    /// Controllers and the principals listed.
+    #[serde(rename = "allowList")]
+    AllowList(Vec<Principal>),
+    #[serde(rename = "public")]
+    Public,
+}
+
+const WRITE_MODE: &str = "writeMode";
+/// The principals of the allow-list, in text form, separated by spaces.
+const WRITE_ALLOW_LIST: &str = "writeAllowList";
+
+impl WriteMode {
+    /// The mode kept in the settings of `fs`, only controllers if there's
+    /// none.
+    pub fn read<M: Memory>(fs: &FileSystem<M>) -> io::Result<Self> {
+        let mode = fs.setting(WRITE_MODE)?;
+        Ok(match mode.as_deref() {
+            None | Some("controllers") => WriteMode::Controllers,
+            Some("public") => WriteMode::Public,
+            Some("allowList") => {
+                let list = fs.setting(WRITE_ALLOW_LIST)?.unwrap_or_default();
+                let principals = list
+                    .split_whitespace()
+                    .map(|p| Principal::from_text(p).map_err(|e| Error::corrupted(e.to_string())))
+                    .collect::<Result<_, _>>()?;
+                WriteMode::AllowList(principals)
+            }
+            Some(mode) => return Err(Error::corrupted(format!("bad write mode {}", mode)).into()),
+        })
+    }
+
+    /// Keeps the mode in the settings of `fs`.
+    pub fn write<M: Memory>(&self, fs: &mut FileSystem<M>) -> io::Result<()> {
+        let (mode, list) = match self {
+            WriteMode::Controllers => ("controllers", None),
+            WriteMode::AllowList(principals) => {
+                let texts: Vec<_> = principals.iter().map(Principal::to_text).collect();
+                ("allowList", Some(texts.join(" ")))
+            }
+            WriteMode::Public => ("public", None),
+        };
+        fs.set_setting(WRITE_MODE, Some(mode.to_owned()))?;
+        fs.set_setting(WRITE_ALLOW_LIST, list)
+    }
+
+    /// Whether the mode lets `principal` change anything.
+    pub fn admits(&self, principal: &Principal, is_controller: bool) -> bool {
+        is_controller
+            || match self {
+                WriteMode::Controllers => false,
+                WriteMode::AllowList(principals) => principals.contains(principal),
+                WriteMode::Public => true,
+            }
+    }
+}
+
+#[test]
+fn access() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    let path = |names: &[&str]| names.iter().map(|&n| n.to_owned()).collect::<Vec<_>>();
+    fs.make_directory_recursive(vec!["home", "alice"]).unwrap();
+    assert!(check(&fs, &path(&["home"]), "bob", Access::Write).is_ok());
+
+    let root = Acl {
+        writers: vec!["admin".to_owned()],
+        readers: vec![Acl::EVERYONE.to_owned()],
+        ..Default::default()
+    };
+    fs.set_acl(Vec::<String>::new(), Some(root)).unwrap();
+    let existing = existing_len(&fs, &path(&["home", "alice", "a", "b"]));
+    assert_eq!(existing, 2);
+    fs.make_directory_recursive(vec!["home", "alice", "a", "b"])
+        .unwrap();
+    record_owner(
+        &mut fs,
+        &path(&["home", "alice", "a", "b"]),
+        existing,
+        "alice",
+    )
+    .unwrap();
+
+    let alice = path(&["home", "alice", "a", "b", "c.txt"]);
+    assert!(check(&fs, &alice, "alice", Access::Write).is_ok());
+    assert!(check(&fs, &alice, "alice", Access::Own).is_ok());
+    assert!(check(&fs, &alice, "bob", Access::Read).is_ok());
+    assert!(check(&fs, &alice, "bob", Access::Write).is_err());
+    assert!(check(&fs, &path(&["home"]), "alice", Access::Write).is_err());
+    assert!(check(&fs, &alice, "admin", Access::Own).is_ok());
+    assert!(is_admin(&fs, "admin").unwrap());
+    assert!(!is_admin(&fs, "alice").unwrap());
+
+    // Writers can't change the ACL, and reads can be closed off.
+    let acl = Acl {
+        writers: vec!["bob".to_owned()],
+        ..Acl::owned_by("alice")
+    };
+    fs.set_acl(path(&["home", "alice", "a"]), Some(acl))
+        .unwrap();
+    assert!(check(&fs, &alice, "bob", Access::Write).is_ok());
+    assert!(check(&fs, &alice, "bob", Access::Own).is_err());
+    fs.set_acl(
+        Vec::<String>::new(),
+        Some(Acl {
+            writers: vec!["admin".to_owned()],
+            ..Default::default()
+        }),
+    )
+    .unwrap();
+    assert!(check(&fs, &alice, "bob", Access::Read).is_ok());
+    assert!(check(&fs, &alice, "carol", Access::Read).is_err());
+}
+
+#[test]
+fn private_file() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    let path = |names: &[&str]| names.iter().map(|&n| n.to_owned()).collect::<Vec<_>>();
+    fs.make_directory_recursive(vec!["pub"]).unwrap();
+    for name in ["open.txt", "owned.txt", "secret.txt", "shared.txt"] {
+        fs.write_atomic(vec!["pub", name], &b"x"[..]).unwrap();
+    }
+    let root = Acl {
+        writers: vec!["admin".to_owned()],
+        readers: vec![Acl::EVERYONE.to_owned()],
+        ..Default::default()
+    };
+    fs.set_acl(Vec::<String>::new(), Some(root)).unwrap();
+    let acl = |readers: &[&str]| Acl {
+        readers: readers.iter().map(|&r| r.to_owned()).collect(),
+        ..Acl::owned_by("alice")
+    };
+    fs.set_acl(path(&["pub", "owned.txt"]), Some(acl(&[])))
+        .unwrap();
+    fs.set_acl(path(&["pub", "secret.txt"]), Some(acl(&["alice"])))
+        .unwrap();
+    fs.set_acl(path(&["pub", "shared.txt"]), Some(acl(&["bob"])))
+        .unwrap();
+
+    // Only recording an owner leaves reads to the root, while readers
+    // listed closer to the file take over from it.
+    let read = |name: &str, principal: &str| {
+        check(&fs, &path(&["pub", name]), principal, Access::Read).is_ok()
+    };
+    assert!(read("open.txt", "bob"));
+    assert!(read("owned.txt", "bob"));
+    assert!(!read("secret.txt", "bob"));
+    assert!(read("secret.txt", "alice"));
+    assert!(read("secret.txt", "admin"));
+    assert!(read("shared.txt", "bob"));
+    assert!(!read("shared.txt", "carol"));
+    assert!(check(&fs, &path(&["pub", "shared.txt"]), "bob", Access::Write).is_err());
+    assert!(check(&fs, &path(&["pub", "secret.txt"]), "alice", Access::Own).is_ok());
+}
+
+#[test]
+fn write_mode() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut memory = HeapMemory::default();
+    let mut fs = FileSystem::new(&mut memory).unwrap();
+    let alice = Principal::from_text("2vxsx-fae").unwrap();
+    let bob = Principal::management_canister();
+    assert_eq!(WriteMode::read(&fs).unwrap(), WriteMode::Controllers);
+    assert!(WriteMode::Controllers.admits(&alice, true));
+    assert!(!WriteMode::Controllers.admits(&alice, false));
+
+    let mode = WriteMode::AllowList(vec![alice, bob]);
+    mode.write(&mut fs).unwrap();
+    assert!(mode.admits(&bob, false));
+
+    // The mode is kept along with the root directory's ACL.
+    fs.set_acl(Vec::<String>::new(), Some(Acl::owned_by("admin")))
+        .unwrap();
+    fs.persist().unwrap();
+    drop(fs);
+    let mut fs = FileSystem::open(&mut memory).unwrap();
+    assert_eq!(WriteMode::read(&fs).unwrap(), mode);
+    assert!(fs.read_root_directory().unwrap().acl.is_some());
+    WriteMode::Public.write(&mut fs).unwrap();
+    assert_eq!(WriteMode::read(&fs).unwrap(), WriteMode::Public);
+    assert_eq!(fs.setting(WRITE_ALLOW_LIST).unwrap(), None);
+}
@@ -0,0 +1,502 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, Read};
+
+use candid::{CandidType, Deserialize, Int, Nat};
+use sha2::{Digest, Sha256};
+
+use crate::directory::EntryKind;
+use crate::error::Error;
+use crate::file_system::FileSystem;
+use crate::memory::Memory;
+
+/// Files are served in chunks of this size, which keeps responses within
+/// the message limit.
+pub const CHUNK_SIZE: usize = 1 << 20;
+
+/// Batches which see no new chunks for this long are dropped, along with
+/// their chunks, the next time a batch is created.
+const BATCH_EXPIRY_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+/// The only encoding which is stored. Files hold one content each, so other
+/// encodings uploaded by tools are accepted and dropped.
+const IDENTITY: &str = "identity";
+
+/// The empty record some methods take.
+#[derive(CandidType, Deserialize)]
+pub struct Empty {}
+
+#[derive(CandidType, Deserialize)]
+pub struct CreateAssetArguments {
+    pub key: String,
+    pub content_type: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetAssetContentArguments {
+    pub key: String,
+    pub content_encoding: String,
+    pub chunk_ids: Vec<Nat>,
+    pub sha256: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UnsetAssetContentArguments {
+    pub key: String,
+    pub content_encoding: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DeleteAssetArguments {
+    pub key: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum BatchOperation {
+    CreateAsset(CreateAssetArguments),
+    SetAssetContent(SetAssetContentArguments),
+    UnsetAssetContent(UnsetAssetContentArguments),
+    DeleteAsset(DeleteAssetArguments),
+    Clear(Empty),
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CommitBatchArguments {
+    pub batch_id: Nat,
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CreateBatchResponse {
+    pub batch_id: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CreateChunkArguments {
+    pub batch_id: Nat,
+    pub content: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CreateChunkResponse {
+    pub chunk_id: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StoreArguments {
+    pub key: String,
+    pub content_type: String,
+    pub content_encoding: String,
+    pub content: Vec<u8>,
+    pub sha256: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetArguments {
+    pub key: String,
+    pub accept_encodings: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EncodedAsset {
+    pub content: Vec<u8>,
+    pub content_type: String,
+    pub content_encoding: String,
+    pub total_length: Nat,
+    pub sha256: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetChunkArguments {
+    pub key: String,
+    pub content_encoding: String,
+    pub index: Nat,
+    pub sha256: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetChunkResponse {
+    pub content: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AssetEncodingDetails {
+    pub content_encoding: String,
+    pub sha256: Option<Vec<u8>>,
+    pub length: Nat,
+    pub modified: Int,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AssetDetails {
+    pub key: String,
+    pub content_type: String,
+    pub encodings: Vec<AssetEncodingDetails>,
+}
+
+struct Chunk {
+    batch_id: u64,
+    content: Vec<u8>,
+}
+
+/// Uploads in progress through the interface of the IC asset canister, so
+/// tools like `dfx deploy` and `ic-asset` can manage files in the
+/// filesystem. Assets are files, and their keys are absolute paths. Chunks
+/// are kept on the heap until their batch is committed.
+#[derive(Default)]
+pub struct Assets {
+    next_id: u64,
+    /// When each batch expires.
+    batches: HashMap<u64, u64>,
+    chunks: HashMap<u64, Chunk>,
+}
+
+fn to_u64(n: &Nat) -> io::Result<u64> {
+    u64::try_from(&n.0).map_err(|_| io::ErrorKind::InvalidInput.into())
+}
+
+/// The path of the asset with `key`, which has to be absolute.
+pub fn key_path(key: &str) -> io::Result<Vec<String>> {
+    if !key.starts_with('/') {
+        return Err(Error::InvalidPath.into());
+    }
+    let path = key
+        .split('/')
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    if path.is_empty() {
+        return Err(Error::InvalidPath.into());
+    }
+    Ok(path)
+}
+
+fn check_sha256(expected: &Option<Vec<u8>>, content: impl Fn(&mut Sha256)) -> io::Result<()> {
+    if let Some(expected) = expected {
+        let mut hasher = Sha256::new();
+        content(&mut hasher);
+        if hasher.finalize()[..] != expected[..] {
+            return Err(Error::corrupted("content doesn't match its sha256").into());
+        }
+    }
+    Ok(())
+}
+
+impl Assets {
+    pub fn create_batch(&mut self, now: u64) -> CreateBatchResponse {
+        let expired = self
+            .batches
+            .iter()
+            .filter(|(_, &expires)| expires <= now)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            self.drop_batch(id);
+        }
+        let id = self.next_id();
+        self.batches
+            .insert(id, now.saturating_add(BATCH_EXPIRY_NANOS));
+        CreateBatchResponse {
+            batch_id: id.into(),
+        }
+    }
+
+    pub fn create_chunk(
+        &mut self,
+        args: CreateChunkArguments,
+        now: u64,
+    ) -> io::Result<CreateChunkResponse> {
+        let batch_id = to_u64(&args.batch_id)?;
+        let expires = self.batches.get_mut(&batch_id).ok_or(Error::NotFound)?;
+        *expires = now.saturating_add(BATCH_EXPIRY_NANOS);
+        let id = self.next_id();
+        let chunk = Chunk {
+            batch_id,
+            content: args.content,
+        };
+        self.chunks.insert(id, chunk);
+        Ok(CreateChunkResponse {
+            chunk_id: id.into(),
+        })
+    }
+
+    /// Applies the operations in order, and drops the batch with any chunks
+    /// left. Operations before one that fails stay applied.
+    pub fn commit_batch<M: Memory>(
+        &mut self,
+        fs: &mut FileSystem<M>,
+        args: CommitBatchArguments,
+    ) -> io::Result<()> {
+        let batch_id = to_u64(&args.batch_id)?;
+        if !self.batches.contains_key(&batch_id) {
+            return Err(Error::NotFound.into());
+        }
+        let result = args
+            .operations
+            .into_iter()
+            .try_for_each(|op| self.apply(fs, op));
+        self.drop_batch(batch_id);
+        result
+    }
+
+    fn apply<M: Memory>(&mut self, fs: &mut FileSystem<M>, op: BatchOperation) -> io::Result<()> {
+        match op {
+            BatchOperation::CreateAsset(args) => create_asset(fs, &args.key, args.content_type),
+            BatchOperation::SetAssetContent(args) => {
+                let chunks = args
+                    .chunk_ids
+                    .iter()
+                    .map(|id| {
+                        self.chunks
+                            .remove(&to_u64(id)?)
+                            .ok_or(Error::NotFound.into())
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                if args.content_encoding != IDENTITY {
+                    return Ok(());
+                }
+                check_sha256(&args.sha256, |hasher| {
+                    chunks.iter().for_each(|c| hasher.update(&c.content))
+                })?;
+                let content = chunks
+                    .iter()
+                    .fold(Box::new(io::empty()) as Box<dyn Read>, |r, chunk| {
+                        Box::new(r.chain(&chunk.content[..]))
+                    });
+                fs.write_atomic(key_path(&args.key)?, content).map(drop)
+            }
+            BatchOperation::UnsetAssetContent(args) => match args.content_encoding.as_str() {
+                IDENTITY => fs.write_atomic(key_path(&args.key)?, io::empty()).map(drop),
+                _ => Ok(()),
+            },
+            BatchOperation::DeleteAsset(args) => {
+                let path = key_path(&args.key)?;
+                if !fs.exists(&path) {
+                    return Ok(());
+                }
+                fs.remove(path)
+            }
+            BatchOperation::Clear(_) => {
+                for (name, _) in fs.list_directory(Vec::<String>::new())? {
+                    fs.remove(vec![name])?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn drop_batch(&mut self, id: u64) {
+        self.batches.remove(&id);
+        self.chunks.retain(|_, chunk| chunk.batch_id != id);
+    }
+}
+
+/// Creates the file for an asset with its directories, or changes the
+/// content type of the one there.
+fn create_asset<M: Memory>(
+    fs: &mut FileSystem<M>,
+    key: &str,
+    content_type: String,
+) -> io::Result<()> {
+    let path = key_path(key)?;
+    let (name, parent) = path.split_last().unwrap();
+    if !parent.is_empty() {
+        fs.make_directory_recursive(parent.to_vec())?;
+    }
+    fs.with_directory_mut(parent, |dir, _| match dir.entry_with_name_mut(name) {
+        Some(entry) if entry.kind == EntryKind::Directory => Err(Error::IsADirectory.into()),
+        Some(entry) => {
+            entry.content_type = content_type;
+            Ok(())
+        }
+        None => dir.add_file(name.clone(), content_type).map(drop),
+    })
+}
+
+/// Stores an asset in one go, for those small enough to fit in a message.
+pub fn store<M: Memory>(fs: &mut FileSystem<M>, args: StoreArguments) -> io::Result<()> {
+    let identity = args.content_encoding == IDENTITY;
+    if identity {
+        check_sha256(&args.sha256, |hasher| hasher.update(&args.content))?;
+    }
+    create_asset(fs, &args.key, args.content_type)?;
+    if !identity {
+        return Ok(());
+    }
+    fs.write_atomic(key_path(&args.key)?, &args.content[..])
+        .map(drop)
+}
+
+/// The first chunk of an asset. Only the identity encoding is stored, so it
+/// has to be among `accept_encodings`.
+pub fn get<M: Memory>(fs: &FileSystem<M>, args: GetArguments) -> io::Result<EncodedAsset> {
+    if !args.accept_encodings.iter().any(|e| e == IDENTITY) {
+        return Err(Error::NotFound.into());
+    }
+    fs.with_file(key_path(&args.key)?, |file| {
+        let mut content = vec![0u8; file.size.min(CHUNK_SIZE as u64) as usize];
+        let len = file.read_at(fs, 0, &mut content)?;
+        content.truncate(len);
+        Ok(EncodedAsset {
+            content,
+            content_type: file.content_type.clone(),
+            content_encoding: IDENTITY.to_owned(),
+            total_length: file.size.into(),
+            sha256: None,
+        })
+    })
+}
+
+pub fn get_chunk<M: Memory>(
+    fs: &FileSystem<M>,
+    args: GetChunkArguments,
+) -> io::Result<GetChunkResponse> {
+    if args.content_encoding != IDENTITY {
+        return Err(Error::NotFound.into());
+    }
+    let offset = to_u64(&args.index)?
+        .checked_mul(CHUNK_SIZE as u64)
+        .ok_or(io::ErrorKind::InvalidInput)?;
+    fs.with_file(key_path(&args.key)?, |file| {
+        if offset >= file.size {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let mut content = vec![0u8; (file.size - offset).min(CHUNK_SIZE as u64) as usize];
+        let len = file.read_at(fs, offset, &mut content)?;
+        content.truncate(len);
+        Ok(GetChunkResponse { content })
+    })
+}
+
+/// Every file in the filesystem as an asset. Hashes aren't stored, so
+/// tools compare by length and upload files again when unsure.
+pub fn list<M: Memory>(fs: &FileSystem<M>) -> io::Result<Vec<AssetDetails>> {
+    let mut assets = vec![];
+    let mut pending = vec![(String::new(), fs.read_root_directory()?)];
+    while let Some((path, dir)) = pending.pop() {
+        for entry in dir.entries {
+            let key = format!("{}/{}", path, entry.name);
+            match entry.kind {
+                EntryKind::Directory => pending.push((key, fs.read_directory(&entry)?)),
+                EntryKind::File => assets.push(AssetDetails {
+                    key,
+                    content_type: entry.content_type,
+                    encodings: vec![AssetEncodingDetails {
+                        content_encoding: IDENTITY.to_owned(),
+                        sha256: None,
+                        length: entry.size.into(),
+                        modified: Int::from(entry.modified),
+                    }],
+                }),
+            }
+        }
+    }
+    assets.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(assets)
+}
+
+#[test]
+fn assets() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::with_max_size(16 << 20)).unwrap();
+    let mut assets = Assets::default();
+    let batch_id = assets.create_batch(0).batch_id;
+    let mut chunk = |content: Vec<u8>| {
+        let args = CreateChunkArguments {
+            batch_id: batch_id.clone(),
+            content,
+        };
+        assets.create_chunk(args, 1).unwrap().chunk_id
+    };
+    let chunk_ids = vec![chunk(vec![1u8; CHUNK_SIZE]), chunk(vec![2u8; 10])];
+    let gzipped = vec![chunk(vec![3u8; 5])];
+    let operations = vec![
+        BatchOperation::CreateAsset(CreateAssetArguments {
+            key: "/img/a.png".to_owned(),
+            content_type: "image/png".to_owned(),
+        }),
+        BatchOperation::SetAssetContent(SetAssetContentArguments {
+            key: "/img/a.png".to_owned(),
+            content_encoding: IDENTITY.to_owned(),
+            chunk_ids,
+            sha256: None,
+        }),
+        BatchOperation::SetAssetContent(SetAssetContentArguments {
+            key: "/img/a.png".to_owned(),
+            content_encoding: "gzip".to_owned(),
+            chunk_ids: gzipped,
+            sha256: None,
+        }),
+    ];
+    let args = CommitBatchArguments {
+        batch_id: batch_id.clone(),
+        operations,
+    };
+    assets.commit_batch(&mut fs, args).unwrap();
+    assert!(assets.chunks.is_empty() && assets.batches.is_empty());
+
+    let args = GetArguments {
+        key: "/img/a.png".to_owned(),
+        accept_encodings: vec!["gzip".to_owned(), IDENTITY.to_owned()],
+    };
+    let asset = get(&fs, args).unwrap();
+    assert_eq!(asset.content_type, "image/png");
+    assert_eq!(asset.total_length, (CHUNK_SIZE + 10) as u64);
+    assert_eq!(asset.content.len(), CHUNK_SIZE);
+    let args = GetChunkArguments {
+        key: "/img/a.png".to_owned(),
+        content_encoding: IDENTITY.to_owned(),
+        index: 1u64.into(),
+        sha256: None,
+    };
+    assert_eq!(get_chunk(&fs, args).unwrap().content, [2u8; 10]);
+
+    // Stored content is checked against its hash.
+    let content = b"hello".to_vec();
+    let mut args = StoreArguments {
+        key: "/hello.txt".to_owned(),
+        content_type: "text/plain".to_owned(),
+        content_encoding: IDENTITY.to_owned(),
+        sha256: Some(Sha256::digest(b"other").to_vec()),
+        content: content.clone(),
+    };
+    assert!(store(&mut fs, args).is_err());
+    args = StoreArguments {
+        key: "/hello.txt".to_owned(),
+        content_type: "text/plain".to_owned(),
+        content_encoding: IDENTITY.to_owned(),
+        sha256: Some(Sha256::digest(&content).to_vec()),
+        content,
+    };
+    store(&mut fs, args).unwrap();
+    let keys = list(&fs)
+        .unwrap()
+        .into_iter()
+        .map(|a| a.key)
+        .collect::<Vec<_>>();
+    assert_eq!(keys, ["/hello.txt", "/img/a.png"]);
+
+    // Batches expire unless chunks keep coming in.
+    let expired = assets.create_batch(0).batch_id;
+    assets.create_batch(BATCH_EXPIRY_NANOS);
+    let args = CreateChunkArguments {
+        batch_id: expired,
+        content: vec![],
+    };
+    assert!(assets.create_chunk(args, BATCH_EXPIRY_NANOS).is_err());
+
+    let batch_id = assets.create_batch(BATCH_EXPIRY_NANOS).batch_id;
+    let operations = vec![BatchOperation::Clear(Empty {})];
+    let args = CommitBatchArguments {
+        batch_id,
+        operations,
+    };
+    assets.commit_batch(&mut fs, args).unwrap();
+    assert!(list(&fs).unwrap().is_empty());
+}
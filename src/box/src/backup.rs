@@ -0,0 +1,265 @@
+use std::io::{self, Read, Seek, Write};
+
+use crate::bitmap::BitState;
+use crate::block::Block;
+use crate::checksum::Checksum;
+use crate::error::Error;
+use crate::file_system::FileSystem;
+use crate::memory::{to_usize, Memory};
+use crate::serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 8] = b"BOXDELTA";
+const VERSION: u64 = 1;
+
+const END: u8 = 0;
+const BLOCK: u8 = 1;
+
+/// Writes are tracked per extent of this many blocks.
+const EXTENT_BLOCKS: u64 = 64;
+const EXTENT_SIZE: u64 = EXTENT_BLOCKS * Block::SIZE as u64;
+
+/// Memory which remembers in which generation each extent was last
+/// written. The `FileSystem` uses the sequence number of the next preamble
+/// as the generation, so a snapshot is identified by the sequence number of
+/// the preamble written for it.
+pub struct TrackedMemory<M> {
+    memory: M,
+    /// Generation of the last write to each extent. Extents past the end
+    /// were last written in `baseline` or earlier.
+    generations: Vec<u64>,
+    baseline: u64,
+    generation: u64,
+}
+
+impl<M> TrackedMemory<M> {
+    pub fn new(memory: M) -> Self {
+        TrackedMemory {
+            memory,
+            generations: vec![],
+            baseline: 0,
+            generation: 1,
+        }
+    }
+
+    /// Starts the next generation, which later writes are recorded in.
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    /// Forgets which extents were written when, as after an upgrade, and
+    /// assumes all of them were written in `generation`.
+    pub fn reset(&mut self, generation: u64) {
+        self.generations.clear();
+        self.baseline = generation;
+        self.generation = generation + 1;
+    }
+
+    /// The generation in which the byte at `offset` was last written.
+    pub fn generation_at(&self, offset: u64) -> u64 {
+        let extent = (offset / EXTENT_SIZE) as usize;
+        self.generations
+            .get(extent)
+            .copied()
+            .unwrap_or(self.baseline)
+    }
+
+    fn record(&mut self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let first = (offset / EXTENT_SIZE) as usize;
+        let last = ((offset + len - 1) / EXTENT_SIZE) as usize;
+        if self.generations.len() <= last {
+            self.generations.resize(last + 1, self.baseline);
+        }
+        for generation in self.generations[first..=last].iter_mut() {
+            *generation = self.generation;
+        }
+    }
+}
+
+impl<M: Memory> Memory for TrackedMemory<M> {
+    fn page_size(&self) -> u64 {
+        self.memory.page_size()
+    }
+
+    fn max_pages(&self) -> u64 {
+        self.memory.max_pages()
+    }
+
+    fn page_count(&self) -> io::Result<u64> {
+        self.memory.page_count()
+    }
+
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        self.memory.grow(num_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.memory.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let written = self.memory.write(offset, buf)?;
+        self.record(offset, written as u64);
+        Ok(written)
+    }
+
+    fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.memory.zero(offset, len)?;
+        self.record(offset, len);
+        Ok(())
+    }
+}
+
+/// An incremental backup holds the blocks in use which were written after
+/// the snapshot it's based on, and the whole preamble:
+///
+/// ```text
+/// "BOXDELTA" version since snapshot block_size
+/// (1 index data)*
+/// 0 checksum
+/// ```
+///
+/// A backup based on snapshot 0 holds all blocks in use. Blocks freed since
+/// the snapshot are left as they are when the backup is applied.
+impl<M: Memory> FileSystem<M> {
+    /// Persists the preamble, which makes a new snapshot, and writes the
+    /// blocks which changed since the snapshot `since` to `w`. Returns the
+    /// new snapshot, which the next backup can be based on.
+    pub fn export_incremental(&mut self, since: u64, w: impl Write) -> io::Result<u64> {
+        self.persist()?;
+        let snapshot = self.sequence;
+
+        let mut checksum = Checksum::default();
+        let mut w = io::BufWriter::new(w);
+        let mut header = MAGIC.to_vec();
+        VERSION.serialize(&mut header)?;
+        since.serialize(&mut header)?;
+        snapshot.serialize(&mut header)?;
+        (Block::SIZE as u64).serialize(&mut header)?;
+        checksum.update(&header);
+        w.write_all(&header)?;
+
+        let preamble = self.preamble_blocks();
+        let changed = self
+            .bitmap
+            .iter()
+            .enumerate()
+            .filter(|&(i, state)| i >= preamble && state == BitState::Occupied)
+            .map(|(i, _)| Block::at(i))
+            .filter(|block| self.memory.generation_at(block.offset()) > since);
+        // The preamble goes last, so an interrupted restore doesn't point
+        // to blocks which aren't there yet.
+        let blocks = changed.chain((0..preamble).map(Block::at));
+
+        let len = self.memory.len()?;
+        let mut data = [0u8; Block::SIZE];
+        for block in blocks {
+            data.fill(0);
+            if block.offset() < len {
+                let mut r = self.memory.reader();
+                r.seek(io::SeekFrom::Start(block.offset()))?;
+                r.read_exact(&mut data)?;
+            }
+            let mut record = vec![BLOCK];
+            (block.index as u64).serialize(&mut record)?;
+            record.extend_from_slice(&data);
+            checksum.update(&record);
+            w.write_all(&record)?;
+        }
+
+        w.write_all(&[END])?;
+        checksum.update(&[END]);
+        checksum.value().serialize(&mut w)?;
+        w.flush()?;
+        Ok(snapshot)
+    }
+
+    /// Writes the blocks of a backup from `export_incremental` into the
+    /// memory and restores the filesystem from them. Backups have to be
+    /// applied in order, starting with one based on snapshot 0 on a
+    /// filesystem from `allocate`, and without persisting in between.
+    /// Returns the snapshot the memory is at now.
+    pub fn apply_incremental(&mut self, r: impl Read) -> io::Result<u64> {
+        let mut r = io::BufReader::new(r);
+        let mut header = [0u8; 40];
+        r.read_exact(&mut header)?;
+        let mut checksum = Checksum::default();
+        checksum.update(&header);
+
+        let mut fields = &header[8..];
+        let version = u64::deserialize_into_default(&mut fields)?;
+        let since = u64::deserialize_into_default(&mut fields)?;
+        let snapshot = u64::deserialize_into_default(&mut fields)?;
+        let block_size = u64::deserialize_into_default(&mut fields)?;
+        if &header[..8] != MAGIC || version != VERSION || block_size != Block::SIZE as u64 {
+            return Err(Error::corrupted("not a supported incremental backup").into());
+        }
+        if since != self.sequence {
+            return Err(Error::corrupted(format!(
+                "backup is based on snapshot {}, not {}",
+                since, self.sequence
+            ))
+            .into());
+        }
+
+        let mut data = [0u8; Block::SIZE];
+        loop {
+            let tag = u8::deserialize_into_default(&mut r)?;
+            checksum.update(&[tag]);
+            if tag == END {
+                break;
+            }
+            if tag != BLOCK {
+                return Err(Error::corrupted(format!("bad record {}", tag)).into());
+            }
+            let index = u64::deserialize_into_default(&mut r)?;
+            r.read_exact(&mut data)?;
+            checksum.update(&index.to_be_bytes());
+            checksum.update(&data);
+
+            let mut w = self.memory.writer();
+            w.seek(io::SeekFrom::Start(Block::at(to_usize(index)?).offset()))?;
+            w.write_all(&data)?;
+        }
+
+        if u64::deserialize_into_default(&mut r)? != checksum.value() {
+            return Err(Error::corrupted("backup doesn't match its checksum").into());
+        }
+        self.restore()?;
+        Ok(snapshot)
+    }
+}
+
+#[test]
+fn incremental_backup() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.write_atomic(vec!["a"], &[1u8; 4000][..]).unwrap();
+    fs.write_atomic(vec!["b"], &[2u8; 4000][..]).unwrap();
+
+    let mut full = vec![];
+    let snapshot = fs.export_incremental(0, &mut full).unwrap();
+
+    fs.write_atomic(vec!["c"], &[3u8; 100][..]).unwrap();
+    fs.remove(vec!["a"]).unwrap();
+    let mut delta = vec![];
+    let next = fs.export_incremental(snapshot, &mut delta).unwrap();
+    assert!(delta.len() < full.len());
+
+    let mut copy = FileSystem::allocate(HeapMemory::default());
+    assert!(copy.apply_incremental(&delta[..]).is_err());
+    assert_eq!(copy.apply_incremental(&full[..]).unwrap(), snapshot);
+    assert!(copy.exists(vec!["a"]));
+    assert_eq!(copy.apply_incremental(&delta[..]).unwrap(), next);
+
+    let mut data = vec![];
+    copy.read_file(vec!["b"], &mut data).unwrap();
+    assert_eq!(data, [2u8; 4000]);
+    data.clear();
+    copy.read_file(vec!["c"], &mut data).unwrap();
+    assert_eq!(data, [3u8; 100]);
+    assert!(!copy.exists(vec!["a"]));
+}
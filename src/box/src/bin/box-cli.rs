@@ -0,0 +1,176 @@
+//! Creates and inspects filesystem images offline, e.g. to prepare an image
+//! before uploading it as a whole, or to look into one taken from a
+//! canister. Images are files in the layout of stable memory.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::process::exit;
+
+use r#box::{DropPolicy, EntryKind, FileMemory, FileSystem};
+
+const USAGE: &str = "usage: box-cli <image> <command> [args]
+
+commands:
+  mkfs                      create an image with an empty filesystem
+  ls [path]                 list a directory
+  tree [path]               list everything below a directory
+  cat <path>                write a file to stdout
+  put <file> <path> [type]  copy a local file in, with a content type if new
+  get <path> <file>         copy a file out
+  rm <path>                 remove a file, or a directory with its contents
+  sync [-n] <dir> [path]    make a directory a copy of a local one; -n only
+                            shows what would change
+  fsck [--repair]           read every file and look for leaked blocks
+  df                        show the space used
+
+Paths are separated by '/', and the root is '/' or left out.";
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.len() < 2 {
+        eprintln!("{}", USAGE);
+        exit(2);
+    }
+    if let Err(e) = run(&args[0], &args[1], &args[2..]) {
+        eprintln!("box-cli: {}", e);
+        exit(1);
+    }
+}
+
+/// Splits a path like `docs/a.txt` into names.
+fn names(path: Option<&String>) -> Vec<String> {
+    path.map_or(&[][..], |p| p.as_bytes())
+        .split(|&b| b == b'/')
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect()
+}
+
+fn run(image: &str, command: &str, args: &[String]) -> io::Result<()> {
+    let usage = || io::Error::new(io::ErrorKind::InvalidInput, USAGE);
+    let open = |create: bool| {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(create)
+            .open(image)
+            .map(FileMemory::new)
+    };
+    if command == "mkfs" {
+        return match args {
+            [] => FileSystem::new(open(true)?)?.close(),
+            _ => Err(usage()),
+        };
+    }
+
+    // Commands which only read leave the image as it is.
+    let mut fs = FileSystem::open(open(false)?)?.with_drop_policy(DropPolicy::Ignore);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match (command, args) {
+        ("ls", [] | [_]) => {
+            for (name, kind) in fs.list_directory(names(args.first()))? {
+                match kind {
+                    EntryKind::Directory => writeln!(out, "{}/", name)?,
+                    EntryKind::File => writeln!(out, "{}", name)?,
+                }
+            }
+        }
+        ("tree", [] | [_]) => {
+            for item in fs.tree(names(args.first()), None)? {
+                let (depth, name, kind, size) = item?;
+                match kind {
+                    EntryKind::Directory => writeln!(out, "{:2$}{}/", "", name, 2 * depth)?,
+                    EntryKind::File => writeln!(out, "{:3$}{}  {}", "", name, size, 2 * depth)?,
+                }
+            }
+        }
+        ("cat", [path]) => {
+            fs.read_file(names(Some(path)), &mut out)?;
+        }
+        ("get", [path, file]) => {
+            fs.read_file(names(Some(path)), File::create(file)?)?;
+        }
+        ("put", [file, path] | [file, path, _]) => {
+            let path = names(Some(path));
+            let (name, parent) = path.split_last().ok_or_else(usage)?;
+            if !parent.is_empty() {
+                fs.make_directory_recursive(parent.to_vec())?;
+            }
+            // Files which exist keep their content type.
+            if let (Some(content_type), false) = (args.get(2), fs.exists(&path)) {
+                fs.with_directory_mut(parent, |dir, _| {
+                    dir.add_file(name.clone(), content_type.clone()).map(drop)
+                })?;
+            }
+            fs.write_atomic(path, File::open(file)?)?;
+            return fs.close();
+        }
+        ("rm", [path]) => {
+            fs.remove(names(Some(path)))?;
+            return fs.close();
+        }
+        ("sync", _) => {
+            let (dry_run, args) = match args {
+                [flag, rest @ ..] if flag == "-n" => (true, rest),
+                _ => (false, args),
+            };
+            let (dir, path) = match args {
+                [dir] => (dir, None),
+                [dir, path] => (dir, Some(path)),
+                _ => return Err(usage()),
+            };
+            let report = fs.sync_from(dir, names(path), dry_run)?;
+            for path in &report.uploaded {
+                writeln!(out, "+ {}", path)?;
+            }
+            for path in &report.removed {
+                writeln!(out, "- {}", path)?;
+            }
+            writeln!(out, "{} unchanged", report.unchanged)?;
+            if !dry_run {
+                return fs.close();
+            }
+        }
+        ("fsck", [] | [_]) => {
+            let repair = match args.first().map(String::as_str) {
+                None => false,
+                Some("--repair") => true,
+                Some(_) => return Err(usage()),
+            };
+            let (mut files, mut bytes) = (0, 0);
+            for entry in fs.entries_recursive()? {
+                if entry.kind == EntryKind::File {
+                    bytes += io::copy(&mut entry.read_from_file_system(&fs)?, &mut io::sink())?;
+                    files += 1;
+                }
+            }
+            let garbage = fs.collect_garbage(!repair)?;
+            writeln!(out, "{} files, {} bytes read", files, bytes)?;
+            writeln!(
+                out,
+                "{} leaked blocks ({} bytes)",
+                garbage.leaked_blocks, garbage.leaked_bytes
+            )?;
+            if repair && garbage.leaked_blocks > 0 {
+                writeln!(out, "leaked blocks freed")?;
+                return fs.close();
+            }
+        }
+        ("df", []) => {
+            let usage = fs.usage();
+            let size = |blocks: u64| blocks * usage.block_size;
+            let free = usage.total_blocks - usage.used_blocks;
+            writeln!(out, "{:>14} {:>14} {:>14}", "size", "used", "free")?;
+            writeln!(
+                out,
+                "{:>14} {:>14} {:>14}",
+                size(usage.total_blocks),
+                size(usage.used_blocks),
+                size(free)
+            )?;
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
@@ -1,24 +1,372 @@
 use std::fmt;
-use std::io::{self, Read, Write};
+use std::io::{self, Read, Seek, Write};
+use std::ops::Range;
 
 use crate::block::Block;
+use crate::checksum::Checksum;
 use crate::memory::Memory;
 use crate::serde::{Deserialize, Serialize};
 
+/// Number of blocks covered by a single bit of the summary level.
+const WORD_BITS: usize = 64;
+const WORD_BYTES: usize = WORD_BITS / 8;
+
+/// Granularity in bytes at which modifications of the map are tracked for
+/// incremental persistence.
+const DIRTY_CHUNK_BYTES: usize = 64;
+
+/// Bytes of the map allocated at a time. Pages which were never written to
+/// are all free and pages without free blocks are all occupied, so neither
+/// is allocated, and the heap usage follows the part of the memory which is
+/// being filled rather than its size.
+const PAGE_BYTES: usize = 512;
+
+const ZERO_PAGE: [u8; PAGE_BYTES] = [0u8; PAGE_BYTES];
+const FULL_PAGE: [u8; PAGE_BYTES] = [u8::MAX; PAGE_BYTES];
+
+/// A page of the map, held on the heap only while it has both free and
+/// occupied blocks.
+#[derive(Clone)]
+enum Page {
+    Free,
+    Full,
+    Mixed(Box<[u8; PAGE_BYTES]>),
+}
+
+impl Page {
+    fn bytes(&self) -> &[u8; PAGE_BYTES] {
+        match self {
+            Page::Free => &ZERO_PAGE,
+            Page::Full => &FULL_PAGE,
+            Page::Mixed(bytes) => bytes,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Bitmap {
-    map: Vec<u8>,
+    map: Vec<Page>,
+    /// Length of the map in bytes.
+    len: usize,
+    /// One bit per 64-block word of `map`, set when the word has at least
+    /// one free block. Derived from `map` and never persisted.
+    summary: Vec<u64>,
+    /// Rotating allocation hint: `occupy_next` continues searching from the
+    /// block after the previous allocation.
+    cursor: usize,
+    /// Number of occupied blocks, kept up to date by every mutation.
+    occupied: usize,
+    /// For each of the two persisted copies of the map, one bit per chunk
+    /// modified since that copy was last written.
+    dirty: [Vec<u64>; 2],
+    /// Checksum of each chunk as of the last time it was written or read,
+    /// empty while all of them are free.
+    hashes: Vec<u64>,
+    /// Sum of `hashes`, which `write_dirty` keeps up to date by only
+    /// rehashing the chunks it writes.
+    checksum: u64,
+    policy: AllocationPolicy,
+    /// Number of free blocks held back for metadata. Never persisted.
+    reserved: usize,
+}
+
+/// How free blocks are chosen when a cluster grows.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum AllocationPolicy {
+    /// Take the lowest run of free blocks which fits.
+    FirstFit,
+    /// Continue searching after the previous allocation, wrapping around at
+    /// the end.
+    #[default]
+    NextFit,
+    /// Take the smallest run of free blocks which fits, keeping large runs
+    /// available for large files.
+    BestFit,
+    /// Place new blocks right after the last block of the growing cluster,
+    /// falling back to `NextFit` where that space is taken.
+    PreferExtension,
 }
 
 impl Bitmap {
-    pub fn new<M: Memory>() -> Self {
-        Self {
-            map: vec![0u8; Self::len_for_memory_impl::<M>()],
+    pub fn new(memory: &(impl Memory + ?Sized)) -> Self {
+        let len = Self::len_for_memory(memory);
+        let mut bitmap = Self {
+            map: vec![Page::Free; len.div_ceil(PAGE_BYTES)],
+            len,
+            summary: vec![],
+            cursor: 0,
+            occupied: 0,
+            dirty: [vec![], vec![]],
+            hashes: vec![],
+            checksum: 0,
+            policy: AllocationPolicy::default(),
+            reserved: 0,
+        };
+        bitmap.rebuild_summary();
+        bitmap.mark_all_dirty();
+        bitmap
+    }
+
+    fn byte(&self, offset: usize) -> u8 {
+        self.map[offset / PAGE_BYTES].bytes()[offset % PAGE_BYTES]
+    }
+
+    fn byte_mut(&mut self, offset: usize) -> &mut u8 {
+        let page = &mut self.map[offset / PAGE_BYTES];
+        if !matches!(page, Page::Mixed(_)) {
+            *page = Page::Mixed(Box::new(*page.bytes()));
+        }
+        match page {
+            Page::Mixed(bytes) => &mut bytes[offset % PAGE_BYTES],
+            _ => unreachable!(),
+        }
+    }
+
+    /// Bytes of the map in page `page`, which is shorter than the others if
+    /// it's the last.
+    fn page_len(&self, page: usize) -> usize {
+        (self.len - page * PAGE_BYTES).min(PAGE_BYTES)
+    }
+
+    /// Drops the bytes of page `page` from the heap if it has no free blocks
+    /// left.
+    fn compact(&mut self, page: usize) {
+        let len = self.page_len(page);
+        if let Page::Mixed(bytes) = &self.map[page] {
+            if bytes[..len].iter().all(|byte| *byte == u8::MAX) {
+                self.map[page] = Page::Full;
+            }
+        }
+    }
+
+    /// The bytes of the page containing `offset`, starting at `offset` and
+    /// ending at `end` or the end of the page.
+    fn bytes(&self, offset: usize, end: usize) -> &[u8] {
+        let start = offset % PAGE_BYTES;
+        let end = start + (end - offset).min(PAGE_BYTES - start);
+        &self.map[offset / PAGE_BYTES].bytes()[start..end]
+    }
+
+    fn write_range(&self, mut w: impl Write, start: usize, end: usize) -> io::Result<()> {
+        let mut offset = start;
+        while offset < end {
+            let bytes = self.bytes(offset, end);
+            w.write_all(bytes)?;
+            offset += bytes.len();
+        }
+        Ok(())
+    }
+
+    /// Length of the prefix of the map which may contain occupied blocks.
+    /// Everything after it is known to be free.
+    pub fn extent(&self) -> usize {
+        match self
+            .map
+            .iter()
+            .rposition(|page| !matches!(page, Page::Free))
+        {
+            Some(page) => ((page + 1) * PAGE_BYTES).min(self.len),
+            None => 0,
+        }
+    }
+
+    /// Writes the first `extent` bytes of the map.
+    pub fn write_extent(&self, w: impl Write, extent: usize) -> io::Result<()> {
+        self.write_range(w, 0, extent)
+    }
+
+    /// Replaces the map with `extent` bytes read from `r`, followed by free
+    /// blocks only. Only pages with both free and occupied blocks are
+    /// allocated.
+    pub fn read_extent(&mut self, mut r: impl Read, extent: usize) -> io::Result<()> {
+        if extent > self.len {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+
+        self.map = vec![Page::Free; self.len.div_ceil(PAGE_BYTES)];
+        let mut page = ZERO_PAGE;
+        for (i, start) in (0..extent).step_by(PAGE_BYTES).enumerate() {
+            let len = (extent - start).min(PAGE_BYTES);
+            page[len..].fill(0);
+            r.read_exact(&mut page[..len])?;
+            if page.iter().any(|byte| *byte != 0) {
+                self.map[i] = Page::Mixed(Box::new(page));
+                self.compact(i);
+            }
+        }
+
+        self.rebuild_summary();
+        self.mark_clean();
+        self.hashes = vec![];
+        self.checksum = 0;
+        for chunk in 0..extent.div_ceil(DIRTY_CHUNK_BYTES) {
+            self.rehash(chunk);
+        }
+        Ok(())
+    }
+
+    fn word_count(&self) -> usize {
+        self.len.div_ceil(WORD_BYTES)
+    }
+
+    fn word_has_free(&self, word: usize) -> bool {
+        let start = word * WORD_BYTES;
+        let end = (start + WORD_BYTES).min(self.len);
+        self.bytes(start, end).iter().any(|byte| *byte != u8::MAX)
+    }
+
+    fn update_summary(&mut self, word: usize) {
+        let bit = 1 << (word % 64);
+        if self.word_has_free(word) {
+            self.summary[word / 64] |= bit;
+        } else {
+            self.summary[word / 64] &= !bit;
         }
     }
 
-    pub fn len_for_memory_impl<M: Memory>() -> usize {
-        M::MAX_SIZE / Block::SIZE / 8
+    fn rebuild_summary(&mut self) {
+        self.summary = vec![0u64; self.word_count().div_ceil(64)];
+        for word in 0..self.word_count() {
+            self.update_summary(word);
+        }
+        self.occupied = (0..self.map.len())
+            .map(|i| match &self.map[i] {
+                Page::Free => 0,
+                Page::Full => self.page_len(i) * 8,
+                Page::Mixed(bytes) => (bytes[..self.page_len(i)].iter())
+                    .map(|b| b.count_ones() as usize)
+                    .sum(),
+            })
+            .sum();
+    }
+
+    fn chunk_count(&self) -> usize {
+        self.len.div_ceil(DIRTY_CHUNK_BYTES)
+    }
+
+    fn mark_dirty(&mut self, byte_offset: usize) {
+        let chunk = byte_offset / DIRTY_CHUNK_BYTES;
+        for dirty in &mut self.dirty {
+            dirty[chunk / 64] |= 1 << (chunk % 64);
+        }
+    }
+
+    /// Updates the checksum of a chunk, which is 0 for chunks without
+    /// occupied blocks, so the free part of the map needs no hashing.
+    fn rehash(&mut self, chunk: usize) {
+        let start = chunk * DIRTY_CHUNK_BYTES;
+        let bytes = self.bytes(start, (start + DIRTY_CHUNK_BYTES).min(self.len));
+        let hash = if bytes.iter().all(|byte| *byte == 0) {
+            0
+        } else {
+            let mut checksum = Checksum::default();
+            checksum.update(&(chunk as u64).to_be_bytes());
+            checksum.update(bytes);
+            checksum.value()
+        };
+        if self.hashes.is_empty() {
+            self.hashes = vec![0; self.chunk_count()];
+        }
+        self.checksum = self
+            .checksum
+            .wrapping_sub(self.hashes[chunk])
+            .wrapping_add(hash);
+        self.hashes[chunk] = hash;
+    }
+
+    /// Checksum of the map as last written by `write_dirty` or read by
+    /// `read_extent`: the sum of the checksums of its chunks, each with its
+    /// index, so it's updated without hashing the chunks which didn't
+    /// change.
+    pub fn checksum(&self) -> u64 {
+        self.checksum
+    }
+
+    pub fn mark_all_dirty(&mut self) {
+        self.mark_copy_dirty(0);
+        self.mark_copy_dirty(1);
+    }
+
+    /// Marks the whole map as modified for one copy only, e.g. for a copy
+    /// which is known to be outdated.
+    pub fn mark_copy_dirty(&mut self, copy: usize) {
+        self.dirty[copy] = vec![u64::MAX; self.chunk_count().div_ceil(64)];
+    }
+
+    /// Marks the chunks in which the map differs from `previous`, the map
+    /// `copy` was last written with, as modified for that copy only. Chunks
+    /// past the extent of `previous` count as modified, as what's stored
+    /// there may be older still.
+    pub fn mark_copy_dirty_since(&mut self, copy: usize, previous: &Bitmap) {
+        let written = previous.extent();
+        let mut dirty = vec![0u64; self.chunk_count().div_ceil(64)];
+        for chunk in 0..self.chunk_count() {
+            let start = chunk * DIRTY_CHUNK_BYTES;
+            let end = (start + DIRTY_CHUNK_BYTES).min(self.len);
+            if end > written || self.bytes(start, end) != previous.bytes(start, end) {
+                dirty[chunk / 64] |= 1 << (chunk % 64);
+            }
+        }
+        self.dirty[copy] = dirty;
+    }
+
+    /// Whether anything changed since `copy` was last written.
+    pub fn is_copy_dirty(&self, copy: usize) -> bool {
+        self.dirty[copy].iter().any(|&bits| bits != 0)
+    }
+
+    pub fn mark_clean(&mut self) {
+        self.dirty = [
+            vec![0u64; self.chunk_count().div_ceil(64)],
+            vec![0u64; self.chunk_count().div_ceil(64)],
+        ];
+    }
+
+    /// Writes only the chunks of the map modified since `copy` was last
+    /// written, seeking to their offsets relative to `offset` in `w`, and
+    /// updates the checksum for them. Chunks past the extent are skipped, as
+    /// they are never read back. Returns the number of bytes written.
+    pub fn write_dirty<W: Write + Seek>(
+        &mut self,
+        copy: usize,
+        mut w: W,
+        offset: u64,
+    ) -> io::Result<usize> {
+        let chunks = self.extent().div_ceil(DIRTY_CHUNK_BYTES);
+        // Marking a whole copy dirty sets the bits past the last chunk too.
+        let dirty = set_bits(&self.dirty[copy])
+            .take_while(|&chunk| chunk < self.chunk_count())
+            .collect::<Vec<_>>();
+        let mut written = 0;
+        let mut runs = dirty
+            .iter()
+            .copied()
+            .filter(|&chunk| chunk < chunks)
+            .peekable();
+        while let Some(first) = runs.next() {
+            let mut last = first;
+            while runs.peek() == Some(&(last + 1)) {
+                last += 1;
+                runs.next();
+            }
+
+            let start = first * DIRTY_CHUNK_BYTES;
+            let end = ((last + 1) * DIRTY_CHUNK_BYTES).min(self.len);
+            w.seek(io::SeekFrom::Start(offset + start as u64))?;
+            self.write_range(&mut w, start, end)?;
+            written += end - start;
+        }
+
+        for chunk in dirty {
+            self.rehash(chunk);
+        }
+        self.dirty[copy] = vec![0u64; self.chunk_count().div_ceil(64)];
+        Ok(written)
+    }
+
+    pub fn len_for_memory(memory: &(impl Memory + ?Sized)) -> usize {
+        // A bit per block, which is at most a few MiB even for 64-bit memories.
+        (memory.max_size() / Block::SIZE as u64 / 8) as usize
     }
 
     pub fn occupy(&mut self, index: usize) {
@@ -27,7 +375,15 @@ impl Bitmap {
 
         assert!(byte_offset < self.len());
 
-        self.map[byte_offset] |= 1 << bit_offset;
+        if self.byte(byte_offset) & (1 << bit_offset) == 0 {
+            self.occupied += 1;
+            self.mark_dirty(byte_offset);
+        }
+        *self.byte_mut(byte_offset) |= 1 << bit_offset;
+        if self.byte(byte_offset) == u8::MAX {
+            self.update_summary(byte_offset / WORD_BYTES);
+            self.compact(byte_offset / PAGE_BYTES);
+        }
     }
 
     pub fn free(&mut self, index: usize) {
@@ -36,11 +392,164 @@ impl Bitmap {
 
         assert!(byte_offset < self.len());
 
-        self.map[byte_offset] &= !(1 << bit_offset);
+        if self.byte(byte_offset) & (1 << bit_offset) != 0 {
+            self.occupied -= 1;
+            self.mark_dirty(byte_offset);
+            *self.byte_mut(byte_offset) &= !(1 << bit_offset);
+        }
+        self.summary[byte_offset / WORD_BYTES / 64] |= 1 << (byte_offset / WORD_BYTES % 64);
+    }
+
+    pub fn occupy_range(&mut self, range: Range<usize>) {
+        self.set_range(range, true);
+    }
+
+    pub fn free_range(&mut self, range: Range<usize>) {
+        self.set_range(range, false);
+    }
+
+    /// Sets or clears all bits of `range`, touching whole bytes at a time
+    /// where possible.
+    fn set_range(&mut self, range: Range<usize>, occupied: bool) {
+        if range.is_empty() {
+            return;
+        }
+        assert!((range.end - 1) / 8 < self.len());
+
+        let mut i = range.start;
+        while i < range.end {
+            let byte_offset = i / 8;
+            let bit_offset = i % 8;
+            let bits = (8 - bit_offset).min(range.end - i);
+            let mask = (((1u16 << bits) - 1) << bit_offset) as u8;
+
+            let before = (self.byte(byte_offset) & mask).count_ones() as usize;
+            if before != if occupied { bits } else { 0 } {
+                self.mark_dirty(byte_offset);
+                if occupied {
+                    *self.byte_mut(byte_offset) |= mask;
+                    self.occupied += bits - before;
+                } else {
+                    *self.byte_mut(byte_offset) &= !mask;
+                    self.occupied -= before;
+                }
+            }
+            i += bits;
+        }
+
+        for word in range.start / WORD_BITS..=(range.end - 1) / WORD_BITS {
+            self.update_summary(word);
+        }
+        if occupied {
+            for page in range.start / 8 / PAGE_BYTES..=(range.end - 1) / 8 / PAGE_BYTES {
+                self.compact(page);
+            }
+        }
+    }
+
+    /// Counts the free blocks in a row starting at `start`, up to `len`.
+    fn run_len(&self, start: usize, len: usize) -> usize {
+        let slots = self.len() * 8;
+        (start..(start + len).min(slots))
+            .take_while(|i| self[*i] == BitState::Free)
+            .count()
+    }
+
+    pub fn policy(&self) -> AllocationPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: AllocationPolicy) {
+        self.policy = policy;
+    }
+
+    /// Finds and occupies `len` contiguous free blocks according to the
+    /// allocation policy. Returns the index of the first block.
+    pub fn allocate_contiguous(&mut self, len: usize) -> Option<usize> {
+        self.allocate_contiguous_after(None, len)
+    }
+
+    /// Like `allocate_contiguous`, for blocks which are going to follow the
+    /// block `after`.
+    pub fn allocate_contiguous_after(&mut self, after: Option<usize>, len: usize) -> Option<usize> {
+        if len == 0 {
+            return None;
+        }
+
+        let next_fit = |bitmap: &Self| {
+            bitmap
+                .find_contiguous(bitmap.cursor, len)
+                .or_else(|| bitmap.find_contiguous(0, len))
+        };
+        let start = match self.policy {
+            AllocationPolicy::FirstFit => self.find_contiguous(0, len),
+            AllocationPolicy::NextFit => next_fit(self),
+            AllocationPolicy::BestFit => self.find_best_fit(len),
+            AllocationPolicy::PreferExtension => after
+                .map(|after| after + 1)
+                .filter(|start| self.run_len(*start, len) == len)
+                .or_else(|| next_fit(self)),
+        }?;
+        self.occupy_range(start..start + len);
+        self.cursor = start + len;
+        Some(start)
+    }
+
+    fn find_best_fit(&self, len: usize) -> Option<usize> {
+        let slots = self.len() * 8;
+        let mut best: Option<(usize, usize)> = None;
+        let mut start = self.next_free(0)?;
+        loop {
+            let run = self.run_len(start, slots - start);
+            if run == len {
+                return Some(start);
+            }
+            if run > len && best.is_none_or(|(_, best_run)| run < best_run) {
+                best = Some((start, run));
+            }
+            match self.next_free(start + run) {
+                Some(next) => start = next,
+                None => return best.map(|(start, _)| start),
+            }
+        }
+    }
+
+    fn find_contiguous(&self, from: usize, len: usize) -> Option<usize> {
+        let mut start = self.next_free(from)?;
+        loop {
+            let run = self.run_len(start, len);
+            if run == len {
+                return Some(start);
+            }
+            start = self.next_free(start + run)?;
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.map.len()
+        self.len
+    }
+
+    pub fn occupied_blocks(&self) -> usize {
+        self.occupied
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.len() * 8 - self.occupied
+    }
+
+    pub fn reserved(&self) -> usize {
+        self.reserved
+    }
+
+    /// Holds back `blocks` free blocks, which only allocations for metadata
+    /// may take. See `free_data_blocks`.
+    pub fn set_reserved(&mut self, blocks: usize) {
+        self.reserved = blocks;
+    }
+
+    /// Free blocks outside of the reserve for metadata.
+    pub fn free_data_blocks(&self) -> usize {
+        self.free_blocks().saturating_sub(self.reserved)
     }
 
     pub fn iter(&self) -> impl '_ + Iterator<Item = BitState> {
@@ -51,16 +560,56 @@ impl Bitmap {
         }
     }
 
-    pub fn occupy_next(&mut self) -> Option<usize> {
-        let mut result = None;
-        for (i, state) in self.iter().enumerate() {
-            if let BitState::Free = state {
-                result = Some(i);
-                break;
+    /// Finds the lowest free block at or after `from` by consulting the
+    /// summary level, so only the words known to have free space have to
+    /// be inspected.
+    pub fn next_free(&self, from: usize) -> Option<usize> {
+        let mut word = from / WORD_BITS;
+        if word >= self.word_count() {
+            return None;
+        }
+
+        if let Some(i) = self.free_in_word(word, from) {
+            return Some(i);
+        }
+
+        word += 1;
+        while word < self.word_count() {
+            let summary = self.summary[word / 64] >> (word % 64);
+            if summary == 0 {
+                word = (word / 64 + 1) * 64;
+                continue;
             }
+            word += summary.trailing_zeros() as usize;
+            return self.free_in_word(word, word * WORD_BITS);
         }
+        None
+    }
+
+    fn free_in_word(&self, word: usize, from: usize) -> Option<usize> {
+        let start = word * WORD_BYTES;
+        let end = (start + WORD_BYTES).min(self.len);
+        (from / 8..end).find_map(|byte_offset| {
+            let mut byte = self.byte(byte_offset);
+            if byte_offset == from / 8 {
+                byte |= (1 << (from % 8)) - 1;
+            }
+            match byte {
+                u8::MAX => None,
+                byte => Some(byte_offset * 8 + (!byte).trailing_zeros() as usize),
+            }
+        })
+    }
+
+    pub fn first_free(&self) -> Option<usize> {
+        self.next_free(0)
+    }
+
+    pub fn occupy_next(&mut self) -> Option<usize> {
+        let result = self.next_free(self.cursor).or_else(|| self.first_free());
         if let Some(i) = &result {
             self.occupy(*i);
+            self.cursor = *i + 1;
         }
         result
     }
@@ -80,16 +629,16 @@ impl fmt::Debug for Bitmap {
 }
 
 impl Serialize for Bitmap {
-    fn serialize(&self, mut writer: impl Write) -> io::Result<usize> {
-        writer.write_all(&self.map)?;
-        Ok(self.map.len())
+    fn serialize(&self, writer: impl Write) -> io::Result<usize> {
+        self.write_range(writer, 0, self.len)?;
+        Ok(self.len)
     }
 }
 
 impl Deserialize for Bitmap {
-    fn deserialize(&mut self, mut r: impl Read) -> io::Result<usize> {
-        r.read_exact(&mut self.map)?;
-        Ok(self.map.len())
+    fn deserialize(&mut self, r: impl Read) -> io::Result<usize> {
+        self.read_extent(r, self.len)?;
+        Ok(self.len)
     }
 }
 
@@ -109,9 +658,9 @@ impl std::ops::Index<usize> for Bitmap {
         let byte_offset = index / 8;
         let bit_offset = index % 8;
 
-        assert!(byte_offset < self.map.len());
+        assert!(byte_offset < self.len);
 
-        match (self.map[byte_offset] >> bit_offset) & 1 {
+        match (self.byte(byte_offset) >> bit_offset) & 1 {
             1 => &OCCUPIED,
             0 => &FREE,
             _ => unreachable!(),
@@ -133,7 +682,7 @@ impl<'a> Iterator for BitStateIterator<'a> {
             return None;
         }
 
-        let state = match (self.map.map[self.byte_offset] >> self.bit_offset) & 1 {
+        let state = match (self.map.byte(self.byte_offset) >> self.bit_offset) & 1 {
             1 => BitState::Occupied,
             0 => BitState::Free,
             _ => unreachable!(),
@@ -153,7 +702,7 @@ impl<'a> Iterator for BitStateIterator<'a> {
 fn bitmap() {
     use crate::heap_memory::HeapMemory;
 
-    let mut bitmap: Bitmap = Bitmap::new::<HeapMemory>();
+    let mut bitmap: Bitmap = Bitmap::new(&HeapMemory::default());
 
     assert_eq!(bitmap[7], BitState::Free);
 
@@ -161,7 +710,7 @@ fn bitmap() {
 
     assert_eq!(bitmap[7], BitState::Occupied);
 
-    let slots = Bitmap::len_for_memory_impl::<HeapMemory>();
+    let slots = Bitmap::len_for_memory(&HeapMemory::default());
 
     assert_eq!(bitmap[slots - 1], BitState::Free);
     assert_eq!(bitmap[0], BitState::Free);
@@ -175,3 +724,262 @@ fn bitmap() {
     bitmap.free(slots - 1);
     assert_eq!(bitmap[slots - 1], BitState::Free);
 }
+
+#[test]
+fn counts() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut bitmap: Bitmap = Bitmap::new(&HeapMemory::default());
+    let slots = Bitmap::len_for_memory(&HeapMemory::default()) * 8;
+    assert_eq!(bitmap.free_blocks(), slots);
+
+    bitmap.occupy(5);
+    bitmap.occupy(5);
+    bitmap.occupy_range(3..20);
+    assert_eq!(bitmap.occupied_blocks(), 17);
+
+    bitmap.free(5);
+    bitmap.free(5);
+    bitmap.free_range(0..4);
+    assert_eq!(bitmap.occupied_blocks(), 15);
+    assert_eq!(bitmap.free_blocks(), slots - 15);
+
+    let mut data = vec![];
+    bitmap.serialize(&mut data).unwrap();
+    let mut restored = Bitmap::new(&HeapMemory::default());
+    restored.deserialize(&*data).unwrap();
+    assert_eq!(restored.occupied_blocks(), 15);
+    assert_eq!(
+        restored.occupied_blocks(),
+        restored.iter().filter(|s| s == &BitState::Occupied).count()
+    );
+}
+
+#[test]
+fn summary() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut bitmap: Bitmap = Bitmap::new(&HeapMemory::default());
+    let slots = Bitmap::len_for_memory(&HeapMemory::default()) * 8;
+
+    for i in 0..slots {
+        assert_eq!(bitmap.occupy_next(), Some(i));
+    }
+    assert_eq!(bitmap.first_free(), None);
+    assert_eq!(bitmap.occupy_next(), None);
+
+    bitmap.free(300);
+    bitmap.free(70);
+    assert_eq!(bitmap.first_free(), Some(70));
+    assert_eq!(bitmap.occupy_next(), Some(70));
+    assert_eq!(bitmap.occupy_next(), Some(300));
+
+    let mut restored = Bitmap::new(&HeapMemory::default());
+    bitmap.free(129);
+    let mut data = vec![];
+    bitmap.serialize(&mut data).unwrap();
+    restored.deserialize(&*data).unwrap();
+    assert_eq!(restored.first_free(), Some(129));
+}
+
+#[test]
+fn cursor() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut bitmap: Bitmap = Bitmap::new(&HeapMemory::default());
+    let slots = Bitmap::len_for_memory(&HeapMemory::default()) * 8;
+
+    for i in 0..10 {
+        assert_eq!(bitmap.occupy_next(), Some(i));
+    }
+
+    // Freed blocks behind the cursor are only reused after wrapping around.
+    bitmap.free(3);
+    assert_eq!(bitmap.first_free(), Some(3));
+    assert_eq!(bitmap.occupy_next(), Some(10));
+
+    for i in 11..slots {
+        assert_eq!(bitmap.occupy_next(), Some(i));
+    }
+    assert_eq!(bitmap.occupy_next(), Some(3));
+    assert_eq!(bitmap.occupy_next(), None);
+}
+
+#[test]
+fn ranges() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut bitmap: Bitmap = Bitmap::new(&HeapMemory::default());
+
+    bitmap.occupy_range(3..150);
+    assert_eq!(bitmap[2], BitState::Free);
+    assert!((3..150).all(|i| bitmap[i] == BitState::Occupied));
+    assert_eq!(bitmap[150], BitState::Free);
+    assert_eq!(bitmap.next_free(3), Some(150));
+
+    bitmap.free_range(10..20);
+    assert!((10..20).all(|i| bitmap[i] == BitState::Free));
+    assert_eq!(bitmap[20], BitState::Occupied);
+
+    // The gap of three blocks at the start is too small, the freed range fits.
+    assert_eq!(bitmap.allocate_contiguous(5), Some(10));
+    assert_eq!(bitmap.allocate_contiguous(8), Some(150));
+
+    let slots = Bitmap::len_for_memory(&HeapMemory::default()) * 8;
+    bitmap.occupy_range(158..slots);
+    assert_eq!(bitmap.allocate_contiguous(3), Some(0));
+    assert_eq!(bitmap.allocate_contiguous(3), Some(15));
+    assert_eq!(bitmap.allocate_contiguous(3), None);
+}
+
+/// Indices of the set bits of `words`, skipping words without any.
+fn set_bits(words: &[u64]) -> impl '_ + Iterator<Item = usize> {
+    words
+        .iter()
+        .enumerate()
+        .filter(|(_, bits)| **bits != 0)
+        .flat_map(|(i, &bits)| {
+            (0..64)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+}
+
+#[test]
+fn dirty() {
+    use crate::heap_memory::HeapMemory;
+    use crate::memory::Memory;
+
+    let mut heap = HeapMemory::default();
+    let mut bitmap: Bitmap = Bitmap::new(&HeapMemory::default());
+    let len = bitmap.len();
+
+    // Nothing is written past the extent in use, which is empty so far.
+    assert_eq!(bitmap.write_dirty(0, heap.writer(), 0).unwrap(), 0);
+
+    // Neither setting occupied bits nor clearing free ones dirties the map.
+    bitmap.occupy_range(0..0);
+    bitmap.free(3);
+    assert_eq!(bitmap.write_dirty(0, heap.writer(), 0).unwrap(), 0);
+
+    bitmap.occupy(3);
+    bitmap.occupy_range(10..20);
+    bitmap.occupy(len * 8 - 1);
+    assert_eq!(
+        bitmap.write_dirty(0, heap.writer(), 0).unwrap(),
+        len.min(DIRTY_CHUNK_BYTES)
+    );
+
+    // The other copy was never written, so all of it is still dirty.
+    let offset = len as u64;
+    assert_eq!(bitmap.write_dirty(1, heap.writer(), offset).unwrap(), len);
+
+    let mut restored = Bitmap::new(&HeapMemory::default());
+    restored.deserialize(heap.reader()).unwrap();
+    assert!(restored.iter().eq(bitmap.iter()));
+    assert_eq!(restored.write_dirty(0, heap.writer(), 0).unwrap(), 0);
+    assert!(!restored.is_copy_dirty(0));
+    assert_eq!(restored.checksum(), bitmap.checksum());
+
+    // Only the written chunks are hashed again, which gives the checksum of
+    // the map as read back.
+    bitmap.free_range(10..20);
+    bitmap.write_dirty(0, heap.writer(), 0).unwrap();
+    let mut reread = Bitmap::new(&HeapMemory::default());
+    reread.read_extent(heap.reader(), len).unwrap();
+    assert_eq!(reread.checksum(), bitmap.checksum());
+    assert_ne!(reread.checksum(), restored.checksum());
+}
+
+#[test]
+fn dirty_since() {
+    use crate::heap_memory::HeapMemory;
+
+    let memory = HeapMemory::with_max_size(1 << 26);
+    let mut heap = HeapMemory::with_max_size(1 << 26);
+    let mut bitmap = Bitmap::new(&memory);
+    let chunk_blocks = DIRTY_CHUNK_BYTES * 8;
+    bitmap.occupy_range(0..chunk_blocks * 4);
+    bitmap.mark_clean();
+
+    // Only the chunks which differ from the other copy are rewritten in it,
+    // and those past the extent that copy was written with.
+    let previous = bitmap.clone();
+    bitmap.free(chunk_blocks + 1);
+    bitmap.occupy(PAGE_BYTES * 8 + chunk_blocks);
+    bitmap.mark_clean();
+    bitmap.mark_copy_dirty_since(1, &previous);
+    assert!(!bitmap.is_copy_dirty(0));
+    assert!(bitmap.is_copy_dirty(1));
+    let written = bitmap.write_dirty(1, heap.writer(), 0).unwrap();
+    assert_eq!(written, DIRTY_CHUNK_BYTES + PAGE_BYTES);
+
+    bitmap.mark_copy_dirty_since(0, &bitmap.clone());
+    assert_eq!(bitmap.write_dirty(0, heap.writer(), 0).unwrap(), 0);
+}
+
+#[test]
+fn policies() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut bitmap: Bitmap = Bitmap::new(&HeapMemory::default());
+    let slots = Bitmap::len_for_memory(&HeapMemory::default()) * 8;
+
+    // Holes of 6 blocks at 0, 2 blocks at 20 and 4 blocks at 30.
+    bitmap.occupy_range(6..20);
+    bitmap.occupy_range(22..30);
+    bitmap.occupy_range(34..slots);
+
+    bitmap.set_policy(AllocationPolicy::BestFit);
+    assert_eq!(bitmap.allocate_contiguous(2), Some(20));
+    assert_eq!(bitmap.allocate_contiguous(3), Some(30));
+
+    bitmap.set_policy(AllocationPolicy::NextFit);
+    assert_eq!(bitmap.allocate_contiguous(1), Some(33));
+
+    bitmap.set_policy(AllocationPolicy::FirstFit);
+    assert_eq!(bitmap.allocate_contiguous(1), Some(0));
+
+    bitmap.set_policy(AllocationPolicy::PreferExtension);
+    assert_eq!(bitmap.allocate_contiguous_after(Some(2), 2), Some(3));
+    assert_eq!(bitmap.allocate_contiguous_after(Some(20), 1), Some(5));
+}
+
+#[test]
+fn extent() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut bitmap: Bitmap = Bitmap::new(&HeapMemory::default());
+    assert_eq!(bitmap.extent(), 0);
+    assert!(bitmap.map.iter().all(|page| matches!(page, Page::Free)));
+
+    bitmap.occupy_range(0..10);
+    bitmap.occupy(300);
+    assert_eq!(bitmap.extent(), bitmap.len().min(PAGE_BYTES));
+
+    let mut data = vec![];
+    bitmap.write_extent(&mut data, bitmap.extent()).unwrap();
+    let mut restored = Bitmap::new(&HeapMemory::default());
+    restored.read_extent(&*data, data.len()).unwrap();
+    assert!(restored.iter().eq(bitmap.iter()));
+    assert_eq!(restored.occupied_blocks(), 11);
+    assert_eq!(restored.first_free(), Some(10));
+
+    // Freeing doesn't need to allocate anything.
+    let mut empty = Bitmap::new(&HeapMemory::default());
+    empty.free_range(0..100);
+    empty.free(200);
+    assert_eq!(empty.extent(), 0);
+
+    // Neither are pages without free blocks. Stable memory takes a map of
+    // several pages.
+    let mut full = Bitmap::new(&crate::stable_memory::StableMemory::default());
+    full.occupy_range(0..PAGE_BYTES * 8 + 3);
+    assert!(matches!(full.map[0], Page::Full));
+    assert!(matches!(full.map[1], Page::Mixed(_)));
+    full.free(5);
+    assert!(matches!(full.map[0], Page::Mixed(_)));
+    full.occupy(5);
+    assert!(matches!(full.map[0], Page::Full));
+    assert_eq!(full.occupied_blocks(), PAGE_BYTES * 8 + 3);
+}
@@ -11,6 +11,11 @@ impl Block {
     pub fn at(index: usize) -> Self {
         Block { index }
     }
+
+    /// The offset of the block in memory.
+    pub fn offset(self) -> u64 {
+        self.index as u64 * Self::SIZE as u64
+    }
 }
 
 impl Add<usize> for Block {
@@ -1,189 +1,1235 @@
+#[cfg(test)]
+use std::cell::Cell;
 use std::cell::RefCell;
-use std::io::{self, Read, Seek, Write};
+use std::convert::TryFrom;
+use std::io;
 
+use ic_cdk::export::candid::de::IDLDeserialize;
 use ic_cdk::export::candid::types::Serializer;
-use ic_cdk::export::candid::{CandidType, Deserialize};
+use ic_cdk::export::candid::{candid_method, export_service, CandidType, Deserialize, Principal};
 use ic_cdk::export::serde::Deserializer;
-use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use ic_cdk_macros::{heartbeat, init, inspect_message, post_upgrade, pre_upgrade, query, update};
 use percent_encoding::{percent_decode, utf8_percent_encode, CONTROLS};
 
-use crate::directory;
-use crate::file_system::FileSystem;
+use crate::access::{self, Access, WriteMode};
+use crate::assets::{self, Assets};
+use crate::directory::{Acl, Directory, Entry, Fallback, Redirect};
+use crate::error::Error;
+use crate::file_system::{Durability, FileSystem, MetadataChange, SortOrder, WriteFileMode};
+use crate::http::{self, HttpRequest, HttpResponse, StreamingCallbackHttpResponse, StreamingToken};
+use crate::interop::{ApiError, EntryStat, FileInfo};
+use crate::maintenance::{self, Schedule};
+use crate::memory::Memory;
 use crate::stable_memory::StableMemory;
+use crate::tenants::{self, Tenancy};
+use crate::tokens;
+use crate::tree::FindCursor;
+use crate::uploads::{UploadStatus, Uploads};
 
 thread_local! {
-    static FILE_SYSTEM: RefCell<FileSystem<StableMemory>> =
-        RefCell::new(FileSystem::allocate(StableMemory));
+    // Persisting after every change keeps stable memory consistent between
+    // messages, so an upgrade has next to nothing left to write.
+    static FILE_SYSTEM: RefCell<FileSystem<StableMemory>> = RefCell::new(
+        FileSystem::allocate(StableMemory::default())
+            .with_clock(ic_cdk::api::time)
+            .with_durability(Durability::AfterEveryMutation),
+    );
+    // Uploads through the asset canister interface don't survive upgrades.
+    static ASSETS: RefCell<Assets> = RefCell::new(Assets::default());
+    // Uploads in progress are kept in the settings, and loaded again after
+    // upgrades.
+    static UPLOADS: RefCell<Uploads> = RefCell::new(Uploads::default());
+    static SCHEDULE: RefCell<Schedule> = RefCell::new(Schedule::default());
+}
+
+/// Who runs the canister. Admins default to whoever installs it, and reads
+/// are open to everyone unless `publicReads` is false. Only controllers may
+/// change anything unless `writeMode` lets others. With `tenancy` enabled,
+/// everyone else is kept in a home directory of their own.
+#[derive(Default, CandidType, Deserialize)]
+struct InitArgs {
+    admins: Option<Vec<Principal>>,
+    #[serde(rename = "publicReads")]
+    public_reads: Option<bool>,
+    #[serde(rename = "writeMode")]
+    write_mode: Option<WriteMode>,
+    tenancy: Option<Tenancy>,
+}
+
+/// Sets the ACL of the root directory from `args`, keeping what they leave
+/// out. Admins are its writers.
+fn configure(fs: &mut FileSystem<StableMemory>, args: Option<InitArgs>) -> io::Result<()> {
+    let mut root = fs.read_root_directory()?.acl.unwrap_or_else(|| Acl {
+        writers: vec![caller()],
+        readers: vec![Acl::EVERYONE.to_owned()],
+        ..Default::default()
+    });
+    let args = args.unwrap_or_default();
+    if let Some(admins) = args.admins {
+        root.writers = admins.iter().map(Principal::to_text).collect();
+    }
+    if let Some(public) = args.public_reads {
+        root.readers.retain(|p| p != Acl::EVERYONE);
+        if public {
+            root.readers.push(Acl::EVERYONE.to_owned());
+        }
+    }
+    if let Some(mode) = args.write_mode {
+        mode.write(fs)?;
+    }
+    if let Some(tenancy) = args.tenancy {
+        tenancy.write(fs)?;
+    }
+    fs.set_acl(Vec::<String>::new(), Some(root))
+}
+
+fn caller() -> String {
+    caller_principal().to_text()
+}
+
+#[cfg(not(test))]
+fn caller_principal() -> Principal {
+    ic_cdk::caller()
+}
+
+// There's no call to take the caller from in tests, which set it here.
+#[cfg(test)]
+thread_local! {
+    static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+}
+
+#[cfg(test)]
+fn caller_principal() -> Principal {
+    CALLER.with(Cell::get)
+}
+
+/// Whether the caller controls the canister.
+#[cfg(target_arch = "wasm32")]
+fn is_controller() -> bool {
+    #[link(wasm_import_module = "ic0")]
+    extern "C" {
+        fn is_controller(src: u32, size: u32) -> u32;
+    }
+    let caller = ic_cdk::caller();
+    let bytes = caller.as_slice();
+    // Safety: the system only reads the principal's bytes.
+    unsafe { is_controller(bytes.as_ptr() as u32, bytes.len() as u32) == 1 }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn is_controller() -> bool {
+    false
+}
+
+/// Fails unless the caller may have `access` to `path`. Anything but reads
+/// also needs the write mode to let the caller in.
+fn authorize(fs: &FileSystem<StableMemory>, path: &[String], access: Access) -> io::Result<()> {
+    if access != Access::Read {
+        authorize_writes(fs)?;
+    }
+    access::check(fs, path, &caller(), access)
+}
+
+/// Fails unless the caller is an admin.
+fn authorize_admin(fs: &FileSystem<StableMemory>) -> io::Result<()> {
+    if !access::is_admin(fs, &caller())? {
+        return Err(Error::AccessDenied.into());
+    }
+    Ok(())
+}
+
+/// Fails unless the write mode lets the caller change anything.
+fn authorize_writes(fs: &FileSystem<StableMemory>) -> io::Result<()> {
+    if !WriteMode::read(fs)?.admits(&caller_principal(), is_controller()) {
+        return Err(Error::AccessDenied.into());
+    }
+    Ok(())
+}
+
+/// Where the caller's paths start: its home directory in multi-tenant
+/// mode, which anonymous callers have none of, and otherwise the root
+/// directory. Admins always start at the root directory.
+fn tenant_root(fs: &FileSystem<StableMemory>) -> io::Result<Vec<String>> {
+    if !Tenancy::read(fs)?.enabled || access::is_admin(fs, &caller())? {
+        return Ok(vec![]);
+    }
+    if caller_principal() == Principal::anonymous() {
+        return Err(Error::AccessDenied.into());
+    }
+    Ok(tenants::home(&caller()))
+}
+
+/// `path` as the caller sees it, from its root.
+fn rooted(fs: &FileSystem<StableMemory>, path: Path) -> io::Result<Path> {
+    let mut segments = tenant_root(fs)?;
+    segments.extend(path.segments);
+    Ok(Path { segments })
+}
+
+/// Like `rooted`, making the caller's home directory if it has none yet
+/// and may write.
+fn rooted_mut(fs: &mut FileSystem<StableMemory>, path: Path) -> io::Result<Path> {
+    let root = tenant_root(fs)?;
+    if !root.is_empty() && !fs.exists(&root) {
+        authorize_writes(fs)?;
+        tenants::make_home(fs, &caller())?;
+    }
+    rooted(fs, path)
+}
+
+/// Fails if the caller is a tenant without room in its quota for the file
+/// at `path` to grow to `size` bytes.
+fn check_quota(fs: &FileSystem<StableMemory>, path: &[String], size: u64) -> io::Result<()> {
+    if tenant_root(fs)?.is_empty() {
+        return Ok(());
+    }
+    let current = fs.metadata(path).map_or(0, |meta| meta.size);
+    tenants::check_quota(fs, &caller(), size.saturating_sub(current))
 }
 
 #[init]
-fn init() {
-    FILE_SYSTEM.with(|fs| fs.borrow_mut().init()).unwrap()
+#[candid_method(init)]
+fn init(args: Option<InitArgs>) {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            fs.init()?;
+            configure(&mut fs, args)
+        })
+        .unwrap()
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    FILE_SYSTEM.with(|fs| fs.borrow_mut().persist()).unwrap()
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            // Only a call which failed halfway leaves anything behind.
+            if fs.is_persisted() {
+                return Ok(());
+            }
+            fs.persist()
+        })
+        .unwrap()
 }
 
+/// Canisters installed before ACLs get whoever upgrades them as their
+/// admin, and keep their reads open.
 #[post_upgrade]
-fn post_upgrade() {
-    FILE_SYSTEM.with(|fs| fs.borrow_mut().restore()).unwrap()
+fn post_upgrade(args: Option<InitArgs>) {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            fs.restore()?;
+            let uploads = Uploads::load(&fs)?;
+            UPLOADS.with(|u| *u.borrow_mut() = uploads);
+            configure(&mut fs, args)
+        })
+        .unwrap()
+}
+
+/// Instructions a heartbeat may take for maintenance, well below the limit
+/// of a message, as a slice may run past it.
+const MAINTENANCE_BUDGET: u64 = 1_000_000_000;
+
+/// Instructions the current message has taken so far.
+#[cfg(target_arch = "wasm32")]
+fn instructions() -> u64 {
+    #[link(wasm_import_module = "ic0")]
+    extern "C" {
+        fn performance_counter(counter_type: u32) -> u64;
+    }
+    // Safety: counter 0 is the instructions of the current message.
+    unsafe { performance_counter(0) }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn instructions() -> u64 {
+    0
+}
+
+/// Runs the maintenance jobs which are due, in slices, until the budget is
+/// spent. Only one heartbeat per tick does anything. A job which fails is
+/// tried again after its interval.
+#[heartbeat]
+fn heartbeat() {
+    let now = ic_cdk::api::time();
+    if !SCHEDULE.with(|s| s.borrow_mut().wake(now)) {
+        return;
+    }
+    let out_of_budget = || instructions() > MAINTENANCE_BUDGET;
+    for job in SCHEDULE.with(|s| s.borrow().due(now)) {
+        if out_of_budget() {
+            break;
+        }
+        let done = FILE_SYSTEM.with(|fs| {
+            UPLOADS.with(|u| {
+                let (mut fs, mut uploads) = (fs.borrow_mut(), u.borrow_mut());
+                maintenance::run(&mut fs, &mut uploads, job, now, out_of_budget)
+            })
+        });
+        match done {
+            Ok(false) => {}
+            Ok(true) => SCHEDULE.with(|s| s.borrow_mut().finished(job, now)),
+            Err(e) => {
+                ic_cdk::println!("{:?} failed: {}", job, e);
+                SCHEDULE.with(|s| s.borrow_mut().finished(job, now));
+            }
+        }
+    }
 }
 
+// The filesystem's own endpoints return errors rather than trap. Unlike a
+// trap, an error doesn't roll the call back, so what a call changed before
+// failing stays. Endpoints fail before they change anything, except for
+// `writeFile`, which traps when space runs out part way through a write.
+
+/// Without a limit, lists the whole directory, which can be too large for
+/// a response. The cursor is the offset of the next page.
 #[query(name = "openDirectory")]
-fn open_directory(path: Path) -> Directory {
+#[candid_method(query, rename = "openDirectory")]
+fn open_directory(
+    path: Path,
+    offset: Option<u64>,
+    limit: Option<u64>,
+    prefix: Option<String>,
+    order: Option<SortOrder>,
+) -> Result<(Directory, Option<u64>), ApiError> {
     FILE_SYSTEM
         .with(|fs| {
             let fs = fs.borrow();
-            fs.with_directory(path, |dir| Ok(Directory::from(dir)))
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Read)?;
+            let to_usize = |n: u64| usize::try_from(n).unwrap_or(usize::MAX);
+            let (entries, next) = fs.directory_page(
+                path,
+                offset.map_or(0, to_usize),
+                limit.map_or(usize::MAX, to_usize),
+                prefix.as_deref().unwrap_or_default(),
+                order.unwrap_or_default(),
+            )?;
+            let dir = Directory {
+                entries,
+                ..Default::default()
+            };
+            Ok::<_, io::Error>((dir, next.map(|next| next as u64)))
         })
-        .unwrap()
+        .map_err(ApiError::from)
+}
+
+/// Entries of a `listTree` page, which keeps it well below the response
+/// limit.
+const TREE_PAGE: usize = 1000;
+
+#[derive(CandidType)]
+struct TreeEntry {
+    /// Relative to the directory listed.
+    path: Path,
+    entry: Entry,
+}
+
+/// The entries below the directory at `path` in the order of
+/// `FileSystem::tree`, down to `max_depth`, where its own entries have
+/// depth 0. The cursor is the path of the last entry, to list the rest.
+#[query(name = "listTree")]
+#[candid_method(query, rename = "listTree")]
+fn list_tree(
+    path: Path,
+    max_depth: Option<u64>,
+    cursor: Option<Path>,
+) -> Result<(Vec<TreeEntry>, Option<Path>), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let fs = fs.borrow();
+            let root = tenant_root(&fs)?;
+            let path: Vec<String> = rooted(&fs, path)?.into();
+            authorize(&fs, &path, Access::Read)?;
+            tree_page(&fs, &root, &path, max_depth, cursor, TREE_PAGE)
+        })
+        .map_err(ApiError::from)
+}
+
+/// A `listTree` page of up to `limit` entries below `path`, with cursors
+/// relative to `root`.
+fn tree_page<M: Memory>(
+    fs: &FileSystem<M>,
+    root: &[String],
+    path: &[String],
+    max_depth: Option<u64>,
+    cursor: Option<Path>,
+    limit: usize,
+) -> io::Result<(Vec<TreeEntry>, Option<Path>)> {
+    let found = fs.find_to_depth(
+        path,
+        max_depth.map(|depth| usize::try_from(depth).unwrap_or(usize::MAX)),
+        |_| true,
+        limit,
+        limit,
+        cursor.map(|cursor| FindCursor::after([root, &cursor.segments[..]].concat())),
+    )?;
+    let entries = found
+        .matches
+        .into_iter()
+        .map(|(entry_path, entry)| TreeEntry {
+            path: Path {
+                segments: entry_path[path.len()..].to_vec(),
+            },
+            entry,
+        })
+        .collect();
+    let cursor = found.cursor.map(|cursor| Path {
+        segments: cursor.path()[root.len()..].to_vec(),
+    });
+    Ok((entries, cursor))
 }
 
 #[query(name = "openFile")]
-fn open_file(path: Path) -> File {
+#[candid_method(query, rename = "openFile")]
+fn open_file(path: Path) -> Result<FileInfo, ApiError> {
     FILE_SYSTEM
         .with(|fs| {
             let fs = fs.borrow();
-            fs.with_file(path, |file| Ok(File::from(file)))
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Read)?;
+            fs.with_file(path, |file| Ok(FileInfo::from(file)))
         })
-        .unwrap()
+        .map_err(ApiError::from)
+}
+
+#[query(name = "statEntry")]
+#[candid_method(query, rename = "statEntry")]
+fn stat_entry(path: Path) -> Result<EntryStat, ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let fs = fs.borrow();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Read)?;
+            EntryStat::of(&fs, &path.segments)
+        })
+        .map_err(ApiError::from)
 }
 
+/// With `if_none_match`, also returns the file's ETag, and nothing else if
+/// it's among the tags given. An empty string fetches the ETag.
 #[query(name = "readFile")]
-fn read_file(path: Path, start: Option<i64>, end: Option<i64>) -> Vec<u8> {
+#[candid_method(query, rename = "readFile")]
+fn read_file(
+    path: Path,
+    start: Option<i64>,
+    end: Option<i64>,
+    if_none_match: Option<String>,
+) -> Result<(Vec<u8>, Option<String>), ApiError> {
     FILE_SYSTEM
         .with(|fs| {
             let fs = fs.borrow();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Read)?;
             fs.with_file(path, |file| {
-                let size = file.size as i64;
+                read_range(&fs, file, start, end, if_none_match)
+            })
+        })
+        .map_err(ApiError::from)
+}
 
-                let mut start = start.unwrap_or_default();
-                let mut end = end.unwrap_or(file.size as i64);
+/// The part of `file` which `readFile` returns, and its ETag if asked for.
+fn read_range<M: Memory>(
+    fs: &FileSystem<M>,
+    file: &Entry,
+    start: Option<i64>,
+    end: Option<i64>,
+    if_none_match: Option<String>,
+) -> io::Result<(Vec<u8>, Option<String>)> {
+    let etag = match if_none_match {
+        Some(tags) => {
+            let etag = http::etag(fs, file)?;
+            if http::etag_matches(&tags, &etag) {
+                return Ok((vec![], Some(etag)));
+            }
+            Some(etag)
+        }
+        None => None,
+    };
 
-                if start < 0 {
-                    start = size + start;
-                }
-                if end < 0 {
-                    end = size + end;
-                }
+    let size = i64::try_from(file.size).map_err(|_| io::ErrorKind::InvalidData)?;
 
-                if start > end {
-                    return Err(io::ErrorKind::InvalidInput.into());
-                }
+    let mut start = start.unwrap_or_default();
+    let mut end = end.unwrap_or(size);
 
-                let len = end - start;
+    if start < 0 {
+        start += size;
+    }
+    if end < 0 {
+        end += size;
+    }
 
-                let mut data = vec![0u8; len as usize];
+    if start < 0 || start > end {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
 
-                let mut r = file.read_from_file_system(&fs);
+    let len = usize::try_from(end - start).map_err(|_| io::ErrorKind::InvalidInput)?;
 
-                if start > 0 {
-                    r.seek(io::SeekFrom::Start(start as u64))?;
-                }
+    let mut data = vec![0u8; len];
+    if file.read_at(fs, start as u64, &mut data)? < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok((data, etag))
+}
 
-                r.read_exact(&mut data)?;
-                Ok(data)
-            })
+#[derive(CandidType, Deserialize)]
+struct FileChunk {
+    data: Vec<u8>,
+    #[serde(rename = "nextCursor")]
+    next_cursor: Option<u64>,
+}
+
+/// Pages through the file at `path` in chunks which fit in a response. The
+/// cursor is the offset of the next chunk, and there is none after the
+/// last one. A file written to in between reads as a mix of both versions,
+/// which the ETag from `statEntry` tells apart.
+#[query(name = "readFileChunk")]
+#[candid_method(query, rename = "readFileChunk")]
+fn read_file_chunk(path: Path, cursor: Option<u64>) -> Result<FileChunk, ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let fs = fs.borrow();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Read)?;
+            fs.with_file(path, |file| file_chunk(&fs, file, cursor))
         })
-        .unwrap()
+        .map_err(ApiError::from)
+}
+
+/// The chunk of `file` which `readFileChunk` returns at `cursor`.
+fn file_chunk<M: Memory>(
+    fs: &FileSystem<M>,
+    file: &Entry,
+    cursor: Option<u64>,
+) -> io::Result<FileChunk> {
+    let offset = cursor.unwrap_or_default();
+    if offset > file.size {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    let mut data = vec![0u8; (file.size - offset).min(http::CHUNK_SIZE) as usize];
+    if file.read_at(fs, offset, &mut data)? < data.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let next = offset + data.len() as u64;
+    Ok(FileChunk {
+        data,
+        next_cursor: Some(next).filter(|&next| next < file.size),
+    })
 }
 
 #[update(name = "createDirectory")]
-fn create_directory(path: Path) -> Directory {
+#[candid_method(update, rename = "createDirectory")]
+fn create_directory(path: Path) -> Result<Directory, ApiError> {
     FILE_SYSTEM
         .with(|fs| -> io::Result<Directory> {
             let mut fs = fs.borrow_mut();
-            fs.make_directory_recursive(path)?;
-            Ok(Directory { entries: vec![] })
+            let path = rooted_mut(&mut fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            let existing = access::existing_len(&fs, &path.segments);
+            fs.make_directory_recursive(path.segments.clone())?;
+            access::record_owner(&mut fs, &path.segments, existing, &caller())?;
+            Ok(Directory::default())
         })
-        .unwrap()
+        .map_err(ApiError::from)
 }
 
 #[update(name = "createFile")]
-fn create_file(mut path: Path, content_type: String) -> File {
-    let filename = path.pop().expect("path cannot be empty");
-
+#[candid_method(update, rename = "createFile")]
+fn create_file(path: Path, content_type: String) -> Result<FileInfo, ApiError> {
     FILE_SYSTEM
         .with(|fs| {
             let mut fs = fs.borrow_mut();
+            let mut path = rooted_mut(&mut fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            let filename = path.pop().ok_or(Error::InvalidPath)?;
             fs.with_directory_mut(path, |dir, _| {
-                dir.add_file(filename, content_type.clone());
-                Ok(File { size: 0, content_type })
+                dir.add_file(filename, content_type.clone())?.acl = Some(Acl::owned_by(caller()));
+                Ok(FileInfo {
+                    size: 0,
+                    content_type,
+                })
             })
         })
-        .unwrap()
+        .map_err(ApiError::from)
 }
 
+/// Appending writes at the end of the file, so it takes no offset.
 #[update(name = "writeFile")]
-fn write_file(path: Path, data: Vec<u8>, offset: Option<i64>) {
+#[candid_method(update, rename = "writeFile")]
+fn write_file(
+    path: Path,
+    data: Vec<u8>,
+    offset: Option<i64>,
+    mode: Option<WriteFileMode>,
+) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let path = rooted_mut(&mut fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            let mode = mode.unwrap_or_default();
+            let size = fs.with_file(path.segments.clone(), |file| Ok(file.size))?;
+            let offset = match (mode, offset) {
+                (WriteFileMode::Append, Some(_)) => Err(io::ErrorKind::InvalidInput),
+                (WriteFileMode::Append, None) => Ok(size),
+                (_, offset) => u64::try_from(offset.unwrap_or_default())
+                    .map_err(|_| io::ErrorKind::InvalidInput),
+            }?;
+            let end = offset
+                .checked_add(data.len() as u64)
+                .ok_or(io::ErrorKind::InvalidInput)?;
+            check_quota(&fs, &path.segments, end)?;
+            // Once the write has begun, a failure traps, so that the IC rolls
+            // back whatever was written rather than leave part of it.
+            if let Err(e) = fs.write_with_mode(path, offset, &data, mode) {
+                ic_cdk::trap(&format!("writeFile failed: {}", e));
+            }
+            Ok::<_, io::Error>(())
+        })
+        .map_err(ApiError::from)
+}
+
+#[update(name = "moveEntry")]
+#[candid_method(update, rename = "moveEntry")]
+fn move_entry(from: Path, to: Path) -> Result<(), ApiError> {
     FILE_SYSTEM
         .with(|fs| {
             let mut fs = fs.borrow_mut();
-            fs.with_file_mut(path, |file, fs| {
-                let mut w = file.write_to_file_system(fs);
-                if let Some(offset) = offset {
-                    w.seek(io::SeekFrom::Start(offset as u64))?;
-                }
-                w.write_all(&data)?;
-                Ok(())
+            let (from, to) = (rooted(&fs, from)?, rooted_mut(&mut fs, to)?);
+            authorize(&fs, &from.segments, Access::Write)?;
+            authorize(&fs, &to.segments, Access::Write)?;
+            fs.rename(from, to)
+        })
+        .map_err(ApiError::from)
+}
+
+/// Removes the entry with everything inside. What's left after the first
+/// slice of a large directory is removed from the heartbeat.
+#[update(name = "removeEntry")]
+#[candid_method(update, rename = "removeEntry")]
+fn remove_entry(path: Path) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            maintenance::remove_tree(&mut fs, path.segments)
+        })
+        .map_err(ApiError::from)
+}
+
+#[update(name = "copyFile")]
+#[candid_method(update, rename = "copyFile")]
+fn copy_file(from: Path, to: Path) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let (from, to) = (rooted(&fs, from)?, rooted_mut(&mut fs, to)?);
+            authorize(&fs, &from.segments, Access::Read)?;
+            authorize(&fs, &to.segments, Access::Write)?;
+            check_quota(&fs, &to.segments, fs.metadata(&from.segments)?.size)?;
+            let existing = access::existing_len(&fs, &to.segments);
+            fs.copy_file(from, to.segments.clone())?;
+            access::record_owner(&mut fs, &to.segments, existing, &caller())
+        })
+        .map_err(ApiError::from)
+}
+
+#[update(name = "beginUpload")]
+#[candid_method(update, rename = "beginUpload")]
+fn begin_upload(path: Path, content_type: String, total_size: u64) -> Result<u64, ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let path = rooted_mut(&mut fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            check_quota(&fs, &path.segments, total_size)?;
+            let now = ic_cdk::api::time();
+            UPLOADS.with(|u| {
+                let mut uploads = u.borrow_mut();
+                uploads.begin(
+                    &mut fs,
+                    path.into(),
+                    content_type,
+                    total_size,
+                    caller(),
+                    now,
+                )
             })
         })
-        .unwrap()
+        .map_err(ApiError::from)
 }
 
-#[derive(CandidType, Deserialize)]
-struct Directory {
-    pub entries: Vec<Entry>,
+#[update(name = "putChunk")]
+#[candid_method(update, rename = "putChunk")]
+fn put_chunk(upload_id: u64, offset: u64, data: Vec<u8>) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            authorize_writes(&fs)?;
+            let now = ic_cdk::api::time();
+            UPLOADS.with(|u| {
+                let mut uploads = u.borrow_mut();
+                authorize_upload(&fs, &uploads, upload_id)?;
+                uploads.put_chunk(&mut fs, upload_id, offset, &data, now)
+            })
+        })
+        .map_err(ApiError::from)
 }
 
-impl<'a> From<&'a directory::Directory> for Directory {
-    fn from(dir: &'a directory::Directory) -> Self {
-        Directory {
-            entries: dir.entries.iter().map(Entry::from).collect(),
-        }
-    }
+#[query(name = "uploadStatus")]
+#[candid_method(query, rename = "uploadStatus")]
+fn upload_status(upload_id: u64) -> Result<UploadStatus, ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            UPLOADS.with(|u| {
+                let uploads = u.borrow();
+                authorize_upload(&fs.borrow(), &uploads, upload_id)?;
+                uploads.status(upload_id, ic_cdk::api::time())
+            })
+        })
+        .map_err(ApiError::from)
+}
+
+#[update(name = "commitUpload")]
+#[candid_method(update, rename = "commitUpload")]
+fn commit_upload(upload_id: u64) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            UPLOADS.with(|u| {
+                let mut uploads = u.borrow_mut();
+                authorize_upload(&fs, &uploads, upload_id)?;
+                // Writing may have been revoked since the upload began.
+                let path = uploads.path(upload_id).ok_or(Error::NotFound)?.to_vec();
+                authorize(&fs, &path, Access::Write)?;
+                let status = uploads.status(upload_id, ic_cdk::api::time())?;
+                check_quota(&fs, &path, status.total_size)?;
+                let existing = access::existing_len(&fs, &path);
+                uploads.commit(&mut fs, upload_id)?;
+                access::record_owner(&mut fs, &path, existing, &caller())
+            })
+        })
+        .map_err(ApiError::from)
+}
+
+#[update(name = "abortUpload")]
+#[candid_method(update, rename = "abortUpload")]
+fn abort_upload(upload_id: u64) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            authorize_writes(&fs)?;
+            UPLOADS.with(|u| {
+                let mut uploads = u.borrow_mut();
+                authorize_upload(&fs, &uploads, upload_id)?;
+                uploads.abort(&mut fs, upload_id)
+            })
+        })
+        .map_err(ApiError::from)
+}
+
+#[update(name = "setHeaders")]
+#[candid_method(update, rename = "setHeaders")]
+fn set_headers(path: Path, headers: Vec<(String, String)>) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            fs.set_headers(path, headers)
+        })
+        .map_err(ApiError::from)
 }
 
+#[update(name = "setFallback")]
+#[candid_method(update, rename = "setFallback")]
+fn set_fallback(path: Path, fallback: Fallback) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            fs.set_fallback(path, fallback)
+        })
+        .map_err(ApiError::from)
+}
+
+#[update(name = "setListing")]
+#[candid_method(update, rename = "setListing")]
+fn set_listing(path: Path, enabled: bool) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            fs.set_listing(path, enabled)
+        })
+        .map_err(ApiError::from)
+}
+
+#[update(name = "setRedirect")]
+#[candid_method(update, rename = "setRedirect")]
+fn set_redirect(path: Path, redirect: Option<Redirect>) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            let existing = access::existing_len(&fs, &path.segments);
+            fs.set_redirect(path.segments.clone(), redirect)?;
+            access::record_owner(&mut fs, &path.segments, existing, &caller())
+        })
+        .map_err(ApiError::from)
+}
+
+#[update(name = "setMetadata")]
+#[candid_method(update, rename = "setMetadata")]
+fn set_metadata(path: Path, change: MetadataChange) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            fs.set_metadata(path, change)
+        })
+        .map_err(ApiError::from)
+}
+
+/// Moves the modification time of the entry at `path` to now, creating an
+/// empty one if there's none.
+#[update(name = "touch")]
+#[candid_method(update, rename = "touch")]
+fn touch(path: Path) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let path = rooted_mut(&mut fs, path)?;
+            authorize(&fs, &path.segments, Access::Write)?;
+            let existing = access::existing_len(&fs, &path.segments);
+            fs.touch(path.segments.clone())?;
+            access::record_owner(&mut fs, &path.segments, existing, &caller())
+        })
+        .map_err(ApiError::from)
+}
+
+/// Only owners of the entry or of a directory above it, and admins, may
+/// change who has access. The empty path is the root directory, which
+/// admins are the writers of, or the home directory of a tenant.
+#[update(name = "setAcl")]
+#[candid_method(update, rename = "setAcl")]
+fn set_acl(path: Path, acl: Option<Acl>) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            let path = rooted(&fs, path)?;
+            if path.segments.is_empty() && acl.is_none() {
+                let message = "the root directory can't be left without an ACL";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+            authorize(&fs, &path.segments, Access::Own)?;
+            fs.set_acl(path, acl)
+        })
+        .map_err(ApiError::from)
+}
+
+/// The ACL of the entry itself, without those of the directories above it.
+#[query(name = "getAcl")]
+#[candid_method(query, rename = "getAcl")]
+fn get_acl(path: Path) -> Result<Option<Acl>, ApiError> {
+    FILE_SYSTEM
+        .with(|fs| -> io::Result<Option<Acl>> {
+            let fs = fs.borrow();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Read)?;
+            match fs.resolve(&path.segments)? {
+                Some(entry) => Ok(entry.acl),
+                None => Ok(fs.read_root_directory()?.acl),
+            }
+        })
+        .map_err(ApiError::from)
+}
+
+#[update(name = "setWriteMode")]
+#[candid_method(update, rename = "setWriteMode")]
+fn set_write_mode(mode: WriteMode) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            if !is_controller() {
+                return Err(Error::AccessDenied.into());
+            }
+            mode.write(&mut fs.borrow_mut())
+        })
+        .map_err(ApiError::from)
+}
+
+#[query(name = "getWriteMode")]
+#[candid_method(query, rename = "getWriteMode")]
+fn get_write_mode() -> Result<WriteMode, ApiError> {
+    FILE_SYSTEM
+        .with(|fs| WriteMode::read(&fs.borrow()))
+        .map_err(ApiError::from)
+}
+
+/// Admins may switch multi-tenant mode on and off, which leaves the files
+/// where they are. HTTP requests and the asset interface aren't confined
+/// to home directories, so private homes take reads which aren't public.
+#[update(name = "setTenancy")]
+#[candid_method(update, rename = "setTenancy")]
+fn set_tenancy(tenancy: Tenancy) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            authorize(&fs, &[], Access::Write)?;
+            tenancy.write(&mut fs)
+        })
+        .map_err(ApiError::from)
+}
+
+#[query(name = "getTenancy")]
+#[candid_method(query, rename = "getTenancy")]
+fn get_tenancy() -> Result<Tenancy, ApiError> {
+    FILE_SYSTEM
+        .with(|fs| Tenancy::read(&fs.borrow()))
+        .map_err(ApiError::from)
+}
+
+/// Gives a tenant a quota other than that of every tenant, or with `None`
+/// takes it back.
+#[update(name = "setTenantQuota")]
+#[candid_method(update, rename = "setTenantQuota")]
+fn set_tenant_quota(tenant: Principal, quota: Option<u64>) -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            authorize(&fs, &[], Access::Write)?;
+            tenants::set_quota(&mut fs, &tenant.to_text(), quota)
+        })
+        .map_err(ApiError::from)
+}
+
+/// The bytes the caller keeps in its home directory, and its quota.
+#[query(name = "tenantUsage")]
+#[candid_method(query, rename = "tenantUsage")]
+fn tenant_usage() -> Result<(u64, Option<u64>), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let fs = fs.borrow();
+            let tenant = caller();
+            Ok::<_, io::Error>((tenants::usage(&fs, &tenant)?, tenants::quota(&fs, &tenant)?))
+        })
+        .map_err(ApiError::from)
+}
+
+/// Files `stats` lists as the largest.
+const LARGEST_FILES: usize = 10;
+
 #[derive(CandidType, Deserialize)]
-struct Entry {
-    pub name: String,
-    pub kind: EntryKind,
-}
-
-impl<'a> From<&'a directory::Entry> for Entry {
-    fn from(e: &'a directory::Entry) -> Self {
-        Entry {
-            name: e.name.clone(),
-            kind: match e.kind {
-                crate::directory::EntryKind::Directory => EntryKind::Directory,
-                crate::directory::EntryKind::File => EntryKind::File(e.into()),
-            },
+struct FileSize {
+    path: Path,
+    size: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct StorageStats {
+    #[serde(rename = "totalBytes")]
+    total_bytes: u64,
+    #[serde(rename = "usedBytes")]
+    used_bytes: u64,
+    /// What's left for files, without the space held back for metadata.
+    #[serde(rename = "freeBytes")]
+    free_bytes: u64,
+    files: u64,
+    directories: u64,
+    #[serde(rename = "fileBytes")]
+    file_bytes: u64,
+    #[serde(rename = "largestFiles")]
+    largest_files: Vec<FileSize>,
+    #[serde(rename = "fragmentedFiles")]
+    fragmented_files: u64,
+    fragments: u64,
+    #[serde(rename = "formatVersion")]
+    format_version: u8,
+}
+
+/// A summary of the whole filesystem for admins, which walks the tree once.
+#[query(name = "stats")]
+#[candid_method(query, rename = "stats")]
+fn stats() -> Result<StorageStats, ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let fs = fs.borrow();
+            authorize_admin(&fs)?;
+            let stats = fs.stats(LARGEST_FILES)?;
+            let usage = stats.usage;
+            let free = usage.total_blocks - usage.used_blocks;
+            let free = free.saturating_sub(usage.reserved_blocks);
+            Ok::<_, io::Error>(StorageStats {
+                total_bytes: usage.total_blocks * usage.block_size,
+                used_bytes: usage.used_blocks * usage.block_size,
+                free_bytes: free * usage.block_size,
+                files: stats.files,
+                directories: stats.directories,
+                file_bytes: stats.file_bytes,
+                largest_files: stats
+                    .largest_files
+                    .into_iter()
+                    .map(|(segments, size)| FileSize {
+                        path: Path { segments },
+                        size,
+                    })
+                    .collect(),
+                fragmented_files: stats.fragmented_files,
+                fragments: stats.fragments,
+                format_version: stats.format_version,
+            })
+        })
+        .map_err(ApiError::from)
+}
+
+/// The secret access tokens are signed with, made of fresh randomness the
+/// first time.
+async fn token_secret() -> io::Result<Vec<u8>> {
+    if let Some(secret) = FILE_SYSTEM.with(|fs| tokens::secret(&fs.borrow()))? {
+        return Ok(secret);
+    }
+    let (random,): (Vec<u8>,) = ic_cdk::call(Principal::management_canister(), "raw_rand", ())
+        .await
+        .map_err(|(_, message)| io::Error::other(message))?;
+    FILE_SYSTEM.with(|fs| {
+        let mut fs = fs.borrow_mut();
+        // Another call may have made one while this one waited.
+        match tokens::secret(&fs)? {
+            Some(secret) => Ok(secret),
+            None => tokens::set_secret(&mut fs, Some(&random)).map(|()| random),
         }
+    })
+}
+
+/// Signs an access token which lets HTTP requests read `path`, and what's
+/// below it, for `ttl` seconds, the "presigned URL" of other storage.
+/// Returns the path and query of the URL to read it with. Whoever may read
+/// the path may share it.
+#[update(name = "createAccessToken")]
+#[candid_method(update, rename = "createAccessToken")]
+async fn create_access_token(path: Path, ttl: u64) -> Result<String, ApiError> {
+    let path = FILE_SYSTEM
+        .with(|fs| {
+            let fs = fs.borrow();
+            let path = rooted(&fs, path)?;
+            authorize(&fs, &path.segments, Access::Read)?;
+            Ok::<_, io::Error>(path)
+        })
+        .map_err(ApiError::from)?;
+    let secret = token_secret().await.map_err(ApiError::from)?;
+    let expires = ic_cdk::api::time().saturating_add(ttl.saturating_mul(1_000_000_000));
+    let token = tokens::sign(&secret, &path.segments, expires);
+    Ok(http::token_url(&path.segments, &token))
+}
+
+/// Revokes every access token signed so far.
+#[update(name = "revokeAccessTokens")]
+#[candid_method(update, rename = "revokeAccessTokens")]
+fn revoke_access_tokens() -> Result<(), ApiError> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            authorize(&fs, &[], Access::Write)?;
+            tokens::set_secret(&mut fs, None)
+        })
+        .map_err(ApiError::from)
+}
+
+/// Fails unless the caller began the upload, or is an admin. Uploads which
+/// don't exist are left to fail on their own.
+fn authorize_upload(
+    fs: &FileSystem<StableMemory>,
+    uploads: &Uploads,
+    upload_id: u64,
+) -> io::Result<()> {
+    match uploads.owner(upload_id) {
+        Some(owner) if owner != caller() => authorize_admin(fs),
+        _ => Ok(()),
     }
 }
 
-#[derive(CandidType, Deserialize)]
-struct File {
-    size: u64,
-    #[serde(rename = "contentType")]
-    content_type: String,
+// The interface of the IC asset canister, so its tools work unchanged. As
+// in that canister, only admins may change assets, as far as the write mode
+// lets them.
+
+#[query(name = "list")]
+#[candid_method(query, rename = "list")]
+fn list_assets(_: assets::Empty) -> Vec<assets::AssetDetails> {
+    FILE_SYSTEM
+        .with(|fs| {
+            let fs = fs.borrow();
+            authorize(&fs, &[], Access::Read)?;
+            assets::list(&fs)
+        })
+        .unwrap()
+}
+
+#[query(name = "get")]
+#[candid_method(query, rename = "get")]
+fn get_asset(args: assets::GetArguments) -> assets::EncodedAsset {
+    FILE_SYSTEM
+        .with(|fs| {
+            let fs = fs.borrow();
+            authorize(&fs, &assets::key_path(&args.key)?, Access::Read)?;
+            assets::get(&fs, args)
+        })
+        .unwrap()
+}
+
+#[query(name = "get_chunk")]
+#[candid_method(query, rename = "get_chunk")]
+fn get_asset_chunk(args: assets::GetChunkArguments) -> assets::GetChunkResponse {
+    FILE_SYSTEM
+        .with(|fs| {
+            let fs = fs.borrow();
+            authorize(&fs, &assets::key_path(&args.key)?, Access::Read)?;
+            assets::get_chunk(&fs, args)
+        })
+        .unwrap()
+}
+
+#[update(name = "create_batch")]
+#[candid_method(update, rename = "create_batch")]
+fn create_batch(_: assets::Empty) -> assets::CreateBatchResponse {
+    FILE_SYSTEM
+        .with(|fs| authorize(&fs.borrow(), &[], Access::Write))
+        .unwrap();
+    ASSETS.with(|a| a.borrow_mut().create_batch(ic_cdk::api::time()))
+}
+
+#[update(name = "create_chunk")]
+#[candid_method(update, rename = "create_chunk")]
+fn create_chunk(args: assets::CreateChunkArguments) -> assets::CreateChunkResponse {
+    FILE_SYSTEM
+        .with(|fs| authorize(&fs.borrow(), &[], Access::Write))
+        .unwrap();
+    ASSETS
+        .with(|a| a.borrow_mut().create_chunk(args, ic_cdk::api::time()))
+        .unwrap()
+}
+
+#[update(name = "commit_batch")]
+#[candid_method(update, rename = "commit_batch")]
+fn commit_batch(args: assets::CommitBatchArguments) {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            authorize(&fs, &[], Access::Write)?;
+            ASSETS.with(|a| a.borrow_mut().commit_batch(&mut fs, args))
+        })
+        .unwrap()
+}
+
+#[update(name = "store")]
+#[candid_method(update, rename = "store")]
+fn store_asset(args: assets::StoreArguments) {
+    FILE_SYSTEM
+        .with(|fs| {
+            let mut fs = fs.borrow_mut();
+            authorize(&fs, &[], Access::Write)?;
+            assets::store(&mut fs, args)
+        })
+        .unwrap()
+}
+
+// Serves files through the HTTP gateway.
+
+#[query]
+#[candid_method(query)]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let callback = candid::Func {
+        principal: ic_cdk::id(),
+        method: "http_request_streaming_callback".to_owned(),
+    };
+    FILE_SYSTEM.with(|fs| http::http_request(&fs.borrow(), request, callback))
+}
+
+#[query]
+#[candid_method(query)]
+fn http_request_streaming_callback(token: StreamingToken) -> StreamingCallbackHttpResponse {
+    FILE_SYSTEM
+        .with(|fs| http::http_request_streaming_callback(&fs.borrow(), token))
+        .unwrap()
+}
+
+/// Arguments of ingress messages which carry no file contents may take
+/// this many bytes.
+const MAX_INGRESS_ARGS: usize = 64 << 10;
+
+/// Turns away ingress messages the call would fail for anyway, before the
+/// canister pays for them: to methods which aren't updates, with arguments
+/// too large or not Candid, and from callers the write mode or the ACLs
+/// keep out. Calls from other canisters don't come through here, and the
+/// methods check them all the same.
+#[inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    let args = ic_cdk::api::call::arg_data_raw();
+    if FILE_SYSTEM
+        .with(|fs| inspect(&fs.borrow(), &method, &args))
+        .is_ok()
+    {
+        ic_cdk::api::call::accept_message();
+    }
 }
 
-impl<'a> From<&'a directory::Entry> for File {
-    fn from(entry: &'a directory::Entry) -> Self {
-        Self {
-            size: entry.size as u64,
-            content_type: entry.content_type.clone(),
+fn inspect(fs: &FileSystem<StableMemory>, method: &str, args: &[u8]) -> io::Result<()> {
+    let contents = matches!(
+        method,
+        "writeFile" | "putChunk" | "create_chunk" | "commit_batch" | "store"
+    );
+    if !contents && args.len() > MAX_INGRESS_ARGS {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    let paths = |count| -> io::Result<Vec<Path>> {
+        let malformed = |_| io::Error::from(io::ErrorKind::InvalidInput);
+        let mut de = IDLDeserialize::new(args).map_err(malformed)?;
+        (0..count)
+            .map(|_| de.get_value().map_err(malformed))
+            .collect()
+    };
+    match method {
+        "createDirectory" | "createFile" | "writeFile" | "beginUpload" | "setHeaders"
+        | "setFallback" | "setListing" | "setRedirect" | "setMetadata" | "touch"
+        | "removeEntry" => inspect_path(fs, paths(1)?.remove(0), Access::Write),
+        "moveEntry" => {
+            for path in paths(2)? {
+                inspect_path(fs, path, Access::Write)?;
+            }
+            Ok(())
+        }
+        "copyFile" => {
+            let mut paths = paths(2)?;
+            inspect_path(fs, paths.remove(0), Access::Read)?;
+            inspect_path(fs, paths.remove(0), Access::Write)
         }
+        "setAcl" => inspect_path(fs, paths(1)?.remove(0), Access::Own),
+        "createAccessToken" => inspect_path(fs, paths(1)?.remove(0), Access::Read),
+        "putChunk" | "commitUpload" | "abortUpload" => authorize_writes(fs),
+        "setWriteMode" if is_controller() => Ok(()),
+        "setTenancy" | "setTenantQuota" | "revokeAccessTokens" | "create_batch"
+        | "create_chunk" | "commit_batch" | "store" => authorize(fs, &[], Access::Write),
+        _ => Err(Error::AccessDenied.into()),
     }
 }
 
-#[derive(CandidType, Deserialize)]
-enum EntryKind {
-    Directory,
-    File(File),
+/// Like `authorize` for `path` as the caller sees it. A tenant without a
+/// home directory yet is let in as far as the write mode goes, as the call
+/// makes one for it.
+fn inspect_path(fs: &FileSystem<StableMemory>, path: Path, access: Access) -> io::Result<()> {
+    let root = tenant_root(fs)?;
+    if access != Access::Read && !root.is_empty() && !fs.exists(&root) {
+        return authorize_writes(fs);
+    }
+    authorize(fs, &rooted(fs, path)?.segments, access)
 }
 
 struct Path {
@@ -194,15 +1240,11 @@ impl Path {
     pub fn pop(&mut self) -> Option<String> {
         self.segments.pop()
     }
-
-    pub fn len(&self) -> usize {
-        self.segments.len()
-    }
 }
 
-impl Into<Vec<String>> for Path {
-    fn into(self) -> Vec<String> {
-        self.segments
+impl From<Path> for Vec<String> {
+    fn from(path: Path) -> Self {
+        path.segments
     }
 }
 
@@ -252,3 +1294,227 @@ impl CandidType for Path {
             .idl_serialize(serializer)
     }
 }
+
+// Gathers the `candid_method`s above, so it has to come after all of them.
+export_service!();
+
+/// The interface as the endpoints define it, which is how dfx and other
+/// tools ask a canister for its interface until there's a standard way.
+#[query(name = "__get_candid_interface_tmp_hack")]
+fn candid_interface() -> String {
+    __export_service()
+}
+
+#[test]
+fn read_file_etag() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.write_atomic(vec!["a.txt"], &b"Hello World"[..]).unwrap();
+    let read = |fs: &FileSystem<HeapMemory>, if_none_match: Option<&str>| {
+        fs.with_file(vec!["a.txt"], |file| {
+            read_range(fs, file, Some(-5), None, if_none_match.map(str::to_owned))
+        })
+        .unwrap()
+    };
+
+    // Without tags there's no ETag, and an empty string just fetches it.
+    assert_eq!(read(&fs, None), (b"World".to_vec(), None));
+    let (data, etag) = read(&fs, Some(""));
+    let etag = etag.unwrap();
+    assert_eq!(data, b"World");
+
+    // A matching tag leaves out the contents, and others don't.
+    assert_eq!(read(&fs, Some(&etag)), (vec![], Some(etag.clone())));
+    let tags = format!("\"x\", {}", etag);
+    assert_eq!(read(&fs, Some(&tags)).0, b"");
+    assert_eq!(read(&fs, Some("\"x\"")).0, b"World");
+
+    // Changed contents come with a new ETag.
+    fs.write_atomic(vec!["a.txt"], &b"Hello There"[..]).unwrap();
+    let (data, changed) = read(&fs, Some(&etag));
+    assert_eq!(data, b"There");
+    assert_ne!(changed.unwrap(), etag);
+}
+
+#[test]
+fn read_file_chunks() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::with_max_size(16 << 20)).unwrap();
+    let chunk = |fs: &FileSystem<HeapMemory>, cursor| {
+        fs.with_file(vec!["a.bin"], |file| file_chunk(fs, file, cursor))
+    };
+    let size = 2 * http::CHUNK_SIZE as usize + 10;
+    let contents = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+    fs.write_atomic(vec!["a.bin"], &contents[..]).unwrap();
+
+    // Cursors lead through the whole file, with none after the last chunk.
+    let (mut data, mut cursor, mut chunks) = (vec![], None, 0);
+    loop {
+        let next = chunk(&fs, cursor).unwrap();
+        assert!(next.data.len() as u64 <= http::CHUNK_SIZE);
+        data.extend(next.data);
+        chunks += 1;
+        match next.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!((chunks, data == contents), (3, true));
+    assert_eq!(
+        chunk(&fs, Some(size as u64 - 1)).unwrap().data,
+        [contents[size - 1]]
+    );
+    assert!(chunk(&fs, Some(size as u64 + 1)).is_err());
+
+    // A file of exactly one chunk, or of none, takes a single call.
+    fs.write_atomic(vec!["a.bin"], &contents[..http::CHUNK_SIZE as usize])
+        .unwrap();
+    assert_eq!(chunk(&fs, None).unwrap().next_cursor, None);
+    fs.write_atomic(vec!["a.bin"], &b""[..]).unwrap();
+    let empty = chunk(&fs, None).unwrap();
+    assert_eq!((empty.data.len(), empty.next_cursor), (0, None));
+}
+
+#[test]
+fn list_tree_pages() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    for path in [
+        &["t", "docs", "a.txt"][..],
+        &["t", "docs", "old", "b.txt"],
+        &["t", "docs", "old", "deep", "c.txt"],
+        &["t", "docs", "z.txt"],
+    ] {
+        fs.make_directory_recursive(path[..path.len() - 1].to_vec())
+            .unwrap();
+        fs.write_atomic(path.to_vec(), &b"x"[..]).unwrap();
+    }
+    let root = vec!["t".to_owned()];
+    let path = vec!["t".to_owned(), "docs".to_owned()];
+    let list = |max_depth: Option<u64>, cursor: Option<Path>, limit: usize| {
+        let (entries, cursor) = tree_page(&fs, &root, &path, max_depth, cursor, limit).unwrap();
+        let paths = entries
+            .into_iter()
+            .map(|e| e.path.segments.join("/"))
+            .collect::<Vec<_>>();
+        (paths, cursor)
+    };
+
+    // Paths are relative to the directory listed, and depth 0 is its own
+    // entries.
+    let (mut all, _) = list(None, None, 100);
+    all.sort();
+    assert_eq!(
+        all,
+        [
+            "a.txt",
+            "old",
+            "old/b.txt",
+            "old/deep",
+            "old/deep/c.txt",
+            "z.txt"
+        ]
+    );
+    let (mut shallow, _) = list(Some(0), None, 100);
+    shallow.sort();
+    assert_eq!(shallow, ["a.txt", "old", "z.txt"]);
+    let (mut one, _) = list(Some(1), None, 100);
+    one.sort();
+    assert_eq!(one, ["a.txt", "old", "old/b.txt", "old/deep", "z.txt"]);
+
+    // Pages resume after their cursor, which is relative to the root,
+    // until every entry has been listed once.
+    let (mut paged, mut cursor) = list(None, None, 2);
+    assert_eq!(paged.len(), 2);
+    while let Some(after) = cursor {
+        assert_eq!(after.segments[0], "docs");
+        let (page, next) = list(None, Some(after), 2);
+        assert!(!page.is_empty() && page.len() <= 2);
+        paged.extend(page);
+        cursor = next;
+    }
+    paged.sort();
+    assert_eq!(paged, all);
+}
+
+#[test]
+fn inspect_ingress() {
+    use candid::Encode;
+
+    let mut fs = FileSystem::new(StableMemory::default()).unwrap();
+    let admin = Principal::from_text("2vxsx-fae").unwrap();
+    let alice = Principal::management_canister();
+    let call_as = |principal: Principal| CALLER.with(|caller| caller.set(principal));
+    let path = |path: &str| Encode!(&path).unwrap();
+    fs.make_directory_recursive(vec!["docs"]).unwrap();
+    fs.set_acl(
+        Vec::<String>::new(),
+        Some(Acl {
+            writers: vec![admin.to_text()],
+            readers: vec![Acl::EVERYONE.to_owned()],
+            ..Default::default()
+        }),
+    )
+    .unwrap();
+    WriteMode::AllowList(vec![admin, alice])
+        .write(&mut fs)
+        .unwrap();
+
+    // Admins get through, as long as the arguments are fine.
+    call_as(admin);
+    assert!(inspect(&fs, "createDirectory", &path("docs/a")).is_ok());
+    assert!(inspect(&fs, "createDirectory", b"not candid").is_err());
+    assert!(inspect(&fs, "setTenancy", &[]).is_ok());
+    assert!(inspect(&fs, "openFile", &path("docs/a.txt")).is_err());
+    assert!(inspect(&fs, "noSuchMethod", &[]).is_err());
+    let large = Encode!(&"x".repeat(MAX_INGRESS_ARGS)).unwrap();
+    assert!(inspect(&fs, "createDirectory", &large).is_err());
+    let contents = Encode!(&"a.txt", &vec![0u8; MAX_INGRESS_ARGS]).unwrap();
+    assert!(inspect(&fs, "writeFile", &contents).is_ok());
+
+    // Others only where the ACLs let them in, and not at all once the
+    // write mode keeps them out.
+    call_as(alice);
+    assert!(inspect(&fs, "createFile", &path("docs/a.txt")).is_err());
+    assert!(inspect(&fs, "setTenancy", &[]).is_err());
+    fs.set_acl(
+        vec!["docs"],
+        Some(Acl {
+            writers: vec![alice.to_text()],
+            ..Default::default()
+        }),
+    )
+    .unwrap();
+    assert!(inspect(&fs, "createFile", &path("docs/a.txt")).is_ok());
+    let moved = Encode!(&"docs/a.txt", &"b.txt").unwrap();
+    assert!(inspect(&fs, "moveEntry", &moved).is_err());
+    assert!(inspect(&fs, "putChunk", &[]).is_ok());
+    WriteMode::Controllers.write(&mut fs).unwrap();
+    assert!(inspect(&fs, "createFile", &path("docs/a.txt")).is_err());
+    assert!(inspect(&fs, "putChunk", &[]).is_err());
+    assert!(inspect(&fs, "setWriteMode", &[]).is_err());
+}
+
+/// `box.did`, which clients are generated from, has to describe the same
+/// interface as the endpoints, though it's written by hand to keep its
+/// comments and argument names. Fails with the generated interface to
+/// compare it with otherwise.
+#[test]
+fn box_did() {
+    use candid::utils::{service_compatible, CandidSource};
+
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("box.did");
+    let generated = candid_interface();
+    let checks = [
+        (CandidSource::Text(&generated), CandidSource::File(&path)),
+        (CandidSource::File(&path), CandidSource::Text(&generated)),
+    ];
+    for (new, old) in checks {
+        if let Err(e) = service_compatible(new, old) {
+            panic!("box.did is out of date: {}\n{}", e, generated);
+        }
+    }
+}
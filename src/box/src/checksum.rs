@@ -0,0 +1,56 @@
+use std::io;
+
+const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const PRIME: u64 = 0x0100_0000_01b3;
+
+/// 64-bit FNV-1a checksum. Not suitable against tampering, but enough to
+/// detect torn or partial writes.
+#[derive(Clone, Copy, Debug)]
+pub struct Checksum(u64);
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Checksum(OFFSET_BASIS)
+    }
+}
+
+impl Checksum {
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(PRIME);
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Lets anything serializable be fed into the checksum directly.
+impl io::Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn checksum() {
+    assert_eq!(Checksum::default().value(), OFFSET_BASIS);
+
+    let mut checksum = Checksum::default();
+    checksum.update(b"a");
+    assert_eq!(checksum.value(), 0xaf63_dc4c_8601_ec8c);
+
+    let mut split = Checksum::default();
+    io::Write::write_all(&mut split, b"Hello, ").unwrap();
+    io::Write::write_all(&mut split, b"World!").unwrap();
+    let mut whole = Checksum::default();
+    whole.update(b"Hello, World!");
+    assert_eq!(split.value(), whole.value());
+}
@@ -1,12 +1,32 @@
+use std::borrow::Cow;
 use std::io;
-use std::ops::RangeInclusive;
 
 use crate::bitmap::Bitmap;
 use crate::block::Block;
+use crate::error::Error;
+use crate::memory::{read_slices, seek_target, to_usize, write_slices};
 use crate::serde::{Deserialize, Serialize};
 
-#[derive(Default, Debug, PartialEq)]
+/// Number of block pointers stored in a single index block. The first four
+/// bytes of every index block link to the next index block in the chain.
+pub const POINTERS_PER_INDEX_BLOCK: usize = (Block::SIZE - 4) / 4;
+
+/// Buffer capacity of `BufClusterReader` and `BufClusterWriter`.
+pub const BUF_CAPACITY: usize = 8 * Block::SIZE;
+
+/// A `ClusterReader` which reads ahead in large chunks, so many small
+/// sequential reads result in few reads of the underlying memory.
+pub type BufClusterReader<'a, R> = io::BufReader<ClusterReader<'a, R>>;
+
+/// A `ClusterWriter` which collects small sequential writes and passes them
+/// on in large chunks. Must be flushed explicitly to observe write errors.
+pub type BufClusterWriter<'a, W> = io::BufWriter<ClusterWriter<'a, W>>;
+
+#[derive(Default, Debug, PartialEq, Clone)]
 pub struct Cluster {
+    head: Option<Block>,
+    block_count: usize,
+    index: Vec<Block>,
     blocks: Vec<Block>,
 }
 
@@ -19,13 +39,141 @@ impl Cluster {
         self.blocks.iter()
     }
 
-    pub fn reader<'a, R>(&'a self, reader: R) -> ClusterReader<'a, R> {
-        ClusterReader {
-            cluster: self,
-            reader,
-            cluster_block_index: 0,
-            block_offset: 0,
+    pub fn block_count(&self) -> usize {
+        self.blocks.len().max(self.block_count)
+    }
+
+    pub fn get(&self, i: usize) -> Option<Block> {
+        self.blocks.get(i).copied()
+    }
+
+    /// Points the `i`-th block of the cluster at `block`, rewriting its slot
+    /// in the index. The block's contents are not copied. Returns the block
+    /// that was previously referenced.
+    pub fn set_block(
+        &mut self,
+        i: usize,
+        block: Block,
+        mut w: impl io::Write + io::Seek,
+    ) -> io::Result<Block> {
+        let index_block = self
+            .index
+            .get(i / POINTERS_PER_INDEX_BLOCK)
+            .filter(|_| i < self.block_count)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        let pointer = index_block.offset() + 4 + (i % POINTERS_PER_INDEX_BLOCK) as u64 * 4;
+        w.seek(io::SeekFrom::Start(pointer))?;
+        w.write_all(&(block.index as u32).to_be_bytes())?;
+
+        Ok(std::mem::replace(&mut self.blocks[i], block))
+    }
+
+    /// Drops all but the first `block_count` blocks, along with the index
+    /// blocks no longer needed to list them. Returns the dropped blocks, which
+    /// the caller has to free. The cluster must be loaded.
+    pub fn truncate(&mut self, block_count: usize) -> Vec<Block> {
+        if block_count >= self.blocks.len() {
+            return vec![];
         }
+
+        let mut released = self.blocks.split_off(block_count);
+        self.block_count = self.block_count.min(block_count);
+
+        let index_count = block_count.div_ceil(POINTERS_PER_INDEX_BLOCK);
+        released.extend(self.index.drain(index_count.min(self.index.len())..));
+        if self.index.is_empty() {
+            self.head = None;
+        }
+
+        released
+    }
+
+    pub fn index_blocks(&self) -> impl '_ + Iterator<Item = &Block> {
+        self.index.iter()
+    }
+
+    /// A copy of the handle without the block list, as it is serialized.
+    pub fn handle(&self) -> Cluster {
+        Cluster {
+            head: self.head,
+            block_count: self.block_count,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.blocks.len() >= self.block_count
+    }
+
+    /// Reads the block list from the chain of index blocks referenced by
+    /// this cluster's handle. A handle or index which points past the end of
+    /// the memory is refused, so a corrupted block count can't make it
+    /// allocate more than the memory could hold. Nothing of an index which
+    /// fails to load is kept.
+    pub fn load(&mut self, r: impl io::Read + io::Seek) -> io::Result<()> {
+        if self.is_loaded() {
+            return Ok(());
+        }
+
+        let result = self.read_index(r);
+        if result.is_err() {
+            self.index.clear();
+            self.blocks.clear();
+        }
+        result
+    }
+
+    fn read_index(&mut self, mut r: impl io::Read + io::Seek) -> io::Result<()> {
+        self.index.clear();
+        self.blocks.clear();
+
+        let memory_blocks = to_usize(r.seek(io::SeekFrom::End(0))? / Block::SIZE as u64)?;
+        let check = |block: Block| {
+            if block.index < memory_blocks {
+                Ok(block)
+            } else {
+                Err(Error::corrupted(format!(
+                    "cluster points to block {}",
+                    block.index
+                )))
+            }
+        };
+        if self.block_count > memory_blocks {
+            return Err(Error::corrupted(format!(
+                "cluster of {} blocks in a memory of {}",
+                self.block_count, memory_blocks
+            ))
+            .into());
+        }
+
+        let mut next = self.head;
+        while self.blocks.len() < self.block_count {
+            let index_block = next.ok_or_else(|| Error::corrupted("cluster index is truncated"))?;
+            let index_block = check(index_block)?;
+
+            let mut buf = [0u8; Block::SIZE];
+            r.seek(io::SeekFrom::Start(index_block.offset()))?;
+            r.read_exact(&mut buf)?;
+            self.index.push(index_block);
+
+            next = match read_u32(&buf, 0) {
+                0 => None,
+                link => Some(Block::at(link as _)),
+            };
+
+            let pointers = (self.block_count - self.blocks.len()).min(POINTERS_PER_INDEX_BLOCK);
+            for slot in 0..pointers {
+                let block = check(Block::at(read_u32(&buf, 4 + slot * 4) as _))?;
+                self.blocks.push(block);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn reader<'a, R>(&'a self, reader: R) -> ClusterReader<'a, R> {
+        ClusterReader::new(Cow::Borrowed(self), reader)
     }
 
     pub fn writer<'a, W>(&'a mut self, bitmap: &'a mut Bitmap, writer: W) -> ClusterWriter<'a, W> {
@@ -35,86 +183,73 @@ impl Cluster {
             writer,
             cluster_block_index: 0,
             block_offset: 0,
+            data: false,
         }
     }
 
-    pub fn len(&self) -> usize {
-        Block::SIZE * self.blocks.len()
+    pub fn len(&self) -> u64 {
+        Block::SIZE as u64 * self.block_count() as u64
     }
 }
 
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+/// Only the fixed-size handle (head index block and block count) is
+/// serialized. The block list itself lives in the index blocks.
 impl Serialize for Cluster {
     fn serialize(&self, mut w: impl io::Write) -> io::Result<usize> {
-        let mut ranges: Vec<RangeInclusive<Block>> = vec![];
-
-        for block in self.blocks.iter() {
-            if let Some(last) = ranges.last_mut() {
-                if *last.end() + 1 == *block {
-                    *last = *last.start()..=*block;
-                    continue;
-                }
-            }
-            ranges.push(*block..=*block);
-        }
-
-        let mut bytes_written = 0;
-        let mut write = |buf: u32| -> io::Result<()> {
-            w.write_all(&buf.to_be_bytes())?;
-            bytes_written += std::mem::size_of::<u32>();
-            Ok(())
-        };
-        write(ranges.len() as _)?;
-
-        for range in ranges {
-            let start = range.start().index;
-            let len = range.end().index - start + 1;
-            if len == 1 {
-                write(start as _)?;
-            } else {
-                write(start as u32 | (1 << 31))?;
-                write(len as _)?;
-            }
-        }
-
-        Ok(bytes_written)
+        let head = self.head.map(|b| b.index as u32).unwrap_or(0);
+        w.write_all(&head.to_be_bytes())?;
+        w.write_all(&(self.block_count as u32).to_be_bytes())?;
+        Ok(8)
     }
 }
 
 impl Deserialize for Cluster {
     fn deserialize(&mut self, mut r: impl io::Read) -> io::Result<usize> {
-        let mut bytes_read = 0;
-        let mut read = || -> io::Result<u32> {
-            let mut buf = [0u8; 4];
-            r.read_exact(&mut buf)?;
-            bytes_read += 4;
-            Ok(u32::from_be_bytes(buf))
-        };
+        let mut buf = [0u8; 8];
+        r.read_exact(&mut buf)?;
 
-        let len = read()?;
-        for _ in 0..len {
-            let mut index = read()?;
-            if (index & (1 << 31)) >> 31 == 1 {
-                index &= !(1 << 31);
-                let range_len = read()?;
-                for i in index..index + range_len {
-                    self.blocks.push(Block::at(i as _));
-                }
-            } else {
-                self.blocks.push(Block::at(index as _));
-            }
-        }
+        self.head = match read_u32(&buf, 0) {
+            0 => None,
+            head => Some(Block::at(head as _)),
+        };
+        self.block_count = read_u32(&buf, 4) as _;
+        self.index.clear();
+        self.blocks.clear();
 
-        Ok(bytes_read)
+        Ok(8)
     }
 }
 
 pub struct ClusterReader<'a, R> {
-    cluster: &'a Cluster,
+    cluster: Cow<'a, Cluster>,
     reader: R,
     cluster_block_index: usize,
     block_offset: usize,
 }
 
+impl<'a, R> ClusterReader<'a, R> {
+    pub fn new(cluster: Cow<'a, Cluster>, reader: R) -> Self {
+        ClusterReader {
+            cluster,
+            reader,
+            cluster_block_index: 0,
+            block_offset: 0,
+        }
+    }
+}
+
+impl<'a, R: io::Read + io::Seek> ClusterReader<'a, R> {
+    pub fn buffered(self) -> BufClusterReader<'a, R> {
+        io::BufReader::with_capacity(BUF_CAPACITY, self)
+    }
+}
+
 impl<'a, R> io::Read for ClusterReader<'a, R>
 where
     R: io::Read + io::Seek,
@@ -124,42 +259,44 @@ where
             return Ok(0);
         }
 
-        let block = &self.cluster.blocks[self.cluster_block_index];
+        let block = self.cluster.blocks[self.cluster_block_index];
         self.reader.seek(io::SeekFrom::Start(
-            (block.index * Block::SIZE + self.block_offset) as _,
+            block.offset() + self.block_offset as u64,
         ))?;
 
-        let max_read = buf.len().min(Block::SIZE - self.block_offset);
+        // Satisfy as much of the read as possible from the run of blocks
+        // which are laid out contiguously in memory.
+        let mut run = 1;
+        while Block::SIZE * run - self.block_offset < buf.len()
+            && self.cluster.get(self.cluster_block_index + run) == Some(block + run)
+        {
+            run += 1;
+        }
 
-        let read_bytes = self.reader.read(&mut buf[..max_read])?;
+        let max_read = buf.len().min(Block::SIZE * run - self.block_offset);
 
-        self.block_offset += read_bytes;
+        let read_bytes = self.reader.read(&mut buf[..max_read])?;
 
-        if self.block_offset >= Block::SIZE {
-            self.cluster_block_index += 1;
-            self.block_offset = 0;
-        }
+        let offset = self.block_offset + read_bytes;
+        self.cluster_block_index += offset / Block::SIZE;
+        self.block_offset = offset % Block::SIZE;
 
         Ok(read_bytes)
     }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        read_slices(self, bufs)
+    }
 }
 
 impl<'a, R> io::Seek for ClusterReader<'a, R> {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        let new_offset = match pos {
-            io::SeekFrom::Start(offset) => offset,
-            io::SeekFrom::Current(offset) => {
-                ((self.cluster_block_index * Block::SIZE + self.block_offset) as i64 + offset)
-                    as u64
-            }
-            io::SeekFrom::End(offset) => (self.cluster.len() as i64 + offset) as u64,
-        };
+        let offset =
+            self.cluster_block_index as u64 * Block::SIZE as u64 + self.block_offset as u64;
+        let new_offset = seek_target(pos, offset, self.cluster.len())?;
 
-        {
-            let new_offset = new_offset as usize;
-            self.cluster_block_index = new_offset / Block::SIZE;
-            self.block_offset = new_offset % Block::SIZE;
-        }
+        self.cluster_block_index = to_usize(new_offset / Block::SIZE as u64)?;
+        self.block_offset = (new_offset % Block::SIZE as u64) as usize;
 
         Ok(new_offset)
     }
@@ -171,6 +308,119 @@ pub struct ClusterWriter<'a, W> {
     bitmap: &'a mut Bitmap,
     cluster_block_index: usize,
     block_offset: usize,
+    /// Whether the cluster holds file contents, which must leave the blocks
+    /// reserved for metadata alone.
+    data: bool,
+}
+
+impl<'a, W> ClusterWriter<'a, W>
+where
+    W: io::Write + io::Seek,
+{
+    pub fn buffered(self) -> BufClusterWriter<'a, W> {
+        io::BufWriter::with_capacity(BUF_CAPACITY, self)
+    }
+
+    /// Keeps the writer out of the blocks reserved for metadata, so writing
+    /// file contents can't take the space needed to update directories.
+    pub fn for_data(mut self) -> Self {
+        self.data = true;
+        self
+    }
+
+    fn available_blocks(&self) -> usize {
+        if self.data {
+            self.bitmap.free_data_blocks()
+        } else {
+            self.bitmap.free_blocks()
+        }
+    }
+
+    /// The length the cluster can grow to with the blocks still available,
+    /// leaving room for the index blocks that takes.
+    pub fn max_len(&self) -> u64 {
+        let budget = self.cluster.blocks.len() + self.cluster.index.len() + self.available_blocks();
+        let blocks = budget - budget.div_ceil(POINTERS_PER_INDEX_BLOCK + 1);
+        blocks as u64 * Block::SIZE as u64
+    }
+
+    /// Allocates a single block, meant to follow the block `after`.
+    fn allocate(&mut self, after: Option<Block>) -> io::Result<Block> {
+        if self.available_blocks() == 0 {
+            return Err(Error::OutOfSpace.into());
+        }
+        self.bitmap
+            .allocate_contiguous_after(after.map(|block| block.index), 1)
+            .map(Block::at)
+            .ok_or_else(|| Error::OutOfSpace.into())
+    }
+
+    /// Extends the cluster with enough blocks to hold `len` bytes, as one run
+    /// where possible, without writing any data. Fails without allocating
+    /// anything if there's not enough space for the blocks and their index.
+    pub fn reserve(&mut self, len: u64) -> io::Result<()> {
+        let total = to_usize(len.div_ceil(Block::SIZE as u64))?;
+        let missing = total.saturating_sub(self.cluster.blocks.len());
+        if missing == 0 {
+            return Ok(());
+        }
+
+        let index_blocks = total
+            .div_ceil(POINTERS_PER_INDEX_BLOCK)
+            .saturating_sub(self.cluster.index.len());
+        if self.available_blocks() < missing + index_blocks {
+            return Err(Error::OutOfSpace.into());
+        }
+
+        let last = self.cluster.blocks.last().map(|block| block.index);
+        match self.bitmap.allocate_contiguous_after(last, missing) {
+            Some(start) => {
+                for i in 0..missing {
+                    self.cluster.extend(Block::at(start + i));
+                }
+            }
+            None => {
+                for _ in 0..missing {
+                    let block = self.allocate(self.cluster.blocks.last().copied())?;
+                    self.cluster.extend(block);
+                }
+            }
+        }
+        self.write_index()
+    }
+
+    fn write_u32_at(&mut self, offset: u64, value: u32) -> io::Result<()> {
+        self.writer.seek(io::SeekFrom::Start(offset))?;
+        self.writer.write_all(&value.to_be_bytes())
+    }
+
+    /// Writes the pointers of all blocks which are not yet recorded in the
+    /// index, allocating and linking new index blocks as needed.
+    fn write_index(&mut self) -> io::Result<()> {
+        while self.cluster.block_count < self.cluster.blocks.len() {
+            let n = self.cluster.block_count;
+            let (i, slot) = (n / POINTERS_PER_INDEX_BLOCK, n % POINTERS_PER_INDEX_BLOCK);
+
+            if i == self.cluster.index.len() {
+                let index_block = self.allocate(None)?;
+                self.write_u32_at(index_block.offset(), 0)?;
+                match self.cluster.index.last() {
+                    Some(prev) => {
+                        let prev = prev.offset();
+                        self.write_u32_at(prev, index_block.index as _)?;
+                    }
+                    None => self.cluster.head = Some(index_block),
+                }
+                self.cluster.index.push(index_block);
+            }
+
+            let pointer = self.cluster.index[i].offset() + 4 + slot as u64 * 4;
+            let block = self.cluster.blocks[n].index as u32;
+            self.write_u32_at(pointer, block)?;
+            self.cluster.block_count += 1;
+        }
+        Ok(())
+    }
 }
 
 impl<'a, W> io::Write for ClusterWriter<'a, W>
@@ -178,34 +428,59 @@ where
     W: io::Write + io::Seek,
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        while self.cluster_block_index >= self.cluster.blocks.len() {
-            let block = self
-                .bitmap
-                .occupy_next()
-                .map(Block::at)
-                .ok_or_else(|| io::ErrorKind::OutOfMemory)?;
-            self.cluster.extend(block);
+        if self.cluster_block_index >= self.cluster.blocks.len() {
+            // Try to reserve the whole remainder of the buffer in one run, so
+            // large writes end up laid out sequentially.
+            let end = self.cluster_block_index * Block::SIZE + self.block_offset + buf.len();
+            let missing = end.div_ceil(Block::SIZE) - self.cluster.blocks.len();
+            let last = self.cluster.blocks.last().map(|block| block.index);
+            let start = if self.available_blocks() >= missing {
+                self.bitmap.allocate_contiguous_after(last, missing)
+            } else {
+                None
+            };
+            if let Some(start) = start {
+                for i in 0..missing {
+                    self.cluster.extend(Block::at(start + i));
+                }
+            }
+
+            while self.cluster_block_index >= self.cluster.blocks.len() {
+                let block = self.allocate(self.cluster.blocks.last().copied())?;
+                self.cluster.extend(block);
+            }
+            self.write_index()?;
         }
 
-        let block = &self.cluster.blocks[self.cluster_block_index];
+        let block = self.cluster.blocks[self.cluster_block_index];
         self.writer.seek(io::SeekFrom::Start(
-            (block.index * Block::SIZE + self.block_offset) as _,
+            block.offset() + self.block_offset as u64,
         ))?;
 
-        let max_write = buf.len().min(Block::SIZE - self.block_offset);
+        // Extend the write over all following blocks which are laid out
+        // contiguously in memory, so it reaches the memory in one piece.
+        let mut run = 1;
+        while Block::SIZE * run - self.block_offset < buf.len()
+            && self.cluster.get(self.cluster_block_index + run) == Some(block + run)
+        {
+            run += 1;
+        }
 
-        let written_bytes = self.writer.write(&buf[..max_write])?;
+        let max_write = buf.len().min(Block::SIZE * run - self.block_offset);
 
-        self.block_offset += written_bytes;
+        let written_bytes = self.writer.write(&buf[..max_write])?;
 
-        if self.block_offset >= Block::SIZE {
-            self.cluster_block_index += 1;
-            self.block_offset = 0;
-        }
+        let offset = self.block_offset + written_bytes;
+        self.cluster_block_index += offset / Block::SIZE;
+        self.block_offset = offset % Block::SIZE;
 
         Ok(written_bytes)
     }
 
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        write_slices(self, bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.writer.flush()
     }
@@ -213,25 +488,54 @@ where
 
 impl<'a, W> io::Seek for ClusterWriter<'a, W> {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        let new_offset = match pos {
-            io::SeekFrom::Start(offset) => offset,
-            io::SeekFrom::Current(offset) => {
-                ((self.cluster_block_index * Block::SIZE + self.block_offset) as i64 + offset)
-                    as u64
-            }
-            io::SeekFrom::End(offset) => (self.cluster.len() as i64 + offset) as u64,
-        };
+        let offset =
+            self.cluster_block_index as u64 * Block::SIZE as u64 + self.block_offset as u64;
+        let new_offset = seek_target(pos, offset, self.cluster.len())?;
 
-        {
-            let new_offset = new_offset as usize;
-            self.cluster_block_index = new_offset / Block::SIZE;
-            self.block_offset = new_offset % Block::SIZE;
-        }
+        self.cluster_block_index = to_usize(new_offset / Block::SIZE as u64)?;
+        self.block_offset = (new_offset % Block::SIZE as u64) as usize;
 
         Ok(new_offset)
     }
 }
 
+#[cfg(test)]
+#[derive(Default)]
+struct CountingMemory {
+    heap: crate::heap_memory::HeapMemory,
+    reads: std::cell::Cell<usize>,
+    writes: usize,
+}
+
+#[cfg(test)]
+impl crate::memory::Memory for CountingMemory {
+    fn page_size(&self) -> u64 {
+        self.heap.page_size()
+    }
+
+    fn max_pages(&self) -> u64 {
+        self.heap.max_pages()
+    }
+
+    fn page_count(&self) -> io::Result<u64> {
+        self.heap.page_count()
+    }
+
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        self.heap.grow(num_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.set(self.reads.get() + 1);
+        self.heap.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        self.heap.write(offset, buf)
+    }
+}
+
 #[test]
 fn reader() {
     use crate::heap_memory::HeapMemory;
@@ -242,10 +546,10 @@ fn reader() {
 
     {
         let mut w = heap.writer();
-        w.write(b"FIRST BLOCK START").unwrap();
+        w.write_all(b"FIRST BLOCK START").unwrap();
         w.seek(io::SeekFrom::Start((Block::SIZE * 2) as u64))
             .unwrap();
-        w.write(b"THIRD BLOCK START").unwrap();
+        w.write_all(b"THIRD BLOCK START").unwrap();
     }
 
     let mut cluster = Cluster::default();
@@ -269,7 +573,7 @@ fn writer() {
     use std::io::{Read, Seek, Write};
 
     let mut heap = HeapMemory::default();
-    let mut bitmap = Bitmap::new::<HeapMemory>();
+    let mut bitmap = Bitmap::new(&HeapMemory::default());
     let mut cluster = Cluster::default();
 
     {
@@ -314,35 +618,148 @@ fn writer() {
 }
 
 #[test]
-fn serde() {
-    let mut cluster = Cluster::default();
-    // Range 1   (1 -> 2)
-    cluster.extend(Block::at(1));
-    cluster.extend(Block::at(2));
-    cluster.extend(Block::at(3));
-    // Range 2   (5)
-    cluster.extend(Block::at(5));
+fn index() {
+    use crate::heap_memory::HeapMemory;
+    use crate::memory::Memory;
+    use std::io::Write;
 
-    let mut data = vec![];
-    cluster.serialize(&mut data).unwrap();
+    const DATA_BLOCKS: usize = POINTERS_PER_INDEX_BLOCK + 3;
 
-    #[rustfmt::skip]
-    assert_eq!(&data, &vec![
-        //                                                 👇 marks number of ranges (2)
-        0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0000_000__10__,
+    let mut heap = HeapMemory::default();
+    let mut bitmap = Bitmap::new(&HeapMemory::default());
+    let mut cluster = Cluster::default();
+
+    cluster
+        .writer(&mut bitmap, heap.writer())
+        .write_all(&[7u8; Block::SIZE * DATA_BLOCKS])
+        .unwrap();
 
-        // Range 1
-        // 👇 marks range > 1 indices                         👇 marks start index (1)
-        0b__1__000_0000, 0b0000_0000, 0b0000_0000, 0b0000_000__1__,
-        //                                                    👇 marks length of range (3)
-        0b____0000_0000, 0b0000_0000, 0b0000_0000, 0b0000_00__11__,
+    assert_eq!(cluster.blocks().count(), DATA_BLOCKS);
+    assert_eq!(cluster.index_blocks().count(), 2);
 
-        // Range 2
-        // 👇 marks single index range                       👇 marks index (5)
-        0b__0__000_0000, 0b0000_0000, 0b0000_0000, 0b0000_0__101__,
-    ]);
+    let mut handle = vec![];
+    assert_eq!(cluster.serialize(&mut handle).unwrap(), 8);
 
     let mut cluster2 = Cluster::default();
-    cluster2.deserialize(&*data).unwrap();
+    cluster2.deserialize(&*handle).unwrap();
+    assert!(!cluster2.is_loaded());
+    assert_eq!(cluster2.len(), cluster.len());
+
+    cluster2.load(heap.reader()).unwrap();
     assert_eq!(cluster, cluster2);
+
+    // Corrupted handles and indexes are refused rather than trusted.
+    let mut huge = handle.clone();
+    huge[4..].copy_from_slice(&u32::MAX.to_be_bytes());
+    let mut cluster3 = Cluster::default();
+    cluster3.deserialize(&*huge).unwrap();
+    assert!(cluster3.load(heap.reader()).is_err());
+
+    let pointer = cluster.index_blocks().next().unwrap().offset() + 4;
+    heap.write(pointer, &u32::MAX.to_be_bytes()).unwrap();
+    cluster2.deserialize(&*handle).unwrap();
+    assert!(cluster2.load(heap.reader()).is_err());
+}
+
+#[test]
+fn corrupted_index() {
+    use crate::heap_memory::HeapMemory;
+    use crate::memory::Memory;
+    use std::io::Write;
+
+    let mut heap = HeapMemory::default();
+    let mut bitmap = Bitmap::new(&HeapMemory::default());
+    let mut cluster = Cluster::default();
+    cluster
+        .writer(&mut bitmap, heap.writer())
+        .write_all(&[7u8; Block::SIZE * (POINTERS_PER_INDEX_BLOCK + 3)])
+        .unwrap();
+    let mut handle = vec![];
+    cluster.serialize(&mut handle).unwrap();
+    let link = cluster.index_blocks().next().unwrap().offset();
+
+    // Each corruption is an error of its own rather than a trap, an
+    // allocation for every claimed block or a block past the memory.
+    let load = |heap: &HeapMemory, handle: &[u8]| {
+        let mut cluster = Cluster::default();
+        cluster.deserialize(handle).unwrap();
+        let err = cluster.load(heap.reader()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(cluster.blocks().count(), 0);
+        err.to_string()
+    };
+
+    let mut huge = handle.clone();
+    huge[4..].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(load(&heap, &huge).contains("blocks in a memory of"));
+
+    heap.write(link, &0u32.to_be_bytes()).unwrap();
+    assert!(load(&heap, &handle).contains("truncated"));
+
+    heap.write(link, &u32::MAX.to_be_bytes()).unwrap();
+    assert!(load(&heap, &handle).contains("points to block"));
+
+    let next = cluster.index_blocks().nth(1).unwrap().index as u32;
+    heap.write(link, &next.to_be_bytes()).unwrap();
+    heap.write(link + 4, &u32::MAX.to_be_bytes()).unwrap();
+    assert!(load(&heap, &handle).contains("points to block"));
+}
+
+#[test]
+fn buffered() {
+    use crate::memory::Memory;
+    use std::io::{BufRead, Write};
+
+    let mut memory = CountingMemory::default();
+    let mut bitmap = Bitmap::new(&CountingMemory::default());
+    let mut cluster = Cluster::default();
+
+    {
+        let mut w = cluster.writer(&mut bitmap, memory.writer()).buffered();
+        for i in 0..Block::SIZE * 4 {
+            w.write_all(&[i as u8]).unwrap();
+        }
+        w.flush().unwrap();
+    }
+
+    // One write for the four contiguous data blocks, plus the link and four
+    // pointers of the index block.
+    assert_eq!(memory.writes, 1 + 5);
+
+    let mut r = cluster.reader(memory.reader()).buffered();
+    for i in 0..Block::SIZE * 4 {
+        let buf = r.fill_buf().unwrap();
+        assert_eq!(buf[0], i as u8);
+        r.consume(1);
+    }
+}
+
+#[test]
+fn contiguous_reads() {
+    use crate::memory::Memory;
+    use std::io::{Read, Write};
+
+    let mut memory = CountingMemory::default();
+    let mut bitmap = Bitmap::new(&CountingMemory::default());
+    let mut cluster = Cluster::default();
+
+    let data = (0..Block::SIZE * 6).map(|i| i as u8).collect::<Vec<_>>();
+    cluster
+        .writer(&mut bitmap, memory.writer())
+        .write_all(&data)
+        .unwrap();
+    assert!(cluster
+        .blocks()
+        .zip(cluster.blocks().skip(1))
+        .all(|(a, b)| *a + 1 == *b));
+
+    let mut read_data = vec![0u8; data.len()];
+    cluster
+        .reader(memory.reader())
+        .read_exact(&mut read_data)
+        .unwrap();
+    assert_eq!(read_data, data);
+
+    // Six contiguous blocks are read with one memory read.
+    assert_eq!(memory.reads.get(), 1);
 }
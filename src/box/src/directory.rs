@@ -1,43 +1,221 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::io;
 
-use crate::cluster::{Cluster, ClusterReader, ClusterWriter};
+use crate::backup::TrackedMemory;
+use crate::block::Block;
+use crate::checksum::Checksum;
+use crate::cluster::{Cluster, ClusterReader, ClusterWriter, BUF_CAPACITY};
+use crate::error::Error;
 use crate::file_system::FileSystem;
-use crate::memory::{Memory, MemoryReader, MemoryWriter};
-use crate::serde::{Deserialize, Serialize};
+use crate::memory::{
+    read_slices, seek_target, to_usize, write_slices, Memory, MemoryReader, MemoryWriter,
+};
+use crate::serde::{
+    read_bytes, read_string, with_max_string_len, Compact, Deserialize, Serialize, MAX_STRING_LEN,
+};
+use crate::tail::Tail;
+
+/// Directories used to start with their entry count as 8 bytes, followed by
+/// entries with 8-byte lengths and inode numbers. The first byte of those is
+/// 0, so later formats start with their number instead. In the compact
+/// format, the count, lengths and inode numbers are varints.
+const COMPACT_FORMAT: u8 = 2;
+
+/// Like the compact format, but each entry is a number of fields, each with
+/// its id and length, so fields can be added without a new format. Readers
+/// skip fields they don't know, and fields which are missing keep their
+/// default, which is why fields holding the default aren't written.
+const TAGGED_FORMAT: u8 = 3;
+
+/// Like the tagged format, with fields of the directory itself between the
+/// count and the entries, written like those of an entry. Only the root
+/// directory, which has no entry of its own, is written in it, and only
+/// when it has an ACL or settings.
+const ROOT_FORMAT: u8 = 4;
+
+/// The newest format directories are written in, which builds knowing only
+/// older ones can't read.
+pub const FORMAT_VERSION: u8 = ROOT_FORMAT;
+
+/// Fields of an entry in the tagged format.
+const KIND: u64 = 1;
+const NAME: u64 = 2;
+const CONTENT_TYPE: u64 = 3;
+const INODE: u64 = 4;
+const HEADERS: u64 = 5;
+const FALLBACK: u64 = 6;
+const LISTING: u64 = 7;
+const REDIRECT: u64 = 8;
+const ACL: u64 = 9;
+/// Only written among the fields of the root directory itself.
+const SETTINGS: u64 = 10;
+const XATTRS: u64 = 11;
+
+/// Longest entry name accepted by default, in bytes.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Rules for entry names, so every entry stays reachable by path.
+#[derive(Debug, Clone, Copy)]
+pub struct NamePolicy {
+    /// Longest accepted name in bytes, after normalization.
+    pub max_len: usize,
+    /// Applied to names before they are stored or looked up, such as
+    /// Unicode NFC normalization.
+    pub normalize: Option<fn(&str) -> String>,
+    /// Whether lookups ignore case.
+    pub case_insensitive: bool,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        NamePolicy {
+            max_len: MAX_NAME_LEN,
+            normalize: None,
+            case_insensitive: false,
+        }
+    }
+}
+
+impl NamePolicy {
+    pub fn normalized<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match self.normalize {
+            Some(normalize) => Cow::Owned(normalize(name)),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    /// Returns `name` as it is to be stored, or fails with `NameInvalid` if
+    /// it's empty, too long, `.` or `..`, or contains a `/`.
+    pub fn validate(&self, name: &str) -> io::Result<String> {
+        let name = self.normalized(name);
+        let valid = !name.is_empty()
+            && name.len() <= self.max_len
+            && name != "."
+            && name != ".."
+            && !name.contains('/');
+        if !valid {
+            return Err(Error::NameInvalid.into());
+        }
+        Ok(name.into_owned())
+    }
+
+    /// A key which is equal for all names matching each other.
+    pub fn key(&self, name: &str) -> String {
+        let name = self.normalized(name);
+        if self.case_insensitive {
+            name.chars().flat_map(char::to_lowercase).collect()
+        } else {
+            name.into_owned()
+        }
+    }
+
+    /// Whether the stored name `a` matches the normalized name `b`.
+    pub fn matches(&self, a: &str, b: &str) -> bool {
+        if self.case_insensitive {
+            a.chars()
+                .flat_map(char::to_lowercase)
+                .eq(b.chars().flat_map(char::to_lowercase))
+        } else {
+            a == b
+        }
+    }
+}
 
 #[derive(Default, Debug)]
 pub struct Directory {
     pub entries: Vec<Entry>,
+    /// `listing_checksum` as of reading the directory, to tell whether it
+    /// changed when it's written back.
+    pub(crate) listing: Option<u64>,
+    /// Rules for the names of new entries and for lookups.
+    pub names: NamePolicy,
+    /// Who may access the root directory and everything in the filesystem.
+    /// Other directories keep theirs in their entry.
+    pub acl: Option<Acl>,
+    /// Settings of whatever the filesystem is embedded in, kept with the
+    /// root directory by name. Only `FileSystem::set_setting` changes them.
+    pub settings: Vec<(String, String)>,
 }
 
 impl Directory {
-    pub fn add_file(&mut self, name: impl Into<String>, content_type: impl Into<String>) -> &mut Entry {
-        self.entries.push(Entry {
+    /// Checksum over the names and inodes of all entries, which changes
+    /// when entries are added, removed or renamed.
+    pub fn listing_checksum(&self) -> u64 {
+        let mut checksum = Checksum::default();
+        for entry in self.entries.iter() {
+            checksum.update(entry.name.as_bytes());
+            checksum.update(&[0]);
+            checksum.update(&entry.inode.to_be_bytes());
+        }
+        checksum.value()
+    }
+
+    pub fn add_file(
+        &mut self,
+        name: impl AsRef<str>,
+        content_type: impl Into<String>,
+    ) -> io::Result<&mut Entry> {
+        let entry = Entry {
             kind: EntryKind::File,
-            name: name.into(),
             content_type: content_type.into(),
             ..Default::default()
-        });
-        self.entries.last_mut().unwrap()
+        };
+        self.add_entry(name.as_ref(), entry)
     }
 
-    pub fn add_directory(&mut self, name: impl Into<String>) -> &mut Entry {
-        self.entries.push(Entry {
+    pub fn add_directory(&mut self, name: impl AsRef<str>) -> io::Result<&mut Entry> {
+        let entry = Entry {
             kind: EntryKind::Directory,
-            name: name.into(),
             ..Default::default()
-        });
-        self.entries.last_mut().unwrap()
+        };
+        self.add_entry(name.as_ref(), entry)
+    }
+
+    /// Like `add_file`, but replaces an entry which already has the name
+    /// instead of failing. The replaced entry is returned, so its blocks can
+    /// be released.
+    pub fn replace_file(
+        &mut self,
+        name: impl AsRef<str>,
+        content_type: impl Into<String>,
+    ) -> io::Result<(&mut Entry, Option<Entry>)> {
+        let name = self.names.validate(name.as_ref())?;
+        let replaced = self.remove_entry(&name);
+        Ok((self.add_file(name, content_type)?, replaced))
+    }
+
+    /// Adds `entry` under `name`, which has to be valid according to the
+    /// name policy and not taken yet.
+    fn add_entry(&mut self, name: &str, mut entry: Entry) -> io::Result<&mut Entry> {
+        entry.name = self.names.validate(name)?;
+        if self.position(&entry.name).is_some() {
+            return Err(Error::AlreadyExists.into());
+        }
+        self.entries.push(entry);
+        Ok(self.entries.last_mut().unwrap())
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        let n = self.names.normalized(name);
+        self.entries
+            .iter()
+            .position(|e| self.names.matches(&e.name, &n))
     }
 
     pub fn entry_with_name(&self, name: impl AsRef<str>) -> Option<&Entry> {
-        let n = name.as_ref();
-        self.entries.iter().find(|e| e.name == n)
+        let i = self.position(name.as_ref())?;
+        self.entries.get(i)
     }
 
     pub fn entry_with_name_mut(&mut self, name: impl AsRef<str>) -> Option<&mut Entry> {
-        let n = name.as_ref();
-        self.entries.iter_mut().find(|e| e.name == n)
+        let i = self.position(name.as_ref())?;
+        self.entries.get_mut(i)
+    }
+
+    pub fn remove_entry(&mut self, name: impl AsRef<str>) -> Option<Entry> {
+        let i = self.position(name.as_ref())?;
+        Some(self.entries.remove(i))
     }
 
     pub fn file_with_name_or_create_mut(
@@ -45,25 +223,11 @@ impl Directory {
         name: impl Into<String> + AsRef<str>,
         content_type: impl Into<String>,
     ) -> io::Result<&mut Entry> {
-        let n = name.as_ref();
-
-        let mut idx = None;
-
-        for (i, e) in self.entries.iter_mut().enumerate() {
-            if e.name == n {
-                if e.kind == EntryKind::Directory {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("name {} exists as a directory", name.as_ref()),
-                    ));
-                }
-                idx = Some(i);
-                break;
+        match self.position(name.as_ref()) {
+            None => self.add_file(name, content_type),
+            Some(idx) if self.entries[idx].kind == EntryKind::Directory => {
+                Err(Error::IsADirectory.into())
             }
-        }
-
-        match idx {
-            None => Ok(self.add_file(name, content_type)),
             Some(idx) => Ok(self.entries.get_mut(idx).unwrap()),
         }
     }
@@ -85,25 +249,23 @@ impl Directory {
                 Some(Entry {
                     kind: EntryKind::File,
                     ..
-                }) => Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    format!("{} is not a directory", segment.into()),
-                )),
+                }) => Err(Error::NotADirectory.into()),
 
                 Some(e) => {
-                    let mut existing_dir = e.read_from_file_system(fs).read_directory()?;
+                    let mut existing_dir = fs.read_directory(e)?;
                     existing_dir.make_directory_recursive(fs, path)?;
-                    e.write_to_file_system(fs).write_directory(&existing_dir)?;
-                    Ok(())
+                    fs.write_directory(e, &mut existing_dir)
                 }
 
                 None => {
-                    let mut new_dir = Directory::default();
+                    let mut new_dir = Directory {
+                        names: self.names,
+                        ..Default::default()
+                    };
                     new_dir.make_directory_recursive(fs, path)?;
 
-                    let d = self.add_directory(segment);
-                    d.write_to_file_system(fs).write_directory(&new_dir)?;
-                    Ok(())
+                    let d = self.add_directory(segment)?;
+                    fs.write_directory(d, &mut new_dir)
                 }
             },
         }
@@ -111,24 +273,195 @@ impl Directory {
 }
 
 impl Serialize for Directory {
-    fn serialize(&self, w: impl io::Write) -> io::Result<usize> {
-        self.entries.serialize(w)
+    fn serialize(&self, mut w: impl io::Write) -> io::Result<usize> {
+        let own = self.acl.is_some() || !self.settings.is_empty();
+        let format = if own { ROOT_FORMAT } else { TAGGED_FORMAT };
+        let mut written =
+            format.serialize(&mut w)? + Compact(self.entries.len()).serialize(&mut w)?;
+        if own {
+            let acl = acl_field(&self.acl)?;
+            let settings = pairs_field(&self.settings)?;
+            written += write_fields(
+                &mut w,
+                &[(ACL, acl.as_slice()), (SETTINGS, settings.as_slice())],
+            )?;
+        }
+        for entry in self.entries.iter() {
+            written += entry.serialize(&mut w)?;
+        }
+        Ok(written)
     }
 }
 
 impl Deserialize for Directory {
-    fn deserialize(&mut self, r: impl io::Read) -> io::Result<usize> {
-        self.entries.deserialize(r)
+    fn deserialize(&mut self, mut r: impl io::Read) -> io::Result<usize> {
+        let (format, count, own, mut read) = read_header(&mut r)?;
+        self.acl = own.acl;
+        self.settings = own.settings;
+        for _ in 0..count {
+            let mut entry = Entry::default();
+            read += entry.deserialize_format(&mut r, format)?;
+            self.entries.push(entry);
+        }
+        Ok(read)
     }
 }
 
-#[derive(Default, Debug)]
+/// Reads the format of a serialized directory, 0 for the original one, how
+/// many entries it has and the fields of the root directory, as a directory
+/// without entries. Also returns the number of bytes read.
+fn read_header(mut r: impl io::Read) -> io::Result<(u8, usize, Directory, usize)> {
+    let first = u8::deserialize_into_default(&mut r)?;
+    let mut own = Directory::default();
+    if first == COMPACT_FORMAT || first == TAGGED_FORMAT || first == ROOT_FORMAT {
+        let mut count = 0usize;
+        let mut n = Compact(&mut count).deserialize(&mut r)?;
+        if first == ROOT_FORMAT {
+            n += read_fields(r, |id, data| {
+                match id {
+                    ACL => own.acl = Some(read_acl(data)?),
+                    SETTINGS => own.settings = read_pairs(data)?,
+                    _ => {}
+                }
+                Ok(())
+            })?;
+        }
+        return Ok((first, count, own, 1 + n));
+    }
+
+    let mut count = [first; 8];
+    io::Read::read_exact(&mut r, &mut count[1..])?;
+    Ok((0, to_usize(u64::from_be_bytes(count))?, own, 8))
+}
+
+/// Yields the entries of a serialized directory one at a time, so lookups
+/// can stop at the first match without materializing the whole directory.
+pub struct DirectoryReader<R> {
+    reader: R,
+    format: u8,
+    remaining: usize,
+    names: NamePolicy,
+    max_string_len: usize,
+}
+
+impl<R: io::Read> DirectoryReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let (format, remaining, _, _) = read_header(&mut reader)?;
+        Ok(DirectoryReader {
+            reader,
+            format,
+            remaining,
+            names: NamePolicy::default(),
+            max_string_len: MAX_STRING_LEN,
+        })
+    }
+
+    /// Sets the rules `entry_with_name` matches names by.
+    pub fn with_name_policy(mut self, names: NamePolicy) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Refuses entries with names or other strings over `len` bytes as
+    /// corrupted, rather than those over `MAX_STRING_LEN`.
+    pub fn with_max_string_len(mut self, len: usize) -> Self {
+        self.max_string_len = len;
+        self
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn entry_with_name(&mut self, name: impl AsRef<str>) -> io::Result<Option<Entry>> {
+        let names = self.names;
+        let n = names.normalized(name.as_ref());
+        for entry in self {
+            let entry = entry?;
+            if names.matches(&entry.name, &n) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<R: io::Read> Iterator for DirectoryReader<R> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let mut entry = Entry::default();
+        let (reader, format) = (&mut self.reader, self.format);
+        match with_max_string_len(self.max_string_len, || {
+            entry.deserialize_format(reader, format)
+        }) {
+            Ok(_) => Some(Ok(entry)),
+            Err(e) => {
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// A named reference to an inode. Only the kind, name, content type, HTTP
+/// settings and inode number are stored in the directory; the remaining
+/// fields mirror the inode and are filled in by the `FileSystem` when the
+/// directory is read, and written back to the inode table when it is
+/// written.
+#[derive(Default, Debug, Clone)]
 pub struct Entry {
     pub kind: EntryKind,
-    pub size: usize,
+    pub size: u64,
     pub name: String,
     pub content_type: String,
     pub cluster: Cluster,
+    /// Number of the inode in the inode table, 0 until one is allocated.
+    pub inode: u64,
+    pub created: u64,
+    pub modified: u64,
+    /// Number of entries of a directory, as of the last time it was written.
+    pub entry_count: u64,
+    /// Sealed files can't be written, truncated or removed, and sealed
+    /// directories can't have entries added or removed. Only
+    /// `FileSystem::set_sealed` changes it.
+    pub sealed: bool,
+    /// Contents of a small file, packed into a block shared with other files
+    /// instead of a cluster.
+    pub inline: Option<Vec<u8>>,
+    /// Last partial block of a file, packed into a block shared with the
+    /// tails of other files.
+    pub tail: Option<Tail>,
+    /// Time after which the entry is removed by
+    /// `FileSystem::purge_expired`, on the same scale as the clock.
+    pub expires: Option<u64>,
+    /// Extra headers for serving the entry over HTTP, like Cache-Control.
+    /// Those of a directory apply to everything below it. Only
+    /// `FileSystem::set_headers` changes them.
+    pub headers: Vec<(String, String)>,
+    /// How the HTTP gateway treats paths which don't exist. Only
+    /// `FileSystem::set_fallback` changes it.
+    pub fallback: Fallback,
+    /// Whether the HTTP gateway lists the contents of a directory without
+    /// an `index.html`, and of the directories below it. Only
+    /// `FileSystem::set_listing` changes it.
+    pub listing: bool,
+    /// Where the HTTP gateway sends requests for the entry, and for
+    /// everything below a directory, instead of serving it. Only
+    /// `FileSystem::set_redirect` changes it.
+    pub redirect: Option<Redirect>,
+    /// Who may access the entry, and everything below a directory, besides
+    /// those the directories above it let in. Only `FileSystem::set_acl`
+    /// changes it.
+    pub acl: Option<Acl>,
+    /// Extended attributes, values by name for whatever clients keep along
+    /// with the entry. Only `FileSystem::set_metadata` changes them.
+    pub xattrs: Vec<(String, Vec<u8>)>,
 }
 
 impl Entry {
@@ -142,69 +475,450 @@ impl Entry {
     pub fn read_from_file_system<'a, M: Memory>(
         &'a self,
         fs: &'a FileSystem<M>,
-    ) -> EntryReader<'a, ClusterReader<'a, MemoryReader<'a, M>>> {
-        self.reader(fs.read_from_cluster(&self.cluster))
+    ) -> io::Result<EntryReader<'a, ContentReader<'a, M>>> {
+        let content = match (&self.inline, &self.tail) {
+            (Some(data), _) => ContentReader::Inline(io::Cursor::new(data)),
+            (None, Some(tail)) => ContentReader::Packed {
+                cluster: fs.read_from_cluster(&self.cluster)?,
+                split: self.size - tail.len as u64,
+                tail: io::Cursor::new(fs.read_tail(tail)?),
+            },
+            (None, None) => ContentReader::Cluster(fs.read_from_cluster(&self.cluster)?),
+        };
+        Ok(self.reader(content))
     }
 
-    pub fn reader<R>(&self, reader: R) -> EntryReader<R> {
+    pub fn reader<R>(&self, reader: R) -> EntryReader<'_, R> {
         EntryReader {
             entry: self,
             reader,
             offset: 0,
+            buf: Vec::new(),
+            pos: 0,
         }
     }
 
     pub fn write_to_file_system<'a, M: Memory>(
         &'a mut self,
         fs: &'a mut FileSystem<M>,
-    ) -> EntryWriter<'a, ClusterWriter<'a, MemoryWriter<'a, M>>> {
-        let writer = fs.write_into_cluster(&mut self.cluster);
-        EntryWriter {
+    ) -> io::Result<EntryWriter<'a, ClusterWriter<'a, MemoryWriter<'a, TrackedMemory<M>>>>> {
+        let writer = match self.kind {
+            EntryKind::File if self.sealed => return Err(Error::Sealed.into()),
+            EntryKind::File => {
+                self.move_inline_to_cluster(fs)?;
+                self.move_tail_to_cluster(fs)?;
+                fs.write_into_cluster(&mut self.cluster)?.for_data()
+            }
+            EntryKind::Directory => fs.write_into_cluster(&mut self.cluster)?,
+        };
+        Ok(EntryWriter {
             entry_size: &mut self.size,
+            max_size: writer.max_len(),
             writer,
             offset: 0,
+        })
+    }
+
+    /// Reads into `buf` from `offset`, which only falls short at the end of
+    /// the file. Returns the number of bytes read.
+    pub fn read_at<M: Memory>(
+        &self,
+        fs: &FileSystem<M>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let mut r = self.read_from_file_system(fs)?;
+        io::Seek::seek(&mut r, io::SeekFrom::Start(offset))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match io::Read::read(&mut r, &mut buf[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(read)
+    }
+
+    /// Checksum of the contents of a file, which reads all of it.
+    pub fn content_checksum<M: Memory>(&self, fs: &FileSystem<M>) -> io::Result<u64> {
+        let mut checksum = Checksum::default();
+        io::copy(&mut self.read_from_file_system(fs)?, &mut checksum)?;
+        Ok(checksum.value())
+    }
+
+    /// Writes `data` at `offset`. Writing past the end fills the gap with
+    /// zeros.
+    pub fn write_at<M: Memory>(
+        &mut self,
+        fs: &mut FileSystem<M>,
+        offset: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut w = self.write_to_file_system(fs)?;
+        io::Seek::seek(&mut w, io::SeekFrom::Start(offset))?;
+        io::Write::write_all(&mut w, data)
+    }
+
+    /// Reserves blocks for `len` bytes of content up front, so writing that
+    /// much can't run out of space halfway. The size of the entry is
+    /// unchanged.
+    pub fn preallocate<M: Memory>(&mut self, fs: &mut FileSystem<M>, len: u64) -> io::Result<()> {
+        if self.sealed {
+            return Err(Error::Sealed.into());
         }
+        self.move_inline_to_cluster(fs)?;
+        self.move_tail_to_cluster(fs)?;
+        fs.write_into_cluster(&mut self.cluster)?
+            .for_data()
+            .reserve(len)
     }
 
-    pub fn writer<W>(&mut self, writer: W) -> EntryWriter<W> {
+    /// Writes inline contents into the cluster, so they can be written in
+    /// place. The `FileSystem` moves them back inline while they're small.
+    fn move_inline_to_cluster<M: Memory>(&mut self, fs: &mut FileSystem<M>) -> io::Result<()> {
+        if let Some(data) = self.inline.take() {
+            let mut w = fs.write_into_cluster(&mut self.cluster)?.for_data();
+            if let Err(e) = io::Write::write_all(&mut w, &data) {
+                self.inline = Some(data);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a packed tail back behind the full blocks in the cluster and
+    /// frees its fragment. The `FileSystem` packs it again when the entry is
+    /// stored.
+    fn move_tail_to_cluster<M: Memory>(&mut self, fs: &mut FileSystem<M>) -> io::Result<()> {
+        if let Some(tail) = self.tail {
+            let data = fs.read_tail(&tail)?;
+            let mut w = fs.write_into_cluster(&mut self.cluster)?.for_data();
+            io::Seek::seek(&mut w, io::SeekFrom::Start(self.size - tail.len as u64))?;
+            io::Write::write_all(&mut w, &data)?;
+            self.tail = None;
+            fs.free_tail(tail)?;
+        }
+        Ok(())
+    }
+
+    /// Shrinks the entry to `len` bytes, releasing the blocks past it. Does
+    /// nothing if the entry isn't larger than `len`.
+    pub fn truncate<M: Memory>(&mut self, fs: &mut FileSystem<M>, len: u64) -> io::Result<()> {
+        if self.sealed {
+            return Err(Error::Sealed.into());
+        }
+        if len < self.size {
+            self.move_tail_to_cluster(fs)?;
+            match &mut self.inline {
+                Some(data) => data.truncate(to_usize(len)?),
+                None => fs.truncate_cluster(&mut self.cluster, len)?,
+            }
+            self.size = len;
+        }
+        Ok(())
+    }
+
+    pub fn writer<W>(&mut self, writer: W) -> EntryWriter<'_, W> {
         EntryWriter {
             entry_size: &mut self.size,
+            max_size: u64::MAX,
             writer,
             offset: 0,
         }
     }
 }
 
+impl Entry {
+    /// Reads an entry in the given directory format.
+    fn deserialize_format(&mut self, mut r: impl io::Read, format: u8) -> io::Result<usize> {
+        match format {
+            TAGGED_FORMAT | ROOT_FORMAT => self.deserialize(r),
+            COMPACT_FORMAT => Ok(self.kind.deserialize(&mut r)?
+                + Compact(&mut self.name).deserialize(&mut r)?
+                + Compact(&mut self.content_type).deserialize(&mut r)?
+                + Compact(&mut self.inode).deserialize(r)?),
+            _ => Ok(self.kind.deserialize(&mut r)?
+                + self.name.deserialize(&mut r)?
+                + self.content_type.deserialize(&mut r)?
+                + self.inode.deserialize(r)?),
+        }
+    }
+}
+
+/// Entries are written in the tagged format.
 impl Serialize for Entry {
-    fn serialize(&self, mut w: impl io::Write) -> io::Result<usize> {
-        Ok(self.kind.serialize(&mut w)?
-            + self.name.as_str().serialize(&mut w)?
-            + self.content_type.as_str().serialize(&mut w)?
-            + self.size.serialize(&mut w)?
-            + self.cluster.serialize(w)?)
+    fn serialize(&self, w: impl io::Write) -> io::Result<usize> {
+        let mut kind = vec![];
+        self.kind.serialize(&mut kind)?;
+        let mut inode = vec![];
+        if self.inode != 0 {
+            Compact(self.inode).serialize(&mut inode)?;
+        }
+        let headers = pairs_field(&self.headers)?;
+        let mut redirect = vec![];
+        if let Some(Redirect { status, location }) = &self.redirect {
+            Compact(*status as u64).serialize(&mut redirect)?;
+            Compact(location.as_str()).serialize(&mut redirect)?;
+        }
+        let acl = acl_field(&self.acl)?;
+        let mut xattrs = vec![];
+        if !self.xattrs.is_empty() {
+            Compact(self.xattrs.len()).serialize(&mut xattrs)?;
+            for (name, value) in self.xattrs.iter() {
+                Compact(name.as_str()).serialize(&mut xattrs)?;
+                Compact(value.len()).serialize(&mut xattrs)?;
+                value.as_slice().serialize(&mut xattrs)?;
+            }
+        }
+        let fallback: &[u8] = match self.fallback {
+            Fallback::Inherit => &[],
+            Fallback::Document => &[1],
+            Fallback::Disabled => &[2],
+        };
+        write_fields(
+            w,
+            &[
+                (KIND, kind.as_slice()),
+                (NAME, self.name.as_bytes()),
+                (CONTENT_TYPE, self.content_type.as_bytes()),
+                (INODE, inode.as_slice()),
+                (HEADERS, headers.as_slice()),
+                (FALLBACK, fallback),
+                (LISTING, if self.listing { &[1] } else { &[] }),
+                (REDIRECT, redirect.as_slice()),
+                (ACL, acl.as_slice()),
+                (XATTRS, xattrs.as_slice()),
+            ],
+        )
     }
 }
 
 impl Deserialize for Entry {
-    fn deserialize(&mut self, mut r: impl io::Read) -> io::Result<usize> {
-        Ok(self.kind.deserialize(&mut r)?
-            + self.name.deserialize(&mut r)?
-            + self.content_type.deserialize(&mut r)?
-            + self.size.deserialize(&mut r)?
-            + self.cluster.deserialize(r)?)
+    fn deserialize(&mut self, r: impl io::Read) -> io::Result<usize> {
+        read_fields(r, |id, data| {
+            match id {
+                KIND => {
+                    self.kind.deserialize(data)?;
+                }
+                NAME => self.name = read_string(data, data.len())?,
+                CONTENT_TYPE => self.content_type = read_string(data, data.len())?,
+                INODE => {
+                    Compact(&mut self.inode).deserialize(data)?;
+                }
+                HEADERS => self.headers = read_pairs(data)?,
+                FALLBACK => {
+                    self.fallback = match data.first() {
+                        Some(1) => Fallback::Document,
+                        Some(2) => Fallback::Disabled,
+                        _ => Fallback::Inherit,
+                    }
+                }
+                LISTING => self.listing = data.first() == Some(&1),
+                REDIRECT => {
+                    let mut data = data;
+                    let (mut status, mut location) = (0u64, String::new());
+                    Compact(&mut status).deserialize(&mut data)?;
+                    Compact(&mut location).deserialize(&mut data)?;
+                    let status = u16::try_from(status)
+                        .map_err(|_| Error::corrupted(format!("bad redirect status {}", status)))?;
+                    self.redirect = Some(Redirect { status, location });
+                }
+                ACL => self.acl = Some(read_acl(data)?),
+                XATTRS => {
+                    let mut data = data;
+                    let mut count = 0usize;
+                    Compact(&mut count).deserialize(&mut data)?;
+                    self.xattrs.clear();
+                    for _ in 0..count {
+                        let (mut name, mut len) = (String::new(), 0usize);
+                        Compact(&mut name).deserialize(&mut data)?;
+                        Compact(&mut len).deserialize(&mut data)?;
+                        self.xattrs.push((name, read_bytes(&mut data, len)?));
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        })
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// Writes fields in the tagged format, leaving out empty ones.
+fn write_fields(mut w: impl io::Write, fields: &[(u64, &[u8])]) -> io::Result<usize> {
+    let count = fields.iter().filter(|(_, data)| !data.is_empty()).count();
+    let mut written = Compact(count).serialize(&mut w)?;
+    for (id, data) in fields.iter().filter(|(_, data)| !data.is_empty()) {
+        written += Compact(*id).serialize(&mut w)?
+            + Compact(data.len()).serialize(&mut w)?
+            + data.serialize(&mut w)?;
+    }
+    Ok(written)
+}
+
+/// Reads fields in the tagged format, passing each one's id and data to
+/// `f`. Returns the number of bytes read.
+fn read_fields(
+    mut r: impl io::Read,
+    mut f: impl FnMut(u64, &[u8]) -> io::Result<()>,
+) -> io::Result<usize> {
+    let mut count = 0usize;
+    let mut read = Compact(&mut count).deserialize(&mut r)?;
+    for _ in 0..count {
+        let (mut id, mut len) = (0u64, 0usize);
+        read += Compact(&mut id).deserialize(&mut r)? + Compact(&mut len).deserialize(&mut r)?;
+        let data = read_bytes(&mut r, len)?;
+        read += len;
+        f(id, &data)?;
+    }
+    Ok(read)
+}
+
+/// Name and value pairs, like headers, as a field. Empty for none.
+fn pairs_field(pairs: &[(String, String)]) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    if !pairs.is_empty() {
+        Compact(pairs.len()).serialize(&mut data)?;
+        for (name, value) in pairs.iter() {
+            Compact(name.as_str()).serialize(&mut data)?;
+            Compact(value.as_str()).serialize(&mut data)?;
+        }
+    }
+    Ok(data)
+}
+
+fn read_pairs(mut data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let mut count = 0usize;
+    Compact(&mut count).deserialize(&mut data)?;
+    let mut pairs = vec![];
+    for _ in 0..count {
+        let (mut name, mut value) = (String::new(), String::new());
+        Compact(&mut name).deserialize(&mut data)?;
+        Compact(&mut value).deserialize(&mut data)?;
+        pairs.push((name, value));
+    }
+    Ok(pairs)
+}
+
+fn acl_field(acl: &Option<Acl>) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    if let Some(acl) = acl {
+        Compact(acl.owner.as_str()).serialize(&mut data)?;
+        for principals in [&acl.writers, &acl.readers] {
+            Compact(principals.len()).serialize(&mut data)?;
+            for principal in principals.iter() {
+                Compact(principal.as_str()).serialize(&mut data)?;
+            }
+        }
+    }
+    Ok(data)
+}
+
+fn read_acl(mut data: &[u8]) -> io::Result<Acl> {
+    let mut acl = Acl::default();
+    Compact(&mut acl.owner).deserialize(&mut data)?;
+    for principals in [&mut acl.writers, &mut acl.readers] {
+        let mut count = 0usize;
+        Compact(&mut count).deserialize(&mut data)?;
+        for _ in 0..count {
+            let mut principal = String::new();
+            Compact(&mut principal).deserialize(&mut data)?;
+            principals.push(principal);
+        }
+    }
+    Ok(acl)
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(
+    feature = "interop",
+    derive(candid::CandidType, ::serde::Serialize, ::serde::Deserialize)
+)]
 pub enum EntryKind {
+    #[default]
     File,
     Directory,
 }
 
-impl Default for EntryKind {
-    fn default() -> Self {
-        EntryKind::File
+/// Where the HTTP gateway turns for paths which don't exist, instead of
+/// answering 404, as single-page apps route on the client. The nearest
+/// setting along a path applies.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(
+    feature = "interop",
+    derive(candid::CandidType, ::serde::Serialize, ::serde::Deserialize)
+)]
+pub enum Fallback {
+    /// Whatever applies to the parent directory.
+    #[default]
+    Inherit,
+    /// Set on a file: it's served for missing paths in its directory and
+    /// below.
+    Document,
+    /// Set on a directory: missing paths below it are 404s.
+    Disabled,
+}
+
+/// An HTTP redirect, to a path on the same host or to a full URL.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "interop",
+    derive(candid::CandidType, ::serde::Serialize, ::serde::Deserialize)
+)]
+pub struct Redirect {
+    /// 301, 302, 307 or 308.
+    pub status: u16,
+    pub location: String,
+}
+
+/// Principals, in their text form, who may access an entry and everything
+/// below a directory. The nearest ACL along a path which lists any writers
+/// or readers decides, while owners own everything below their entry. An
+/// ACL which lists neither only records the owner.
+#[derive(Default, Debug, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "interop",
+    derive(candid::CandidType, ::serde::Serialize, ::serde::Deserialize)
+)]
+pub struct Acl {
+    /// Whoever created the entry, empty for none. Owners may also change
+    /// the ACL.
+    pub owner: String,
+    pub writers: Vec<String>,
+    pub readers: Vec<String>,
+}
+
+impl Acl {
+    /// Stands for every principal among the writers or readers.
+    pub const EVERYONE: &'static str = "*";
+
+    pub fn owned_by(owner: impl Into<String>) -> Self {
+        Acl {
+            owner: owner.into(),
+            ..Default::default()
+        }
     }
+
+    pub fn is_owner(&self, principal: &str) -> bool {
+        !self.owner.is_empty() && self.owner == principal
+    }
+
+    /// Writers may also read.
+    pub fn can_write(&self, principal: &str) -> bool {
+        self.is_owner(principal) || includes(&self.writers, principal)
+    }
+
+    pub fn can_read(&self, principal: &str) -> bool {
+        self.can_write(principal) || includes(&self.readers, principal)
+    }
+}
+
+fn includes(principals: &[String], principal: &str) -> bool {
+    principals
+        .iter()
+        .any(|p| p == principal || p == Acl::EVERYONE)
 }
 
 impl Serialize for EntryKind {
@@ -224,17 +938,31 @@ impl Deserialize for EntryKind {
         let kind = match code[0] {
             1 => EntryKind::File,
             2 => EntryKind::Directory,
-            _ => return Err(io::ErrorKind::InvalidInput.into()),
+            code => return Err(Error::corrupted(format!("unknown entry kind {}", code)).into()),
         };
         *self = kind;
         Ok(1)
     }
 }
 
+/// Reads the content of an entry, buffering up to one block at a time.
 pub struct EntryReader<'a, R> {
     entry: &'a Entry,
     reader: R,
-    offset: usize,
+    /// Offset of `reader`, at the end of the buffered bytes.
+    offset: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, R> EntryReader<'a, R> {
+    fn buffered(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn position(&self) -> u64 {
+        self.offset - self.buffered() as u64
+    }
 }
 
 impl<'a, R> EntryReader<'a, R>
@@ -244,33 +972,136 @@ where
     pub fn read_directory(&mut self) -> io::Result<Directory> {
         Directory::deserialize_into_default(self)
     }
-}
 
-impl<'a, R: io::Read> io::Read for EntryReader<'a, R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let read_len = buf.len().min(self.entry.size - self.offset);
+    pub fn into_directory_reader(self) -> io::Result<DirectoryReader<Self>> {
+        DirectoryReader::new(self)
+    }
+
+    fn read_unbuffered(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.entry.size.saturating_sub(self.offset);
+        let read_len = buf.len().min(to_usize(remaining).unwrap_or(usize::MAX));
         if read_len == 0 {
             return Ok(0);
         }
 
         let read_bytes = self.reader.read(&mut buf[..read_len])?;
-        self.offset += read_bytes;
+        self.offset += read_bytes as u64;
         Ok(read_bytes)
     }
 }
 
+impl<'a, R: io::Read> io::Read for EntryReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Large reads skip the buffer once it's drained.
+        if self.buffered() == 0 && buf.len() >= Block::SIZE {
+            return self.read_unbuffered(buf);
+        }
+
+        let available = io::BufRead::fill_buf(self)?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        io::BufRead::consume(self, n);
+        Ok(n)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        read_slices(self, bufs)
+    }
+}
+
+impl<'a, R: io::Read> io::BufRead for EntryReader<'a, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffered() == 0 {
+            let mut block = std::mem::take(&mut self.buf);
+            block.resize(Block::SIZE, 0);
+            let read = self.read_unbuffered(&mut block);
+            block.truncate(*read.as_ref().unwrap_or(&0));
+            self.buf = block;
+            self.pos = 0;
+            read?;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.buf.len());
+    }
+}
+
 impl<'a, R: io::Seek> io::Seek for EntryReader<'a, R> {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        let new_offset = self.reader.seek(pos)?;
-        self.offset = new_offset as _;
+        // Seeks are relative to the entry rather than the underlying cluster.
+        let target = seek_target(pos, self.position(), self.entry.size)?;
+        let new_offset = self.reader.seek(io::SeekFrom::Start(target))?;
+        self.offset = new_offset;
+        self.buf.clear();
+        self.pos = 0;
         Ok(new_offset)
     }
 }
 
+/// Reads the contents of an entry, wherever they're stored.
+pub enum ContentReader<'a, M> {
+    Inline(io::Cursor<&'a Vec<u8>>),
+    Cluster(ClusterReader<'a, MemoryReader<'a, TrackedMemory<M>>>),
+    /// The full blocks in the cluster, followed by the packed tail.
+    Packed {
+        cluster: ClusterReader<'a, MemoryReader<'a, TrackedMemory<M>>>,
+        /// Offset at which the tail starts.
+        split: u64,
+        tail: io::Cursor<Vec<u8>>,
+    },
+}
+
+impl<'a, M: Memory> io::Read for ContentReader<'a, M> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ContentReader::Inline(r) => r.read(buf),
+            ContentReader::Cluster(r) => r.read(buf),
+            ContentReader::Packed { cluster, tail, .. } => match cluster.read(buf)? {
+                0 => tail.read(buf),
+                n => Ok(n),
+            },
+        }
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        match self {
+            ContentReader::Inline(r) => r.read_vectored(bufs),
+            ContentReader::Cluster(r) => r.read_vectored(bufs),
+            ContentReader::Packed { .. } => read_slices(self, bufs),
+        }
+    }
+}
+
+impl<'a, M: Memory> io::Seek for ContentReader<'a, M> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match self {
+            ContentReader::Inline(r) => r.seek(pos),
+            ContentReader::Cluster(r) => r.seek(pos),
+            ContentReader::Packed {
+                cluster,
+                split,
+                tail,
+            } => {
+                let current = cluster.stream_position()? + tail.position();
+                let len = *split + tail.get_ref().len() as u64;
+                let target = seek_target(pos, current, len)?;
+                cluster.seek(io::SeekFrom::Start(target.min(*split)))?;
+                tail.set_position(target.saturating_sub(*split));
+                Ok(target)
+            }
+        }
+    }
+}
+
 pub struct EntryWriter<'a, W> {
-    entry_size: &'a mut usize,
+    entry_size: &'a mut u64,
+    /// How far the entry can grow, so a seek too far out fails before it
+    /// fills up the memory with zeros.
+    max_size: u64,
     writer: W,
-    offset: usize,
+    offset: u64,
 }
 
 impl<'a, W> EntryWriter<'a, W>
@@ -278,27 +1109,361 @@ where
     W: io::Write,
 {
     pub fn write_directory(&mut self, directory: &Directory) -> io::Result<usize> {
-        directory.serialize(self)
+        let mut w = io::BufWriter::with_capacity(BUF_CAPACITY, self);
+        let n = directory.serialize(&mut w)?;
+        io::Write::flush(&mut w)?;
+        Ok(n)
     }
 }
 
 impl<'a, W: io::Write> io::Write for EntryWriter<'a, W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let written_bytes = self.writer.write(&buf)?;
-        self.offset += written_bytes;
+        let written_bytes = self.writer.write(buf)?;
+        self.offset += written_bytes as u64;
         *self.entry_size = (*self.entry_size).max(self.offset);
         Ok(written_bytes)
     }
 
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        write_slices(self, bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.writer.flush()
     }
 }
 
-impl<'a, W: io::Seek> io::Seek for EntryWriter<'a, W> {
+/// Seeking past the end of the entry extends it with zeros, so the gap
+/// never exposes stale bytes of reused blocks.
+impl<'a, W: io::Write + io::Seek> io::Seek for EntryWriter<'a, W> {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        let new_offset = self.writer.seek(pos)?;
-        self.offset = new_offset as _;
-        Ok(new_offset)
+        let target = seek_target(pos, self.offset, *self.entry_size)?;
+        if target > self.max_size.max(*self.entry_size) {
+            return Err(Error::OutOfSpace.into());
+        }
+
+        self.offset = target.min(*self.entry_size);
+        self.writer.seek(io::SeekFrom::Start(self.offset))?;
+        let zeros = [0u8; Block::SIZE];
+        while self.offset < target {
+            let n = (target - self.offset).min(zeros.len() as u64) as usize;
+            io::Write::write_all(self, &zeros[..n])?;
+        }
+
+        Ok(target)
+    }
+}
+
+#[test]
+fn directory_reader() {
+    let mut dir = Directory::default();
+    dir.add_file("a.txt", "text/plain").unwrap();
+    dir.add_directory("b").unwrap();
+    dir.add_file("c.txt", "text/plain").unwrap();
+
+    let mut data = vec![];
+    dir.serialize(&mut data).unwrap();
+
+    let mut r = DirectoryReader::new(&*data).unwrap();
+    assert_eq!(r.remaining(), 3);
+
+    let b = r.entry_with_name("b").unwrap().unwrap();
+    assert_eq!(b.kind, EntryKind::Directory);
+    assert_eq!(r.remaining(), 1);
+
+    let names = DirectoryReader::new(&*data)
+        .unwrap()
+        .map(|e| e.map(|e| e.name))
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(names, vec!["a.txt", "b", "c.txt"]);
+}
+
+#[test]
+fn formats() {
+    let mut dir = Directory::default();
+    dir.add_file("a.txt", "text/plain").unwrap();
+    dir.add_directory("b").unwrap().inode = 300;
+
+    // Directories written in earlier formats can still be read.
+    let mut legacy = vec![];
+    dir.entries.len().serialize(&mut legacy).unwrap();
+    let mut compact = vec![COMPACT_FORMAT];
+    Compact(dir.entries.len()).serialize(&mut compact).unwrap();
+    for entry in dir.entries.iter() {
+        entry.kind.serialize(&mut legacy).unwrap();
+        entry.name.as_str().serialize(&mut legacy).unwrap();
+        entry.content_type.as_str().serialize(&mut legacy).unwrap();
+        entry.inode.serialize(&mut legacy).unwrap();
+        entry.kind.serialize(&mut compact).unwrap();
+        Compact(entry.name.as_str())
+            .serialize(&mut compact)
+            .unwrap();
+        Compact(entry.content_type.as_str())
+            .serialize(&mut compact)
+            .unwrap();
+        Compact(entry.inode).serialize(&mut compact).unwrap();
+    }
+    let mut tagged = vec![];
+    dir.serialize(&mut tagged).unwrap();
+    assert_eq!(tagged[0], TAGGED_FORMAT);
+
+    // The root directory carries its ACL and settings in a format of its own.
+    dir.acl = Some(Acl::owned_by("aaaaa-aa"));
+    dir.settings = vec![("mode".to_owned(), "public".to_owned())];
+    let mut root = vec![];
+    dir.serialize(&mut root).unwrap();
+    assert_eq!(root[0], ROOT_FORMAT);
+    dir.acl = None;
+    dir.settings.clear();
+
+    for data in [&legacy, &compact, &tagged, &root] {
+        let read = Directory::deserialize_into_default(&data[..]).unwrap();
+        assert_eq!(read.listing_checksum(), dir.listing_checksum());
+        assert_eq!(read.entries[0].content_type, "text/plain");
+        assert_eq!(read.acl.is_some(), data[0] == ROOT_FORMAT);
+        assert_eq!(read.settings.len(), (data[0] == ROOT_FORMAT) as usize);
+        let mut r = DirectoryReader::new(&data[..]).unwrap();
+        assert_eq!(r.entry_with_name("b").unwrap().unwrap().inode, 300);
+    }
+
+    // Unknown fields are skipped, and missing ones keep their default.
+    let mut entry = vec![];
+    Compact(2usize).serialize(&mut entry).unwrap();
+    for (id, data) in [(99u64, "future"), (NAME, "c")] {
+        Compact(id).serialize(&mut entry).unwrap();
+        Compact(data).serialize(&mut entry).unwrap();
+    }
+    let read = Entry::deserialize_into_default(&entry[..]).unwrap();
+    assert_eq!(
+        (read.kind, read.name.as_str(), read.inode),
+        (EntryKind::File, "c", 0)
+    );
+}
+
+#[test]
+fn tagged_entries() {
+    // An entry from a newer writer, with a field this reader doesn't know in
+    // between the ones it does, and another entry after it.
+    let mut data = vec![TAGGED_FORMAT];
+    Compact(2usize).serialize(&mut data).unwrap();
+    write_fields(
+        &mut data,
+        &[
+            (NAME, b"new"),
+            (99, &[0xff; 300]),
+            (CONTENT_TYPE, b"text/plain"),
+        ],
+    )
+    .unwrap();
+    let entry = Entry {
+        name: "b".to_owned(),
+        inode: 7,
+        ..Default::default()
+    };
+    entry.serialize(&mut data).unwrap();
+
+    let dir = Directory::deserialize_into_default(&data[..]).unwrap();
+    let first = &dir.entries[0];
+    assert_eq!(
+        (first.kind, first.name.as_str(), first.content_type.as_str()),
+        (EntryKind::File, "new", "text/plain")
+    );
+    assert_eq!(
+        (dir.entries[1].name.as_str(), dir.entries[1].inode),
+        ("b", 7)
+    );
+    let mut r = DirectoryReader::new(&data[..]).unwrap();
+    assert_eq!(r.entry_with_name("b").unwrap().unwrap().inode, 7);
+
+    // Fields holding their default aren't written.
+    let mut data = vec![];
+    Entry {
+        name: "c".to_owned(),
+        ..Default::default()
+    }
+    .serialize(&mut data)
+    .unwrap();
+    let mut ids = vec![];
+    read_fields(&data[..], |id, _| {
+        ids.push(id);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(ids, [KIND, NAME]);
+}
+
+#[test]
+fn entry_lines() {
+    use std::io::{BufRead, Read, Seek};
+
+    let text = (0..200)
+        .map(|i| format!("line {}\n", i))
+        .collect::<String>();
+    let entry = Entry {
+        size: text.len() as u64,
+        ..Default::default()
+    };
+
+    let lines = entry
+        .reader(io::Cursor::new(text.as_bytes()))
+        .lines()
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(lines.len(), 200);
+    assert_eq!(lines[199], "line 199");
+
+    // Seeking relative to the current position accounts for buffered bytes.
+    let mut r = entry.reader(io::Cursor::new(text.as_bytes()));
+    let mut line = String::new();
+    r.read_line(&mut line).unwrap();
+    assert_eq!(line, "line 0\n");
+    r.seek(io::SeekFrom::Current(7)).unwrap();
+    let mut buf = [0u8; 6];
+    r.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"line 2");
+}
+
+#[test]
+fn large_offsets() {
+    use std::io::Seek;
+
+    // Offsets past 4 GiB are kept whole, whatever the pointer width.
+    let size = 5 << 30;
+    let entry = Entry {
+        size,
+        ..Default::default()
+    };
+    let mut r = entry.reader(io::Cursor::new(vec![]));
+    assert_eq!(r.seek(io::SeekFrom::End(-1)).unwrap(), size - 1);
+    assert_eq!(
+        r.seek(io::SeekFrom::Current(1 << 32)).unwrap(),
+        size - 1 + (1 << 32)
+    );
+
+    // Seeks which overflow or end up before the start fail.
+    r.seek(io::SeekFrom::Start(u64::MAX)).unwrap();
+    assert!(r.seek(io::SeekFrom::Current(1)).is_err());
+    assert!(r.seek(io::SeekFrom::End(-(size as i64) - 1)).is_err());
+}
+
+#[test]
+fn seek_past_end() {
+    use std::io::{Seek, Write};
+
+    // Stale bytes, as left behind in a reused block.
+    let mut data = io::Cursor::new(vec![0xff; 1024]);
+    let mut entry = Entry::default();
+
+    {
+        let mut w = entry.writer(&mut data);
+        w.write_all(b"ab").unwrap();
+        w.seek(io::SeekFrom::Start(600)).unwrap();
+        w.write_all(b"c").unwrap();
+    }
+
+    assert_eq!(entry.size, 601);
+    let data = data.into_inner();
+    assert_eq!(&data[..2], b"ab");
+    assert!(data[2..600].iter().all(|&b| b == 0));
+    assert_eq!(data[600], b'c');
+}
+
+#[test]
+fn seek_from_end() {
+    use std::io::{Read, Seek, Write};
+
+    let mut data = io::Cursor::new(vec![]);
+    let mut entry = Entry::default();
+
+    {
+        let mut w = entry.writer(&mut data);
+        w.write_all(b"Hello World").unwrap();
+        assert_eq!(w.seek(io::SeekFrom::End(-5)).unwrap(), 6);
+        w.write_all(b"There").unwrap();
+        assert_eq!(w.seek(io::SeekFrom::Current(-11)).unwrap(), 0);
+        assert!(w.seek(io::SeekFrom::Current(-1)).is_err());
     }
+    assert_eq!(entry.size, 11);
+
+    // Trailing bytes of the last block are never part of the entry.
+    data.get_mut().resize(Block::SIZE, 0xff);
+    let mut r = entry.reader(&mut data);
+    assert_eq!(r.seek(io::SeekFrom::End(-5)).unwrap(), 6);
+    let mut rest = String::new();
+    r.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "There");
+}
+
+#[test]
+fn names() {
+    let mut dir = Directory::default();
+    for name in ["", ".", "..", "a/b", &"x".repeat(MAX_NAME_LEN + 1)].iter() {
+        let err = dir.add_file(name, "").unwrap_err();
+        assert!(matches!(Error::from(err), Error::NameInvalid));
+    }
+    dir.add_file("Readme.md", "").unwrap();
+    let err = dir.add_directory("Readme.md").unwrap_err();
+    assert!(matches!(Error::from(err), Error::AlreadyExists));
+    assert!(dir.entry_with_name("README.md").is_none());
+
+    // Decomposed and precomposed forms name the same entry once normalized.
+    let mut dir = Directory {
+        names: NamePolicy {
+            normalize: Some(|name| {
+                name.replace("A\u{30a}", "\u{c5}")
+                    .replace("a\u{30a}", "\u{e5}")
+            }),
+            case_insensitive: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    dir.add_file("A\u{30a}ngstro\u{308}m", "").unwrap();
+    assert_eq!(dir.entries[0].name, "\u{c5}ngstro\u{308}m");
+    assert!(dir.entry_with_name("\u{e5}NGSTRO\u{308}M").is_some());
+    assert!(dir.add_file("\u{c5}ngstro\u{308}m", "").is_err());
+
+    let mut data = vec![];
+    dir.serialize(&mut data).unwrap();
+    let mut r = DirectoryReader::new(&*data)
+        .unwrap()
+        .with_name_policy(dir.names);
+    assert!(r
+        .entry_with_name("a\u{30a}ngstro\u{308}m")
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn replace_file() {
+    let mut dir = Directory::default();
+    dir.add_directory("a").unwrap();
+
+    let (entry, replaced) = dir.replace_file("a", "text/plain").unwrap();
+    assert_eq!(entry.kind, EntryKind::File);
+    assert_eq!(replaced.unwrap().kind, EntryKind::Directory);
+
+    let (_, replaced) = dir.replace_file("b", "text/plain").unwrap();
+    assert!(replaced.is_none());
+    assert_eq!(dir.entries.len(), 2);
+}
+
+#[test]
+fn read_at_write_at() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.write_atomic(vec!["a"], &b"hello world"[..]).unwrap();
+    fs.write_at(vec!["a"], 6, b"there").unwrap();
+    fs.write_at(vec!["a"], 1200, b"!").unwrap();
+
+    let mut buf = [0u8; 5];
+    assert_eq!(fs.read_at(vec!["a"], 6, &mut buf).unwrap(), 5);
+    assert_eq!(&buf, b"there");
+    assert_eq!(fs.read_at(vec!["a"], 1000, &mut buf).unwrap(), 5);
+    assert_eq!(buf, [0u8; 5]);
+    assert_eq!(fs.read_at(vec!["a"], 1199, &mut buf).unwrap(), 2);
+    assert_eq!(&buf[..2], b"\0!");
+    assert_eq!(fs.read_at(vec!["a"], 2000, &mut buf).unwrap(), 0);
 }
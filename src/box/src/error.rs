@@ -0,0 +1,119 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Failures of filesystem operations.
+///
+/// The filesystem is built on `std::io`, so errors travel as `io::Error`
+/// with a matching `io::ErrorKind`. Converting such an `io::Error` back with
+/// `Error::from` recovers the original variant.
+#[derive(Debug)]
+pub enum Error {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    OutOfSpace,
+    Corrupted {
+        detail: String,
+    },
+    NameInvalid,
+    QuotaExceeded,
+    InvalidPath,
+    /// The entry is sealed and can't be changed or removed.
+    Sealed,
+    /// The operation would reach into or remove a mounted filesystem.
+    MountPoint,
+    /// The caller isn't let in by the ACLs along the path.
+    AccessDenied,
+    Io(io::Error),
+}
+
+impl Error {
+    pub fn corrupted(detail: impl Into<String>) -> Self {
+        Error::Corrupted {
+            detail: detail.into(),
+        }
+    }
+
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::NotFound => io::ErrorKind::NotFound,
+            Error::NotADirectory => io::ErrorKind::InvalidInput,
+            Error::IsADirectory => io::ErrorKind::InvalidInput,
+            Error::AlreadyExists => io::ErrorKind::AlreadyExists,
+            Error::OutOfSpace => io::ErrorKind::OutOfMemory,
+            Error::Corrupted { .. } => io::ErrorKind::InvalidData,
+            Error::NameInvalid => io::ErrorKind::InvalidInput,
+            Error::QuotaExceeded => io::ErrorKind::Other,
+            Error::InvalidPath => io::ErrorKind::InvalidInput,
+            Error::Sealed => io::ErrorKind::PermissionDenied,
+            Error::MountPoint => io::ErrorKind::InvalidInput,
+            Error::AccessDenied => io::ErrorKind::PermissionDenied,
+            Error::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound => write!(f, "no such file or directory"),
+            Error::NotADirectory => write!(f, "not a directory"),
+            Error::IsADirectory => write!(f, "is a directory"),
+            Error::AlreadyExists => write!(f, "entry already exists"),
+            Error::OutOfSpace => write!(f, "no space left"),
+            Error::Corrupted { detail } => write!(f, "filesystem corrupted: {}", detail),
+            Error::NameInvalid => write!(f, "invalid entry name"),
+            Error::QuotaExceeded => write!(f, "quota exceeded"),
+            Error::InvalidPath => write!(f, "invalid path"),
+            Error::Sealed => write!(f, "entry is sealed"),
+            Error::MountPoint => write!(f, "path crosses a mount point"),
+            Error::AccessDenied => write!(f, "access denied"),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            *e.into_inner().unwrap().downcast::<Error>().unwrap()
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+#[test]
+fn round_trip() {
+    let e: io::Error = Error::IsADirectory.into();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert!(matches!(Error::from(e), Error::IsADirectory));
+
+    let e: io::Error = Error::corrupted("bad index").into();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(e.to_string(), "filesystem corrupted: bad index");
+
+    let e = Error::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+    assert!(matches!(e, Error::Io(_)));
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+}
@@ -0,0 +1,194 @@
+use std::cell::Cell;
+use std::io;
+
+use crate::memory::Memory;
+
+/// A kind of memory operation.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operation {
+    Read,
+    Write,
+    Grow,
+}
+
+/// What goes wrong with an operation.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Fault {
+    /// The operation fails without doing anything.
+    Fail,
+    /// A read or write only handles half of the bytes, but at least one.
+    /// Growing fails instead.
+    Truncate,
+}
+
+/// Wraps a memory to make chosen operations go wrong, for testing error
+/// handling and crash consistency. Operations of each kind are counted from
+/// 0. Faults return an `io::Error` of kind `Other`.
+pub struct FaultyMemory<M> {
+    memory: M,
+    counts: [Cell<u64>; 3],
+    faults: Vec<(Operation, u64, Fault)>,
+    /// Bytes left to write before the trap, if one is set.
+    trap_in: Option<u64>,
+    trapped: bool,
+}
+
+impl<M: Memory> FaultyMemory<M> {
+    pub fn new(memory: M) -> Self {
+        FaultyMemory {
+            memory,
+            counts: Default::default(),
+            faults: vec![],
+            trap_in: None,
+            trapped: false,
+        }
+    }
+
+    /// Makes operation number `n` of kind `operation` go wrong.
+    pub fn with_fault(mut self, operation: Operation, n: u64, fault: Fault) -> Self {
+        self.add_fault(operation, n, fault);
+        self
+    }
+
+    pub fn add_fault(&mut self, operation: Operation, n: u64, fault: Fault) {
+        self.faults.push((operation, n, fault));
+    }
+
+    /// Simulates a trap once `len` more bytes are written: the write which
+    /// goes past that is cut short, and it and all writes and grows after it
+    /// fail. Reopening the inner memory shows what a canister would find
+    /// after the trap.
+    pub fn with_trap_after(mut self, len: u64) -> Self {
+        self.set_trap_after(len);
+        self
+    }
+
+    pub fn set_trap_after(&mut self, len: u64) {
+        self.trap_in = Some(len);
+    }
+
+    pub fn trapped(&self) -> bool {
+        self.trapped
+    }
+
+    /// How many operations of kind `operation` were attempted.
+    pub fn count(&self, operation: Operation) -> u64 {
+        self.counts[operation as usize].get()
+    }
+
+    /// Counts an operation of kind `operation`, returning the fault it's
+    /// meant to run into.
+    fn fault(&self, operation: Operation) -> Option<Fault> {
+        let n = self.count(operation);
+        self.counts[operation as usize].set(n + 1);
+        self.faults
+            .iter()
+            .find(|&&(op, i, _)| op == operation && i == n)
+            .map(|&(_, _, fault)| fault)
+    }
+
+    fn error(what: &str) -> io::Error {
+        io::Error::other(format!("injected {}", what))
+    }
+}
+
+impl<M: Memory> Memory for FaultyMemory<M> {
+    fn page_size(&self) -> u64 {
+        self.memory.page_size()
+    }
+
+    fn max_pages(&self) -> u64 {
+        self.memory.max_pages()
+    }
+
+    fn page_count(&self) -> io::Result<u64> {
+        self.memory.page_count()
+    }
+
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        if self.trapped {
+            return Err(Self::error("trap"));
+        }
+        match self.fault(Operation::Grow) {
+            Some(_) => Err(Self::error("grow failure")),
+            None => self.memory.grow(num_pages),
+        }
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match self.fault(Operation::Read) {
+            Some(Fault::Fail) => return Err(Self::error("read failure")),
+            Some(Fault::Truncate) => buf.len().div_ceil(2),
+            None => buf.len(),
+        };
+        self.memory.read(offset, &mut buf[..len])
+    }
+
+    /// Zeroing goes through here too, so it counts towards the trap.
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        if self.trapped {
+            return Err(Self::error("trap"));
+        }
+        let len = match self.fault(Operation::Write) {
+            Some(Fault::Fail) => return Err(Self::error("write failure")),
+            Some(Fault::Truncate) => buf.len().div_ceil(2),
+            None => buf.len(),
+        };
+
+        if let Some(left) = self.trap_in {
+            if len as u64 > left {
+                self.memory.write(offset, &buf[..left as usize])?;
+                self.trapped = true;
+                return Err(Self::error("trap"));
+            }
+            self.trap_in = Some(left - len as u64);
+        }
+        self.memory.write(offset, &buf[..len])
+    }
+}
+
+#[test]
+fn faulty_memory() {
+    use crate::file_system::{DropPolicy, FileSystem};
+    use crate::heap_memory::HeapMemory;
+
+    let mut memory = FaultyMemory::new(HeapMemory::default())
+        .with_fault(Operation::Grow, 1, Fault::Fail)
+        .with_fault(Operation::Write, 1, Fault::Truncate);
+    memory.grow(1).unwrap();
+    assert!(memory.grow(1).is_err());
+    assert_eq!(memory.write(0, &[1; 10]).unwrap(), 10);
+    assert_eq!(memory.write(0, &[1; 10]).unwrap(), 5);
+    assert_eq!(memory.count(Operation::Write), 2);
+    memory.set_trap_after(12);
+    memory.write(0, &[2; 10]).unwrap();
+    assert!(memory.write(0, &[3; 10]).is_err());
+    assert!(memory.trapped());
+    let mut buf = [0u8; 4];
+    memory.read(0, &mut buf).unwrap();
+    assert_eq!(buf, [3, 3, 2, 2]);
+
+    // Whatever point a write traps at, the last persisted state survives.
+    let mut heap = HeapMemory::default();
+    {
+        let mut fs = FileSystem::new(&mut heap).unwrap();
+        fs.write_atomic(vec!["a"], &[1u8; 2000][..]).unwrap();
+    }
+    for len in (0..8000).step_by(97) {
+        let memory = FaultyMemory::new(&mut heap).with_trap_after(len);
+        let mut fs = FileSystem::open(memory)
+            .unwrap()
+            .with_drop_policy(DropPolicy::Ignore);
+        let _ = fs
+            .write_atomic(vec!["b"], &[2u8; 3000][..])
+            .and_then(|_| fs.persist());
+        drop(fs);
+
+        let fs = FileSystem::open(&mut heap)
+            .unwrap()
+            .with_drop_policy(DropPolicy::Ignore);
+        let mut data = vec![];
+        fs.read_file(vec!["a"], &mut data).unwrap();
+        assert_eq!(data, [1u8; 2000]);
+    }
+}
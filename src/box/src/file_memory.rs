@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+
+use crate::error::Error;
+use crate::memory::{to_usize, Memory};
+
+/// Memory backed by a file, so the filesystem can be used natively, e.g. by
+/// tools working on images taken from a canister. Pages are the size of
+/// stable memory pages, so images can be copied between the two as they
+/// are. The file is grown by whole pages.
+pub struct FileMemory {
+    file: File,
+    max_pages: u64,
+}
+
+impl FileMemory {
+    const PAGE_SIZE: u64 = 65536;
+
+    /// Uses `file`, which has to be open for reading and writing. Defaults
+    /// to the size limit of stable memory, which is what the layout of the
+    /// preamble depends on.
+    pub fn new(file: File) -> Self {
+        FileMemory {
+            file,
+            max_pages: 65535,
+        }
+    }
+
+    pub fn with_max_pages(mut self, max_pages: u64) -> Self {
+        self.max_pages = max_pages;
+        self
+    }
+
+    /// How much of `len` bytes at `offset` lies within the file.
+    fn clamp(&self, offset: u64, len: usize) -> io::Result<usize> {
+        let available = self.len()?.saturating_sub(offset);
+        Ok(len.min(to_usize(available).unwrap_or(usize::MAX)))
+    }
+}
+
+impl Memory for FileMemory {
+    fn page_size(&self) -> u64 {
+        Self::PAGE_SIZE
+    }
+
+    fn max_pages(&self) -> u64 {
+        self.max_pages
+    }
+
+    fn page_count(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len() / Self::PAGE_SIZE)
+    }
+
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        let pages = self.page_count()? + num_pages;
+        if pages > self.max_pages {
+            return Err(Error::OutOfSpace.into());
+        }
+        self.file.set_len(pages * Self::PAGE_SIZE)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.clamp(offset, buf.len())?;
+        let mut file = &self.file;
+        file.seek(io::SeekFrom::Start(offset))?;
+        file.read(&mut buf[..len])
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let len = self.clamp(offset, buf.len())?;
+        self.file.seek(io::SeekFrom::Start(offset))?;
+        self.file.write(&buf[..len])
+    }
+}
+
+#[test]
+fn file_memory() {
+    use crate::file_system::FileSystem;
+
+    let path = std::env::temp_dir().join(format!("box-file-memory-{}", std::process::id()));
+    let open = || {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .unwrap();
+        FileMemory::new(file).with_max_pages(64)
+    };
+
+    {
+        let mut fs = FileSystem::new(open()).unwrap();
+        fs.write_atomic(vec!["a"], &[5u8; 3000][..]).unwrap();
+    }
+    let mut memory = open();
+    assert_eq!(memory.len().unwrap() % FileMemory::PAGE_SIZE, 0);
+    assert!(memory.grow(64).is_err());
+
+    let fs = FileSystem::open(memory).unwrap();
+    let mut data = vec![];
+    fs.read_file(vec!["a"], &mut data).unwrap();
+    assert_eq!(data, [5u8; 3000]);
+    drop(fs);
+    std::fs::remove_file(&path).unwrap();
+}
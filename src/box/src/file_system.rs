@@ -1,29 +1,316 @@
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::io;
+use std::io::{self, Read, Seek, Write};
+use std::sync::{Mutex, PoisonError};
 
-use crate::bitmap::Bitmap;
+use crate::backup::TrackedMemory;
+use crate::bitmap::{AllocationPolicy, BitState, Bitmap};
 use crate::block::Block;
-use crate::cluster::{Cluster, ClusterReader, ClusterWriter};
-use crate::directory::{Directory, Entry, EntryKind};
-use crate::memory::{Memory, MemoryReader, MemoryWriter};
-use crate::serde::{Deserialize, Serialize};
+use crate::checksum::Checksum;
+use crate::cluster::{BufClusterReader, Cluster, ClusterReader, ClusterWriter, BUF_CAPACITY};
+use crate::directory::{
+    Acl, Directory, DirectoryReader, Entry, EntryKind, Fallback, NamePolicy, Redirect,
+    FORMAT_VERSION,
+};
+use crate::error::Error;
+use crate::inode::{Inode, InodeTable};
+use crate::memory::{to_usize, Memory, MemoryReader, MemoryWriter};
+use crate::mount::Mount;
+use crate::observer::{self, Event, FsObserver};
+use crate::serde::{with_max_string_len, Deserialize, Serialize, MAX_STRING_LEN};
+use crate::tail::{Tail, TailAllocator};
 
 pub struct FileSystem<M: Memory> {
+    pub(crate) bitmap: Bitmap,
+    root_cluster: Cluster,
+    inodes: InodeTable,
+    /// Records which extents were written since which snapshot, for
+    /// incremental backups.
+    pub(crate) memory: TrackedMemory<M>,
+    /// Writers handed out and mutating operations so far, which tells work
+    /// spread across calls whether anything changed in between.
+    mutations: u64,
+    /// The pass of `defragment` in progress, if any.
+    defragment_pass: Option<DefragmentPass>,
+    /// The pass of `collect_garbage_slice` in progress, if any.
+    garbage_pass: Option<GarbagePass>,
+    drop_policy: DropPolicy,
+    secure_delete: bool,
+    /// Sequence number of the most recently written preamble copy.
+    pub(crate) sequence: u64,
+    durability: Durability,
+    /// Mutating operations since the preamble was last persisted.
+    unpersisted: usize,
+    /// Source of the timestamps of inodes.
+    clock: fn() -> u64,
+    propagate_modified: bool,
+    names: NamePolicy,
+    /// Files up to this size are kept inline.
+    inline_limit: usize,
+    /// Strings in metadata over this length are taken for corruption.
+    max_string_len: usize,
+    /// Blocks shared by the tails and inline contents of files, rebuilt from
+    /// the inodes when first needed after a restore.
+    tails: Option<TailAllocator>,
+    tail_packing: bool,
+    observer: Option<Box<dyn FsObserver>>,
+    /// Filesystems mounted at directories of this one, by mount point.
+    mounts: Vec<(Vec<String>, Box<dyn Mount>)>,
+    /// Subtree sizes computed by `dir_size`, by directory, if caching is on.
+    dir_sizes: Option<Mutex<HashMap<Vec<String>, DirSize>>>,
+}
+
+/// Files up to this size are kept inline by default.
+pub const DEFAULT_INLINE_LIMIT: usize = 256;
+
+/// The contents of a preamble copy.
+struct Preamble {
+    sequence: u64,
     bitmap: Bitmap,
     root_cluster: Cluster,
-    memory: M,
+    inode_areas: [Cluster; 2],
+}
+
+/// What happens to unpersisted changes when a `FileSystem` is dropped
+/// without calling `close`.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum DropPolicy {
+    /// Persist the preamble and ignore any error.
+    #[default]
+    BestEffort,
+    /// Don't persist anything.
+    Ignore,
+    /// Persist the preamble and assert success in debug builds.
+    DebugAssert,
+}
+
+/// When the preamble is persisted, in addition to explicit calls of
+/// `persist` and `close`. Continuous persistence protects against losing
+/// changes when `pre_upgrade` can't run, at the cost of more writes.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum Durability {
+    /// Only when asked to, or on drop according to the `DropPolicy`.
+    #[default]
+    Manual,
+    /// After every mutating operation.
+    AfterEveryMutation,
+    /// After every `n` mutating operations.
+    EveryNOperations(usize),
+}
+
+/// The clusters found so far by a pass of `FileSystem::defragment`, in
+/// tree order with the root directory's first, and the entries left to
+/// look up.
+struct DefragmentPass {
+    /// `FileSystem::mutations` as of the previous call.
+    mutations: u64,
+    pending: Vec<Entry>,
+    clusters: Vec<Cluster>,
+    /// The cluster and position in it of each data block of `clusters`.
+    owners: HashMap<usize, (usize, usize)>,
+    cursor: DefragmentCursor,
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+struct DefragmentCursor {
+    cluster: usize,
+    block: usize,
+    position: usize,
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct DefragmentProgress {
+    pub visited: usize,
+    pub moved: usize,
+    pub done: bool,
+}
+
+/// Blocks found occupied in the bitmap without being referenced anywhere.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct GarbageReport {
+    pub leaked_blocks: usize,
+    pub leaked_bytes: u64,
+}
+
+/// How far a call of `FileSystem::collect_garbage_slice` got.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct GarbageProgress {
+    pub visited: usize,
+    pub done: bool,
+    /// What the pass freed, once it's done.
+    pub report: GarbageReport,
+}
+
+/// The blocks found reachable so far by a pass of `collect_garbage_slice`,
+/// and the entries left to visit.
+struct GarbagePass {
+    mutations: u64,
+    reachable: Bitmap,
+    pending: Vec<Entry>,
+}
+
+/// How far a call of `FileSystem::purge_expired` or `remove_tree` got.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct PurgeProgress {
+    pub removed: usize,
+    /// No expired entries are left, except sealed ones.
+    pub done: bool,
+}
+
+/// The files below a directory, as returned by `FileSystem::dir_size`.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct DirSize {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// Space taken and left in the filesystem, as returned by
+/// `FileSystem::usage`. Counts are in blocks of `block_size` bytes.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct Usage {
+    pub block_size: u64,
+    /// All blocks the memory can hold once grown to its limit.
+    pub total_blocks: u64,
+    /// Blocks taken by the preamble, metadata and contents.
+    pub used_blocks: u64,
+    /// Free blocks held back for metadata, see `with_metadata_reserve`.
+    pub reserved_blocks: u64,
+}
+
+/// A summary of the whole filesystem, as returned by `FileSystem::stats`.
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct Stats {
+    pub usage: Usage,
+    pub files: u64,
+    pub directories: u64,
+    /// Bytes of all files together.
+    pub file_bytes: u64,
+    /// The largest files with their paths, largest first.
+    pub largest_files: Vec<(Vec<String>, u64)>,
+    /// Files whose data blocks aren't in one run. `defragment` lays them
+    /// out in tree order, but around index blocks, which stay in between.
+    pub fragmented_files: u64,
+    /// Runs of adjacent data blocks of all files together.
+    pub fragments: u64,
+    /// See `FORMAT_VERSION`.
+    pub format_version: u8,
+}
+
+/// The order `FileSystem::directory_page` lists entries in.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(
+    feature = "interop",
+    derive(candid::CandidType, ::serde::Serialize, ::serde::Deserialize)
+)]
+pub enum SortOrder {
+    /// As the entries are stored, which is the cheapest, as only one page
+    /// of them is read.
+    #[default]
+    Stored,
+    Name,
+    NameDescending,
+    /// Oldest modification first.
+    Modified,
+    ModifiedDescending,
+}
+
+/// Where `FileSystem::write_with_mode` puts data, and what happens to the
+/// contents past it.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(
+    feature = "interop",
+    derive(candid::CandidType, ::serde::Serialize, ::serde::Deserialize)
+)]
+pub enum WriteFileMode {
+    /// At the offset, keeping the contents past the data.
+    #[default]
+    Overwrite,
+    /// At the end of the file, wherever that is.
+    Append,
+    /// At the offset, dropping the contents past the data.
+    Truncate,
+}
+
+/// Changes to the metadata of an entry, made by `FileSystem::set_metadata`
+/// without touching its contents. What's `None` stays as it is.
+#[derive(Default, Debug, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "interop",
+    derive(candid::CandidType, ::serde::Serialize, ::serde::Deserialize)
+)]
+pub struct MetadataChange {
+    /// Only files have one.
+    #[cfg_attr(feature = "interop", serde(rename = "contentType"))]
+    pub content_type: Option<String>,
+    pub headers: Option<Vec<(String, String)>>,
+    pub xattrs: Option<Vec<(String, Vec<u8>)>>,
+    pub created: Option<u64>,
+    pub modified: Option<u64>,
+}
+
+/// Metadata of a file or directory, as returned by `FileSystem::metadata`.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(
+    feature = "interop",
+    derive(candid::CandidType, ::serde::Serialize, ::serde::Deserialize)
+)]
+pub struct Metadata {
+    pub kind: EntryKind,
+    pub size: u64,
+    pub created: u64,
+    pub modified: u64,
+    /// Data blocks of the entry, without its index blocks.
+    #[cfg_attr(feature = "interop", serde(rename = "blockCount"))]
+    pub block_count: usize,
+    /// Number of entries of a directory, 0 for files.
+    #[cfg_attr(feature = "interop", serde(rename = "entryCount"))]
+    pub entry_count: u64,
 }
 
 impl<M: Memory> FileSystem<M> {
-    fn preamble_blocks() -> usize {
-        Bitmap::len_for_memory_impl::<M>() / Block::SIZE + 8
+    /// The preamble is kept in two copies which are written alternately, so
+    /// a trap while persisting leaves the older copy intact. Each copy holds
+    /// the bitmap, the handles of the root cluster and of both areas of the
+    /// inode table, the extent of the bitmap in use, a sequence number and a
+    /// checksum over all of them.
+    fn preamble_copy_blocks(&self) -> usize {
+        (self.bitmap.len() + 6 * 8).div_ceil(Block::SIZE)
+    }
+
+    pub(crate) fn preamble_blocks(&self) -> usize {
+        2 * self.preamble_copy_blocks()
+    }
+
+    fn preamble_offset(&self, copy: usize) -> u64 {
+        Block::at(copy * self.preamble_copy_blocks()).offset()
     }
 
     pub fn allocate(memory: M) -> Self {
         Self {
-            bitmap: Bitmap::new::<M>(),
+            bitmap: Bitmap::new(&memory),
             root_cluster: Cluster::default(),
-            memory,
+            inodes: InodeTable::default(),
+            memory: TrackedMemory::new(memory),
+            mutations: 0,
+            defragment_pass: None,
+            garbage_pass: None,
+            drop_policy: DropPolicy::default(),
+            secure_delete: false,
+            sequence: 0,
+            durability: Durability::default(),
+            unpersisted: 0,
+            clock: || 0,
+            propagate_modified: false,
+            names: NamePolicy::default(),
+            inline_limit: DEFAULT_INLINE_LIMIT,
+            max_string_len: MAX_STRING_LEN,
+            tails: Some(TailAllocator::default()),
+            tail_packing: false,
+            observer: None,
+            mounts: vec![],
+            dir_sizes: None,
         }
     }
 
@@ -39,406 +326,4101 @@ impl<M: Memory> FileSystem<M> {
         Ok(fs)
     }
 
-    pub fn init(&mut self) -> io::Result<()> {
-        for i in 0..Self::preamble_blocks() {
-            self.bitmap.occupy(i);
-        }
-
-        Directory::default().serialize(
-            self.root_cluster
-                .writer(&mut self.bitmap, self.memory.writer()),
-        )?;
-
-        Ok(())
+    pub fn with_drop_policy(mut self, policy: DropPolicy) -> Self {
+        self.drop_policy = policy;
+        self
     }
 
-    pub fn restore(&mut self) -> io::Result<()> {
-        let mut r = self.memory.reader();
-        self.bitmap.deserialize(&mut r)?;
-        self.root_cluster.deserialize(r)?;
-        Ok(())
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy;
     }
 
-    pub fn persist(&mut self) -> io::Result<()> {
-        let mut w = self.memory.writer();
-        self.bitmap.serialize(&mut w)?;
-        self.root_cluster.serialize(w)?;
-        Ok(())
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
     }
 
-    pub fn with_root_directory<R>(
-        &self,
-        f: impl FnOnce(&Directory) -> io::Result<R>,
-    ) -> io::Result<R> {
-        let dir = self.read_root_directory()?;
-        f(&dir)
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
     }
 
-    pub fn with_directory<R>(
-        &self,
-        path: impl IntoIterator<Item = impl AsRef<str>>,
-        f: impl FnOnce(&Directory) -> io::Result<R>,
-    ) -> io::Result<R> {
-        let mut dir = self.read_root_directory()?;
-        for segment in path {
-            dir = match dir.entry_with_name(&segment) {
-                None => return Err(io::ErrorKind::NotFound.into()),
-                Some(entry) => entry.read_from_file_system(&self).read_directory()?,
-            };
-        }
-        f(&dir)
+    /// Overwrite blocks with zeros when they are released by `truncate`,
+    /// `remove` or relocation, so no deleted data is left in memory.
+    pub fn with_secure_delete(mut self, enabled: bool) -> Self {
+        self.secure_delete = enabled;
+        self
     }
 
-    pub fn with_file<R, S: AsRef<str>>(
-        &self,
-        path: impl Into<Vec<S>>,
-        f: impl FnOnce(&Entry) -> io::Result<R>,
-    ) -> io::Result<R> {
-        let mut path = path.into();
-        let filename = path
-            .pop()
-            .ok_or::<io::Error>(io::ErrorKind::InvalidInput.into())?;
-
-        self.with_directory(path, |dir| {
-            let entry = dir
-                .entry_with_name(filename)
-                .ok_or::<io::Error>(io::ErrorKind::InvalidInput.into())?;
-            if let EntryKind::File = entry.kind {
-                f(entry)
-            } else {
-                Err(io::ErrorKind::InvalidInput.into())
-            }
-        })
+    pub fn set_secure_delete(&mut self, enabled: bool) {
+        self.secure_delete = enabled;
     }
 
-    pub fn with_file_mut<R, S: AsRef<str>>(
-        &mut self,
-        path: impl Into<Vec<S>>,
-        f: impl FnOnce(&mut Entry, &mut FileSystem<M>) -> io::Result<R>,
-    ) -> io::Result<R> {
-        let mut path = path.into();
-        let filename = path
-            .pop()
-            .ok_or::<io::Error>(io::ErrorKind::InvalidInput.into())?;
-
-        self.with_directory_mut(path, |dir, fs| {
-            let entry = dir
-                .entry_with_name_mut(filename)
-                .ok_or::<io::Error>(io::ErrorKind::InvalidInput.into())?;
-            if let EntryKind::File = entry.kind {
-                f(entry, fs)
-            } else {
-                Err(io::ErrorKind::InvalidInput.into())
-            }
-        })
+    /// Sets the clock which timestamps inodes, in nanoseconds since the
+    /// epoch. Without one, all timestamps are 0.
+    pub fn with_clock(mut self, clock: fn() -> u64) -> Self {
+        self.clock = clock;
+        self
     }
 
-    pub fn with_root_directory_mut<R>(
-        &mut self,
-        f: impl FnOnce(&mut Directory, &mut Self) -> io::Result<R>,
-    ) -> io::Result<R> {
-        let mut dir = self.read_root_directory()?;
-        let r = f(&mut dir, self);
-        self.write_root_directory(&dir)?;
-        r
+    pub fn set_clock(&mut self, clock: fn() -> u64) {
+        self.clock = clock;
     }
 
-    pub fn with_directory_mut<R>(
-        &mut self,
-        path: impl IntoIterator<Item = impl AsRef<str>>,
-        f: impl FnOnce(&mut Directory, &mut Self) -> io::Result<R>,
-    ) -> io::Result<R> {
-        self.with_root_directory_mut(|root, fs| {
-            fs.with_directory_mut_rec(root, path.into_iter(), f)
-        })
+    /// The time by the clock of the filesystem.
+    pub fn now(&self) -> u64 {
+        (self.clock)()
     }
 
-    fn with_directory_mut_rec<R>(
-        &mut self,
-        dir: &mut Directory,
-        mut path: impl Iterator<Item = impl AsRef<str>>,
-        f: impl FnOnce(&mut Directory, &mut Self) -> io::Result<R>,
-    ) -> io::Result<R> {
-        match path.next() {
-            Some(segment) => match dir.entry_with_name_mut(&segment) {
-                None => Err(io::ErrorKind::NotFound.into()),
-                Some(Entry {
-                    kind: EntryKind::File,
-                    ..
-                }) => Err(io::ErrorKind::Other.into()),
-                Some(
-                    entry @ Entry {
-                        kind: EntryKind::Directory,
-                        ..
-                    },
-                ) => {
-                    let mut subdir = entry.read_from_file_system(&self).read_directory()?;
-                    let r = self.with_directory_mut_rec(&mut subdir, path, f)?;
-                    entry.write_to_file_system(self).write_directory(&subdir)?;
-                    Ok(r)
-                }
-            },
-            None => f(dir, self),
-        }
+    /// Makes a change to the entries of a directory also move the
+    /// modification time of all directories above it forward.
+    pub fn with_modified_propagation(mut self, enabled: bool) -> Self {
+        self.propagate_modified = enabled;
+        self
     }
 
-    pub fn write_into_cluster<'a>(
-        &'a mut self,
-        cluster: &'a mut Cluster,
-    ) -> ClusterWriter<'a, MemoryWriter<'a, M>> {
-        cluster.writer(&mut self.bitmap, self.memory.writer())
+    pub fn set_modified_propagation(&mut self, enabled: bool) {
+        self.propagate_modified = enabled;
     }
 
-    pub fn write_into_root_cluster(&mut self) -> ClusterWriter<MemoryWriter<M>> {
-        self.root_cluster
-            .writer(&mut self.bitmap, self.memory.writer())
+    /// Sets the rules for entry names, which apply to lookups and to entries
+    /// added from then on.
+    pub fn with_name_policy(mut self, names: NamePolicy) -> Self {
+        self.names = names;
+        self
     }
 
-    pub fn read_from_cluster<'a>(&'a self, cluster: &'a Cluster) -> ClusterReader<MemoryReader<M>> {
-        cluster.reader(self.memory.reader())
+    pub fn set_name_policy(&mut self, names: NamePolicy) {
+        self.names = names;
     }
 
-    pub fn read_from_root_cluster(&self) -> ClusterReader<MemoryReader<M>> {
-        self.root_cluster.reader(self.memory.reader())
+    pub fn name_policy(&self) -> NamePolicy {
+        self.names
     }
 
-    pub fn read_root_directory(&self) -> io::Result<Directory> {
-        let r = self.read_from_root_cluster();
-        Directory::deserialize_into_default(r)
+    /// Keeps the contents of files up to `len` bytes inline, packed into
+    /// blocks shared with other files like tails, rather than in a block of
+    /// their own. At most a block's worth is kept inline, and 0 turns
+    /// inlining off.
+    pub fn with_inline_limit(mut self, len: usize) -> Self {
+        self.set_inline_limit(len);
+        self
     }
 
-    pub fn write_root_directory(&mut self, directory: &Directory) -> io::Result<()> {
-        directory.serialize(self.write_into_root_cluster())?;
-        Ok(())
+    pub fn set_inline_limit(&mut self, len: usize) {
+        self.inline_limit = len.min(Block::SIZE);
     }
 
-    pub fn make_directory_recursive<P, S>(&mut self, path: P) -> io::Result<()>
-    where
-        P: IntoIterator<Item = S>,
-        S: Into<String> + AsRef<str>,
-    {
-        self.with_root_directory_mut(|root, fs| root.make_directory_recursive(fs, path.into_iter()))
+    /// Refuses names, content types and other strings in the metadata over
+    /// `len` bytes as corrupted when reading them. The default is
+    /// `MAX_STRING_LEN`, 64 KiB.
+    pub fn with_max_string_len(mut self, len: usize) -> Self {
+        self.max_string_len = len;
+        self
     }
-}
 
-impl<M: Memory> Serialize for FileSystem<M> {
-    fn serialize(&self, mut w: impl io::Write) -> io::Result<usize> {
-        let mut bytes_written = self.bitmap.serialize(&mut w)?;
-        bytes_written += self.root_cluster.serialize(w)?;
-        Ok(bytes_written)
+    /// Packs the last, partial block of files into blocks shared with other
+    /// files, so many small or oddly sized files don't each waste most of a
+    /// block. Reading a packed tail takes an extra read, and writing a file
+    /// unpacks it first.
+    pub fn with_tail_packing(mut self, enabled: bool) -> Self {
+        self.set_tail_packing(enabled);
+        self
     }
-}
 
-impl<M: Memory> Deserialize for FileSystem<M> {
-    fn deserialize(&mut self, mut r: impl io::Read) -> io::Result<usize> {
-        let mut bytes_read = self.bitmap.deserialize(&mut r)?;
-        bytes_read += self.root_cluster.deserialize(r)?;
-        Ok(bytes_read)
+    pub fn set_tail_packing(&mut self, enabled: bool) {
+        self.tail_packing = enabled;
     }
-}
 
-impl<M: Memory> Drop for FileSystem<M> {
-    fn drop(&mut self) {
-        self.persist().expect("failed to write filesystem preamble");
+    /// Reports entries being created, written, deleted and renamed to
+    /// `observer`.
+    pub fn with_observer(mut self, observer: Box<dyn FsObserver>) -> Self {
+        self.set_observer(Some(observer));
+        self
     }
-}
 
-impl<M: Memory> fmt::Display for FileSystem<M> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "/")?;
+    pub fn set_observer(&mut self, observer: Option<Box<dyn FsObserver>>) {
+        self.observer = observer;
+    }
 
-        let mut dirs = vec![self.read_root_directory().or(Err(fmt::Error))?];
-        while dirs.len() > 0 {
-            let l = dirs.len() - 1;
-            let dir = dirs.last_mut().unwrap();
-            if dir.entries.is_empty() {
-                dirs.pop().unwrap();
-                continue;
-            }
+    /// Keeps the results of `dir_size` for each directory of the subtree
+    /// until something below that directory changes.
+    pub fn with_size_caching(mut self, enabled: bool) -> Self {
+        self.set_size_caching(enabled);
+        self
+    }
 
-            write!(f, "\n{:>width$}", "| ", width = l * 4 + 2)?;
+    pub fn set_size_caching(&mut self, enabled: bool) {
+        self.dir_sizes = enabled.then(Mutex::default);
+    }
 
-            match &dir.entries.remove(0) {
-                Entry {
-                    kind: EntryKind::File,
-                    name,
-                    ..
-                } => {
-                    write!(f, "{}", name)?;
-                }
+    /// Drops the cached sizes of the directories above and below `path`.
+    fn invalidate_sizes(&mut self, path: &[String]) {
+        if let Some(sizes) = self.dir_sizes.as_mut() {
+            sizes
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|dir, _| !dir.starts_with(path) && !path.starts_with(dir));
+        }
+    }
 
-                inner_dir @ Entry {
-                    kind: EntryKind::Directory,
-                    name,
-                    ..
-                } => {
-                    write!(f, "{}/", &name)?;
-                    drop(dir);
-
-                    dirs.push(
-                        inner_dir
-                            .read_from_file_system(self)
-                            .read_directory()
-                            .or(Err(fmt::Error))?,
-                    );
-                }
+    fn notify(&mut self, events: Vec<Event>) {
+        if let Some(observer) = self.observer.as_mut() {
+            for event in events.iter() {
+                event.notify(observer.as_mut());
             }
         }
-        Ok(())
     }
-}
-
-#[test]
-fn test() {
-    use crate::bitmap::BitState;
-    use crate::heap_memory::HeapMemory;
-    use std::io::{Read, Write};
-
-    const DATA_BLOCKS: usize = 128;
 
-    let data: Vec<u8> = (0..Block::SIZE * DATA_BLOCKS)
-        .map(|_| rand::random())
-        .collect();
+    /// Holds back `percent` of all blocks for directories and other
+    /// metadata, so they can still be updated once file contents have
+    /// filled up the rest of the memory.
+    pub fn with_metadata_reserve(mut self, percent: u8) -> Self {
+        self.set_metadata_reserve(percent);
+        self
+    }
 
-    let mut memory = HeapMemory::default();
+    pub fn set_metadata_reserve(&mut self, percent: u8) {
+        let blocks = self.bitmap.len() * 8 * percent.min(100) as usize / 100;
+        self.bitmap.set_reserved(blocks);
+    }
 
-    {
-        let mut fs = FileSystem::new(&mut memory).unwrap();
+    /// Chooses how clusters find free blocks when they grow.
+    pub fn with_allocation_policy(mut self, policy: AllocationPolicy) -> Self {
+        self.bitmap.set_policy(policy);
+        self
+    }
 
-        fs.bitmap.occupy(42);
-        fs.bitmap.occupy(39);
-        fs.bitmap.occupy(58);
+    pub fn set_allocation_policy(&mut self, policy: AllocationPolicy) {
+        self.bitmap.set_policy(policy);
+    }
 
-        {
-            let mut writer = fs.write_into_root_cluster();
-            writer.write_all(&data).unwrap();
+    /// Attaches `fs` at the directory `path`, so paths leading there are
+    /// looked up in `fs` instead. Path based operations like `exists`,
+    /// `metadata`, `read_file`, `write_atomic` and `remove` follow mount
+    /// points, while those handing out entries or directories fail with
+    /// `Error::MountPoint` where they would cross one. Mounted filesystems
+    /// are persisted along with this one.
+    pub fn mount(
+        &mut self,
+        path: impl Into<Vec<String>>,
+        fs: impl Mount + 'static,
+    ) -> io::Result<()> {
+        let path = path.into();
+        if path.is_empty() {
+            return Err(Error::InvalidPath.into());
         }
-
-        {
-            let mut reader = fs.read_from_root_cluster();
-            let mut read_data = vec![];
-            reader.read_to_end(&mut read_data).unwrap();
-            assert_eq!(read_data, data);
+        let overlaps = self.mounts.iter().any(|(point, _)| {
+            point
+                .iter()
+                .zip(path.iter())
+                .all(|(a, b)| self.names.matches(a, b))
+        });
+        if overlaps {
+            return Err(Error::MountPoint.into());
         }
+        self.with_directory(&path, |_| Ok(()))?;
+        self.mounts.push((path, Box::new(fs)));
+        Ok(())
+    }
 
-        assert_eq!(
-            fs.bitmap
+    /// Detaches the filesystem mounted at `path` and hands it back.
+    pub fn unmount(&mut self, path: impl Into<Vec<String>>) -> io::Result<Box<dyn Mount>> {
+        let path = path.into();
+        let i = self
+            .mounts
+            .iter()
+            .position(|(point, _)| self.is_below(&path, point) && point.len() == path.len())
+            .ok_or(Error::NotFound)?;
+        Ok(self.mounts.remove(i).1)
+    }
+
+    /// Whether `path` is `point` or leads through it.
+    fn is_below(&self, path: &[String], point: &[String]) -> bool {
+        path.len() >= point.len()
+            && point
                 .iter()
-                .filter(|s| s == &BitState::Occupied)
-                .count(),
-            FileSystem::<HeapMemory>::preamble_blocks() + DATA_BLOCKS + 3
-        );
+                .zip(path.iter())
+                .all(|(a, b)| self.names.matches(a, b))
     }
 
-    {
-        let fs = FileSystem::open(memory).unwrap();
-        let mut reader = fs.read_from_root_cluster();
+    /// The mount `path` leads into, with the rest of the path relative to
+    /// its root.
+    fn mounted(&self, path: &[String]) -> Option<(usize, Vec<String>)> {
+        self.mounts
+            .iter()
+            .position(|(point, _)| self.is_below(path, point))
+            .map(|i| (i, path[self.mounts[i].0.len()..].to_vec()))
+    }
 
-        let mut read_data = vec![];
-        reader.read_to_end(&mut read_data).unwrap();
-        assert_eq!(read_data, data);
+    /// Fails if `path` leads into a mount.
+    fn ensure_unmounted(&self, path: &[String]) -> io::Result<()> {
+        match self.mounted(path) {
+            Some(_) => Err(Error::MountPoint.into()),
+            None => Ok(()),
+        }
     }
-}
 
-#[test]
-fn a_file() {
-    use crate::directory::EntryKind;
-    use crate::heap_memory::HeapMemory;
-    use std::io::{Read, Write};
+    /// Persists the preamble and releases the memory, reporting any error
+    /// instead of leaving it to `Drop`.
+    pub fn close(mut self) -> io::Result<()> {
+        let result = self.persist();
+        self.drop_policy = DropPolicy::Ignore;
+        result
+    }
 
-    let mut mem = HeapMemory::default();
+    pub fn init(&mut self) -> io::Result<()> {
+        self.bitmap.occupy_range(0..self.preamble_blocks());
+        let next = (self.sequence as usize + 1) % 2;
+        self.inodes = InodeTable::create(&mut self.bitmap, &mut self.memory, next)?;
 
-    {
-        let mut fs = FileSystem::new(&mut mem).unwrap();
+        Directory::default().serialize(
+            self.root_cluster
+                .writer(&mut self.bitmap, self.memory.writer()),
+        )?;
 
-        fs.with_root_directory_mut(|root, fs| {
-            root.add_file("my-file.txt")
-                .write_to_file_system(fs)
-                .write_all(b"Hello World")
-        })
-        .unwrap();
+        Ok(())
     }
 
-    {
-        let fs = FileSystem::open(&mut mem).unwrap();
+    /// Restores the state from the newest intact preamble copy. Checking the
+    /// copies against their checksums is all it validates, so it stays cheap
+    /// for large filesystems: of the inode table only the header is read,
+    /// and the blocks shared by tails are only found again once needed.
+    pub fn restore(&mut self) -> io::Result<()> {
+        let (preamble, older) = match (self.read_preamble(0)?, self.read_preamble(1)?) {
+            (Some(a), Some(b)) if a.sequence > b.sequence => (a, Some(b)),
+            (Some(a), Some(b)) => (b, Some(a)),
+            (a, b) => (
+                a.or(b)
+                    .ok_or_else(|| Error::corrupted("no intact preamble"))?,
+                None,
+            ),
+        };
+        let sequence = preamble.sequence;
 
-        fs.with_root_directory(|root| {
-            let entry = &root.entries[0];
-            assert_eq!(entry.kind, EntryKind::File);
+        let (policy, reserved) = (self.bitmap.policy(), self.bitmap.reserved());
+        self.bitmap = preamble.bitmap;
+        self.bitmap.set_policy(policy);
+        self.bitmap.set_reserved(reserved);
+        self.root_cluster = preamble.root_cluster;
+        self.root_cluster.load(self.memory.reader())?;
+        let next = (sequence as usize + 1) % 2;
+        self.inodes = InodeTable::open(preamble.inode_areas, next, &self.memory)?;
+        self.tails = None;
+        self.sequence = sequence;
+        self.memory.reset(sequence);
+        if let Some(sizes) = self.dir_sizes.as_mut() {
+            sizes
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
 
-            let mut r = entry.read_from_file_system(&fs);
-            let mut result = [0u8; 5];
-            r.read_exact(&mut result)?;
+        // The other copy is outdated, so the next persist rewrites what
+        // differs from it, or all of it if it's torn.
+        match older {
+            Some(older) => self.bitmap.mark_copy_dirty_since(next, &older.bitmap),
+            None => self.bitmap.mark_copy_dirty(next),
+        }
+        Ok(())
+    }
 
-            assert_eq!(&result, b"Hello");
+    /// Reads a preamble copy, returning `None` if it doesn't match its
+    /// checksum, as after a torn write. Only the extent of the bitmap in use
+    /// is read.
+    fn read_preamble(&self, copy: usize) -> io::Result<Option<Preamble>> {
+        let offset = self.preamble_offset(copy);
+        let mut bitmap = Bitmap::new(&self.memory);
+        let mut root_cluster = Cluster::default();
+        let mut inode_areas = [Cluster::default(), Cluster::default()];
+        let (mut extent, mut sequence, mut stored) = (0usize, 0u64, 0u64);
+
+        let mut r = self.memory.reader();
+        let read = r
+            .seek(io::SeekFrom::Start(offset + bitmap.len() as u64))
+            .and_then(|_| root_cluster.deserialize(&mut r))
+            .and_then(|_| inode_areas[0].deserialize(&mut r))
+            .and_then(|_| inode_areas[1].deserialize(&mut r))
+            .and_then(|_| extent.deserialize(&mut r))
+            .and_then(|_| sequence.deserialize(&mut r))
+            .and_then(|_| stored.deserialize(&mut r))
+            .and_then(|_| r.seek(io::SeekFrom::Start(offset)))
+            .and_then(|_| bitmap.read_extent(&mut r, extent));
+        match read {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(None),
+            result => result?,
+        };
+
+        let mut checksum = Checksum::default();
+        bitmap.checksum().serialize(&mut checksum)?;
+        root_cluster.serialize(&mut checksum)?;
+        for area in inode_areas.iter() {
+            area.serialize(&mut checksum)?;
+        }
+        extent.serialize(&mut checksum)?;
+        sequence.serialize(&mut checksum)?;
+        if checksum.value() != stored {
+            return Ok(None);
+        }
+        Ok(Some(Preamble {
+            sequence,
+            bitmap,
+            root_cluster,
+            inode_areas,
+        }))
+    }
+
+    /// Writes the preamble into the copy which wasn't written last. Only the
+    /// parts of the bitmap which changed since that copy was written are
+    /// rewritten, and only they are hashed again for the checksum. The
+    /// checksum goes last, so the copy only becomes valid once everything
+    /// else is in place. The slots of the inode table were already written
+    /// to the area of this copy when they changed, so only the ones it lacks
+    /// from before the last persist are copied.
+    pub fn persist(&mut self) -> io::Result<()> {
+        self.inodes.prepare(&mut self.bitmap, &mut self.memory)?;
+
+        let sequence = self.sequence + 1;
+        let copy = (sequence % 2) as usize;
+        let offset = self.preamble_offset(copy);
+
+        let extent = self.bitmap.extent();
+        let mut w = self.memory.writer();
+        self.bitmap.write_dirty(copy, &mut w, offset)?;
+
+        let mut checksum = Checksum::default();
+        self.bitmap.checksum().serialize(&mut checksum)?;
+        self.root_cluster.serialize(&mut checksum)?;
+        for area in self.inodes.areas() {
+            area.serialize(&mut checksum)?;
+        }
+        extent.serialize(&mut checksum)?;
+        sequence.serialize(&mut checksum)?;
+
+        w.seek(io::SeekFrom::Start(offset + self.bitmap.len() as u64))?;
+        self.root_cluster.serialize(&mut w)?;
+        for area in self.inodes.areas() {
+            area.serialize(&mut w)?;
+        }
+        extent.serialize(&mut w)?;
+        sequence.serialize(&mut w)?;
+        checksum.value().serialize(&mut w)?;
+
+        self.inodes.commit();
+        self.sequence = sequence;
+        self.memory.set_generation(sequence + 1);
+        self.unpersisted = 0;
+
+        for (_, fs) in self.mounts.iter_mut() {
+            fs.persist()?;
+        }
+        Ok(())
+    }
+
+    /// Whether nothing changed since the preamble was last persisted or
+    /// restored, so `persist` would only write what's already there.
+    /// Mounted filesystems aren't asked.
+    pub fn is_persisted(&self) -> bool {
+        self.unpersisted == 0
+            && !self.inodes.is_dirty()
+            && !self.bitmap.is_copy_dirty((self.sequence % 2) as usize)
+    }
+
+    /// Persists the preamble if the durability policy asks for it after
+    /// another mutating operation.
+    fn after_mutation(&mut self) -> io::Result<()> {
+        self.unpersisted += 1;
+        self.mutations += 1;
+        let due = match self.durability {
+            Durability::Manual => false,
+            Durability::AfterEveryMutation => true,
+            Durability::EveryNOperations(n) => self.unpersisted >= n,
+        };
+        if due {
+            self.persist()?;
+        }
+        Ok(())
+    }
+
+    pub fn with_root_directory<R>(
+        &self,
+        f: impl FnOnce(&Directory) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let dir = self.read_root_directory()?;
+        f(&dir)
+    }
+
+    /// Resolves a path by streaming each directory on the way, stopping at the
+    /// first matching entry. Returns `None` for the root directory.
+    pub fn resolve(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> io::Result<Option<Entry>> {
+        let path = names(path);
+        self.ensure_unmounted(&path)?;
+
+        let mut current: Option<Entry> = None;
+        for segment in path {
+            let found = match &current {
+                None => self.root_directory_reader()?.entry_with_name(&segment)?,
+                Some(Entry {
+                    kind: EntryKind::File,
+                    ..
+                }) => return Err(Error::NotADirectory.into()),
+                Some(entry) => self
+                    .directory_reader(entry.read_from_file_system(self)?)?
+                    .entry_with_name(&segment)?,
+            };
+            let mut found = found.ok_or(Error::NotFound)?;
+            self.load_entry(&mut found)?;
+            current = Some(found);
+        }
+        Ok(current)
+    }
+
+    /// Whether `path` leads to a file or directory. The empty path is the
+    /// root directory, which always exists.
+    pub fn exists(&self, path: impl IntoIterator<Item = impl AsRef<str>>) -> bool {
+        let path = names(path);
+        if let Some((i, rest)) = self.mounted(&path) {
+            return self.mounts[i].1.exists(&rest);
+        }
+        self.resolve(path).is_ok()
+    }
+
+    /// Looks up the metadata of the entry at `path`. The root directory has
+    /// no inode, so its timestamps are 0 and its size is that of its blocks.
+    pub fn metadata(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> io::Result<Metadata> {
+        let path = names(path);
+        if let Some((i, rest)) = self.mounted(&path) {
+            return self.mounts[i].1.metadata(&rest);
+        }
+        Ok(match self.resolve(path)? {
+            None => Metadata {
+                kind: EntryKind::Directory,
+                size: self.root_cluster.len(),
+                created: 0,
+                modified: 0,
+                block_count: self.root_cluster.block_count(),
+                entry_count: self.root_directory_reader()?.remaining() as u64,
+            },
+            Some(entry) => Metadata {
+                kind: entry.kind,
+                size: entry.size,
+                created: entry.created,
+                modified: entry.modified,
+                block_count: entry.cluster.block_count(),
+                entry_count: entry.entry_count,
+            },
+        })
+    }
+
+    pub fn with_directory<R>(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+        f: impl FnOnce(&Directory) -> io::Result<R>,
+    ) -> io::Result<R> {
+        f(&self.directory_at(path)?)
+    }
+
+    /// Reads the directory at `path`, the root for an empty path.
+    pub(crate) fn directory_at(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> io::Result<Directory> {
+        match self.resolve(path)? {
+            None => self.read_root_directory(),
+            Some(Entry {
+                kind: EntryKind::File,
+                ..
+            }) => Err(Error::NotADirectory.into()),
+            Some(entry) => self.read_directory(&entry),
+        }
+    }
+
+    pub fn with_file<R, S: AsRef<str>>(
+        &self,
+        path: impl Into<Vec<S>>,
+        f: impl FnOnce(&Entry) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let path = path.into();
+        if path.is_empty() {
+            return Err(Error::InvalidPath.into());
+        }
+
+        match self.resolve(path)? {
+            Some(
+                entry @ Entry {
+                    kind: EntryKind::File,
+                    ..
+                },
+            ) => f(&entry),
+            _ => Err(Error::IsADirectory.into()),
+        }
+    }
+
+    /// Copies the contents of the file at `path` into `w`, returning their
+    /// length.
+    pub fn read_file(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+        mut w: impl io::Write,
+    ) -> io::Result<u64> {
+        let path = names(path);
+        if let Some((i, rest)) = self.mounted(&path) {
+            return self.mounts[i].1.read_file(&rest, &mut w);
+        }
+        self.with_file(path, |file| {
+            io::copy(&mut file.read_from_file_system(self)?, &mut w)
+        })
+    }
+
+    /// Names and kinds of the entries of the directory at `path`.
+    pub fn list_directory(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> io::Result<Vec<(String, EntryKind)>> {
+        let path = names(path);
+        if let Some((i, rest)) = self.mounted(&path) {
+            return self.mounts[i].1.list_directory(&rest);
+        }
+        self.with_directory(path, |dir| {
+            Ok(dir
+                .entries
+                .iter()
+                .map(|entry| (entry.name.clone(), entry.kind))
+                .collect())
+        })
+    }
+
+    /// Up to `limit` entries of the directory at `path` whose names start
+    /// with `prefix`, in `order`, after skipping `offset` of them. Also
+    /// returns the offset of the next page, if there is one. Only the
+    /// entries of the page get their metadata read, but orders other than
+    /// `SortOrder::Stored` read every name, and modification orders every
+    /// inode, to sort them.
+    pub fn directory_page(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+        offset: usize,
+        limit: usize,
+        prefix: &str,
+        order: SortOrder,
+    ) -> io::Result<(Vec<Entry>, Option<usize>)> {
+        match self.resolve(path)? {
+            None => self.page_of(self.root_directory_reader()?, offset, limit, prefix, order),
+            Some(entry) if entry.kind == EntryKind::Directory => {
+                let reader = self.directory_reader(entry.read_from_file_system(self)?)?;
+                self.page_of(reader, offset, limit, prefix, order)
+            }
+            Some(_) => Err(Error::NotADirectory.into()),
+        }
+    }
+
+    fn page_of(
+        &self,
+        entries: impl Iterator<Item = io::Result<Entry>>,
+        offset: usize,
+        limit: usize,
+        prefix: &str,
+        order: SortOrder,
+    ) -> io::Result<(Vec<Entry>, Option<usize>)> {
+        let fold = |name: &str| {
+            let name = self.names.normalized(name);
+            if self.names.case_insensitive {
+                name.to_lowercase()
+            } else {
+                name.into_owned()
+            }
+        };
+        let prefix = fold(prefix);
+        let matching = entries.filter(|entry| match entry {
+            Ok(entry) => fold(&entry.name).starts_with(&prefix),
+            Err(_) => true,
+        });
+        // One more than the page tells whether there is a next one.
+        let take = limit.saturating_add(1);
+        let mut page = match order {
+            SortOrder::Stored => matching
+                .skip(offset)
+                .take(take)
+                .collect::<io::Result<Vec<_>>>()?,
+            _ => {
+                let mut all = matching.collect::<io::Result<Vec<_>>>()?;
+                if matches!(order, SortOrder::Modified | SortOrder::ModifiedDescending) {
+                    for entry in all.iter_mut() {
+                        self.load_entry(entry)?;
+                    }
+                }
+                match order {
+                    SortOrder::Name => all.sort_by(|a, b| a.name.cmp(&b.name)),
+                    SortOrder::NameDescending => all.sort_by(|a, b| b.name.cmp(&a.name)),
+                    SortOrder::Modified => all.sort_by_key(|entry| entry.modified),
+                    _ => all.sort_by_key(|entry| Reverse(entry.modified)),
+                }
+                all.into_iter().skip(offset).take(take).collect()
+            }
+        };
+        let next = (page.len() > limit).then(|| offset + limit);
+        page.truncate(limit);
+        for entry in page.iter_mut() {
+            self.load_entry(entry)?;
+        }
+        Ok((page, next))
+    }
+
+    /// Reads into `buf` from `offset` of the file at `path`, like
+    /// `Entry::read_at`.
+    pub fn read_at<S: AsRef<str>>(
+        &self,
+        path: impl Into<Vec<S>>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        self.with_file(path, |file| file.read_at(self, offset, buf))
+    }
+
+    /// Writes `data` at `offset` of the file at `path`, like
+    /// `Entry::write_at`.
+    pub fn write_at<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        offset: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        self.with_file_mut(path, |file, fs| file.write_at(fs, offset, data))
+    }
+
+    /// Writes `data` to the file at `path` as `mode` says, at `offset` unless
+    /// appending. Returns the offset the data was written at.
+    pub fn write_with_mode<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        offset: u64,
+        data: &[u8],
+        mode: WriteFileMode,
+    ) -> io::Result<u64> {
+        self.with_file_mut(path, |file, fs| {
+            let offset = match mode {
+                WriteFileMode::Append => file.size,
+                WriteFileMode::Overwrite | WriteFileMode::Truncate => offset,
+            };
+            let end = offset
+                .checked_add(data.len() as u64)
+                .ok_or(io::ErrorKind::InvalidInput)?;
+            file.write_at(fs, offset, data)?;
+            if mode == WriteFileMode::Truncate {
+                file.truncate(fs, end)?;
+            }
+            Ok(offset)
+        })
+    }
+
+    /// Copies `len` bytes from `src_offset` of the file at `src` to
+    /// `dst_offset` of the file at `dst`, through a single buffer. The files
+    /// may be the same, and the ranges may overlap. Stops at the end of the
+    /// source and returns the number of bytes copied.
+    pub fn copy_range<S: AsRef<str>, T: AsRef<str>>(
+        &mut self,
+        src: impl Into<Vec<S>>,
+        src_offset: u64,
+        dst: impl Into<Vec<T>>,
+        dst_offset: u64,
+        len: u64,
+    ) -> io::Result<u64> {
+        let dst = dst.into();
+        let src = names(src.into());
+        let source = match self.resolve(&src)? {
+            Some(entry) if entry.kind == EntryKind::File => entry,
+            Some(_) => return Err(Error::IsADirectory.into()),
+            None => return Err(Error::InvalidPath.into()),
+        };
+        let same = src == names(&dst);
+        let len = len.min(source.size.saturating_sub(src_offset));
+        // Like `memmove`, a range copied forwards over itself goes back to
+        // front.
+        let backwards = same && dst_offset > src_offset;
+
+        self.with_file_mut(dst, |file, fs| {
+            let mut buf = vec![0u8; BUF_CAPACITY];
+            let mut done = 0;
+            while done < len {
+                let n = (len - done).min(buf.len() as u64);
+                let at = if backwards { len - done - n } else { done };
+                let from = if same { &*file } else { &source };
+                let read = from.read_at(fs, src_offset + at, &mut buf[..n as usize])?;
+                file.write_at(fs, dst_offset + at, &buf[..read])?;
+                done += n;
+            }
+            Ok(len)
+        })
+    }
+
+    /// Adds up the sizes of the files below the directory at `path`.
+    pub fn dir_size(&self, path: impl IntoIterator<Item = impl AsRef<str>>) -> io::Result<DirSize> {
+        let mut path = names(path);
+        if let Some(size) = self.cached_size(&path) {
+            return Ok(size);
+        }
+        let dir = self.directory_at(&path)?;
+        self.subtree_size(&mut path, dir)
+    }
+
+    /// Counts the blocks in use, from the bitmap rather than the tree.
+    pub fn usage(&self) -> Usage {
+        Usage {
+            block_size: Block::SIZE as u64,
+            total_blocks: self.bitmap.len() as u64 * 8,
+            used_blocks: self.bitmap.occupied_blocks() as u64,
+            reserved_blocks: self.bitmap.reserved() as u64,
+        }
+    }
+
+    /// Walks the whole tree to count the files and directories, keeping the
+    /// `largest` largest files, and loads the block list of every file to
+    /// tell how fragmented they are.
+    pub fn stats(&self, largest: usize) -> io::Result<Stats> {
+        let mut stats = Stats {
+            usage: self.usage(),
+            format_version: FORMAT_VERSION,
+            ..Default::default()
+        };
+        let mut pending = vec![(vec![], self.read_root_directory()?)];
+        while let Some((path, dir)) = pending.pop() {
+            for entry in dir.entries {
+                let mut entry_path: Vec<String> = path.clone();
+                entry_path.push(entry.name.clone());
+                if entry.kind == EntryKind::Directory {
+                    stats.directories += 1;
+                    pending.push((entry_path, self.read_directory(&entry)?));
+                    continue;
+                }
+                let size = entry.size;
+                stats.files += 1;
+                stats.file_bytes += size;
+
+                let mut cluster = entry.cluster;
+                cluster.load(self.memory.reader())?;
+                let mut runs = 0;
+                let mut next = None;
+                for block in cluster.blocks() {
+                    if next != Some(block.index) {
+                        runs += 1;
+                    }
+                    next = Some(block.index + 1);
+                }
+                stats.fragments += runs;
+                stats.fragmented_files += (runs > 1) as u64;
+
+                let at = stats
+                    .largest_files
+                    .partition_point(|&(_, other)| other >= size);
+                if at < largest {
+                    stats.largest_files.insert(at, (entry_path, size));
+                    stats.largest_files.truncate(largest);
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    fn cached_size(&self, path: &[String]) -> Option<DirSize> {
+        let sizes = self.dir_sizes.as_ref()?;
+        let sizes = sizes.lock().unwrap_or_else(PoisonError::into_inner);
+        sizes.get(path).copied()
+    }
+
+    fn subtree_size(&self, path: &mut Vec<String>, dir: Directory) -> io::Result<DirSize> {
+        let mut size = DirSize::default();
+        for entry in dir.entries {
+            if entry.kind == EntryKind::File {
+                size.bytes += entry.size;
+                size.files += 1;
+                continue;
+            }
+            path.push(entry.name.clone());
+            let subdir = match self.cached_size(path) {
+                Some(subdir) => subdir,
+                None => self.subtree_size(path, self.read_directory(&entry)?)?,
+            };
+            path.pop();
+            size.bytes += subdir.bytes;
+            size.files += subdir.files;
+        }
+
+        if let Some(sizes) = self.dir_sizes.as_ref() {
+            sizes
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(path.clone(), size);
+        }
+        Ok(size)
+    }
+
+    /// Changes to the file are written to its inode. The parent directory
+    /// is only rewritten the first time an inode is allocated for the file.
+    pub fn with_file_mut<R, S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        f: impl FnOnce(&mut Entry, &mut FileSystem<M>) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let path = path.into();
+        let names = names(&path);
+        let mut size = 0;
+        let r = self.with_entry_mut(path, |entry, fs| {
+            if entry.kind != EntryKind::File {
+                return Err(Error::IsADirectory.into());
+            }
+            let r = f(entry, fs);
+            entry.modified = (fs.clock)();
+            size = entry.size;
+            r
+        })?;
+        self.notify(vec![Event::WriteComplete(names, size)]);
+        Ok(r)
+    }
+
+    /// Seals or unseals the entry at `path`. This is the only way around
+    /// the seal, so callers have to restrict who may use it.
+    pub fn set_sealed<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        sealed: bool,
+    ) -> io::Result<()> {
+        let path = path.into();
+        if let Some((i, rest)) = self.mounted(&names(&path)) {
+            return self.mounts[i].1.set_sealed(&rest, sealed);
+        }
+        self.with_entry_mut(path, |entry, _| {
+            entry.sealed = sealed;
+            Ok(())
+        })
+    }
+
+    /// Sets the time after which the entry at `path` is removed by
+    /// `purge_expired`, or `None` to keep it.
+    pub fn set_expiry<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        expires: Option<u64>,
+    ) -> io::Result<()> {
+        let path = path.into();
+        if let Some((i, rest)) = self.mounted(&names(&path)) {
+            return self.mounts[i].1.set_expiry(&rest, expires);
+        }
+        self.with_entry_mut(path, |entry, _| {
+            entry.expires = expires;
+            Ok(())
+        })
+    }
+
+    /// Sets the extra HTTP headers of the entry at `path`, replacing those
+    /// it had. The root directory has no entry, so it can't have any, and
+    /// neither can entries of mounted filesystems.
+    pub fn set_headers<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        headers: Vec<(String, String)>,
+    ) -> io::Result<()> {
+        let mut path = names(path.into());
+        check_headers(&headers)?;
+        let name = path.pop().ok_or(Error::InvalidPath)?;
+        self.with_directory_mut(path, |dir, _| {
+            dir.entry_with_name_mut(name)
+                .ok_or(Error::NotFound)?
+                .headers = headers;
+            Ok(())
+        })
+    }
+
+    /// Changes the metadata of the entry at `path` as `change` says, leaving
+    /// its contents alone. Extended attributes need names, each its own.
+    pub fn set_metadata<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        change: MetadataChange,
+    ) -> io::Result<()> {
+        let path = names(path.into());
+        if let Some(headers) = &change.headers {
+            check_headers(headers)?;
+        }
+        if let Some(xattrs) = &change.xattrs {
+            let mut names: Vec<_> = xattrs.iter().map(|(name, _)| name).collect();
+            names.sort();
+            names.dedup();
+            if names.len() < xattrs.len() || names.iter().any(|n| n.is_empty()) {
+                let message = "extended attributes need names, each its own";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+        }
+        let MetadataChange {
+            content_type,
+            headers,
+            xattrs,
+            created,
+            modified,
+        } = change;
+        let (parent, name) = path.split_at(path.len().checked_sub(1).ok_or(Error::InvalidPath)?);
+        self.with_directory_mut(parent, |dir, _| {
+            let entry = dir.entry_with_name_mut(&name[0]).ok_or(Error::NotFound)?;
+            if let Some(content_type) = content_type {
+                if entry.kind != EntryKind::File {
+                    return Err(Error::IsADirectory.into());
+                }
+                entry.content_type = content_type;
+            }
+            if let Some(headers) = headers {
+                entry.headers = headers;
+            }
+            if let Some(xattrs) = xattrs {
+                entry.xattrs = xattrs;
+            }
+            Ok(())
+        })?;
+        if created.is_some() || modified.is_some() {
+            self.with_entry_mut(path, |entry, _| {
+                entry.created = created.unwrap_or(entry.created);
+                entry.modified = modified.unwrap_or(entry.modified);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Moves the modification time of the entry at `path` to now, or makes
+    /// an empty file there if there's none. The directory above it has to
+    /// exist.
+    pub fn touch<S: AsRef<str>>(&mut self, path: impl Into<Vec<S>>) -> io::Result<()> {
+        let mut path = names(path.into());
+        if self.exists(&path) {
+            return self.with_entry_mut(path, |entry, fs| {
+                entry.modified = fs.now();
+                Ok(())
+            });
+        }
+        let name = path.pop().ok_or(Error::InvalidPath)?;
+        self.with_directory_mut(path, |dir, _| dir.add_file(name, String::new()).map(|_| ()))
+    }
+
+    /// Sets how the HTTP gateway treats missing paths at `path`. Only files
+    /// can be a `Fallback::Document`, and only directories can have it
+    /// `Fallback::Disabled`.
+    pub fn set_fallback<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        fallback: Fallback,
+    ) -> io::Result<()> {
+        let mut path = names(path.into());
+        let name = path.pop().ok_or(Error::InvalidPath)?;
+        self.with_directory_mut(path, |dir, _| {
+            let entry = dir.entry_with_name_mut(name).ok_or(Error::NotFound)?;
+            match (fallback, entry.kind) {
+                (Fallback::Document, EntryKind::Directory) => Err(Error::IsADirectory.into()),
+                (Fallback::Disabled, EntryKind::File) => Err(Error::NotADirectory.into()),
+                _ => {
+                    entry.fallback = fallback;
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    /// Turns listing the contents over HTTP on or off for the directory at
+    /// `path` and those below it.
+    pub fn set_listing<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        enabled: bool,
+    ) -> io::Result<()> {
+        let mut path = names(path.into());
+        let name = path.pop().ok_or(Error::InvalidPath)?;
+        self.with_directory_mut(path, |dir, _| {
+            let entry = dir.entry_with_name_mut(name).ok_or(Error::NotFound)?;
+            if entry.kind != EntryKind::Directory {
+                return Err(Error::NotADirectory.into());
+            }
+            entry.listing = enabled;
+            Ok(())
+        })
+    }
+
+    /// Sets where the HTTP gateway sends requests for `path`, or `None` to
+    /// serve it again. Paths which don't exist get an empty file to hold
+    /// the redirect, along with the directories above it, so that moved
+    /// content can leave its old path behind.
+    pub fn set_redirect<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        redirect: Option<Redirect>,
+    ) -> io::Result<()> {
+        let mut path = names(path.into());
+        if let Some(Redirect { status, location }) = &redirect {
+            if ![301, 302, 307, 308].contains(status) {
+                let message = format!("{} isn't a redirect status", status);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+            if location.is_empty() || location.bytes().any(|b| b.is_ascii_control()) {
+                let message = "locations can't be empty or hold control characters";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+        }
+        let name = path.pop().ok_or(Error::InvalidPath)?;
+        if redirect.is_some() && !path.is_empty() {
+            self.make_directory_recursive(path.clone())?;
+        }
+        self.with_directory_mut(path, |dir, _| {
+            if dir.entry_with_name(&name).is_none() && redirect.is_some() {
+                dir.add_file(name.clone(), String::new())?;
+            }
+            dir.entry_with_name_mut(name)
+                .ok_or(Error::NotFound)?
+                .redirect = redirect;
+            Ok(())
+        })
+    }
+
+    /// Sets who may access `path`, or `None` to leave it to the directories
+    /// above it. The empty path sets the ACL of the root directory, which
+    /// applies to everything.
+    pub fn set_acl<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        acl: Option<Acl>,
+    ) -> io::Result<()> {
+        let mut path = names(path.into());
+        let name = path.pop();
+        self.with_directory_mut(path, |dir, _| {
+            match name {
+                Some(name) => dir.entry_with_name_mut(name).ok_or(Error::NotFound)?.acl = acl,
+                None => dir.acl = acl,
+            }
+            Ok(())
+        })
+    }
+
+    /// The setting called `name`, if it's set. Settings are kept with the
+    /// root directory for whatever the filesystem is embedded in, and aren't
+    /// part of images.
+    pub fn setting(&self, name: &str) -> io::Result<Option<String>> {
+        let r = self.read_from_root_cluster().buffered();
+        let dir = self.reading(|| Directory::deserialize_into_default(r))?;
+        Ok(dir
+            .settings
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value))
+    }
+
+    /// Sets the setting called `name`, or clears it with `None`.
+    pub fn set_setting(&mut self, name: &str, value: Option<String>) -> io::Result<()> {
+        self.with_directory_mut(Vec::<String>::new(), |dir, _| {
+            dir.settings.retain(|(n, _)| n != name);
+            dir.settings
+                .extend(value.map(|value| (name.to_owned(), value)));
+            Ok(())
+        })
+    }
+
+    pub(crate) fn with_entry_mut<R, S: AsRef<str>>(
+        &mut self,
+        mut path: Vec<S>,
+        f: impl FnOnce(&mut Entry, &mut FileSystem<M>) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let mut entry = self.resolve(&path)?.ok_or(Error::InvalidPath)?;
+        let r = f(&mut entry, self);
+        self.invalidate_sizes(&names(&path));
+
+        if entry.inode == 0 {
+            let filename = path.pop().unwrap();
+            self.with_directory_mut(path, |dir, _| {
+                let slot = dir.entry_with_name_mut(filename).ok_or(Error::NotFound)?;
+                *slot = entry;
+                Ok(())
+            })?;
+        } else {
+            self.store_entry(&mut entry)?;
+            self.after_mutation()?;
+        }
+        r
+    }
+
+    pub fn with_root_directory_mut<R>(
+        &mut self,
+        f: impl FnOnce(&mut Directory, &mut Self) -> io::Result<R>,
+    ) -> io::Result<R> {
+        self.with_directory_mut(Vec::<String>::new(), f)
+    }
+
+    /// Like `with_root_directory_mut`, without telling the observer.
+    fn modify_root_directory<R>(
+        &mut self,
+        f: impl FnOnce(&mut Directory, &mut Self) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let mut dir = self.read_root_directory()?;
+        let r = f(&mut dir, self);
+        self.store_entries(&mut dir)?;
+        self.write_root_directory(&dir)?;
+        self.after_mutation()?;
+        r
+    }
+
+    pub fn with_directory_mut<R>(
+        &mut self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+        f: impl FnOnce(&mut Directory, &mut Self) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let path = names(path);
+        self.ensure_unmounted(&path)?;
+        let observed = self.observer.is_some();
+        let mut events = vec![];
+        let r = self.modify_root_directory(|root, fs| {
+            fs.with_directory_mut_rec(root, path.iter(), |dir, fs| {
+                let before = observed.then(|| observer::listing(&dir.entries));
+                let r = f(dir, fs)?;
+                if let Some(before) = before {
+                    events = observer::changes(&path, &before, &dir.entries);
+                }
+                Ok(r)
+            })
+        });
+        self.invalidate_sizes(&path);
+        let r = r?;
+        self.notify(events);
+        Ok(r)
+    }
+
+    fn with_directory_mut_rec<R>(
+        &mut self,
+        dir: &mut Directory,
+        mut path: impl Iterator<Item = impl AsRef<str>>,
+        f: impl FnOnce(&mut Directory, &mut Self) -> io::Result<R>,
+    ) -> io::Result<R> {
+        match path.next() {
+            Some(segment) => match dir.entry_with_name_mut(&segment) {
+                None => Err(Error::NotFound.into()),
+                Some(Entry {
+                    kind: EntryKind::File,
+                    ..
+                }) => Err(Error::NotADirectory.into()),
+                Some(
+                    entry @ Entry {
+                        kind: EntryKind::Directory,
+                        ..
+                    },
+                ) => {
+                    let mut subdir = self.read_directory(entry)?;
+                    let r = self.with_directory_mut_rec(&mut subdir, path, f)?;
+                    self.write_directory(entry, &mut subdir)?;
+                    Ok(r)
+                }
+            },
+            None => f(dir, self),
+        }
+    }
+
+    pub fn write_into_cluster<'a>(
+        &'a mut self,
+        cluster: &'a mut Cluster,
+    ) -> io::Result<ClusterWriter<'a, MemoryWriter<'a, TrackedMemory<M>>>> {
+        cluster.load(self.memory.reader())?;
+        self.mutations += 1;
+        Ok(cluster.writer(&mut self.bitmap, self.memory.writer()))
+    }
+
+    pub fn write_into_root_cluster(
+        &mut self,
+    ) -> ClusterWriter<'_, MemoryWriter<'_, TrackedMemory<M>>> {
+        self.mutations += 1;
+        self.root_cluster
+            .writer(&mut self.bitmap, self.memory.writer())
+    }
+
+    pub fn read_from_cluster<'a>(
+        &'a self,
+        cluster: &'a Cluster,
+    ) -> io::Result<ClusterReader<'a, MemoryReader<'a, TrackedMemory<M>>>> {
+        let cluster = if cluster.is_loaded() {
+            Cow::Borrowed(cluster)
+        } else {
+            let mut loaded = cluster.clone();
+            loaded.load(self.memory.reader())?;
+            Cow::Owned(loaded)
+        };
+        Ok(ClusterReader::new(cluster, self.memory.reader()))
+    }
+
+    pub fn read_from_root_cluster(&self) -> ClusterReader<'_, MemoryReader<'_, TrackedMemory<M>>> {
+        self.root_cluster.reader(self.memory.reader())
+    }
+
+    pub fn root_directory_reader(
+        &self,
+    ) -> io::Result<DirectoryReader<BufClusterReader<'_, MemoryReader<'_, TrackedMemory<M>>>>> {
+        self.directory_reader(self.read_from_root_cluster().buffered())
+    }
+
+    /// Streams the entries of the directory read from `r`, with the rules
+    /// of the filesystem for names and strings.
+    fn directory_reader<R: io::Read>(&self, r: R) -> io::Result<DirectoryReader<R>> {
+        Ok(self
+            .reading(|| DirectoryReader::new(r))?
+            .with_name_policy(self.names)
+            .with_max_string_len(self.max_string_len))
+    }
+
+    /// Runs `f`, which reads metadata, with the string limit of the
+    /// filesystem.
+    fn reading<R>(&self, f: impl FnOnce() -> R) -> R {
+        with_max_string_len(self.max_string_len, f)
+    }
+
+    pub fn read_root_directory(&self) -> io::Result<Directory> {
+        let r = self.read_from_root_cluster().buffered();
+        let mut dir = self.reading(|| Directory::deserialize_into_default(r))?;
+        self.load_entries(&mut dir)?;
+        dir.listing = Some(dir.listing_checksum());
+        dir.names = self.names;
+        Ok(dir)
+    }
+
+    /// Reads the directory stored in `entry`, with the metadata of its
+    /// entries filled in from their inodes.
+    pub fn read_directory(&self, entry: &Entry) -> io::Result<Directory> {
+        let mut r = entry.read_from_file_system(self)?;
+        let mut dir = self.reading(|| r.read_directory())?;
+        self.load_entries(&mut dir)?;
+        dir.listing = Some(dir.listing_checksum());
+        dir.names = self.names;
+        Ok(dir)
+    }
+
+    /// Writes `directory` into `entry`, after writing the metadata of its
+    /// entries to their inodes. The modification time and entry count of
+    /// `entry` are updated if entries were added, removed or renamed since
+    /// the directory was read.
+    pub fn write_directory(
+        &mut self,
+        entry: &mut Entry,
+        directory: &mut Directory,
+    ) -> io::Result<()> {
+        if entry.sealed && directory.listing != Some(directory.listing_checksum()) {
+            return Err(Error::Sealed.into());
+        }
+        self.store_entries(directory)?;
+
+        let listing = directory.listing_checksum();
+        if directory.listing != Some(listing) {
+            directory.listing = Some(listing);
+            entry.modified = (self.clock)();
+        }
+        if self.propagate_modified {
+            let newest = directory
+                .entries
+                .iter()
+                .filter(|e| e.kind == EntryKind::Directory)
+                .map(|e| e.modified)
+                .max();
+            entry.modified = entry.modified.max(newest.unwrap_or(0));
+        }
+        entry.entry_count = directory.entries.len() as u64;
+
+        entry
+            .write_to_file_system(self)?
+            .write_directory(directory)?;
+        Ok(())
+    }
+
+    fn load_entries(&self, directory: &mut Directory) -> io::Result<()> {
+        for entry in directory.entries.iter_mut() {
+            self.load_entry(entry)?;
+        }
+        Ok(())
+    }
+
+    fn load_entry(&self, entry: &mut Entry) -> io::Result<()> {
+        if entry.inode == 0 {
+            return Ok(());
+        }
+        let inode = self.stored_inode(entry.inode)?;
+        entry.size = inode.size;
+        entry.created = inode.created;
+        entry.modified = inode.modified;
+        entry.entry_count = inode.entry_count;
+        entry.sealed = inode.sealed;
+        entry.inline = match &inode.inline {
+            Some(fragment) => Some(self.read_tail(fragment)?),
+            None => None,
+        };
+        entry.tail = inode.tail;
+        entry.expires = inode.expires;
+        entry.cluster = inode.cluster;
+        Ok(())
+    }
+
+    fn stored_inode(&self, number: u64) -> io::Result<Inode> {
+        self.inodes
+            .get(&self.memory, number)?
+            .ok_or_else(|| Error::corrupted(format!("dangling inode {}", number)).into())
+    }
+
+    fn store_entries(&mut self, directory: &mut Directory) -> io::Result<()> {
+        for entry in directory.entries.iter_mut() {
+            self.store_entry(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the metadata of `entry` to its inode, allocating one if it
+    /// has none yet. The modification time moves forward whenever the size
+    /// or the contents change. Inline contents get a new fragment when they
+    /// change, and the old one is given back afterwards.
+    fn store_entry(&mut self, entry: &mut Entry) -> io::Result<()> {
+        let inlined = self.inline_small_file(entry)?;
+        self.pack_tail(entry)?;
+
+        let stored = match entry.inode {
+            0 => None,
+            number => Some(self.stored_inode(number)?),
+        };
+        let stored_inline = match stored.as_ref().and_then(|stored| stored.inline) {
+            Some(fragment) => Some((fragment, self.read_tail(&fragment)?)),
+            None => None,
+        };
+        let inline_changed = inlined.is_some()
+            || stored_inline.as_ref().map(|(_, data)| data) != entry.inline.as_ref();
+        let inline = match &entry.inline {
+            _ if !inline_changed => stored_inline.as_ref().map(|(fragment, _)| *fragment),
+            Some(_) if inlined.is_some() => inlined,
+            Some(data) => {
+                let fragment = self
+                    .allocate_fragment(data.len() as u16)?
+                    .ok_or(Error::OutOfSpace)?;
+                self.write_fragment(&fragment, data)?;
+                Some(fragment)
+            }
+            None => None,
+        };
+
+        let mut inode = Inode {
+            size: entry.size,
+            created: entry.created,
+            modified: entry.modified,
+            entry_count: entry.entry_count,
+            sealed: entry.sealed,
+            inline,
+            tail: entry.tail,
+            expires: entry.expires,
+            cluster: entry.cluster.handle(),
+        };
+
+        match &stored {
+            None => {
+                inode.created = (self.clock)();
+                inode.modified = inode.created;
+                entry.inode = self
+                    .inodes
+                    .allocate(&mut self.bitmap, &mut self.memory, &inode)?;
+            }
+            Some(stored) => {
+                if stored.size != inode.size
+                    || stored.cluster != inode.cluster
+                    || inline_changed
+                    || stored.tail != inode.tail
+                {
+                    inode.modified = (self.clock)();
+                }
+                self.inodes
+                    .set(&mut self.bitmap, &mut self.memory, entry.inode, &inode)?;
+            }
+        }
+        if let Some((fragment, _)) = stored_inline.filter(|_| inline_changed) {
+            self.free_tail(fragment)?;
+        }
+
+        entry.created = inode.created;
+        entry.modified = inode.modified;
+        Ok(())
+    }
+
+    /// Moves the contents of a file which fits the inline limit from its
+    /// single block into a fragment, and releases the block. Returns the
+    /// fragment. Files with more blocks, as after `preallocate`, are left
+    /// alone, and so are files there's no room for.
+    fn inline_small_file(&mut self, entry: &mut Entry) -> io::Result<Option<Tail>> {
+        if entry.kind != EntryKind::File
+            || entry.inline.is_some()
+            || entry.size > self.inline_limit as u64
+            || entry.cluster.block_count() != 1
+        {
+            return Ok(None);
+        }
+
+        let mut data = vec![0u8; to_usize(entry.size)?];
+        entry.read_from_file_system(self)?.read_exact(&mut data)?;
+        let fragment = match self.allocate_fragment(data.len() as u16)? {
+            Some(fragment) => fragment,
+            None => return Ok(None),
+        };
+        self.write_fragment(&fragment, &data)?;
+        self.truncate_cluster(&mut entry.cluster, 0)?;
+        entry.inline = Some(data);
+        Ok(Some(fragment))
+    }
+
+    /// Finds room for `len` bytes in one of the blocks shared by tails and
+    /// inline contents, adding a block if none has enough. Returns `None`
+    /// if that would take a block reserved for metadata. Empty fragments
+    /// aren't placed in any block.
+    fn allocate_fragment(&mut self, len: u16) -> io::Result<Option<Tail>> {
+        if len == 0 {
+            return Ok(Some(Tail::default()));
+        }
+        if let Some(fragment) = self.tails()?.allocate(len) {
+            return Ok(Some(fragment));
+        }
+        if self.bitmap.free_data_blocks() == 0 {
+            return Ok(None);
+        }
+        let block = self
+            .bitmap
+            .allocate_contiguous(1)
+            .map(Block::at)
+            .ok_or(Error::OutOfSpace)?;
+        let tails = self.tails()?;
+        tails.add_block(block);
+        Ok(tails.allocate(len))
+    }
+
+    /// The allocator of the blocks shared by tails and inline contents,
+    /// which reads all inodes to rebuild it the first time it's needed after
+    /// a restore.
+    fn tails(&mut self) -> io::Result<&mut TailAllocator> {
+        if self.tails.is_none() {
+            let mut tails = TailAllocator::default();
+            for inode in self.inodes.iter(&self.memory) {
+                let (_, inode) = inode?;
+                for fragment in inode.inline.iter().chain(inode.tail.iter()) {
+                    if fragment.len > 0 {
+                        tails.insert(*fragment);
+                    }
+                }
+            }
+            self.tails = Some(tails);
+        }
+        Ok(self.tails.get_or_insert_with(TailAllocator::default))
+    }
+
+    fn write_fragment(&mut self, fragment: &Tail, data: &[u8]) -> io::Result<()> {
+        let mut w = self.memory.writer();
+        w.seek(io::SeekFrom::Start(fragment.offset()))?;
+        w.write_all(data)
+    }
+
+    /// Moves the last, partial block of a file into a shared tail block
+    /// when tail packing is on. Like `inline_small_file`, files with blocks
+    /// beyond their size are left alone.
+    fn pack_tail(&mut self, entry: &mut Entry) -> io::Result<()> {
+        let len = entry.size % Block::SIZE as u64;
+        if !self.tail_packing
+            || entry.kind != EntryKind::File
+            || entry.inline.is_some()
+            || entry.tail.is_some()
+            || len == 0
+            || entry.cluster.block_count() as u64 != entry.size.div_ceil(Block::SIZE as u64)
+        {
+            return Ok(());
+        }
+
+        let split = entry.size - len;
+        let mut data = vec![0u8; len as usize];
+        let mut r = entry.read_from_file_system(self)?;
+        r.seek(io::SeekFrom::Start(split))?;
+        r.read_exact(&mut data)?;
+
+        let tail = match self.allocate_fragment(len as u16)? {
+            Some(tail) => tail,
+            None => return Ok(()),
+        };
+        self.write_fragment(&tail, &data)?;
+
+        self.truncate_cluster(&mut entry.cluster, split)?;
+        entry.tail = Some(tail);
+        Ok(())
+    }
+
+    pub(crate) fn read_tail(&self, tail: &Tail) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; tail.len as usize];
+        let mut r = self.memory.reader();
+        r.seek(io::SeekFrom::Start(tail.offset()))?;
+        r.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Gives back the fragment of `tail`, and its block once no other tail
+    /// is left in it.
+    pub(crate) fn free_tail(&mut self, tail: Tail) -> io::Result<()> {
+        if self.secure_delete {
+            self.memory.zero(tail.offset(), tail.len as u64)?;
+        }
+        if let Some(block) = self.tails()?.free(tail) {
+            self.release([block])?;
+        }
+        Ok(())
+    }
+
+    /// Releases all blocks holding the contents of `entry`.
+    fn release_contents(&mut self, entry: &mut Entry) -> io::Result<()> {
+        self.truncate_cluster(&mut entry.cluster, 0)?;
+        if let Some(tail) = entry.tail.take() {
+            self.free_tail(tail)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_root_directory(&mut self, directory: &Directory) -> io::Result<()> {
+        let mut w = self.write_into_root_cluster().buffered();
+        directory.serialize(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn make_directory_recursive<P, S>(&mut self, path: P) -> io::Result<()>
+    where
+        P: IntoIterator<Item = S>,
+        S: Into<String> + AsRef<str>,
+    {
+        let path: Vec<String> = path.into_iter().map(Into::into).collect();
+        if let Some((i, rest)) = self.mounted(&path) {
+            return self.mounts[i].1.make_directory_recursive(&rest);
+        }
+        let mut events = vec![];
+        if self.observer.is_some() {
+            for i in 1..=path.len() {
+                if !self.exists(&path[..i]) {
+                    events.push(Event::Create(path[..i].to_vec(), EntryKind::Directory));
+                }
+            }
+        }
+        self.modify_root_directory(|root, fs| root.make_directory_recursive(fs, path.iter()))?;
+        self.notify(events);
+        Ok(())
+    }
+
+    /// Replaces the contents of the file at `path` with everything read from
+    /// `reader`, creating the file if it doesn't exist. The contents go into
+    /// a new entry outside of any directory, which is only swapped in once
+    /// the write is complete, so the file is never left partially written.
+    /// Blocks of an interrupted write are left to `collect_garbage`.
+    pub fn write_atomic<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        mut reader: impl io::Read,
+    ) -> io::Result<u64> {
+        let path = path.into();
+        if let Some((i, rest)) = self.mounted(&names(&path)) {
+            return self.mounts[i].1.write_atomic(&rest, &mut reader);
+        }
+        let name = path.last().ok_or(Error::InvalidPath)?;
+
+        let mut temp = Entry::new(self.names.validate(name.as_ref())?);
+        if let Err(e) = io::copy(&mut reader, &mut temp.write_to_file_system(self)?) {
+            self.release_entry(temp)?;
+            return Err(e);
+        }
+        self.publish(path, temp)
+    }
+
+    /// Swaps `temp`, a file written outside of any directory, in for the
+    /// one at `path`, or adds it there under the name from `path`. A file
+    /// which is replaced keeps its metadata, and its content type unless
+    /// `temp` has one. `temp` is released if it can't be put in place.
+    /// Returns its size.
+    pub(crate) fn publish<S: AsRef<str>>(
+        &mut self,
+        mut path: Vec<S>,
+        mut temp: Entry,
+    ) -> io::Result<u64> {
+        let full_path = names(&path);
+        let name = self.ensure_unmounted(&full_path).and_then(|()| {
+            let name = path.pop().ok_or(Error::InvalidPath)?;
+            self.names.validate(name.as_ref())
+        });
+        let name = match name {
+            Ok(name) => name,
+            Err(e) => {
+                self.release_entry(temp)?;
+                return Err(e);
+            }
+        };
+        temp.name = name.clone();
+        let written = temp.size;
+
+        let mut temp = Some(temp);
+        let result = self.with_directory_mut(path, |dir, fs| {
+            match dir.entry_with_name_mut(&name) {
+                Some(Entry {
+                    kind: EntryKind::Directory,
+                    ..
+                }) => Err(Error::IsADirectory.into()),
+                Some(Entry { sealed: true, .. }) => Err(Error::Sealed.into()),
+                // The file keeps its inode, only the contents are replaced.
+                Some(old) => {
+                    let mut new = temp.take().unwrap();
+                    if new.content_type.is_empty() {
+                        new.content_type = old.content_type.clone();
+                    }
+                    new.headers = old.headers.clone();
+                    new.fallback = old.fallback;
+                    new.redirect = old.redirect.clone();
+                    new.acl = old.acl.clone();
+                    new.xattrs = old.xattrs.clone();
+                    new.inode = old.inode;
+                    new.created = old.created;
+                    let mut old = std::mem::replace(old, new);
+                    fs.release_contents(&mut old)
+                }
+                None => {
+                    dir.entries.push(temp.take().unwrap());
+                    Ok(())
+                }
+            }
+        });
+
+        if let Some(temp) = temp {
+            self.release_entry(temp)?;
+        }
+        result?;
+        self.notify(vec![Event::WriteComplete(full_path, written)]);
+        Ok(written)
+    }
+
+    /// Moves the entry at `from` to `to`, a directory with everything inside
+    /// it. Only the entry moves, the contents stay where they are. The
+    /// parent of `to` has to exist, and `to` itself must not. Sealed
+    /// entries, and directories holding them, stay where they are, as do
+    /// mounts and the entries inside them.
+    pub fn rename<S: AsRef<str>, T: AsRef<str>>(
+        &mut self,
+        from: impl Into<Vec<S>>,
+        to: impl Into<Vec<T>>,
+    ) -> io::Result<()> {
+        let mut from = names(from.into());
+        let mut to = names(to.into());
+        self.ensure_unmounted(&from)?;
+        self.ensure_unmounted(&to)?;
+        if self.is_below(&to, &from)
+            || self
+                .mounts
+                .iter()
+                .any(|(point, _)| self.is_below(point, &from))
+        {
+            return Err(Error::InvalidPath.into());
+        }
+        let name = from.pop().ok_or(Error::InvalidPath)?;
+        let new_name = self.names.validate(&to.pop().ok_or(Error::InvalidPath)?)?;
+        match self.metadata(&to)?.kind {
+            EntryKind::Directory => {}
+            EntryKind::File => return Err(Error::NotADirectory.into()),
+        }
+        to.push(new_name.clone());
+        if self.exists(&to) {
+            return Err(Error::AlreadyExists.into());
+        }
+        to.pop();
+
+        let entry = self.with_directory_mut(&from, |dir, fs| {
+            let entry = dir.entry_with_name(&name).ok_or(Error::NotFound)?;
+            fs.ensure_unsealed(entry)?;
+            Ok(dir.remove_entry(&name).unwrap())
+        })?;
+        // Should the entry not fit in its new directory, it goes back.
+        let backup = entry.clone();
+        let result = self.with_directory_mut(to, |dir, _| {
+            dir.entries.push(Entry {
+                name: new_name,
+                ..entry
+            });
+            Ok(())
+        });
+        if result.is_err() {
+            self.with_directory_mut(from, |dir, _| {
+                dir.entries.push(backup);
+                Ok(())
+            })?;
+        }
+        result
+    }
+
+    /// Copies the contents of the file at `from` to `to`, which is written
+    /// like with `write_atomic`. A new file gets the content type of the
+    /// original, and one which is replaced takes it on.
+    pub fn copy_file<S: AsRef<str>, T: AsRef<str>>(
+        &mut self,
+        from: impl Into<Vec<S>>,
+        to: impl Into<Vec<T>>,
+    ) -> io::Result<u64> {
+        let source = match self.resolve(from.into())? {
+            Some(entry) if entry.kind == EntryKind::File => entry,
+            Some(_) => return Err(Error::IsADirectory.into()),
+            None => return Err(Error::InvalidPath.into()),
+        };
+        let to = names(to.into());
+        let name = to.last().ok_or(Error::InvalidPath)?;
+        let mut temp = Entry {
+            content_type: source.content_type.clone(),
+            ..Entry::new(self.names.validate(name)?)
+        };
+        let mut buf = vec![0u8; BUF_CAPACITY];
+        let mut offset = 0;
+        while offset < source.size {
+            let copied = source.read_at(self, offset, &mut buf).and_then(|n| {
+                temp.write_at(self, offset, &buf[..n])?;
+                Ok(n)
+            });
+            match copied {
+                Ok(0) => break,
+                Ok(n) => offset += n as u64,
+                Err(e) => {
+                    self.release_entry(temp)?;
+                    return Err(e);
+                }
+            }
+        }
+        self.publish(to, temp)
+    }
+
+    /// Removes the entry at `path`. The blocks of a file are released, as are
+    /// those of everything inside a directory.
+    pub fn remove<S: AsRef<str>>(&mut self, path: impl Into<Vec<S>>) -> io::Result<()> {
+        let mut path = path.into();
+        let full_path = names(&path);
+        let name = path.pop().ok_or(Error::InvalidPath)?;
+        match self.mounted(&full_path) {
+            Some((i, rest)) if !rest.is_empty() => return self.mounts[i].1.remove(&rest),
+            _ => {}
+        }
+        if self
+            .mounts
+            .iter()
+            .any(|(point, _)| self.is_below(point, &full_path))
+        {
+            return Err(Error::MountPoint.into());
+        }
+
+        self.with_directory_mut(path, |dir, fs| {
+            let entry = dir.entry_with_name(&name).ok_or(Error::NotFound)?;
+            fs.ensure_unsealed(entry)?;
+            let entry = dir.remove_entry(name).unwrap();
+            fs.release_entry(entry)
+        })
+    }
+
+    /// Removes the entry at `path` like `remove`, but at most `budget`
+    /// entries per call, deepest first, so the tree stays whole in between.
+    /// Meant for trees too large to remove in one message, by calling this
+    /// until it reports `done`. Entries added below `path` meanwhile go as
+    /// well. A sealed entry stops the removal with what's left of the tree.
+    pub fn remove_tree<S: AsRef<str>>(
+        &mut self,
+        path: impl Into<Vec<S>>,
+        budget: usize,
+    ) -> io::Result<PurgeProgress> {
+        let path = names(path.into());
+        let mut progress = PurgeProgress::default();
+        if path.is_empty() {
+            return Err(Error::InvalidPath.into());
+        }
+        if self
+            .mounts
+            .iter()
+            .any(|(point, _)| point.len() > path.len() && self.is_below(point, &path))
+        {
+            return Err(Error::MountPoint.into());
+        }
+        if self.mounted(&path).is_some() || self.metadata(&path)?.kind == EntryKind::File {
+            self.remove(path)?;
+            progress.removed += 1;
+            progress.done = true;
+            return Ok(progress);
+        }
+
+        while progress.removed < budget {
+            // Down the first directory with entries at each level, to one
+            // holding only files and empty directories.
+            let mut dir_path = path.clone();
+            let leaves = 'down: loop {
+                let mut leaves = vec![];
+                for entry in self.directory_at(&dir_path)?.entries {
+                    if entry.kind == EntryKind::Directory
+                        && !self.read_directory(&entry)?.entries.is_empty()
+                    {
+                        dir_path.push(entry.name);
+                        continue 'down;
+                    }
+                    leaves.push(entry.name);
+                }
+                break leaves;
+            };
+            if leaves.is_empty() {
+                self.remove(path)?;
+                progress.removed += 1;
+                progress.done = true;
+                break;
+            }
+            let leaves = leaves.into_iter().take(budget - progress.removed);
+            progress.removed += self.with_directory_mut(dir_path, |dir, fs| {
+                let mut removed = 0;
+                for name in leaves {
+                    fs.ensure_unsealed(dir.entry_with_name(&name).ok_or(Error::NotFound)?)?;
+                    fs.release_entry(dir.remove_entry(name).unwrap())?;
+                    removed += 1;
+                }
+                Ok(removed)
+            })?;
+        }
+        Ok(progress)
+    }
+
+    /// Removes entries which expired at `now`, directories with everything
+    /// inside, at most `budget` per call. Meant to be called from a timer
+    /// until it reports `done`. Expired entries which are sealed, or hold
+    /// sealed entries, are kept. The inode table is checked first, so
+    /// directories are only walked if something has expired.
+    pub fn purge_expired(&mut self, now: u64, budget: usize) -> io::Result<PurgeProgress> {
+        let is_expired = |expires: Option<u64>| expires.is_some_and(|expires| expires <= now);
+        let mut progress = PurgeProgress::default();
+        let mut any_expired = false;
+        for inode in self.inodes.iter(&self.memory) {
+            if is_expired(inode?.1.expires) {
+                any_expired = true;
+                break;
+            }
+        }
+        if !any_expired {
+            progress.done = true;
+            return Ok(progress);
+        }
+
+        let mut expired = vec![];
+        let mut pending = vec![(vec![], self.read_root_directory()?)];
+        while let Some((path, dir)) = pending.pop() {
+            for entry in dir.entries {
+                let mut entry_path: Vec<String> = path.clone();
+                entry_path.push(entry.name.clone());
+                if is_expired(entry.expires) {
+                    expired.push(entry_path);
+                } else if entry.kind == EntryKind::Directory {
+                    pending.push((entry_path, self.read_directory(&entry)?));
+                }
+            }
+        }
+
+        for path in expired.iter().take(budget) {
+            match self.remove(path.clone()) {
+                Ok(()) => progress.removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {}
+                Err(e) => return Err(e),
+            }
+        }
+        progress.done = expired.len() <= budget;
+        Ok(progress)
+    }
+
+    /// Fails if `entry` or anything inside it is sealed.
+    fn ensure_unsealed(&self, entry: &Entry) -> io::Result<()> {
+        if entry.sealed {
+            return Err(Error::Sealed.into());
+        }
+        if entry.kind == EntryKind::Directory {
+            for child in self.read_directory(entry)?.entries.iter() {
+                self.ensure_unsealed(child)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `offset` of `staged`, a file outside of any
+    /// directory, like `Entry::write_at`.
+    #[cfg(all(feature = "canister", any(target_arch = "wasm32", test)))]
+    pub(crate) fn write_staged(
+        &mut self,
+        staged: &mut Entry,
+        offset: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let r = staged.write_at(self, offset, data);
+        self.after_mutation()?;
+        r
+    }
+
+    pub(crate) fn release_entry(&mut self, mut entry: Entry) -> io::Result<()> {
+        if entry.kind == EntryKind::Directory {
+            for child in self.read_directory(&entry)?.entries {
+                self.release_entry(child)?;
+            }
+        }
+        self.release_contents(&mut entry)?;
+        let inode = self
+            .inodes
+            .free(&mut self.bitmap, &mut self.memory, entry.inode)?;
+        if let Some(fragment) = inode.and_then(|inode| inode.inline) {
+            self.free_tail(fragment)?;
+        }
+        Ok(())
+    }
+
+    /// Shrinks `cluster` to the blocks needed for `len` bytes and releases
+    /// the rest.
+    pub fn truncate_cluster(&mut self, cluster: &mut Cluster, len: u64) -> io::Result<()> {
+        cluster.load(self.memory.reader())?;
+
+        let keep = to_usize(len.div_ceil(Block::SIZE as u64))?;
+        let tail = len % Block::SIZE as u64;
+        if self.secure_delete && tail > 0 {
+            if let Some(last) = cluster.get(keep - 1) {
+                let len = Block::SIZE as u64 - tail;
+                self.memory.zero(last.offset() + tail, len)?;
+            }
+        }
+
+        let released = cluster.truncate(keep);
+        self.release(released)
+    }
+
+    fn release(&mut self, blocks: impl IntoIterator<Item = Block>) -> io::Result<()> {
+        for block in blocks {
+            if self.secure_delete {
+                self.memory.zero(block.offset(), Block::SIZE as u64)?;
+            }
+            self.bitmap.free(block.index);
+        }
+        Ok(())
+    }
+
+    /// Finds entries whose name matches that of an earlier entry in the same
+    /// directory, which makes them unreachable by path. Such entries can't be
+    /// added through `Directory`, but appear when the name policy changes,
+    /// e.g. to case-insensitive lookups.
+    pub fn duplicate_names(&self) -> io::Result<Vec<Vec<String>>> {
+        let mut duplicates = vec![];
+        let mut pending = vec![(vec![], self.read_root_directory()?)];
+
+        while let Some((path, dir)) = pending.pop() {
+            let mut seen = HashSet::new();
+            for entry in dir.entries.iter() {
+                let mut entry_path: Vec<String> = path.clone();
+                entry_path.push(entry.name.clone());
+                if !seen.insert(self.names.key(&entry.name)) {
+                    duplicates.push(entry_path.clone());
+                }
+                if entry.kind == EntryKind::Directory {
+                    pending.push((entry_path, self.read_directory(entry)?));
+                }
+            }
+        }
+
+        Ok(duplicates)
+    }
+
+    /// Copies the whole tree into a new filesystem on `memory`, which may be
+    /// any kind of memory, e.g. to move from heap to stable memory or to
+    /// take a backup. Contents, content types, timestamps, seals and expiry
+    /// times are kept, as are the settings of this filesystem. Observers
+    /// and mounts are not carried over.
+    pub fn clone_into<M2: Memory>(&self, memory: M2) -> io::Result<FileSystem<M2>> {
+        let mut target = FileSystem::new(memory)?
+            .with_clock(self.clock)
+            .with_name_policy(self.names)
+            .with_inline_limit(self.inline_limit)
+            .with_tail_packing(self.tail_packing)
+            .with_secure_delete(self.secure_delete)
+            .with_modified_propagation(self.propagate_modified)
+            .with_allocation_policy(self.bitmap.policy());
+        let root = self.read_root_directory()?;
+        self.clone_directory(&mut target, &mut vec![], &root)?;
+        target.persist()?;
+        Ok(target)
+    }
+
+    /// Copies the entries of `dir` into the directory at `path` of
+    /// `target`. Metadata is copied once an entry is complete, as writing
+    /// its contents or entries moves the modification time.
+    fn clone_directory<M2: Memory>(
+        &self,
+        target: &mut FileSystem<M2>,
+        path: &mut Vec<String>,
+        dir: &Directory,
+    ) -> io::Result<()> {
+        target.with_directory_mut(path.iter(), |copy, target| {
+            for entry in dir.entries.iter() {
+                match entry.kind {
+                    EntryKind::File => {
+                        let f = copy.add_file(&entry.name, entry.content_type.clone())?;
+                        f.headers = entry.headers.clone();
+                        f.fallback = entry.fallback;
+                        f.redirect = entry.redirect.clone();
+                        f.acl = entry.acl.clone();
+                        f.xattrs = entry.xattrs.clone();
+                    }
+                    EntryKind::Directory => {
+                        let d = copy.add_directory(&entry.name)?;
+                        d.headers = entry.headers.clone();
+                        d.fallback = entry.fallback;
+                        d.listing = entry.listing;
+                        d.redirect = entry.redirect.clone();
+                        d.acl = entry.acl.clone();
+                        d.xattrs = entry.xattrs.clone();
+                        target.write_directory(d, &mut Directory::default())?;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+        for entry in dir.entries.iter() {
+            path.push(entry.name.clone());
+            match entry.kind {
+                EntryKind::File => target.with_entry_mut(path.clone(), |copy, target| {
+                    let mut r = entry.read_from_file_system(self)?;
+                    io::copy(&mut r, &mut copy.write_to_file_system(target)?)
+                })?,
+                EntryKind::Directory => {
+                    self.clone_directory(target, path, &self.read_directory(entry)?)?;
+                    0
+                }
+            };
+            target.copy_metadata(path.clone(), entry)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    /// Sets the timestamps, seal and expiry time of the entry at `path` to
+    /// those of `from`.
+    pub(crate) fn copy_metadata(&mut self, path: Vec<String>, from: &Entry) -> io::Result<()> {
+        self.with_entry_mut(path, |entry, _| {
+            entry.created = from.created;
+            entry.modified = from.modified;
+            entry.expires = from.expires;
+            entry.sealed = from.sealed;
+            Ok(())
+        })
+    }
+
+    /// Collects every entry reachable from the root directory, depth first.
+    pub fn entries_recursive(&self) -> io::Result<Vec<Entry>> {
+        let mut entries = vec![];
+        let mut pending = self.read_root_directory()?.entries;
+        pending.reverse();
+
+        while let Some(entry) = pending.pop() {
+            if entry.kind == EntryKind::Directory {
+                let mut children = self.read_directory(&entry)?.entries;
+                children.reverse();
+                pending.extend(children);
+            }
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+
+    /// Frees all blocks which are occupied in the bitmap but not referenced
+    /// by the preamble, the inode table or any cluster reachable from the
+    /// root directory, as
+    /// left behind by bugs or interrupted operations. With `dry_run` the
+    /// leaked blocks are only reported.
+    pub fn collect_garbage(&mut self, dry_run: bool) -> io::Result<GarbageReport> {
+        let mut reachable = Bitmap::new(&self.memory);
+        for entry in self.entries_recursive()? {
+            self.mark_reachable(&mut reachable, entry.cluster)?;
+        }
+        self.release_unreachable(reachable, dry_run)
+    }
+
+    /// Like `collect_garbage`, but visits at most `budget` entries per call,
+    /// so a pass over a large tree can be spread across messages by calling
+    /// this until it reports `done`. A pass starts over if the filesystem
+    /// changed since the previous call, as blocks of entries it hasn't
+    /// visited yet may have been taken by ones it has.
+    pub fn collect_garbage_slice(&mut self, budget: usize) -> io::Result<GarbageProgress> {
+        let mut pass = match self.garbage_pass.take() {
+            Some(pass) if pass.mutations == self.mutations => pass,
+            _ => GarbagePass {
+                mutations: self.mutations,
+                reachable: Bitmap::new(&self.memory),
+                pending: self.read_root_directory()?.entries,
+            },
+        };
+        let mut progress = GarbageProgress::default();
+        while progress.visited < budget {
+            let entry = match pass.pending.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if entry.kind == EntryKind::Directory {
+                pass.pending.extend(self.read_directory(&entry)?.entries);
+            }
+            self.mark_reachable(&mut pass.reachable, entry.cluster)?;
+            progress.visited += 1;
+        }
+        if !pass.pending.is_empty() {
+            self.garbage_pass = Some(pass);
+            return Ok(progress);
+        }
+        progress.report = self.release_unreachable(pass.reachable, false)?;
+        progress.done = true;
+        Ok(progress)
+    }
+
+    fn mark_reachable(&self, reachable: &mut Bitmap, mut cluster: Cluster) -> io::Result<()> {
+        cluster.load(self.memory.reader())?;
+        for block in cluster.index_blocks().chain(cluster.blocks()) {
+            reachable.occupy(block.index);
+        }
+        Ok(())
+    }
+
+    /// Frees the blocks occupied in the bitmap which are neither in
+    /// `reachable` nor taken by the preamble, the tails or the clusters of
+    /// the root directory and inode table. With `dry_run` they're only
+    /// reported.
+    fn release_unreachable(
+        &mut self,
+        mut reachable: Bitmap,
+        dry_run: bool,
+    ) -> io::Result<GarbageReport> {
+        reachable.occupy_range(0..self.preamble_blocks());
+        for block in self.tails()?.blocks() {
+            reachable.occupy(block.index);
+        }
+        let [a, b] = self.inodes.areas().clone();
+        for cluster in [self.root_cluster.clone(), a, b] {
+            self.mark_reachable(&mut reachable, cluster)?;
+        }
+
+        let leaked = self
+            .bitmap
+            .iter()
+            .zip(reachable.iter())
+            .enumerate()
+            .filter(|(_, states)| *states == (BitState::Occupied, BitState::Free))
+            .map(|(i, _)| Block::at(i))
+            .collect::<Vec<_>>();
+
+        let report = GarbageReport {
+            leaked_blocks: leaked.len(),
+            leaked_bytes: leaked.len() as u64 * Block::SIZE as u64,
+        };
+        if !dry_run {
+            self.release(leaked)?;
+            self.after_mutation()?;
+        }
+        Ok(report)
+    }
+
+    /// Compacts data blocks towards the start of memory, laying out each
+    /// cluster contiguously in tree order. Blocks in the way are evicted to
+    /// free space first; index blocks are never moved. A pass first looks
+    /// up the block lists of all entries, then relocates their blocks, and
+    /// at most `budget_blocks` entries and blocks are visited per call, so
+    /// it can be spread across multiple messages by calling this until it
+    /// reports `done`. A pass starts over if the filesystem changed since
+    /// the previous call.
+    pub fn defragment(&mut self, budget_blocks: usize) -> io::Result<DefragmentProgress> {
+        let mut pass = match self.defragment_pass.take() {
+            Some(pass) if pass.mutations == self.mutations => pass,
+            _ => {
+                let mut pending = self.read_root_directory()?.entries;
+                pending.reverse();
+                let owners = self
+                    .root_cluster
+                    .blocks()
+                    .enumerate()
+                    .map(|(i, block)| (block.index, (0, i)))
+                    .collect();
+                DefragmentPass {
+                    mutations: self.mutations,
+                    pending,
+                    clusters: vec![Cluster::default()],
+                    owners,
+                    cursor: DefragmentCursor::default(),
+                }
+            }
+        };
+
+        let mut progress = DefragmentProgress::default();
+        while progress.visited < budget_blocks {
+            let entry = match pass.pending.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if entry.kind == EntryKind::Directory {
+                let mut children = self.read_directory(&entry)?.entries;
+                children.reverse();
+                pass.pending.extend(children);
+            }
+            let mut cluster = entry.cluster;
+            cluster.load(self.memory.reader())?;
+            for (i, block) in cluster.blocks().enumerate() {
+                pass.owners.insert(block.index, (pass.clusters.len(), i));
+            }
+            pass.clusters.push(cluster);
+            progress.visited += 1;
+        }
+
+        let mut result = Ok(());
+        if pass.pending.is_empty() {
+            std::mem::swap(&mut pass.clusters[0], &mut self.root_cluster);
+            result = self.defragment_clusters(&mut pass, budget_blocks, &mut progress);
+            std::mem::swap(&mut pass.clusters[0], &mut self.root_cluster);
+        }
+        if progress.moved > 0 {
+            self.after_mutation()?;
+        }
+        result?;
+        if !progress.done {
+            // The pass's own moves don't make it stale.
+            pass.mutations = self.mutations;
+            self.defragment_pass = Some(pass);
+        }
+        Ok(progress)
+    }
+
+    fn defragment_clusters(
+        &mut self,
+        pass: &mut DefragmentPass,
+        budget_blocks: usize,
+        progress: &mut DefragmentProgress,
+    ) -> io::Result<()> {
+        let DefragmentPass {
+            clusters,
+            owners,
+            cursor,
+            ..
+        } = pass;
+
+        while cursor.cluster < clusters.len() {
+            let c = cursor.cluster;
+            while cursor.block < clusters[c].block_count() {
+                if progress.visited == budget_blocks {
+                    return Ok(());
+                }
+                progress.visited += 1;
+                let i = cursor.block;
+                cursor.block += 1;
+
+                // Blocks which are not data blocks of a known cluster (the
+                // preamble and index blocks) stay where they are.
+                while cursor.position < self.bitmap.len() * 8
+                    && self.bitmap[cursor.position] == BitState::Occupied
+                    && !owners.contains_key(&cursor.position)
+                {
+                    cursor.position += 1;
+                }
+
+                let block = clusters[c].get(i).unwrap();
+                let target = Block::at(cursor.position);
+                if block <= target {
+                    cursor.position = cursor.position.max(block.index + 1);
+                    continue;
+                }
+
+                if let Some(&(oc, oi)) = owners.get(&target.index) {
+                    // The block in the way is allocated a new place like any
+                    // data block, outside of the reserve for metadata.
+                    let free = match self.bitmap.free_data_blocks() {
+                        0 => None,
+                        _ => self.bitmap.allocate_contiguous(1).map(Block::at),
+                    };
+                    match free {
+                        Some(free) => {
+                            self.relocate_block(&mut clusters[oc], oi, free)?;
+                            owners.remove(&target.index);
+                            owners.insert(free.index, (oc, oi));
+                            progress.moved += 1;
+                        }
+                        None => {
+                            cursor.position += 1;
+                            continue;
+                        }
+                    }
+                }
+
+                self.relocate_block(&mut clusters[c], i, target)?;
+                owners.remove(&block.index);
+                owners.insert(target.index, (c, i));
+                progress.moved += 1;
+                cursor.position += 1;
+            }
+            cursor.cluster += 1;
+            cursor.block = 0;
+        }
+
+        progress.done = true;
+        Ok(())
+    }
+
+    fn relocate_block(&mut self, cluster: &mut Cluster, i: usize, to: Block) -> io::Result<()> {
+        let from = cluster
+            .get(i)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        let mut data = [0u8; Block::SIZE];
+        let mut r = self.memory.reader();
+        r.seek(io::SeekFrom::Start(from.offset()))?;
+        r.read_exact(&mut data)?;
+
+        let mut w = self.memory.writer();
+        w.seek(io::SeekFrom::Start(to.offset()))?;
+        w.write_all(&data)?;
+
+        self.bitmap.occupy(to.index);
+        cluster.set_block(i, to, self.memory.writer())?;
+        self.release(Some(from))
+    }
+}
+
+impl<M: Memory> Serialize for FileSystem<M> {
+    fn serialize(&self, mut w: impl io::Write) -> io::Result<usize> {
+        let mut bytes_written = self.bitmap.serialize(&mut w)?;
+        bytes_written += self.root_cluster.serialize(w)?;
+        Ok(bytes_written)
+    }
+}
+
+impl<M: Memory> Deserialize for FileSystem<M> {
+    fn deserialize(&mut self, mut r: impl io::Read) -> io::Result<usize> {
+        let mut bytes_read = self.bitmap.deserialize(&mut r)?;
+        bytes_read += self.root_cluster.deserialize(r)?;
+        Ok(bytes_read)
+    }
+}
+
+/// Fails unless the header names are tokens, and the values hold no
+/// control characters besides tabs.
+fn check_headers(headers: &[(String, String)]) -> io::Result<()> {
+    let is_token = |s: &str| {
+        !s.is_empty()
+            && s.bytes()
+                .all(|b| b.is_ascii_graphic() && !b"\"(),/:;<=>?@[\\]{}".contains(&b))
+    };
+    if headers.iter().any(|(name, value)| {
+        !is_token(name) || value.bytes().any(|b| b.is_ascii_control() && b != b'\t')
+    }) {
+        let message = "header names must be tokens, and values can't hold control characters";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    Ok(())
+}
+
+/// Owned names of the components of `path`.
+pub(crate) fn names(path: impl IntoIterator<Item = impl AsRef<str>>) -> Vec<String> {
+    path.into_iter().map(|s| s.as_ref().to_owned()).collect()
+}
+
+impl<M: Memory> Drop for FileSystem<M> {
+    fn drop(&mut self) {
+        match self.drop_policy {
+            DropPolicy::BestEffort => {
+                let _ = self.persist();
+            }
+            DropPolicy::Ignore => {}
+            DropPolicy::DebugAssert => {
+                let result = self.persist();
+                debug_assert!(result.is_ok(), "failed to write filesystem preamble");
+            }
+        }
+    }
+}
+
+/// Draws the tree, one entry per line. The alternate form `{:#}` adds the
+/// size of each file, and a precision limits the depth, e.g. `{:.0}` only
+/// shows the entries of the root directory.
+impl<M: Memory> fmt::Display for FileSystem<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/")?;
+
+        let tree = self
+            .tree(Vec::<String>::new(), f.precision())
+            .or(Err(fmt::Error))?;
+        for entry in tree {
+            let (depth, name, kind, size) = entry.or(Err(fmt::Error))?;
+            write!(f, "\n{:>width$}{}", "| ", name, width = depth * 4 + 2)?;
+            if kind == EntryKind::Directory {
+                write!(f, "/")?;
+            } else if f.alternate() {
+                write!(f, " ({})", size)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test() {
+    use crate::cluster::POINTERS_PER_INDEX_BLOCK;
+    use crate::heap_memory::HeapMemory;
+    use std::io::{Read, Write};
+
+    const DATA_BLOCKS: usize = 128;
+
+    let data: Vec<u8> = (0..Block::SIZE * DATA_BLOCKS)
+        .map(|_| rand::random())
+        .collect();
+
+    let mut memory = HeapMemory::default();
+    let root_cluster;
+
+    {
+        let mut fs = FileSystem::new(&mut memory).unwrap();
+
+        fs.bitmap.occupy(42);
+        fs.bitmap.occupy(39);
+        fs.bitmap.occupy(58);
+
+        {
+            let mut writer = fs.write_into_root_cluster();
+            writer.write_all(&data).unwrap();
+        }
+
+        {
+            let mut reader = fs.read_from_root_cluster();
+            let mut read_data = vec![];
+            reader.read_to_end(&mut read_data).unwrap();
+            assert_eq!(read_data, data);
+        }
+
+        // Each area of the inode table takes a block and its index.
+        assert_eq!(
+            fs.bitmap.occupied_blocks(),
+            fs.preamble_blocks() + DATA_BLOCKS + DATA_BLOCKS / POINTERS_PER_INDEX_BLOCK + 1 + 3 + 4
+        );
+        root_cluster = fs.root_cluster.clone();
+        fs.close().unwrap();
+    }
+
+    {
+        // The block list spans two index blocks, which are followed from the
+        // handle in the preamble.
+        let fs = FileSystem::open(memory).unwrap();
+        assert_eq!(fs.root_cluster.index_blocks().count(), 2);
+        assert_eq!(fs.root_cluster, root_cluster);
+        let mut reader = fs.read_from_root_cluster();
+
+        let mut read_data = vec![];
+        reader.read_to_end(&mut read_data).unwrap();
+        assert_eq!(read_data, data);
+    }
+}
+
+#[test]
+fn a_file() {
+    use crate::directory::EntryKind;
+    use crate::heap_memory::HeapMemory;
+    use std::io::{Read, Write};
+
+    let mut mem = HeapMemory::default();
+
+    {
+        let mut fs = FileSystem::new(&mut mem).unwrap();
+
+        fs.with_root_directory_mut(|root, fs| {
+            root.add_file("my-file.txt", "text/plain")?
+                .write_to_file_system(fs)?
+                .write_all(b"Hello World")
+        })
+        .unwrap();
+    }
+
+    {
+        let fs = FileSystem::open(&mut mem).unwrap();
+
+        fs.with_root_directory(|root| {
+            let entry = &root.entries[0];
+            assert_eq!(entry.kind, EntryKind::File);
+
+            let mut r = entry.read_from_file_system(&fs)?;
+            let mut result = [0u8; 5];
+            r.read_exact(&mut result)?;
+
+            assert_eq!(&result, b"Hello");
+            Ok(())
+        })
+        .unwrap();
+    }
+}
+
+#[test]
+fn a_nested_dir() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::{Read, Write};
+
+    let mut mem = HeapMemory::default();
+
+    {
+        let mut fs = FileSystem::new(&mut mem).unwrap();
+
+        fs.with_root_directory_mut(|root, fs| {
+            let mut dir = Directory::default();
+            dir.add_file("my_file.txt", "text/plain")?
+                .write_to_file_system(fs)?
+                .write_all(b"Hello, World!")?;
+
+            fs.write_directory(root.add_directory("my_dir")?, &mut dir)
+        })
+        .unwrap();
+    }
+
+    {
+        let fs = FileSystem::open(&mut mem).unwrap();
+
+        fs.with_root_directory(|root| {
+            let dir_entry = &root.entries[0];
+            assert_eq!(&dir_entry.name, "my_dir");
+
+            let file_entry = &fs.read_directory(dir_entry)?.entries[0];
+            assert_eq!(&file_entry.name, "my_file.txt");
+
+            let mut result = String::new();
+            file_entry
+                .read_from_file_system(&fs)?
+                .read_to_string(&mut result)?;
+            assert_eq!(&result, "Hello, World!");
+            Ok(())
+        })
+        .unwrap();
+    }
+}
+
+#[test]
+fn close() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut mem = HeapMemory::default();
+
+    let mut fs = FileSystem::new(&mut mem).unwrap();
+    let kept = fs.bitmap.occupy_next().unwrap();
+    fs.close().unwrap();
+
+    let mut fs = FileSystem::open(&mut mem)
+        .unwrap()
+        .with_drop_policy(DropPolicy::Ignore);
+    assert_eq!(fs.bitmap[kept], BitState::Occupied);
+    let lost = fs.bitmap.occupy_next().unwrap();
+    drop(fs);
+
+    let fs = FileSystem::open(&mut mem).unwrap();
+    assert_eq!(fs.bitmap[kept], BitState::Occupied);
+    assert_eq!(fs.bitmap[lost], BitState::Free);
+}
+
+#[test]
+fn defragment() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::{Read, Write};
+
+    const BLOCKS: usize = 8;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+
+    // Leave a gap in front of the files, as if a file had been deleted there.
+    let gap = (0..3)
+        .map(|_| fs.bitmap.occupy_next().unwrap())
+        .collect::<Vec<_>>();
+
+    fs.with_root_directory_mut(|root, fs| {
+        root.add_file("a", "")?;
+        root.add_file("b", "")?;
+        for i in 0..BLOCKS {
+            for (n, name) in ["a", "b"].iter().enumerate() {
+                let mut w = root
+                    .entry_with_name_mut(name)
+                    .unwrap()
+                    .write_to_file_system(fs)?;
+                w.seek(io::SeekFrom::Start((i * Block::SIZE) as _))?;
+                w.write_all(&[n as u8 + 1; Block::SIZE])?;
+            }
+        }
+        Ok(())
+    })
+    .unwrap();
+
+    for i in gap {
+        fs.bitmap.free(i);
+    }
+
+    let mut progress = fs.defragment(3).unwrap();
+    assert_eq!(progress.visited, 3);
+    assert!(!progress.done);
+    let cursor = fs.defragment_pass.as_ref().unwrap().cursor;
+    // Blocks may move under the pass once anything is written, so it
+    // starts over.
+    fs.write_into_root_cluster();
+    progress = fs.defragment(3).unwrap();
+    assert_eq!(fs.defragment_pass.as_ref().unwrap().cursor, cursor);
+    while !progress.done {
+        progress = fs.defragment(3).unwrap();
+    }
+
+    assert_eq!(fs.bitmap.first_free(), Some(fs.bitmap.occupied_blocks()));
+
+    fs.with_root_directory(|root| {
+        let mut index_blocks = fs
+            .root_cluster
+            .index_blocks()
+            .map(|b| b.index)
+            .collect::<Vec<_>>();
+        for entry in root.entries.iter() {
+            let mut cluster = entry.cluster.clone();
+            cluster.load(fs.memory.reader())?;
+            index_blocks.extend(cluster.index_blocks().map(|b| b.index));
+        }
+
+        for (n, entry) in root.entries.iter().enumerate() {
+            let mut data = vec![];
+            entry.read_from_file_system(&fs)?.read_to_end(&mut data)?;
+            assert_eq!(data, vec![n as u8 + 1; Block::SIZE * BLOCKS]);
+
+            let mut cluster = entry.cluster.clone();
+            cluster.load(fs.memory.reader())?;
+            let blocks = cluster.blocks().collect::<Vec<_>>();
+            assert!(blocks
+                .windows(2)
+                .all(|w| { (w[0].index + 1..w[1].index).all(|b| index_blocks.contains(&b)) }));
+        }
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn defragment_reserve() {
+    use crate::heap_memory::HeapMemory;
+
+    // Two files with interleaved blocks, so laying out the first one takes
+    // blocks of the second out of the way.
+    let moved = |reserve_free_blocks: bool| {
+        let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+        fs.write_atomic(["a"], &[][..]).unwrap();
+        fs.write_atomic(["b"], &[][..]).unwrap();
+        for i in 0..4 {
+            let offset = (i * Block::SIZE) as u64;
+            fs.write_at(["a"], offset, &[1u8; Block::SIZE]).unwrap();
+            fs.write_at(["b"], offset, &[2u8; Block::SIZE]).unwrap();
+        }
+        if reserve_free_blocks {
+            let free = fs.bitmap.free_blocks();
+            fs.bitmap.set_reserved(free);
+        }
+
+        let mut moved = 0;
+        loop {
+            let progress = fs.defragment(usize::MAX).unwrap();
+            moved += progress.moved;
+            if progress.done {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        fs.read_file(["b"], &mut data).unwrap();
+        assert_eq!(data, vec![2u8; 4 * Block::SIZE]);
+        moved
+    };
+
+    assert!(moved(false) > 0);
+    // Without free blocks outside of the reserve, nothing is taken out of
+    // the way.
+    assert_eq!(moved(true), 0);
+}
+
+#[test]
+fn make_dir_recursive() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+
+    let path = vec!["one", "two", "three"];
+    fs.make_directory_recursive(path).unwrap();
+
+    assert_eq!(
+        format!("{}", fs),
+        "/
+| one/
+    | two/
+        | three/"
+    )
+}
+
+#[test]
+fn secure_delete() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::Write;
+
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_secure_delete(true)
+        .with_inline_limit(0);
+    let occupied = fs.bitmap.occupied_blocks();
+
+    let blocks = fs
+        .with_root_directory_mut(|root, fs| {
+            let entry = root.add_file("secret.txt", "text/plain")?;
+            entry
+                .write_to_file_system(fs)?
+                .write_all(&[0xaa; Block::SIZE * 3])?;
+            entry.truncate(fs, 10)?;
+            Ok(entry.cluster.blocks().copied().collect::<Vec<_>>())
+        })
+        .unwrap();
+    assert_eq!(blocks.len(), 1);
+
+    let mut data = [0u8; Block::SIZE];
+    fs.memory.read(blocks[0].offset(), &mut data).unwrap();
+    assert_eq!(&data[..10], &[0xaa; 10]);
+    assert!(data[10..].iter().all(|&b| b == 0));
+
+    fs.remove(vec!["secret.txt"]).unwrap();
+    fs.memory.read(blocks[0].offset(), &mut data).unwrap();
+    assert!(data.iter().all(|&b| b == 0));
+    assert!(fs.read_root_directory().unwrap().entries.is_empty());
+    assert_eq!(fs.bitmap.occupied_blocks(), occupied);
+}
+
+#[test]
+fn seek_past_free_space() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.write_atomic(["a.bin"], &[1u8; Block::SIZE][..]).unwrap();
+    let free = fs.bitmap.free_blocks();
+
+    // A gap larger than the free space fails before any zeros are written.
+    let offset = (free as u64 + 2) * Block::SIZE as u64;
+    let err = fs.write_at(["a.bin"], offset, b"x").unwrap_err();
+    assert!(matches!(Error::from(err), Error::OutOfSpace));
+    assert_eq!(fs.bitmap.free_blocks(), free);
+    assert_eq!(
+        fs.resolve(names(["a.bin"])).unwrap().unwrap().size,
+        Block::SIZE as u64
+    );
+
+    // A gap that fits is filled with zeros.
+    fs.write_at(["a.bin"], 3 * Block::SIZE as u64, b"x")
+        .unwrap();
+    let mut data = Vec::new();
+    fs.read_file(["a.bin"], &mut data).unwrap();
+    assert_eq!(data.len(), 3 * Block::SIZE + 1);
+    assert!(data[Block::SIZE..3 * Block::SIZE].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn preallocate() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::Write;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+
+    fs.with_root_directory_mut(|root, fs| {
+        let entry = root.add_file("upload.bin", "application/octet-stream")?;
+        entry.preallocate(fs, Block::SIZE as u64 * 4)?;
+        assert_eq!(entry.size, 0);
+        assert_eq!(entry.cluster.block_count(), 4);
+
+        let blocks = entry.cluster.blocks().copied().collect::<Vec<_>>();
+        assert!(blocks.windows(2).all(|w| w[0] + 1 == w[1]));
+
+        let occupied = fs.bitmap.occupied_blocks();
+        entry
+            .write_to_file_system(fs)?
+            .write_all(&[1u8; Block::SIZE * 4])?;
+        assert_eq!(fs.bitmap.occupied_blocks(), occupied);
+        assert_eq!(entry.size, Block::SIZE as u64 * 4);
+
+        let too_much = (4 + fs.bitmap.free_blocks() + 1) as u64 * Block::SIZE as u64;
+        let err = entry.preallocate(fs, too_much).unwrap_err();
+        assert!(matches!(Error::from(err), Error::OutOfSpace));
+        assert_eq!(fs.bitmap.occupied_blocks(), occupied);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn collect_garbage() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::{Read, Write};
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(vec!["a", "b"]).unwrap();
+    fs.with_directory_mut(vec!["a", "b"], |dir, fs| {
+        dir.add_file("c.txt", "text/plain")?
+            .write_to_file_system(fs)?
+            .write_all(&[7u8; Block::SIZE * 2])
+    })
+    .unwrap();
+
+    let occupied = fs.bitmap.occupied_blocks();
+    let leaked = [
+        fs.bitmap.occupy_next().unwrap(),
+        fs.bitmap.occupy_next().unwrap(),
+    ];
+
+    let expected = GarbageReport {
+        leaked_blocks: 2,
+        leaked_bytes: Block::SIZE as u64 * 2,
+    };
+    assert_eq!(fs.collect_garbage(true).unwrap(), expected);
+    assert_eq!(fs.bitmap[leaked[0]], BitState::Occupied);
+
+    assert_eq!(fs.collect_garbage(false).unwrap(), expected);
+    assert_eq!(fs.bitmap.occupied_blocks(), occupied);
+    assert_eq!(fs.collect_garbage(true).unwrap(), GarbageReport::default());
+
+    fs.with_file(vec!["a", "b", "c.txt"], |file| {
+        let mut data = vec![];
+        file.read_from_file_system(&fs)?.read_to_end(&mut data)?;
+        assert_eq!(data, vec![7u8; Block::SIZE * 2]);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn collect_garbage_slice() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(["a", "b"]).unwrap();
+    fs.write_atomic(["a", "b", "c.bin"], &[7u8; Block::SIZE * 2][..])
+        .unwrap();
+    fs.bitmap.occupy_next().unwrap();
+
+    let progress = fs.collect_garbage_slice(1).unwrap();
+    assert_eq!((progress.visited, progress.done), (1, false));
+    // A change in between starts the pass over.
+    fs.write_atomic(["d.txt"], &b"delta"[..]).unwrap();
+    let occupied = fs.bitmap.occupied_blocks() - 1;
+    let mut progress = fs.collect_garbage_slice(2).unwrap();
+    assert_eq!((progress.visited, progress.done), (2, false));
+    while !progress.done {
+        progress = fs.collect_garbage_slice(2).unwrap();
+    }
+    assert_eq!(progress.report.leaked_blocks, 1);
+    assert_eq!(fs.bitmap.occupied_blocks(), occupied);
+    let mut data = vec![];
+    fs.read_file(["a", "b", "c.bin"], &mut data).unwrap();
+    assert_eq!(data, [7u8; Block::SIZE * 2]);
+}
+
+#[test]
+fn remove_tree() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(["a", "b", "c"]).unwrap();
+    fs.make_directory_recursive(["a", "empty"]).unwrap();
+    for name in ["1.bin", "2.bin", "3.bin"] {
+        fs.write_atomic(["a", "b", name], &[1u8; Block::SIZE][..])
+            .unwrap();
+    }
+    fs.write_atomic(["a", "b", "c", "4.bin"], &[1u8; Block::SIZE][..])
+        .unwrap();
+
+    let progress = fs.remove_tree(["a"], 2).unwrap();
+    assert_eq!((progress.removed, progress.done), (2, false));
+    // Deepest first: the first directory with entries went first.
+    assert!(!fs.exists(["a", "b", "c"]));
+    assert_eq!(fs.directory_at(["a", "b"]).unwrap().entries.len(), 3);
+    let mut removed = progress.removed;
+    loop {
+        let progress = fs.remove_tree(["a"], 2).unwrap();
+        removed += progress.removed;
+        if progress.done {
+            break;
+        }
+    }
+    assert_eq!(removed, 8);
+    assert!(!fs.exists(["a"]));
+    assert_eq!(fs.collect_garbage(true).unwrap().leaked_blocks, 0);
+
+    fs.write_atomic(["d.txt"], &b"delta"[..]).unwrap();
+    fs.set_sealed(["d.txt"], true).unwrap();
+    assert!(fs.remove_tree(["d.txt"], 1).is_err());
+    assert!(fs.remove_tree(["missing"], 1).is_err());
+    assert!(fs.remove_tree(Vec::<String>::new(), 1).is_err());
+}
+
+#[test]
+fn torn_preamble() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut mem = HeapMemory::default();
+
+    fn dir_size<M: Memory>(fs: &FileSystem<M>) -> u64 {
+        fs.read_root_directory().unwrap().entries[0].size
+    }
+    let (leaked, empty) = {
+        let mut fs = FileSystem::new(&mut mem).unwrap();
+        fs.make_directory_recursive(vec!["dir"]).unwrap();
+        fs.persist().unwrap();
+        let empty = dir_size(&fs);
+        let leaked = fs.bitmap.occupy_next().unwrap();
+        fs.make_directory_recursive(vec!["dir", "sub"]).unwrap();
+        fs.close().unwrap();
+        (leaked, empty)
+    };
+
+    {
+        let fs = FileSystem::open(&mut mem)
+            .unwrap()
+            .with_drop_policy(DropPolicy::Ignore);
+        assert_eq!(fs.sequence, 2);
+        assert_eq!(fs.bitmap[leaked], BitState::Occupied);
+        assert_ne!(dir_size(&fs), empty);
+    }
+
+    // Tear the newest copy, so the older one is used.
+    let layout = FileSystem::allocate(HeapMemory::default()).with_drop_policy(DropPolicy::Ignore);
+    let offset = layout.preamble_offset(0) + 5;
+    mem.write(offset, &[0xff]).unwrap();
+    {
+        let fs = FileSystem::open(&mut mem)
+            .unwrap()
+            .with_drop_policy(DropPolicy::Ignore);
+        assert_eq!(fs.sequence, 1);
+        assert_eq!(fs.bitmap[leaked], BitState::Free);
+        assert_eq!(fs.read_root_directory().unwrap().entries[0].name, "dir");
+        // The inode is read from the area of the older copy.
+        assert_eq!(dir_size(&fs), empty);
+    }
+
+    let offset = layout.preamble_offset(1) + 5;
+    mem.write(offset, &[0xff]).unwrap();
+    let err = FileSystem::open(&mut mem).map(drop).unwrap_err();
+    assert!(matches!(Error::from(err), Error::Corrupted { .. }));
+}
+
+#[test]
+fn durability() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_durability(Durability::AfterEveryMutation);
+    fs.make_directory_recursive(vec!["a"]).unwrap();
+    assert_eq!(fs.sequence, 1);
+    assert!(fs.is_persisted());
+    fs.make_directory_recursive(vec!["b"]).unwrap();
+    assert_eq!(fs.sequence, 2);
+
+    fs.set_durability(Durability::EveryNOperations(3));
+    fs.make_directory_recursive(vec!["c"]).unwrap();
+    fs.make_directory_recursive(vec!["d"]).unwrap();
+    assert_eq!(fs.sequence, 2);
+    fs.remove(vec!["d"]).unwrap();
+    assert_eq!(fs.sequence, 3);
+
+    fs.set_durability(Durability::Manual);
+    fs.make_directory_recursive(vec!["e"]).unwrap();
+    assert_eq!(fs.sequence, 3);
+    assert!(!fs.is_persisted());
+}
+
+#[test]
+fn persist_after_restore() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut mem = HeapMemory::default();
+    {
+        let mut fs = FileSystem::new(&mut mem).unwrap();
+        fs.make_directory_recursive(vec!["a"]).unwrap();
+        fs.persist().unwrap();
+        fs.make_directory_recursive(vec!["b"]).unwrap();
+        fs.close().unwrap();
+    }
+
+    let occupied = {
+        let mut fs = FileSystem::open(&mut mem).unwrap();
+        // Nothing is left to write right after restoring.
+        assert!(fs.is_persisted());
+        fs.write_atomic(["a", "c.bin"], &[7u8; Block::SIZE * 3][..])
+            .unwrap();
+        assert!(!fs.is_persisted());
+        let occupied = fs.bitmap.occupied_blocks();
+        fs.close().unwrap();
+        occupied
+    };
+
+    // The older copy only got what differed from it, and is whole.
+    let fs = FileSystem::open(&mut mem)
+        .unwrap()
+        .with_drop_policy(DropPolicy::Ignore);
+    assert_eq!(fs.sequence, 3);
+    assert_eq!(fs.bitmap.occupied_blocks(), occupied);
+    let mut data = vec![];
+    fs.read_file(["a", "c.bin"], &mut data).unwrap();
+    assert_eq!(data, [7u8; Block::SIZE * 3]);
+}
+
+#[test]
+fn inodes() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::{Read, Write};
+
+    let mut mem = HeapMemory::default();
+
+    {
+        let mut fs = FileSystem::new(&mut mem).unwrap().with_clock(|| 7);
+        fs.make_directory_recursive(vec!["dir"]).unwrap();
+        fs.with_directory_mut(vec!["dir"], |dir, _| {
+            dir.add_file("a.txt", "text/plain")?;
             Ok(())
         })
         .unwrap();
-    }
+
+        let root = |fs: &FileSystem<_>| {
+            let mut data = vec![];
+            fs.read_from_root_cluster().read_to_end(&mut data).unwrap();
+            data
+        };
+        let before = root(&fs);
+
+        // Writing a file only changes its inode, not the directories above.
+        fs.with_file_mut(vec!["dir", "a.txt"], |file, fs| {
+            assert_ne!(file.inode, 0);
+            file.write_to_file_system(fs)?.write_all(b"Hello")
+        })
+        .unwrap();
+        assert_eq!(root(&fs), before);
+        assert_eq!(fs.inodes.iter(&fs.memory).count(), 2);
+        fs.close().unwrap();
+    }
+
+    let mut fs = FileSystem::open(&mut mem).unwrap();
+    fs.with_file(vec!["dir", "a.txt"], |file| {
+        assert_eq!((file.size, file.created, file.modified), (5, 7, 7));
+        let mut data = String::new();
+        file.read_from_file_system(&fs)?.read_to_string(&mut data)?;
+        assert_eq!(data, "Hello");
+        Ok(())
+    })
+    .unwrap();
+
+    fs.remove(vec!["dir"]).unwrap();
+    assert_eq!(fs.inodes.iter(&fs.memory).count(), 0);
+}
+
+#[test]
+fn metadata_reserve() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::Write;
+
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_metadata_reserve(5);
+    let reserved = fs.bitmap.reserved();
+    assert!(reserved > 0);
+
+    let err = fs
+        .with_root_directory_mut(|root, fs| {
+            let size = fs.bitmap.free_blocks() * Block::SIZE;
+            root.add_file("big.bin", "")?
+                .write_to_file_system(fs)?
+                .write_all(&vec![1u8; size])
+        })
+        .unwrap_err();
+    assert!(matches!(Error::from(err), Error::OutOfSpace));
+    assert_eq!(fs.bitmap.free_data_blocks(), 0);
+
+    // Directories can still be created in the reserve.
+    fs.make_directory_recursive(vec!["a", "b"]).unwrap();
+    assert!(fs.bitmap.free_blocks() < reserved);
+    assert!(fs.resolve(vec!["a", "b"]).unwrap().is_some());
+}
+
+#[test]
+fn metadata() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::Write;
+
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_clock(|| 3);
+    fs.make_directory_recursive(vec!["docs"]).unwrap();
+    fs.with_directory_mut(vec!["docs"], |dir, fs| {
+        dir.add_file("a.txt", "text/plain")?
+            .write_to_file_system(fs)?
+            .write_all(&[0u8; Block::SIZE + 1])
+    })
+    .unwrap();
+
+    assert!(fs.exists(Vec::<&str>::new()));
+    assert!(fs.exists(vec!["docs", "a.txt"]));
+    assert!(!fs.exists(vec!["docs", "b.txt"]));
+    assert!(!fs.exists(vec!["docs", "a.txt", "c"]));
+
+    let file = fs.metadata(vec!["docs", "a.txt"]).unwrap();
+    assert_eq!(
+        file,
+        Metadata {
+            kind: EntryKind::File,
+            size: Block::SIZE as u64 + 1,
+            created: 3,
+            modified: 3,
+            block_count: 2,
+            entry_count: 0,
+        }
+    );
+    assert_eq!(
+        fs.metadata(vec!["docs"]).unwrap().kind,
+        EntryKind::Directory
+    );
+    assert_eq!(
+        fs.metadata(Vec::<&str>::new()).unwrap().kind,
+        EntryKind::Directory
+    );
+
+    let err = fs.metadata(vec!["missing"]).unwrap_err();
+    assert!(matches!(Error::from(err), Error::NotFound));
+}
+
+#[test]
+fn write_atomic() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::Read;
+
+    struct Failing(usize);
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            let n = buf.len().min(self.0);
+            self.0 -= n;
+            Ok(n)
+        }
+    }
+
+    let read = |fs: &FileSystem<_>| {
+        fs.with_file(vec!["a.txt"], |file| {
+            let mut data = vec![];
+            file.read_from_file_system(fs)?.read_to_end(&mut data)?;
+            Ok(data)
+        })
+        .unwrap()
+    };
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    let occupied = fs.bitmap.occupied_blocks();
+
+    let long = vec![1u8; Block::SIZE * 3];
+    assert_eq!(
+        fs.write_atomic(vec!["a.txt"], &long[..]).unwrap(),
+        long.len() as u64
+    );
+    assert_eq!(read(&fs), long);
+    let inode = fs.resolve(vec!["a.txt"]).unwrap().unwrap().inode;
+
+    fs.write_atomic(vec!["a.txt"], &b"short"[..]).unwrap();
+    assert_eq!(read(&fs), b"short");
+    assert_eq!(fs.resolve(vec!["a.txt"]).unwrap().unwrap().inode, inode);
+    let after_short = fs.bitmap.occupied_blocks();
+    assert!(after_short < occupied + 3);
+
+    // A failed write leaves the old contents and no blocks behind.
+    let err = fs
+        .write_atomic(vec!["a.txt"], Failing(Block::SIZE * 2))
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(read(&fs), b"short");
+    assert_eq!(fs.bitmap.occupied_blocks(), after_short);
+
+    fs.make_directory_recursive(vec!["dir"]).unwrap();
+    let occupied = fs.bitmap.occupied_blocks();
+    let err = fs.write_atomic(vec!["dir"], &b"x"[..]).unwrap_err();
+    assert!(matches!(Error::from(err), Error::IsADirectory));
+    assert_eq!(fs.bitmap.occupied_blocks(), occupied);
+}
+
+#[test]
+fn directory_modified() {
+    use crate::heap_memory::HeapMemory;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NOW: AtomicU64 = AtomicU64::new(1);
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_clock(|| NOW.fetch_add(1, Ordering::Relaxed));
+    fs.make_directory_recursive(vec!["a", "b"]).unwrap();
+
+    let modified = |fs: &FileSystem<_>, path: Vec<&str>| fs.metadata(path).unwrap().modified;
+    let (a, b) = (modified(&fs, vec!["a"]), modified(&fs, vec!["a", "b"]));
+
+    fs.with_directory_mut(vec!["a", "b"], |dir, _| {
+        dir.add_file("1.txt", "")?;
+        Ok(())
+    })
+    .unwrap();
+    let b_meta = fs.metadata(vec!["a", "b"]).unwrap();
+    assert!(b_meta.modified > b);
+    assert_eq!(b_meta.entry_count, 1);
+    assert_eq!(modified(&fs, vec!["a"]), a);
+
+    // Rewriting a directory without changing its entries keeps its time.
+    let b = b_meta.modified;
+    fs.with_directory_mut(vec!["a", "b"], |_, _| Ok(()))
+        .unwrap();
+    assert_eq!(modified(&fs, vec!["a", "b"]), b);
+
+    fs.with_directory_mut(vec!["a", "b"], |dir, _| {
+        dir.entry_with_name_mut("1.txt").unwrap().name = "2.txt".into();
+        Ok(())
+    })
+    .unwrap();
+    assert!(modified(&fs, vec!["a", "b"]) > b);
+
+    fs.set_modified_propagation(true);
+    fs.with_directory_mut(vec!["a", "b"], |dir, _| {
+        dir.add_file("3.txt", "")?;
+        Ok(())
+    })
+    .unwrap();
+    let b_meta = fs.metadata(vec!["a", "b"]).unwrap();
+    assert_eq!(b_meta.entry_count, 2);
+    assert_eq!(modified(&fs, vec!["a"]), b_meta.modified);
+    assert_eq!(fs.metadata(Vec::<&str>::new()).unwrap().entry_count, 1);
 }
 
 #[test]
-fn a_nested_dir() {
+fn sealed() {
     use crate::heap_memory::HeapMemory;
-    use std::io::{Read, Write};
+    use std::io::Write;
 
-    let mut mem = HeapMemory::default();
+    let is_sealed = |r: io::Result<()>| matches!(r.map_err(Error::from), Err(Error::Sealed));
 
-    {
-        let mut fs = FileSystem::new(&mut mem).unwrap();
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(vec!["release"]).unwrap();
+    fs.write_atomic(vec!["release", "v1.bin"], &b"v1"[..])
+        .unwrap();
+    fs.set_sealed(vec!["release", "v1.bin"], true).unwrap();
 
-        fs.with_root_directory_mut(|root, fs| {
-            let mut dir = Directory::default();
-            dir.add_file("my_file.txt")
-                .write_to_file_system(fs)
-                .write_all(b"Hello, World!")?;
+    assert!(is_sealed(
+        fs.with_file_mut(vec!["release", "v1.bin"], |file, fs| {
+            file.write_to_file_system(fs)?.write_all(b"v2")
+        })
+    ));
+    assert!(is_sealed(
+        fs.with_file_mut(vec!["release", "v1.bin"], |file, fs| file.truncate(fs, 0))
+    ));
+    assert!(is_sealed(
+        fs.write_atomic(vec!["release", "v1.bin"], &b"v2"[..])
+            .map(drop)
+    ));
+    assert!(is_sealed(fs.remove(vec!["release", "v1.bin"])));
+    assert!(is_sealed(fs.remove(vec!["release"])));
+    assert_eq!(fs.metadata(vec!["release", "v1.bin"]).unwrap().size, 2);
 
-            root.add_directory("my_dir")
-                .write_to_file_system(fs)
-                .write_directory(&dir)?;
+    // A sealed directory keeps its entries.
+    fs.set_sealed(vec!["release"], true).unwrap();
+    assert!(is_sealed(fs.with_directory_mut(
+        vec!["release"],
+        |dir, _| {
+            dir.add_file("v2.bin", "")?;
             Ok(())
+        }
+    )));
+    assert!(!fs.exists(vec!["release", "v2.bin"]));
+
+    fs.set_sealed(vec!["release"], false).unwrap();
+    fs.set_sealed(vec!["release", "v1.bin"], false).unwrap();
+    fs.remove(vec!["release"]).unwrap();
+    assert!(!fs.exists(vec!["release"]));
+}
+
+#[test]
+fn duplicate_names() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(vec!["docs"]).unwrap();
+    fs.with_directory_mut(vec!["docs"], |dir, _| {
+        dir.add_file("Notes.txt", "")?;
+        dir.add_file("notes.txt", "")?;
+        Ok(())
+    })
+    .unwrap();
+    assert!(fs.duplicate_names().unwrap().is_empty());
+
+    fs.set_name_policy(NamePolicy {
+        case_insensitive: true,
+        ..Default::default()
+    });
+    assert_eq!(
+        fs.duplicate_names().unwrap(),
+        vec![vec!["docs".to_string(), "notes.txt".to_string()]]
+    );
+}
+
+#[test]
+fn max_string_len() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(vec!["docs"]).unwrap();
+    fs.with_directory_mut(vec!["docs"], |dir, _| {
+        dir.add_file("short.txt", "a".repeat(100))?;
+        dir.add_file("long.txt", "a".repeat(1000))?;
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(fs.list_directory(vec!["docs"]).unwrap().len(), 2);
+
+    // Strings up to the limit are read, and longer ones are corrupted.
+    let fs = fs.with_max_string_len(500);
+    assert_eq!(
+        fs.resolve(vec!["docs", "short.txt"])
+            .unwrap()
+            .unwrap()
+            .content_type
+            .len(),
+        100
+    );
+    let err = fs.resolve(vec!["docs", "long.txt"]).unwrap_err();
+    assert!(matches!(Error::from(err), Error::Corrupted { .. }));
+    assert!(fs.list_directory(vec!["docs"]).is_err());
+
+    let fs = fs.with_max_string_len(1000);
+    assert_eq!(fs.list_directory(vec!["docs"]).unwrap().len(), 2);
+}
+
+#[test]
+fn inline_files() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::{Read, Seek, Write};
+
+    let read = |fs: &FileSystem<_>| {
+        fs.with_file(vec!["config.json"], |file| {
+            let mut data = vec![];
+            file.read_from_file_system(fs)?.read_to_end(&mut data)?;
+            Ok(data)
+        })
+        .unwrap()
+    };
+
+    let mut mem = HeapMemory::default();
+    {
+        let mut fs = FileSystem::new(&mut mem).unwrap();
+        fs.with_root_directory_mut(|root, _| {
+            root.add_file("config.json", "")?;
+            root.add_file("other.json", "").map(drop)
         })
         .unwrap();
+        let occupied = fs.bitmap.occupied_blocks();
+
+        for name in ["config.json", "other.json"] {
+            fs.with_file_mut(vec![name], |file, fs| {
+                file.write_to_file_system(fs)?.write_all(b"{}")
+            })
+            .unwrap();
+        }
+        // Both share a block.
+        assert_eq!(fs.bitmap.occupied_blocks(), occupied + 1);
+        assert_eq!(fs.metadata(vec!["config.json"]).unwrap().block_count, 0);
+        fs.close().unwrap();
     }
 
-    {
-        let fs = FileSystem::open(&mut mem).unwrap();
+    let mut fs = FileSystem::open(&mut mem).unwrap();
+    assert_eq!(read(&fs), b"{}");
 
-        fs.with_root_directory(|root| {
-            let dir_entry = &root.entries[0];
-            assert_eq!(&dir_entry.name, "my_dir");
+    // Appending past the limit moves the contents into a cluster.
+    fs.with_file_mut(vec!["config.json"], |file, fs| {
+        let mut w = file.write_to_file_system(fs)?;
+        w.seek(io::SeekFrom::End(-1))?;
+        w.write_all(&[b' '; DEFAULT_INLINE_LIMIT])?;
+        w.write_all(b"}")
+    })
+    .unwrap();
+    let mut expected = b"{".to_vec();
+    expected.extend_from_slice(&[b' '; DEFAULT_INLINE_LIMIT]);
+    expected.push(b'}');
+    assert_eq!(read(&fs), expected);
+    assert_eq!(fs.metadata(vec!["config.json"]).unwrap().block_count, 1);
 
-            let file_entry = &dir_entry
-                .read_from_file_system(&fs)
-                .read_directory()?
-                .entries[0];
-            assert_eq!(&file_entry.name, "my_file.txt");
+    fs.with_file_mut(vec!["config.json"], |file, fs| file.truncate(fs, 1))
+        .unwrap();
+    assert_eq!(read(&fs), b"{");
+    assert_eq!(fs.metadata(vec!["config.json"]).unwrap().block_count, 0);
 
-            let mut result = String::new();
-            file_entry
-                .read_from_file_system(&fs)
-                .read_to_string(&mut result)?;
-            assert_eq!(&result, "Hello, World!");
+    // Their shared block is given back with the last of them.
+    fs.remove(vec!["config.json"]).unwrap();
+    fs.remove(vec!["other.json"]).unwrap();
+    assert_eq!(fs.tails().unwrap().blocks().count(), 0);
+    assert_eq!(fs.collect_garbage(true).unwrap().leaked_blocks, 0);
+}
+
+#[test]
+fn tail_packing() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::{Read, Seek, Write};
+
+    let names = ["a", "b", "c"];
+    let contents = |i: usize, len: usize| vec![b'a' + i as u8; len];
+    let read = |fs: &FileSystem<_>, name: &str| {
+        fs.with_file(vec![name], |file| {
+            let mut data = vec![];
+            file.read_from_file_system(fs)?.read_to_end(&mut data)?;
+            Ok(data)
+        })
+        .unwrap()
+    };
+
+    let mut mem = HeapMemory::default();
+    let occupied;
+    {
+        let mut fs = FileSystem::new(&mut mem)
+            .unwrap()
+            .with_inline_limit(0)
+            .with_tail_packing(true);
+        fs.with_root_directory_mut(|root, _| {
+            for name in names {
+                root.add_file(name, "")?;
+            }
             Ok(())
         })
         .unwrap();
+        occupied = fs.bitmap.occupied_blocks();
+
+        for (i, name) in names.iter().enumerate() {
+            fs.with_file_mut(vec![*name], |file, fs| {
+                file.write_to_file_system(fs)?.write_all(&contents(i, 600))
+            })
+            .unwrap();
+        }
+        // A full block and an index block each, and the tails share another.
+        assert_eq!(fs.bitmap.occupied_blocks(), occupied + 7);
+        fs.close().unwrap();
+    }
+
+    let mut fs = FileSystem::open(&mut mem).unwrap().with_tail_packing(true);
+    for (i, name) in names.iter().enumerate() {
+        assert_eq!(read(&fs, name), contents(i, 600));
+    }
+    // The inode table has been written since.
+    let occupied = fs.bitmap.occupied_blocks() - 7;
+    assert_eq!(fs.collect_garbage(true).unwrap().leaked_blocks, 0);
+
+    let tail = fs
+        .with_file(vec!["b"], |file| {
+            let mut r = file.read_from_file_system(&fs)?;
+            r.seek(io::SeekFrom::Start(500))?;
+            let mut data = vec![];
+            r.read_to_end(&mut data)?;
+            Ok(data)
+        })
+        .unwrap();
+    assert_eq!(tail, contents(1, 100));
+
+    // Growing a file unpacks its tail and packs the new one.
+    fs.with_file_mut(vec!["b"], |file, fs| {
+        let mut w = file.write_to_file_system(fs)?;
+        w.seek(io::SeekFrom::End(0))?;
+        w.write_all(&contents(1, 100))
+    })
+    .unwrap();
+    for (i, name) in names.iter().enumerate() {
+        let len = if *name == "b" { 700 } else { 600 };
+        assert_eq!(read(&fs, name), contents(i, len));
     }
+    assert_eq!(fs.bitmap.occupied_blocks(), occupied + 7);
+
+    for name in names {
+        fs.remove(vec![name]).unwrap();
+    }
+    assert_eq!(fs.bitmap.occupied_blocks(), occupied);
 }
 
 #[test]
-fn make_dir_recursive() {
+fn purge_expired() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut mem = HeapMemory::default();
+    let mut fs = FileSystem::new(&mut mem).unwrap();
+    fs.make_directory_recursive(vec!["uploads"]).unwrap();
+    fs.with_root_directory_mut(|root, _| {
+        root.add_file("a", "")?;
+        root.add_file("b", "")?;
+        Ok(())
+    })
+    .unwrap();
+    fs.with_directory_mut(vec!["uploads"], |dir, _| dir.add_file("c", "").map(drop))
+        .unwrap();
+    assert_eq!(
+        fs.purge_expired(100, 10).unwrap(),
+        PurgeProgress {
+            removed: 0,
+            done: true
+        }
+    );
+
+    fs.set_expiry(vec!["a"], Some(10)).unwrap();
+    fs.set_expiry(vec!["b"], Some(20)).unwrap();
+    fs.set_expiry(vec!["uploads", "c"], Some(10)).unwrap();
+
+    let progress = fs.purge_expired(15, 1).unwrap();
+    assert_eq!((progress.removed, progress.done), (1, false));
+    let progress = fs.purge_expired(15, 1).unwrap();
+    assert_eq!((progress.removed, progress.done), (1, true));
+    assert!(!fs.exists(vec!["a"]) && !fs.exists(vec!["uploads", "c"]));
+    assert!(fs.exists(vec!["b"]) && fs.exists(vec!["uploads"]));
+
+    // Sealed entries outlive their expiry.
+    fs.set_sealed(vec!["b"], true).unwrap();
+    assert_eq!(fs.purge_expired(25, 10).unwrap().removed, 0);
+    fs.set_sealed(vec!["b"], false).unwrap();
+    assert_eq!(fs.purge_expired(25, 10).unwrap().removed, 1);
+    assert!(!fs.exists(vec!["b"]));
+}
+
+#[test]
+fn observer() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct Log(Arc<Mutex<Vec<String>>>);
+
+    impl FsObserver for Log {
+        fn on_create(&mut self, path: &[String], _: EntryKind) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("create {}", path.join("/")));
+        }
+        fn on_write_complete(&mut self, path: &[String], size: u64) {
+            let event = format!("write {} {}", path.join("/"), size);
+            self.0.lock().unwrap().push(event);
+        }
+        fn on_delete(&mut self, path: &[String], _: EntryKind) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("delete {}", path.join("/")));
+        }
+        fn on_rename(&mut self, from: &[String], to: &[String]) {
+            let event = format!("rename {} {}", from.join("/"), to.join("/"));
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    let log = Log::default();
+    let events = log.0.clone();
+    let mut mem = HeapMemory::default();
+    let mut fs = FileSystem::new(&mut mem)
+        .unwrap()
+        .with_observer(Box::new(log));
+
+    fs.make_directory_recursive(vec!["a", "b"]).unwrap();
+    fs.with_directory_mut(vec!["a", "b"], |dir, _| dir.add_file("f", "").map(drop))
+        .unwrap();
+    fs.with_file_mut(vec!["a", "b", "f"], |file, fs| {
+        file.write_to_file_system(fs)?.write_all(b"hello")
+    })
+    .unwrap();
+    fs.write_atomic(vec!["a", "g"], &b"hi"[..]).unwrap();
+    fs.with_directory_mut(vec!["a"], |dir, _| {
+        dir.entry_with_name_mut("g").unwrap().name = "h".to_owned();
+        Ok(())
+    })
+    .unwrap();
+    fs.remove(vec!["a", "b"]).unwrap();
+
+    // Failed changes are not reported.
+    assert!(fs
+        .with_root_directory_mut(|root, _| root.add_file("a", "").map(drop))
+        .is_err());
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            "create a",
+            "create a/b",
+            "create a/b/f",
+            "write a/b/f 5",
+            "create a/g",
+            "write a/g 2",
+            "rename a/g a/h",
+            "delete a/b",
+        ]
+    );
+}
+
+#[test]
+fn mount() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut blobs = FileSystem::new(HeapMemory::default()).unwrap();
+    blobs.make_directory_recursive(vec!["2024"]).unwrap();
+
+    let mut mem = HeapMemory::default();
+    let mut fs = FileSystem::new(&mut mem).unwrap();
+    fs.make_directory_recursive(vec!["data", "blobs"]).unwrap();
+    assert!(fs
+        .mount(vec![], FileSystem::new(HeapMemory::default()).unwrap())
+        .is_err());
+    fs.mount(vec!["data".into(), "blobs".into()], blobs)
+        .unwrap();
+
+    fs.write_atomic(vec!["data", "blobs", "2024", "a.bin"], &b"blob"[..])
+        .unwrap();
+    fs.write_atomic(vec!["data", "index"], &b"a.bin"[..])
+        .unwrap();
+    assert!(fs.exists(vec!["data", "blobs", "2024", "a.bin"]));
+    assert_eq!(
+        fs.metadata(vec!["data", "blobs", "2024", "a.bin"])
+            .unwrap()
+            .size,
+        4
+    );
+    let mut data = vec![];
+    fs.read_file(vec!["data", "blobs", "2024", "a.bin"], &mut data)
+        .unwrap();
+    assert_eq!(data, b"blob");
+
+    // The mounted files are not in this filesystem's directories.
+    assert_eq!(
+        fs.with_directory(vec!["data", "blobs"], |_| Ok(()))
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert!(fs.remove(vec!["data"]).is_err());
+
+    let blobs = fs.unmount(vec!["data".into(), "blobs".into()]).unwrap();
+    assert!(blobs.exists(&["2024".into(), "a.bin".into()]));
+    assert!(!fs.exists(vec!["data", "blobs", "2024"]));
+    fs.remove(vec!["data"]).unwrap();
+}
+
+#[test]
+fn clone_into() {
+    use crate::heap_memory::HeapMemory;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NOW: AtomicU64 = AtomicU64::new(1);
+    let mut mem = HeapMemory::default();
+    let mut fs = FileSystem::new(&mut mem)
+        .unwrap()
+        .with_clock(|| NOW.fetch_add(1, Ordering::Relaxed));
+    fs.make_directory_recursive(vec!["site", "img"]).unwrap();
+    fs.with_directory_mut(vec!["site"], |dir, _| {
+        dir.add_file("index.html", "text/html").map(drop)
+    })
+    .unwrap();
+    fs.with_file_mut(vec!["site", "index.html"], |file, fs| {
+        io::Write::write_all(&mut file.write_to_file_system(fs)?, b"<html>")
+    })
+    .unwrap();
+    fs.write_atomic(vec!["site", "img", "big.bin"], &[7u8; 3000][..])
+        .unwrap();
+    fs.set_sealed(vec!["site", "img"], true).unwrap();
+    fs.set_expiry(vec!["site", "index.html"], Some(99)).unwrap();
+
+    let copy = fs.clone_into(HeapMemory::default()).unwrap();
+    let paths = [
+        vec!["site"],
+        vec!["site", "index.html"],
+        vec!["site", "img"],
+        vec!["site", "img", "big.bin"],
+    ];
+    for path in paths.iter() {
+        let (a, b) = (fs.metadata(path).unwrap(), copy.metadata(path).unwrap());
+        assert_eq!(
+            (a.kind, a.size, a.created, a.modified),
+            (b.kind, b.size, b.created, b.modified)
+        );
+    }
+    for path in [vec!["site", "index.html"], vec!["site", "img", "big.bin"]] {
+        let (mut a, mut b) = (vec![], vec![]);
+        fs.read_file(&path, &mut a).unwrap();
+        copy.read_file(&path, &mut b).unwrap();
+        assert_eq!(a, b);
+    }
+    copy.with_file(vec!["site", "index.html"], |file| {
+        assert_eq!(
+            (file.content_type.as_str(), file.expires),
+            ("text/html", Some(99))
+        );
+        Ok(())
+    })
+    .unwrap();
+    copy.with_directory(vec!["site"], |dir| {
+        assert!(dir.entry_with_name("img").unwrap().sealed);
+        Ok(())
+    })
+    .unwrap();
+}
+
+#[test]
+fn dir_size() {
+    use crate::heap_memory::HeapMemory;
+    use std::io::Write;
+
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_size_caching(true);
+    fs.make_directory_recursive(vec!["a", "b"]).unwrap();
+    fs.write_atomic(vec!["a", "x"], &[0u8; 100][..]).unwrap();
+    fs.write_atomic(vec!["a", "b", "y"], &[0u8; 2000][..])
+        .unwrap();
+    let size = |fs: &FileSystem<_>, path: &[&str]| fs.dir_size(path).unwrap();
+    assert_eq!(
+        size(&fs, &["a"]),
+        DirSize {
+            bytes: 2100,
+            files: 2
+        }
+    );
+    assert_eq!(size(&fs, &[]).bytes, 2100);
+
+    // Cached sizes follow changes below them.
+    fs.with_file_mut(vec!["a", "b", "y"], |file, fs| {
+        file.write_to_file_system(fs)?.write_all(&[1u8; 3000])
+    })
+    .unwrap();
+    assert_eq!(size(&fs, &["a", "b"]).bytes, 3000);
+    fs.remove(vec!["a", "x"]).unwrap();
+    assert_eq!(size(&fs, &["a"]).files, 1);
+    fs.remove(vec!["a", "b", "y"]).unwrap();
+    assert_eq!(size(&fs, &[]), DirSize::default());
+    assert!(fs.dir_size(vec!["a", "c"]).is_err());
+}
+
+#[test]
+fn usage() {
     use crate::heap_memory::HeapMemory;
 
     let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    let before = fs.usage();
+    assert_eq!(before.total_blocks, 512);
+    fs.write_atomic(vec!["a"], &[0u8; 5000][..]).unwrap();
+    let after = fs.usage();
+    assert!(after.used_blocks >= before.used_blocks + 10);
+    fs.remove(vec!["a"]).unwrap();
+    assert_eq!(fs.usage(), before);
+}
 
-    let path = vec!["one", "two", "three"];
-    fs.make_directory_recursive(path).unwrap();
+#[test]
+fn copy_range() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    let data = (0..10000).map(|i| i as u8).collect::<Vec<_>>();
+    fs.write_atomic(vec!["a"], &data[..]).unwrap();
+    fs.write_atomic(vec!["b"], &b"xy"[..]).unwrap();
+    let read = |fs: &FileSystem<_>, name: &str| {
+        let mut data = vec![];
+        fs.read_file(vec![name], &mut data).unwrap();
+        data
+    };
 
     assert_eq!(
-        format!("{}", fs),
-        "/
-| one/
-    | two/
-        | three/"
-    )
+        fs.copy_range(vec!["a"], 100, vec!["b"], 1, 5000).unwrap(),
+        5000
+    );
+    let b = read(&fs, "b");
+    assert_eq!((b.len(), b[0]), (5001, b'x'));
+    assert_eq!(b[1..], data[100..5100]);
+
+    // Overlapping ranges of the same file, in both directions.
+    assert_eq!(
+        fs.copy_range(vec!["a"], 0, vec!["a"], 3000, 9000).unwrap(),
+        9000
+    );
+    let a = read(&fs, "a");
+    assert_eq!((&a[..3000], &a[3000..]), (&data[..3000], &data[..9000]));
+    fs.copy_range(vec!["a"], 3000, vec!["a"], 0, 9000).unwrap();
+    assert_eq!(read(&fs, "a")[..9000], data[..9000]);
+
+    assert_eq!(
+        fs.copy_range(vec!["b"], 5000, vec!["a"], 0, 100).unwrap(),
+        1
+    );
+    assert!(fs.copy_range(vec!["c"], 0, vec!["a"], 0, 1).is_err());
+}
+
+#[test]
+fn directory_page() {
+    use crate::heap_memory::HeapMemory;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Later writes are older, so modification order differs from storage.
+    static NOW: AtomicU64 = AtomicU64::new(1000);
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_clock(|| NOW.fetch_sub(1, Ordering::Relaxed));
+    fs.make_directory_recursive(vec!["d"]).unwrap();
+    for name in ["b.txt", "a.txt", "c.png", "d.txt"] {
+        fs.write_atomic(vec!["d", name], &b"x"[..]).unwrap();
+    }
+    let page = |offset, limit, prefix, order| {
+        let (entries, next) = fs
+            .directory_page(vec!["d"], offset, limit, prefix, order)
+            .unwrap();
+        let names = entries.into_iter().map(|e| e.name).collect::<Vec<_>>();
+        (names, next)
+    };
+
+    assert_eq!(
+        page(0, 2, "", SortOrder::Stored),
+        (vec!["b.txt".to_owned(), "a.txt".to_owned()], Some(2))
+    );
+    assert_eq!(
+        page(2, 2, "", SortOrder::Stored),
+        (vec!["c.png".to_owned(), "d.txt".to_owned()], None)
+    );
+    assert_eq!(
+        page(1, 5, "", SortOrder::Name),
+        (
+            vec!["b.txt".to_owned(), "c.png".to_owned(), "d.txt".to_owned()],
+            None
+        )
+    );
+    assert_eq!(
+        page(0, 2, "", SortOrder::NameDescending).0,
+        ["d.txt", "c.png"]
+    );
+    assert_eq!(
+        page(0, 10, ".", SortOrder::Modified).0,
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        page(0, 2, "", SortOrder::Modified),
+        (vec!["d.txt".to_owned(), "c.png".to_owned()], Some(2))
+    );
+    assert_eq!(page(0, 1, "", SortOrder::ModifiedDescending).0, ["b.txt"]);
+    assert_eq!(page(0, 5, "a", SortOrder::Stored).0, ["a.txt"]);
+    assert!(fs
+        .directory_page(vec!["d", "a.txt"], 0, 1, "", SortOrder::Stored)
+        .is_err());
+}
+
+#[test]
+fn rename_and_copy() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(vec!["a", "b"]).unwrap();
+    fs.make_directory_recursive(vec!["c"]).unwrap();
+    let data = (0..10000).map(|i| i as u8).collect::<Vec<_>>();
+    fs.write_atomic(vec!["a", "b", "f.bin"], &data[..]).unwrap();
+    fs.set_expiry(vec!["a", "b", "f.bin"], Some(9)).unwrap();
+    let read = |fs: &FileSystem<_>, path: Vec<&str>| {
+        let mut data = vec![];
+        fs.read_file(path, &mut data).unwrap();
+        data
+    };
+
+    fs.rename(vec!["a", "b"], vec!["c", "d"]).unwrap();
+    assert!(!fs.exists(vec!["a", "b"]));
+    assert_eq!(read(&fs, vec!["c", "d", "f.bin"]), data);
+    fs.rename(vec!["c", "d", "f.bin"], vec!["c", "d", "g.bin"])
+        .unwrap();
+    assert_eq!(fs.list_directory(vec!["c", "d"]).unwrap().len(), 1);
+    let moved = fs.resolve(vec!["c", "d", "g.bin"]).unwrap().unwrap();
+    assert_eq!((moved.size, moved.expires), (10000, Some(9)));
+
+    // Into itself, onto an entry, out of thin air, or while sealed.
+    assert!(fs.rename(vec!["c"], vec!["c", "d", "e"]).is_err());
+    assert!(fs.rename(vec!["a"], vec!["c", "d"]).is_err());
+    assert!(fs.rename(vec!["x"], vec!["y"]).is_err());
+    assert!(fs.rename(vec!["a"], vec!["c", "d", "g.bin", "a"]).is_err());
+    fs.set_sealed(vec!["c", "d", "g.bin"], true).unwrap();
+    assert!(fs.rename(vec!["c"], vec!["a", "c"]).is_err());
+    assert!(fs.exists(vec!["c", "d", "g.bin"]));
+
+    let mut copied = fs.copy_file(vec!["c", "d", "g.bin"], vec!["a", "h.bin"]);
+    assert_eq!(copied.unwrap(), 10000);
+    assert_eq!(read(&fs, vec!["a", "h.bin"]), data);
+    fs.write_atomic(vec!["a", "i.txt"], &b"i"[..]).unwrap();
+    copied = fs.copy_file(vec!["a", "i.txt"], vec!["a", "h.bin"]);
+    assert_eq!(copied.unwrap(), 1);
+    assert_eq!(read(&fs, vec!["a", "h.bin"]), b"i");
+    assert_eq!(read(&fs, vec!["c", "d", "g.bin"]), data);
+    assert!(fs.copy_file(vec!["c"], vec!["a", "j"]).is_err());
+}
+
+#[test]
+fn set_metadata() {
+    use crate::heap_memory::HeapMemory;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NOW: AtomicU64 = AtomicU64::new(100);
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_clock(|| NOW.fetch_add(1, Ordering::Relaxed));
+    fs.make_directory_recursive(vec!["docs"]).unwrap();
+    fs.write_atomic(vec!["docs", "a.txt"], &b"abc"[..]).unwrap();
+
+    let xattrs = vec![("user.tag".to_owned(), vec![1, 2])];
+    let change = MetadataChange {
+        content_type: Some("text/markdown".to_owned()),
+        headers: Some(vec![("Cache-Control".to_owned(), "no-cache".to_owned())]),
+        xattrs: Some(xattrs.clone()),
+        created: Some(5),
+        modified: Some(6),
+    };
+    fs.set_metadata(vec!["docs", "a.txt"], change).unwrap();
+    let entry = fs.resolve(["docs", "a.txt"]).unwrap().unwrap();
+    assert_eq!(entry.content_type, "text/markdown");
+    assert_eq!(entry.headers.len(), 1);
+    assert_eq!(entry.xattrs, xattrs);
+    assert_eq!((entry.created, entry.modified), (5, 6));
+    let mut contents = vec![];
+    fs.read_file(["docs", "a.txt"], &mut contents).unwrap();
+    assert_eq!(contents, b"abc");
+
+    // What's left out stays.
+    let change = MetadataChange {
+        modified: Some(7),
+        ..Default::default()
+    };
+    fs.set_metadata(vec!["docs", "a.txt"], change).unwrap();
+    let entry = fs.resolve(["docs", "a.txt"]).unwrap().unwrap();
+    assert_eq!(
+        (entry.content_type.as_str(), entry.modified),
+        ("text/markdown", 7)
+    );
+    assert_eq!(entry.xattrs, xattrs);
+
+    let twice = MetadataChange {
+        xattrs: Some(vec![xattrs[0].clone(), xattrs[0].clone()]),
+        ..Default::default()
+    };
+    assert!(fs.set_metadata(vec!["docs", "a.txt"], twice).is_err());
+    let content_type = MetadataChange {
+        content_type: Some("text/plain".to_owned()),
+        ..Default::default()
+    };
+    assert!(fs.set_metadata(vec!["docs"], content_type).is_err());
+    assert!(fs
+        .set_metadata(Vec::<String>::new(), MetadataChange::default())
+        .is_err());
+
+    fs.touch(vec!["docs", "a.txt"]).unwrap();
+    assert!(fs.metadata(["docs", "a.txt"]).unwrap().modified > 100);
+    fs.touch(vec!["docs", "b.txt"]).unwrap();
+    assert_eq!(fs.metadata(["docs", "b.txt"]).unwrap().size, 0);
+    assert!(fs.touch(vec!["missing", "c.txt"]).is_err());
+}
+
+#[test]
+fn headers() {
+    use crate::heap_memory::HeapMemory;
+
+    let header = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+    let mut mem = HeapMemory::default();
+    let mut fs = FileSystem::new(&mut mem).unwrap();
+    fs.make_directory_recursive(vec!["assets"]).unwrap();
+    fs.write_atomic(vec!["assets", "app.js"], &b"js"[..])
+        .unwrap();
+    fs.set_headers(vec!["assets"], vec![header("Cache-Control", "max-age=60")])
+        .unwrap();
+    fs.set_headers(vec!["assets", "app.js"], vec![header("X-A", "1")])
+        .unwrap();
+    fs.set_headers(vec!["assets", "app.js"], vec![header("X-B", "2")])
+        .unwrap();
+
+    // Headers are replaced rather than merged, and kept when the contents
+    // are, across closing the filesystem too.
+    fs.write_atomic(vec!["assets", "app.js"], &b"new js"[..])
+        .unwrap();
+    fs.close().unwrap();
+    let mut fs = FileSystem::open(&mut mem).unwrap();
+    let file = fs.resolve(vec!["assets", "app.js"]).unwrap().unwrap();
+    assert_eq!(file.headers, [header("X-B", "2")]);
+    let copy = fs.clone_into(HeapMemory::default()).unwrap();
+    let dir = copy.resolve(vec!["assets"]).unwrap().unwrap();
+    assert_eq!(dir.headers, [header("Cache-Control", "max-age=60")]);
+
+    // The root directory has none, and malformed headers are refused.
+    let err = fs
+        .set_headers(Vec::<String>::new(), vec![header("X-A", "1")])
+        .unwrap_err();
+    assert!(matches!(Error::from(err), Error::InvalidPath));
+    for bad in [header("", "1"), header("X A", "1"), header("X-A", "1\n")] {
+        assert!(fs.set_headers(vec!["assets"], vec![bad]).is_err());
+    }
+    assert!(fs.set_headers(vec!["missing"], vec![]).is_err());
+}
+
+#[test]
+fn write_with_mode() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.write_atomic(["a.txt"], &b"hello world"[..]).unwrap();
+    let read = |fs: &FileSystem<HeapMemory>| {
+        let mut contents = vec![];
+        fs.read_file(["a.txt"], &mut contents).unwrap();
+        String::from_utf8(contents).unwrap()
+    };
+
+    let mode = WriteFileMode::Overwrite;
+    assert_eq!(fs.write_with_mode(["a.txt"], 0, b"HELLO", mode).unwrap(), 0);
+    assert_eq!(read(&fs), "HELLO world");
+    let mode = WriteFileMode::Append;
+    assert_eq!(fs.write_with_mode(["a.txt"], 0, b"!", mode).unwrap(), 11);
+    assert_eq!(read(&fs), "HELLO world!");
+    let mode = WriteFileMode::Truncate;
+    assert_eq!(fs.write_with_mode(["a.txt"], 0, b"bye", mode).unwrap(), 0);
+    assert_eq!(read(&fs), "bye");
+    // Past the end, the gap is filled with zeros either way.
+    assert_eq!(fs.write_with_mode(["a.txt"], 5, b"!", mode).unwrap(), 5);
+    assert_eq!(read(&fs), "bye\0\0!");
+    assert!(fs.write_with_mode(["b.txt"], 0, b"", mode).is_err());
+    // An end past `u64::MAX` fails before anything is written.
+    assert!(fs.write_with_mode(["a.txt"], u64::MAX, b"!", mode).is_err());
+    assert_eq!(read(&fs), "bye\0\0!");
+}
+
+#[test]
+fn stats() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(["docs", "old"]).unwrap();
+    fs.write_atomic(["docs", "a.txt"], &b"alpha"[..]).unwrap();
+    fs.write_atomic(["b.bin"], &[0u8; 100][..]).unwrap();
+    let block = [1u8; Block::SIZE];
+    fs.write_atomic(["c.bin"], &[][..]).unwrap();
+    fs.write_atomic(["d.bin"], &[][..]).unwrap();
+    // Written in turns, the blocks of the two files interleave.
+    for i in 0..3 {
+        let offset = (i * Block::SIZE) as u64;
+        fs.write_at(["c.bin"], offset, &block).unwrap();
+        fs.write_at(["d.bin"], offset, &block).unwrap();
+    }
+
+    let stats = fs.stats(2).unwrap();
+    assert_eq!((stats.files, stats.directories), (4, 2));
+    assert_eq!(stats.file_bytes, 5 + 100 + 2 * 3 * Block::SIZE as u64);
+    let sizes: Vec<_> = stats.largest_files.iter().map(|(_, size)| *size).collect();
+    assert_eq!(sizes, [3 * Block::SIZE as u64; 2]);
+    assert_eq!(stats.fragmented_files, 2);
+    assert_eq!(stats.fragments, 6);
+    assert_eq!(stats.format_version, FORMAT_VERSION);
+    assert_eq!(stats.usage, fs.usage());
+
+    while !fs.defragment(usize::MAX).unwrap().done {}
+    let stats = fs.stats(0).unwrap();
+    assert!(stats.fragments < 6);
+    assert!(stats.largest_files.is_empty());
+    let largest = fs.stats(3).unwrap().largest_files;
+    assert_eq!(largest[2], (vec!["b.bin".to_owned()], 100));
 }
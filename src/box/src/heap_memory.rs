@@ -2,18 +2,94 @@ use std::fmt;
 use std::io;
 
 use crate::block::Block;
-use crate::memory::Memory;
+use crate::error::Error;
+use crate::memory::{to_usize, Memory};
 
-const HEAP_PAGE_SIZE: usize = 1024;
-
-#[derive(Default)]
+/// Memory on the heap, for tests and for filesystems which don't need to
+/// survive upgrades.
 pub struct HeapMemory {
-    pages: Vec<[u8; HEAP_PAGE_SIZE]>,
+    data: Vec<u8>,
+    page_size: u64,
+    max_pages: u64,
+}
+
+impl Default for HeapMemory {
+    /// 1 KiB pages and 256 KiB at most, which tests fill up quickly.
+    fn default() -> Self {
+        Self::new(1024, 256)
+    }
 }
 
 impl HeapMemory {
+    pub fn new(page_size: u64, max_pages: u64) -> Self {
+        assert!(page_size > 0, "page size must not be 0");
+        HeapMemory {
+            data: vec![],
+            page_size,
+            max_pages,
+        }
+    }
+
+    /// Memory for filesystems of up to `max_size` bytes, in pages the size of
+    /// those of stable memory, e.g. for fuzzing large filesystems. It can't be
+    /// unbounded, as the bitmap and the preamble grow with `max_size`.
+    pub fn with_max_size(max_size: u64) -> Self {
+        const PAGE_SIZE: u64 = 65536;
+        Self::new(PAGE_SIZE, max_size.div_ceil(PAGE_SIZE))
+    }
+
     pub fn iter(&self) -> impl '_ + Iterator<Item = &u8> {
-        self.pages.iter().flat_map(|page| page.iter())
+        self.data.iter()
+    }
+
+    /// The part of `len` bytes at `offset` which lies within the memory.
+    fn range(&self, offset: u64, len: usize) -> std::ops::Range<usize> {
+        let start = to_usize(offset).unwrap_or(usize::MAX).min(self.data.len());
+        start..start + len.min(self.data.len() - start)
+    }
+}
+
+/// Heap memory which runs up against no ceiling short of the one stable
+/// memory has, for fuzzing and property tests of filesystems of any size a
+/// canister can hold. Pages are only allocated as it grows, but the bitmap
+/// covers all of it from the start, as it would in a canister.
+#[derive(Debug)]
+pub struct UnboundedHeapMemory(HeapMemory);
+
+impl Default for UnboundedHeapMemory {
+    fn default() -> Self {
+        let stable = crate::stable_memory::StableMemory::default();
+        UnboundedHeapMemory(HeapMemory::new(stable.page_size(), stable.max_pages()))
+    }
+}
+
+impl Memory for UnboundedHeapMemory {
+    fn page_size(&self) -> u64 {
+        self.0.page_size()
+    }
+
+    fn max_pages(&self) -> u64 {
+        self.0.max_pages()
+    }
+
+    fn page_count(&self) -> io::Result<u64> {
+        self.0.page_count()
+    }
+
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        self.0.grow(num_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(offset, buf)
+    }
+
+    fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.0.zero(offset, len)
     }
 }
 
@@ -21,8 +97,8 @@ impl fmt::Debug for HeapMemory {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Heap {{")?;
         for (i, byte) in self.iter().enumerate() {
-            if i % HEAP_PAGE_SIZE == 0 {
-                write!(f, "\nPage {}:", i / HEAP_PAGE_SIZE)?;
+            if (i as u64).is_multiple_of(self.page_size) {
+                write!(f, "\nPage {}:", i as u64 / self.page_size)?;
             }
             if i % Block::SIZE == 0 {
                 write!(f, "\n  Block {}:", i / Block::SIZE)?;
@@ -44,53 +120,90 @@ impl fmt::Debug for HeapMemory {
 }
 
 impl Memory for HeapMemory {
-    const PAGE_SIZE: usize = HEAP_PAGE_SIZE;
-    const MAX_PAGES: usize = 256;
-
-    fn page_count(&self) -> io::Result<usize> {
-        Ok(self.pages.len())
+    fn page_size(&self) -> u64 {
+        self.page_size
     }
 
-    fn grow(&mut self, num_pages: usize) -> io::Result<()> {
-        self.pages.extend(&vec![[0u8; HEAP_PAGE_SIZE]; num_pages]);
-        Ok(())
+    fn max_pages(&self) -> u64 {
+        self.max_pages
     }
 
-    fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<usize> {
-        let page_index = offset / HEAP_PAGE_SIZE;
-        let page_offset = offset % HEAP_PAGE_SIZE;
-
-        if page_index >= self.pages.len() {
-            return Ok(0);
-        }
-
-        let page = &self.pages[page_index];
-        let data_to_read = &page[page_offset..];
+    fn page_count(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64 / self.page_size)
+    }
 
-        let len_to_read = data_to_read.len().min(buf.len());
-        for i in 0..len_to_read {
-            buf[i] = data_to_read[i];
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        if self.page_count()? + num_pages > self.max_pages {
+            return Err(Error::OutOfSpace.into());
         }
+        let len = to_usize((self.page_count()? + num_pages) * self.page_size)?;
+        self.data.resize(len, 0);
+        Ok(())
+    }
 
-        Ok(len_to_read)
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let range = self.range(offset, buf.len());
+        let len = range.len();
+        buf[..len].copy_from_slice(&self.data[range]);
+        Ok(len)
     }
 
-    fn write(&mut self, offset: usize, buf: &[u8]) -> io::Result<usize> {
-        let page_index = offset / HEAP_PAGE_SIZE;
-        let page_offset = offset % HEAP_PAGE_SIZE;
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let range = self.range(offset, buf.len());
+        let len = range.len();
+        self.data[range].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
 
-        if page_index >= self.pages.len() {
-            return Ok(0);
+    fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let range = self.range(offset, to_usize(len)?);
+        if range.len() as u64 != len {
+            return Err(io::ErrorKind::WriteZero.into());
         }
+        self.data[range].fill(0);
+        Ok(())
+    }
+}
 
-        let page = &mut self.pages[page_index];
-        let data_to_write = &mut page[page_offset..];
+#[test]
+fn heap_memory() {
+    use crate::file_system::FileSystem;
+
+    let mut memory = HeapMemory::new(100, 3);
+    memory.grow(2).unwrap();
+    assert_eq!(memory.len().unwrap(), 200);
+    assert!(memory.grow(2).is_err());
+
+    // Reads and writes span pages, and stop at the end.
+    assert_eq!(memory.write(50, &[1u8; 300]).unwrap(), 150);
+    let mut buf = [0u8; 120];
+    assert_eq!(memory.read(40, &mut buf).unwrap(), 120);
+    assert_eq!(buf[..10], [0u8; 10]);
+    assert_eq!(buf[10..], [1u8; 110]);
+    assert_eq!(memory.read(200, &mut buf).unwrap(), 0);
+    assert!(memory.zero(150, 100).is_err());
+
+    // Filesystems can be far larger than the default allows.
+    let mut fs = FileSystem::new(HeapMemory::with_max_size(16 << 20)).unwrap();
+    let data = (0..4 << 20).map(|i| i as u8).collect::<Vec<u8>>();
+    fs.write_atomic(vec!["big"], &data[..]).unwrap();
+    let mut read = vec![];
+    fs.read_file(vec!["big"], &mut read).unwrap();
+    assert!(read == data);
+}
 
-        let len_to_write = data_to_write.len().min(buf.len());
-        for i in 0..len_to_write {
-            data_to_write[i] = buf[i];
-        }
+#[test]
+fn unbounded_heap_memory() {
+    use crate::file_system::FileSystem;
 
-        Ok(len_to_write)
-    }
+    let mut fs = FileSystem::new(UnboundedHeapMemory::default()).unwrap();
+    assert_eq!(fs.memory.max_size(), 65536 * 65535);
+
+    // Past the ceiling of the default heap memory, growing only as needed.
+    let data = vec![7u8; 1 << 20];
+    fs.write_atomic(vec!["big"], &data[..]).unwrap();
+    let mut read = vec![];
+    fs.read_file(vec!["big"], &mut read).unwrap();
+    assert!(read == data);
+    assert!(fs.memory.len().unwrap() < 4 << 20);
 }
@@ -0,0 +1,939 @@
+use std::io;
+
+use candid::{CandidType, Deserialize, Func, Principal};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use crate::access::{self, Access};
+use crate::directory::{Directory, Entry, EntryKind, Fallback};
+use crate::error::Error;
+use crate::file_system::FileSystem;
+use crate::memory::Memory;
+use crate::tokens;
+
+/// Served for directories which contain one.
+const INDEX: &str = "index.html";
+
+/// Bytes per response body, leaving room for the headers and the Candid
+/// encoding within the 2 MB a reply may take.
+pub(crate) const CHUNK_SIZE: u64 = 2_000_000 - (64 << 10);
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+/// Where the gateway picks up the rest of a file. Gateways pass it back as
+/// it is.
+#[derive(CandidType, Deserialize)]
+pub struct StreamingToken {
+    pub path: Vec<String>,
+    pub offset: u64,
+    /// The access token the file was requested with, if any.
+    pub access_token: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: Func,
+        token: StreamingToken,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingToken>,
+}
+
+fn text_response(status_code: u16, body: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_owned(), "text/plain".to_owned())],
+        body: body.as_bytes().to_vec(),
+        streaming_strategy: None,
+    }
+}
+
+/// A strong ETag from the checksum of the contents of `file`.
+pub fn etag<M: Memory>(fs: &FileSystem<M>, file: &Entry) -> io::Result<String> {
+    Ok(format!("\"{:016x}\"", file.content_checksum(fs)?))
+}
+
+/// Whether an `If-None-Match` value names `etag`. Weak tags match by their
+/// value, as the comparison for `If-None-Match` is weak.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// Sets the header `name`, replacing any of the same name.
+fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    headers.push((name.to_owned(), value.to_owned()));
+}
+
+/// Calls `f` with each directory along `path` which exists, from the
+/// root, with the path to it and its entry for the next name, if any.
+fn walk<M: Memory>(
+    fs: &FileSystem<M>,
+    path: &[String],
+    mut f: impl FnMut(&[String], &Directory, Option<&Entry>),
+) -> io::Result<()> {
+    let mut dir = fs.read_root_directory()?;
+    for (i, name) in path.iter().enumerate() {
+        let entry = dir.entry_with_name(name);
+        f(&path[..i], &dir, entry);
+        match entry {
+            Some(entry) if entry.kind == EntryKind::Directory => dir = fs.read_directory(entry)?,
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+/// The extra headers configured for `path`: those of the directories along
+/// the way, then those of the entry, each replacing the ones before it of
+/// the same name.
+fn configured_headers<M: Memory>(
+    fs: &FileSystem<M>,
+    path: &[String],
+) -> io::Result<Vec<(String, String)>> {
+    let mut headers = vec![];
+    walk(fs, path, |_, _, entry| {
+        for (name, value) in entry.iter().flat_map(|entry| entry.headers.iter()) {
+            set_header(&mut headers, name, value);
+        }
+    })?;
+    Ok(headers)
+}
+
+/// Up to a chunk of `file` from `offset`, and the token for the rest if
+/// there is more.
+fn read_chunk<M: Memory>(
+    fs: &FileSystem<M>,
+    file: &Entry,
+    path: Vec<String>,
+    offset: u64,
+    access_token: Option<String>,
+) -> io::Result<(Vec<u8>, Option<StreamingToken>)> {
+    let mut body = vec![0u8; file.size.saturating_sub(offset).min(CHUNK_SIZE) as usize];
+    let len = file.read_at(fs, offset, &mut body)?;
+    body.truncate(len);
+    let offset = offset + len as u64;
+    let token = Some(StreamingToken {
+        path,
+        offset,
+        access_token,
+    })
+    .filter(|_| offset < file.size);
+    Ok((body, token))
+}
+
+/// Serves the file at the path of `request.url`, or the `index.html` in the
+/// directory there. Paths are percent-decoded, and the query is ignored.
+/// Paths with a redirect set by `FileSystem::set_redirect`, or below a
+/// directory with one, are redirected instead, keeping the query.
+/// Directories without an index are listed if that's enabled for them, as
+/// HTML or as JSON if the request accepts it. Missing paths get the document
+/// their `Fallback` names, if any, as single-page apps expect. Responses
+/// carry an ETag, and a matching `If-None-Match` gets a 304 without the
+/// body. Headers set with `FileSystem::set_headers` are added to both,
+/// replacing the defaults.
+/// Files larger than a chunk come with a streaming strategy, for which the
+/// gateway calls `callback` with the token it's given. Responses aren't
+/// certified, so gateways which insist on certification refuse them.
+/// Requests come from the anonymous principal, so paths it may not read
+/// are forbidden, unless the `token` query parameter holds an access token
+/// for them which hasn't expired.
+pub fn http_request<M: Memory>(
+    fs: &FileSystem<M>,
+    request: HttpRequest,
+    callback: Func,
+) -> HttpResponse {
+    if request.method != "GET" && request.method != "HEAD" {
+        return text_response(405, "Method not allowed");
+    }
+    let url_path = request
+        .url
+        .split(['?', '#'].as_ref())
+        .next()
+        .unwrap_or_default();
+    let path = url_path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| percent_decode_str(s).decode_utf8().map(|s| s.into_owned()))
+        .collect::<Result<Vec<_>, _>>();
+    let mut path = match path {
+        Ok(path) => path,
+        Err(_) => return text_response(400, "Bad request"),
+    };
+    let access_token = query_param(&request.url, "token");
+    match readable(fs, &path, access_token.as_deref()) {
+        Ok(true) => {}
+        Ok(false) => return text_response(403, "Forbidden"),
+        Err(_) => return text_response(500, "Internal server error"),
+    }
+    match redirect(fs, &path) {
+        Ok(Some((status_code, mut location))) => {
+            let url = request.url.split('#').next().unwrap_or_default();
+            if let Some(i) = url.find('?') {
+                location.push_str(&url[i..]);
+            }
+            return HttpResponse {
+                status_code,
+                headers: vec![("Location".to_owned(), location)],
+                body: vec![],
+                streaming_strategy: None,
+            };
+        }
+        Ok(None) => {}
+        Err(_) => return text_response(500, "Internal server error"),
+    }
+    let mut listed = None;
+    if matches!(fs.metadata(&path), Ok(meta) if meta.kind == EntryKind::Directory) {
+        listed = Some(path.clone());
+        path.push(INDEX.to_owned());
+    }
+    let if_none_match = request
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("If-None-Match"))
+        .map(|(_, value)| value.as_str());
+    let json = request.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("Accept") && value.contains("application/json")
+    });
+    let serve = |path, callback| {
+        let method = &request.method;
+        serve_file(
+            fs,
+            path,
+            method,
+            if_none_match,
+            access_token.as_deref(),
+            callback,
+        )
+    };
+
+    let result = match serve(path.clone(), callback.clone()) {
+        Err(e) => match Error::from(e) {
+            Error::NotFound | Error::IsADirectory | Error::NotADirectory => match listed {
+                Some(dir) if matches!(listing_enabled(fs, &dir), Ok(true)) => {
+                    list_directory(fs, &dir, json, &request.method)
+                }
+                _ => match fallback_document(fs, &path) {
+                    Ok(Some(document)) => serve(document, callback),
+                    Ok(None) => Err(Error::NotFound.into()),
+                    Err(e) => Err(e),
+                },
+            },
+            e => Err(e.into()),
+        },
+        response => response,
+    };
+    match result {
+        Ok(response) => response,
+        Err(e) => match Error::from(e) {
+            Error::NotFound | Error::IsADirectory | Error::NotADirectory => {
+                text_response(404, "Not found")
+            }
+            _ => text_response(500, "Internal server error"),
+        },
+    }
+}
+
+fn serve_file<M: Memory>(
+    fs: &FileSystem<M>,
+    path: Vec<String>,
+    method: &str,
+    if_none_match: Option<&str>,
+    access_token: Option<&str>,
+    callback: Func,
+) -> io::Result<HttpResponse> {
+    fs.with_file(path.clone(), |file| {
+        let etag = etag(fs, file)?;
+        let configured = configured_headers(fs, &path)?;
+        let with_configured = |mut headers| {
+            for (name, value) in configured.iter() {
+                set_header(&mut headers, name, value);
+            }
+            headers
+        };
+        if matches!(if_none_match, Some(tags) if etag_matches(tags, &etag)) {
+            return Ok(HttpResponse {
+                status_code: 304,
+                headers: with_configured(vec![("ETag".to_owned(), etag)]),
+                body: vec![],
+                streaming_strategy: None,
+            });
+        }
+        let content_type = match file.content_type.as_str() {
+            "" => "application/octet-stream",
+            content_type => content_type,
+        };
+        let (body, token) = match method {
+            "GET" => read_chunk(fs, file, path, 0, access_token.map(str::to_owned))?,
+            _ => (vec![], None),
+        };
+        let streaming_strategy = token.map(|token| StreamingStrategy::Callback { callback, token });
+        Ok(HttpResponse {
+            status_code: 200,
+            headers: with_configured(vec![
+                ("Content-Type".to_owned(), content_type.to_owned()),
+                ("Content-Length".to_owned(), file.size.to_string()),
+                ("ETag".to_owned(), etag),
+            ]),
+            body,
+            streaming_strategy,
+        })
+    })
+}
+
+/// The document to serve for the missing `path`: the nearest
+/// `Fallback::Document` in the directories along it which exist, unless a
+/// directory after it has fallbacks disabled.
+fn fallback_document<M: Memory>(
+    fs: &FileSystem<M>,
+    path: &[String],
+) -> io::Result<Option<Vec<String>>> {
+    let mut document = None;
+    walk(fs, path, |parent, dir, entry| {
+        let found = dir
+            .entries
+            .iter()
+            .find(|e| e.kind == EntryKind::File && e.fallback == Fallback::Document);
+        if let Some(found) = found {
+            let mut found_path = parent.to_vec();
+            found_path.push(found.name.clone());
+            document = Some(found_path);
+        }
+        if matches!(entry, Some(entry) if entry.fallback == Fallback::Disabled) {
+            document = None;
+        }
+    })?;
+    Ok(document)
+}
+
+/// The status and location of the redirect for `path`, set on it or on a
+/// directory above it. The rest of the path below that directory is added
+/// to its location.
+fn redirect<M: Memory>(fs: &FileSystem<M>, path: &[String]) -> io::Result<Option<(u16, String)>> {
+    let mut found = None;
+    walk(fs, path, |parent, _, entry| {
+        if let (None, Some(redirect)) = (&found, entry.and_then(|e| e.redirect.as_ref())) {
+            let mut location = redirect.location.clone();
+            for name in &path[parent.len() + 1..] {
+                if !location.ends_with('/') {
+                    location.push('/');
+                }
+                location.extend(utf8_percent_encode(name, SEGMENT));
+            }
+            found = Some((redirect.status, location));
+        }
+    })?;
+    Ok(found)
+}
+
+/// Whether the anonymous principal the gateway calls with may read `path`,
+/// or `access_token` lets it.
+fn readable<M: Memory>(
+    fs: &FileSystem<M>,
+    path: &[String],
+    access_token: Option<&str>,
+) -> io::Result<bool> {
+    let anonymous = Principal::anonymous().to_text();
+    match access::check(fs, path, &anonymous, Access::Read) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => match access_token {
+            Some(token) => tokens::grants(fs, path, token, fs.now()),
+            None => Ok(false),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// The percent-decoded value of the parameter `name` in the query of `url`.
+fn query_param(url: &str, name: &str) -> Option<String> {
+    let url = url.split('#').next().unwrap_or_default();
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode_str(value).decode_utf8().ok())
+        .map(|value| value.into_owned())
+}
+
+/// The path and query of the URL which reads `path` with `access_token`.
+pub fn token_url(path: &[String], access_token: &str) -> String {
+    let mut url = String::new();
+    for name in path {
+        url.push('/');
+        url.extend(utf8_percent_encode(name, SEGMENT));
+    }
+    if url.is_empty() {
+        url.push('/');
+    }
+    format!("{}?token={}", url, access_token)
+}
+
+/// Whether listing is enabled for the directory at `path`, or one above it.
+fn listing_enabled<M: Memory>(fs: &FileSystem<M>, path: &[String]) -> io::Result<bool> {
+    let mut enabled = false;
+    walk(fs, path, |_, _, entry| {
+        enabled |= matches!(entry, Some(entry) if entry.listing);
+    })?;
+    Ok(enabled)
+}
+
+/// Characters which can't appear as they are in a path segment of a link.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// The URL path of the directory at `path`, with a trailing `/`.
+fn directory_url(path: &[String]) -> String {
+    let mut url = "/".to_owned();
+    for name in path {
+        url.extend(utf8_percent_encode(name, SEGMENT));
+        url.push('/');
+    }
+    url
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Formats nanoseconds since the epoch as a UTC date and time.
+fn format_time(nanos: u64) -> String {
+    let secs = nanos / 1_000_000_000;
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Lists the directory at `path` as HTML, or as JSON with `json`: an array
+/// of objects with the `name`, `kind`, `size` of files and `modified` time
+/// in nanoseconds of each entry.
+fn list_directory<M: Memory>(
+    fs: &FileSystem<M>,
+    path: &[String],
+    json: bool,
+    method: &str,
+) -> io::Result<HttpResponse> {
+    let dir = fs.directory_at(path)?;
+    let (content_type, body) = if json {
+        let entries = dir
+            .entries
+            .iter()
+            .map(|entry| match entry.kind {
+                EntryKind::File => format!(
+                    "{{\"name\":{},\"kind\":\"file\",\"size\":{},\"modified\":{}}}",
+                    escape_json(&entry.name),
+                    entry.size,
+                    entry.modified
+                ),
+                EntryKind::Directory => format!(
+                    "{{\"name\":{},\"kind\":\"directory\",\"modified\":{}}}",
+                    escape_json(&entry.name),
+                    entry.modified
+                ),
+            })
+            .collect::<Vec<_>>();
+        ("application/json", format!("[{}]", entries.join(",")))
+    } else {
+        let url = directory_url(path);
+        let title = escape_html(&format!("Index of /{}", path.join("/")));
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+             <body>\n<h1>{0}</h1>\n<table>\n\
+             <tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n",
+            title
+        );
+        if let Some((_, parent)) = path.split_last() {
+            html += &format!(
+                "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>\n",
+                escape_html(&directory_url(parent))
+            );
+        }
+        for entry in dir.entries.iter() {
+            let (slash, size) = match entry.kind {
+                EntryKind::File => ("", entry.size.to_string()),
+                EntryKind::Directory => ("/", "-".to_owned()),
+            };
+            let href = format!(
+                "{}{}{}",
+                url,
+                utf8_percent_encode(&entry.name, SEGMENT),
+                slash
+            );
+            html += &format!(
+                "<tr><td><a href=\"{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                escape_html(&href),
+                escape_html(&entry.name),
+                slash,
+                size,
+                format_time(entry.modified)
+            );
+        }
+        html += "</table>\n</body>\n</html>\n";
+        ("text/html; charset=utf-8", html)
+    };
+    let mut headers = vec![
+        ("Content-Type".to_owned(), content_type.to_owned()),
+        ("Content-Length".to_owned(), body.len().to_string()),
+    ];
+    for (name, value) in configured_headers(fs, path)? {
+        set_header(&mut headers, &name, &value);
+    }
+    Ok(HttpResponse {
+        status_code: 200,
+        headers,
+        body: match method {
+            "GET" => body.into_bytes(),
+            _ => vec![],
+        },
+        streaming_strategy: None,
+    })
+}
+
+/// The chunk `token` points to, and the token for the rest. A file which
+/// shrank in between ends early.
+pub fn http_request_streaming_callback<M: Memory>(
+    fs: &FileSystem<M>,
+    token: StreamingToken,
+) -> io::Result<StreamingCallbackHttpResponse> {
+    let StreamingToken {
+        path,
+        offset,
+        access_token,
+    } = token;
+    if !readable(fs, &path, access_token.as_deref())? {
+        return Err(Error::AccessDenied.into());
+    }
+    fs.with_file(path.clone(), |file| {
+        let (body, token) = read_chunk(fs, file, path, offset, access_token)?;
+        Ok(StreamingCallbackHttpResponse { body, token })
+    })
+}
+
+#[test]
+fn serve_files() {
+    use crate::heap_memory::HeapMemory;
+    use candid::Principal;
+
+    let mut fs = FileSystem::new(HeapMemory::with_max_size(16 << 20)).unwrap();
+    fs.make_directory_recursive(vec!["docs", "a b"]).unwrap();
+    fs.with_directory_mut(vec!["docs"], |dir, _| {
+        dir.add_file("index.html", "text/html").map(drop)
+    })
+    .unwrap();
+    fs.write_atomic(vec!["docs", "index.html"], &b"<h1>hi</h1>"[..])
+        .unwrap();
+    let large = (0..CHUNK_SIZE * 2 + 10)
+        .map(|i| i as u8)
+        .collect::<Vec<_>>();
+    fs.write_atomic(vec!["docs", "a b", "large.bin"], &large[..])
+        .unwrap();
+
+    fn request(
+        fs: &FileSystem<HeapMemory>,
+        method: &str,
+        url: &str,
+        if_none_match: Option<&str>,
+    ) -> HttpResponse {
+        let request = HttpRequest {
+            method: method.to_owned(),
+            url: url.to_owned(),
+            headers: if_none_match
+                .map(|tags| ("if-none-match".to_owned(), tags.to_owned()))
+                .into_iter()
+                .collect(),
+            body: vec![],
+        };
+        let callback = Func {
+            principal: Principal::anonymous(),
+            method: "http_request_streaming_callback".to_owned(),
+        };
+        http_request(fs, request, callback)
+    }
+    let get = |fs: &FileSystem<HeapMemory>, url: &str| request(fs, "GET", url, None);
+    let response = get(&fs, "/docs/?lang=en");
+    assert_eq!(response.status_code, 200);
+    assert_eq!(response.body, b"<h1>hi</h1>");
+    assert_eq!(response.headers[0].1, "text/html");
+    assert!(response.streaming_strategy.is_none());
+    assert_eq!(get(&fs, "/docs/missing").status_code, 404);
+    assert_eq!(get(&fs, "/docs/a%20b").status_code, 404);
+    assert_eq!(request(&fs, "POST", "/docs/", None).status_code, 405);
+
+    // Configured headers, nearer ones first.
+    let header = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+    fs.set_headers(
+        vec!["docs"],
+        vec![
+            header("Cache-Control", "max-age=60"),
+            header("X-Frame-Options", "DENY"),
+        ],
+    )
+    .unwrap();
+    fs.set_headers(
+        vec!["docs", "index.html"],
+        vec![header("cache-control", "no-cache")],
+    )
+    .unwrap();
+    let headers = get(&fs, "/docs/").headers;
+    assert_eq!(
+        headers[3..],
+        [
+            header("X-Frame-Options", "DENY"),
+            header("cache-control", "no-cache")
+        ]
+    );
+    assert!(fs
+        .set_headers(vec!["docs"], vec![header("X: Y", "")])
+        .is_err());
+    assert!(fs
+        .set_headers(vec!["docs"], vec![header("X", "a\r\nb")])
+        .is_err());
+
+    // Unchanged files aren't sent again.
+    let etag = response.headers[2].1.clone();
+    let tags = format!("\"x\", W/{}", etag);
+    let response = request(&fs, "GET", "/docs/", Some(&tags));
+    assert_eq!(response.status_code, 304);
+    assert!(response.body.is_empty());
+    let response = request(&fs, "GET", "/docs/", Some("\"x\""));
+    assert_eq!(response.status_code, 200);
+    fs.write_atomic(vec!["docs", "index.html"], &b"<h1>yo</h1>"[..])
+        .unwrap();
+    let response = request(&fs, "GET", "/docs/", Some(&etag));
+    assert_eq!(response.status_code, 200);
+
+    // Large files are streamed in chunks.
+    let response = get(&fs, "/docs/a%20b/large.bin");
+    assert_eq!(response.headers[0].1, "application/octet-stream");
+    assert_eq!(response.body.len() as u64, CHUNK_SIZE);
+    let mut body = response.body;
+    let mut token = response
+        .streaming_strategy
+        .map(|StreamingStrategy::Callback { token, .. }| token);
+    while let Some(next) = token {
+        let response = http_request_streaming_callback(&fs, next).unwrap();
+        body.extend(response.body);
+        token = response.token;
+    }
+    assert_eq!(body, large);
+}
+
+#[test]
+fn streaming() {
+    use crate::heap_memory::HeapMemory;
+    use candid::Principal;
+
+    let mut fs = FileSystem::new(HeapMemory::with_max_size(16 << 20)).unwrap();
+    let large = (0..CHUNK_SIZE * 2 + 10)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    fs.write_atomic(vec!["large.bin"], &large[..]).unwrap();
+    let path = vec!["large.bin".to_owned()];
+    let token = |offset| StreamingToken {
+        path: path.clone(),
+        offset,
+        access_token: None,
+    };
+
+    // The token carries the path and the offset to go on from.
+    let request = |method: &str| HttpRequest {
+        method: method.to_owned(),
+        url: "/large.bin?token=abc".to_owned(),
+        headers: vec![],
+        body: vec![],
+    };
+    let callback = Func {
+        principal: Principal::anonymous(),
+        method: "http_request_streaming_callback".to_owned(),
+    };
+    let response = http_request(&fs, request("GET"), callback.clone());
+    let StreamingStrategy::Callback { token: first, .. } = response.streaming_strategy.unwrap();
+    assert_eq!(
+        (&first.path, first.offset, first.access_token.as_deref()),
+        (&path, CHUNK_SIZE, Some("abc"))
+    );
+    let response = http_request(&fs, request("HEAD"), callback);
+    assert!(response.body.is_empty() && response.streaming_strategy.is_none());
+
+    // Any offset can be picked up from.
+    let response = http_request_streaming_callback(&fs, token(CHUNK_SIZE * 2 - 5)).unwrap();
+    assert_eq!(response.body, large[(CHUNK_SIZE * 2 - 5) as usize..]);
+    assert!(response.token.is_none());
+
+    // A file which shrinks in between ends early.
+    let rest = http_request_streaming_callback(&fs, token(CHUNK_SIZE))
+        .unwrap()
+        .token
+        .unwrap();
+    assert_eq!(rest.offset, CHUNK_SIZE * 2);
+    fs.write_atomic(vec!["large.bin"], &large[..100]).unwrap();
+    let response = http_request_streaming_callback(&fs, rest).unwrap();
+    assert!(response.body.is_empty() && response.token.is_none());
+}
+
+#[test]
+fn fallback() {
+    use crate::heap_memory::HeapMemory;
+    use candid::Principal;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(vec!["assets"]).unwrap();
+    fs.make_directory_recursive(vec!["admin"]).unwrap();
+    fs.write_atomic(vec!["index.html"], &b"app"[..]).unwrap();
+    fs.write_atomic(vec!["admin", "app.html"], &b"admin"[..])
+        .unwrap();
+    fs.write_atomic(vec!["assets", "a.css"], &b"a"[..]).unwrap();
+    let get = |fs: &FileSystem<HeapMemory>, url: &str| {
+        let request = HttpRequest {
+            method: "GET".to_owned(),
+            url: url.to_owned(),
+            headers: vec![],
+            body: vec![],
+        };
+        let callback = Func {
+            principal: Principal::anonymous(),
+            method: "http_request_streaming_callback".to_owned(),
+        };
+        let response = http_request(fs, request, callback);
+        (
+            response.status_code,
+            String::from_utf8(response.body).unwrap(),
+        )
+    };
+    assert_eq!(get(&fs, "/users/1").0, 404);
+
+    fs.set_fallback(vec!["index.html"], Fallback::Document)
+        .unwrap();
+    fs.set_fallback(vec!["admin", "app.html"], Fallback::Document)
+        .unwrap();
+    fs.set_fallback(vec!["assets"], Fallback::Disabled).unwrap();
+    assert_eq!(get(&fs, "/users/1"), (200, "app".to_owned()));
+    assert_eq!(get(&fs, "/admin/"), (200, "admin".to_owned()));
+    assert_eq!(get(&fs, "/admin/users/1"), (200, "admin".to_owned()));
+    assert_eq!(get(&fs, "/assets/a.css"), (200, "a".to_owned()));
+    assert_eq!(get(&fs, "/assets/b.css").0, 404);
+    assert!(fs.set_fallback(vec!["assets"], Fallback::Document).is_err());
+    assert!(fs
+        .set_fallback(vec!["index.html"], Fallback::Disabled)
+        .is_err());
+}
+
+#[test]
+fn listing() {
+    use crate::heap_memory::HeapMemory;
+    use candid::Principal;
+
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_clock(|| 1_700_000_000_000_000_000);
+    fs.make_directory_recursive(vec!["dist", "v1"]).unwrap();
+    fs.make_directory_recursive(vec!["other"]).unwrap();
+    fs.write_atomic(vec!["dist", "a b.txt"], &b"hello"[..])
+        .unwrap();
+    fs.write_atomic(vec!["dist", "v1", "<x>.bin"], &b"x"[..])
+        .unwrap();
+    fs.set_listing(vec!["dist"], true).unwrap();
+    assert!(fs.set_listing(vec!["dist", "a b.txt"], true).is_err());
+    let get = |fs: &FileSystem<HeapMemory>, url: &str, accept: &str| {
+        let request = HttpRequest {
+            method: "GET".to_owned(),
+            url: url.to_owned(),
+            headers: vec![("Accept".to_owned(), accept.to_owned())],
+            body: vec![],
+        };
+        let callback = Func {
+            principal: Principal::anonymous(),
+            method: "http_request_streaming_callback".to_owned(),
+        };
+        let response = http_request(fs, request, callback);
+        (
+            response.status_code,
+            String::from_utf8(response.body).unwrap(),
+        )
+    };
+
+    let (status, html) = get(&fs, "/dist", "text/html");
+    assert_eq!(status, 200);
+    assert!(html.contains("<title>Index of /dist</title>"));
+    assert!(html.contains("<a href=\"/\">../</a>"));
+    assert!(html.contains(
+        "<a href=\"/dist/a%20b.txt\">a b.txt</a></td><td>5</td><td>2023-11-14 22:13:20</td>"
+    ));
+    assert!(html.contains("<a href=\"/dist/v1/\">v1/</a>"));
+    let (status, html) = get(&fs, "/dist/v1/", "text/html");
+    assert_eq!(status, 200);
+    assert!(html.contains("<a href=\"/dist/v1/%3Cx%3E.bin\">&lt;x&gt;.bin</a>"));
+    let (status, json) = get(&fs, "/dist/v1/", "application/json, */*");
+    assert_eq!(status, 200);
+    assert_eq!(
+        json,
+        r#"[{"name":"<x>.bin","kind":"file","size":1,"modified":1700000000000000000}]"#
+    );
+    assert_eq!(get(&fs, "/other/", "text/html").0, 404);
+
+    // An index takes precedence.
+    fs.write_atomic(vec!["dist", "index.html"], &b"index"[..])
+        .unwrap();
+    assert_eq!(get(&fs, "/dist/", "text/html"), (200, "index".to_owned()));
+    assert_eq!(format_time(0), "1970-01-01 00:00:00");
+}
+
+#[test]
+fn redirects() {
+    use crate::directory::{Acl, Redirect};
+    use crate::heap_memory::HeapMemory;
+    use candid::Principal;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(vec!["old"]).unwrap();
+    fs.write_atomic(vec!["old", "a.txt"], &b"a"[..]).unwrap();
+    let redirect = |status, location: &str| {
+        Some(Redirect {
+            status,
+            location: location.to_owned(),
+        })
+    };
+    fs.set_redirect(vec!["old"], redirect(301, "/new/"))
+        .unwrap();
+    fs.set_redirect(
+        vec!["blog", "post"],
+        redirect(302, "https://example.com/post"),
+    )
+    .unwrap();
+    assert!(fs.set_redirect(vec!["x"], redirect(200, "/")).is_err());
+    assert!(fs.set_redirect(vec!["x"], redirect(301, "")).is_err());
+    assert!(fs.set_redirect(vec!["x"], None).is_err());
+    let get = |fs: &FileSystem<HeapMemory>, url: &str| {
+        let request = HttpRequest {
+            method: "GET".to_owned(),
+            url: url.to_owned(),
+            headers: vec![],
+            body: vec![],
+        };
+        let callback = Func {
+            principal: Principal::anonymous(),
+            method: "http_request_streaming_callback".to_owned(),
+        };
+        let response = http_request(fs, request, callback);
+        let location = response
+            .headers
+            .into_iter()
+            .find(|(name, _)| name == "Location")
+            .map(|(_, value)| value);
+        (response.status_code, location)
+    };
+
+    assert_eq!(get(&fs, "/old"), (301, Some("/new/".to_owned())));
+    assert_eq!(
+        get(&fs, "/old/a%20b/c.txt?v=1#top"),
+        (301, Some("/new/a%20b/c.txt?v=1".to_owned()))
+    );
+    assert_eq!(
+        get(&fs, "/blog/post"),
+        (302, Some("https://example.com/post".to_owned()))
+    );
+    assert_eq!(fs.metadata(vec!["blog", "post"]).unwrap().size, 0);
+
+    fs.set_redirect(vec!["old"], None).unwrap();
+    assert_eq!(get(&fs, "/old/a.txt"), (200, None));
+
+    // Paths the anonymous principal may not read are forbidden.
+    let private = Acl {
+        writers: vec!["aaaaa-aa".to_owned()],
+        ..Default::default()
+    };
+    fs.set_acl(Vec::<String>::new(), Some(private)).unwrap();
+    assert_eq!(get(&fs, "/old/a.txt"), (403, None));
+    let public = Acl {
+        readers: vec![Acl::EVERYONE.to_owned()],
+        ..Default::default()
+    };
+    fs.set_acl(vec!["old"], Some(public)).unwrap();
+    assert_eq!(get(&fs, "/old/a.txt"), (200, None));
+
+    // Access tokens let requests read private paths until they expire.
+    fs.set_acl(vec!["old"], None).unwrap();
+    tokens::set_secret(&mut fs, Some(b"secret")).unwrap();
+    let token = tokens::sign(b"secret", &["old".to_owned()], 10);
+    let url = token_url(&["old".to_owned(), "a.txt".to_owned()], &token);
+    assert_eq!(url, format!("/old/a.txt?token={}", token));
+    assert_eq!(get(&fs, "/old/a.txt"), (403, None));
+    assert_eq!(get(&fs, &url), (200, None));
+    assert_eq!(get(&fs, &format!("/b.txt?token={}", token)), (403, None));
+    fs.set_clock(|| 10);
+    assert_eq!(get(&fs, &url), (403, None));
+}
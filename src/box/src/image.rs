@@ -0,0 +1,416 @@
+use std::io::{self, Read, Write};
+
+use crate::checksum::Checksum;
+use crate::directory::{Acl, Directory, Entry, EntryKind, Fallback, Redirect};
+use crate::error::Error;
+use crate::file_system::{DropPolicy, FileSystem};
+use crate::memory::Memory;
+use crate::serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 8] = b"BOXIMAGE";
+/// Version 1 images lack the headers, version 2 images the fallback,
+/// version 3 images the listing flag, version 4 images the redirect and
+/// version 5 images the ACLs and version 6 images the extended attributes.
+/// All of them are still read.
+const VERSION: u64 = 7;
+
+const END: u8 = 0;
+const FILE: u8 = 1;
+const DIRECTORY: u8 = 2;
+
+/// Passes everything through while keeping a checksum of it.
+struct Checksummed<T> {
+    inner: T,
+    checksum: Checksum,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Checksummed {
+            inner,
+            checksum: Checksum::default(),
+        }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.checksum.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.checksum.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// An image holds the tree rather than the blocks, so it doesn't depend on
+/// the memory, its page size or how the blocks are laid out:
+///
+/// ```text
+/// "BOXIMAGE" version acl
+/// (kind path content_type created modified expires sealed headers fallback listing redirect acl xattrs size contents)*
+/// 0 checksum
+/// ```
+///
+/// Entries come in tree order, each directory before its entries, after the
+/// ACL of the root directory. `expires` is 0 for entries which don't
+/// expire, and `redirect` is a status followed by the location, or 0 alone
+/// for entries without one. `acl` is 1 followed by the owner, writers and
+/// readers, or 0 alone. `xattrs` is their count followed by names and
+/// values, each value its length and bytes. Directories have a `size` of 0
+/// and no contents. The checksum covers everything before it.
+impl<M: Memory> FileSystem<M> {
+    /// Writes an image of the whole tree to `w`, which `import` turns back
+    /// into a filesystem on any memory. Returns the length of the image.
+    pub fn export(&self, w: impl Write) -> io::Result<u64> {
+        let mut w = Checksummed::new(io::BufWriter::new(w));
+        let mut written = MAGIC.len() as u64;
+        w.write_all(MAGIC)?;
+        written += VERSION.serialize(&mut w)? as u64;
+
+        let root = self.read_root_directory()?;
+        written += export_acl(&mut w, &root.acl)? as u64;
+        let mut pending = vec![(vec![], root)];
+        while let Some((path, dir)) = pending.pop() {
+            for entry in dir.entries.into_iter().rev() {
+                let mut entry_path: Vec<String> = path.clone();
+                entry_path.push(entry.name.clone());
+                written += self.export_entry(&mut w, &entry_path, &entry)?;
+                if entry.kind == EntryKind::Directory {
+                    pending.push((entry_path, self.read_directory(&entry)?));
+                }
+            }
+        }
+
+        written += END.serialize(&mut w)? as u64;
+        let checksum = w.checksum.value();
+        written += checksum.serialize(&mut w.inner)? as u64;
+        w.flush()?;
+        Ok(written)
+    }
+
+    fn export_entry(&self, mut w: impl Write, path: &[String], entry: &Entry) -> io::Result<u64> {
+        let kind = match entry.kind {
+            EntryKind::File => FILE,
+            EntryKind::Directory => DIRECTORY,
+        };
+        let mut written = kind.serialize(&mut w)? + path.len().serialize(&mut w)?;
+        for name in path {
+            written += name.as_str().serialize(&mut w)?;
+        }
+        written += entry.content_type.as_str().serialize(&mut w)?
+            + entry.created.serialize(&mut w)?
+            + entry.modified.serialize(&mut w)?
+            + entry.expires.unwrap_or(0).serialize(&mut w)?
+            + (entry.sealed as u8).serialize(&mut w)?
+            + entry.headers.len().serialize(&mut w)?;
+        for (name, value) in entry.headers.iter() {
+            written += name.as_str().serialize(&mut w)? + value.as_str().serialize(&mut w)?;
+        }
+        let fallback = match entry.fallback {
+            Fallback::Inherit => 0u8,
+            Fallback::Document => 1,
+            Fallback::Disabled => 2,
+        };
+        written += fallback.serialize(&mut w)? + (entry.listing as u8).serialize(&mut w)?;
+        written += match &entry.redirect {
+            Some(Redirect { status, location }) => {
+                (*status as u64).serialize(&mut w)? + location.as_str().serialize(&mut w)?
+            }
+            None => 0u64.serialize(&mut w)?,
+        };
+        written += export_acl(&mut w, &entry.acl)? + entry.xattrs.len().serialize(&mut w)?;
+        for (name, value) in entry.xattrs.iter() {
+            written += name.as_str().serialize(&mut w)?
+                + value.len().serialize(&mut w)?
+                + value.as_slice().serialize(&mut w)?;
+        }
+
+        let mut written = written as u64;
+        match entry.kind {
+            EntryKind::File => {
+                written += entry.size.serialize(&mut w)? as u64;
+                written += io::copy(&mut entry.read_from_file_system(self)?, &mut w)?;
+            }
+            EntryKind::Directory => written += 0u64.serialize(&mut w)? as u64,
+        }
+        Ok(written)
+    }
+
+    /// Builds a filesystem on `memory` from an image written by `export`.
+    /// Fails if the image is of an unknown version or doesn't match its
+    /// checksum, in which case nothing is persisted to `memory`.
+    pub fn import(memory: M, r: impl Read) -> io::Result<Self> {
+        let mut fs = Self::new(memory)?;
+        match fs.import_entries(r) {
+            Ok(()) => Ok(fs),
+            Err(e) => {
+                fs.set_drop_policy(DropPolicy::Ignore);
+                Err(e)
+            }
+        }
+    }
+
+    fn import_entries(&mut self, r: impl Read) -> io::Result<()> {
+        let mut r = Checksummed::new(io::BufReader::new(r));
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::corrupted("not a filesystem image").into());
+        }
+        let version = u64::deserialize_into_default(&mut r)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(Error::corrupted(format!("unsupported image version {}", version)).into());
+        }
+
+        let root_acl = if version > 5 {
+            import_acl(&mut r)?
+        } else {
+            None
+        };
+
+        // Metadata is set once all entries are in place, as adding entries
+        // moves the modification time of directories.
+        let mut imported = vec![];
+        loop {
+            let kind = u8::deserialize_into_default(&mut r)?;
+            let kind = match kind {
+                END => break,
+                FILE => EntryKind::File,
+                DIRECTORY => EntryKind::Directory,
+                kind => return Err(Error::corrupted(format!("bad entry kind {}", kind)).into()),
+            };
+            let (path, entry) = Self::import_entry(&mut r, kind, version)?;
+            self.create_entry(&path, &entry)?;
+            if kind == EntryKind::File {
+                let copied = self.with_entry_mut(path.clone(), |file, fs| {
+                    io::copy(
+                        &mut (&mut r).take(entry.size),
+                        &mut file.write_to_file_system(fs)?,
+                    )
+                })?;
+                if copied != entry.size {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+            imported.push((path, entry));
+        }
+
+        let expected = r.checksum.value();
+        if u64::deserialize_into_default(&mut r.inner)? != expected {
+            return Err(Error::corrupted("image doesn't match its checksum").into());
+        }
+        for (path, entry) in imported {
+            self.copy_metadata(path, &entry)?;
+        }
+        if root_acl.is_some() {
+            self.set_acl(Vec::<String>::new(), root_acl)?;
+        }
+        self.persist()
+    }
+
+    fn import_entry(
+        mut r: impl Read,
+        kind: EntryKind,
+        version: u64,
+    ) -> io::Result<(Vec<String>, Entry)> {
+        let mut path = vec![];
+        for _ in 0..usize::deserialize_into_default(&mut r)? {
+            path.push(String::deserialize_into_default(&mut r)?);
+        }
+        let mut entry = Entry {
+            kind,
+            name: path.last().cloned().ok_or(Error::InvalidPath)?,
+            ..Default::default()
+        };
+        let mut sealed = 0u8;
+        let mut expires = 0u64;
+        entry.content_type.deserialize(&mut r)?;
+        entry.created.deserialize(&mut r)?;
+        entry.modified.deserialize(&mut r)?;
+        expires.deserialize(&mut r)?;
+        sealed.deserialize(&mut r)?;
+        if version > 1 {
+            for _ in 0..usize::deserialize_into_default(&mut r)? {
+                let name = String::deserialize_into_default(&mut r)?;
+                entry
+                    .headers
+                    .push((name, String::deserialize_into_default(&mut r)?));
+            }
+        }
+        if version > 2 {
+            entry.fallback = match u8::deserialize_into_default(&mut r)? {
+                0 => Fallback::Inherit,
+                1 => Fallback::Document,
+                2 => Fallback::Disabled,
+                n => return Err(Error::corrupted(format!("bad fallback {}", n)).into()),
+            };
+        }
+        if version > 3 {
+            entry.listing = u8::deserialize_into_default(&mut r)? != 0;
+        }
+        if version > 4 {
+            entry.redirect = match u64::deserialize_into_default(&mut r)? {
+                0 => None,
+                status @ (301 | 302 | 307 | 308) => Some(Redirect {
+                    status: status as u16,
+                    location: String::deserialize_into_default(&mut r)?,
+                }),
+                n => return Err(Error::corrupted(format!("bad redirect status {}", n)).into()),
+            };
+        }
+        if version > 5 {
+            entry.acl = import_acl(&mut r)?;
+        }
+        if version > 6 {
+            for _ in 0..usize::deserialize_into_default(&mut r)? {
+                let name = String::deserialize_into_default(&mut r)?;
+                let mut value = vec![0; usize::deserialize_into_default(&mut r)?];
+                value.as_mut_slice().deserialize(&mut r)?;
+                entry.xattrs.push((name, value));
+            }
+        }
+        entry.size.deserialize(&mut r)?;
+        entry.expires = Some(expires).filter(|&expires| expires != 0);
+        entry.sealed = sealed != 0;
+        Ok((path, entry))
+    }
+
+    /// Adds an empty entry like `entry` at `path`, in an existing directory.
+    pub(crate) fn create_entry(&mut self, path: &[String], entry: &Entry) -> io::Result<()> {
+        let (name, parent) = path.split_last().ok_or(Error::InvalidPath)?;
+        self.with_directory_mut(parent, |dir, fs| match entry.kind {
+            EntryKind::File => {
+                let f = dir.add_file(name, entry.content_type.clone())?;
+                f.headers = entry.headers.clone();
+                f.fallback = entry.fallback;
+                f.redirect = entry.redirect.clone();
+                f.acl = entry.acl.clone();
+                f.xattrs = entry.xattrs.clone();
+                Ok(())
+            }
+            EntryKind::Directory => {
+                let d = dir.add_directory(name)?;
+                d.headers = entry.headers.clone();
+                d.fallback = entry.fallback;
+                d.listing = entry.listing;
+                d.redirect = entry.redirect.clone();
+                d.acl = entry.acl.clone();
+                d.xattrs = entry.xattrs.clone();
+                fs.write_directory(d, &mut Directory::default())
+            }
+        })
+    }
+}
+
+fn export_acl(mut w: impl Write, acl: &Option<Acl>) -> io::Result<usize> {
+    let acl = match acl {
+        Some(acl) => acl,
+        None => return 0u8.serialize(w),
+    };
+    let mut written = 1u8.serialize(&mut w)? + acl.owner.as_str().serialize(&mut w)?;
+    for principals in [&acl.writers, &acl.readers] {
+        written += principals.len().serialize(&mut w)?;
+        for principal in principals.iter() {
+            written += principal.as_str().serialize(&mut w)?;
+        }
+    }
+    Ok(written)
+}
+
+fn import_acl(mut r: impl Read) -> io::Result<Option<Acl>> {
+    if u8::deserialize_into_default(&mut r)? == 0 {
+        return Ok(None);
+    }
+    let mut acl = Acl::default();
+    acl.owner.deserialize(&mut r)?;
+    for principals in [&mut acl.writers, &mut acl.readers] {
+        for _ in 0..usize::deserialize_into_default(&mut r)? {
+            principals.push(String::deserialize_into_default(&mut r)?);
+        }
+    }
+    Ok(Some(acl))
+}
+
+#[test]
+fn export_import() {
+    use crate::file_system::MetadataChange;
+    use crate::heap_memory::HeapMemory;
+
+    let mut mem = HeapMemory::default();
+    let mut fs = FileSystem::new(&mut mem).unwrap().with_clock(|| 5);
+    fs.make_directory_recursive(vec!["docs", "old"]).unwrap();
+    fs.write_atomic(vec!["docs", "a.txt"], &b"alpha"[..])
+        .unwrap();
+    fs.write_atomic(vec!["docs", "old", "b.bin"], &[1u8; 1500][..])
+        .unwrap();
+    fs.set_sealed(vec!["docs", "a.txt"], true).unwrap();
+    let headers = vec![("Cache-Control".to_owned(), "no-cache".to_owned())];
+    fs.set_headers(vec!["docs"], headers.clone()).unwrap();
+    fs.set_fallback(vec!["docs", "old"], Fallback::Disabled)
+        .unwrap();
+    fs.set_listing(vec!["docs", "old"], true).unwrap();
+    let xattrs = vec![("origin".to_owned(), vec![0, 1, 2])];
+    let change = MetadataChange {
+        xattrs: Some(xattrs.clone()),
+        ..Default::default()
+    };
+    fs.set_metadata(vec!["docs", "old"], change).unwrap();
+    let redirect = Redirect {
+        status: 308,
+        location: "/docs/".to_owned(),
+    };
+    fs.set_redirect(vec!["doc"], Some(redirect.clone()))
+        .unwrap();
+    let acl = Acl {
+        writers: vec!["aaaaa-aa".to_owned()],
+        ..Acl::owned_by("2vxsx-fae")
+    };
+    fs.set_acl(vec!["docs", "a.txt"], Some(acl.clone()))
+        .unwrap();
+    let public = Acl {
+        readers: vec![Acl::EVERYONE.to_owned()],
+        ..Default::default()
+    };
+    fs.set_acl(Vec::<String>::new(), Some(public.clone()))
+        .unwrap();
+
+    let mut image = vec![];
+    let len = fs.export(&mut image).unwrap();
+    assert_eq!(len, image.len() as u64);
+
+    let mut copy = FileSystem::import(HeapMemory::default(), &image[..]).unwrap();
+    let mut data = vec![];
+    copy.read_file(vec!["docs", "old", "b.bin"], &mut data)
+        .unwrap();
+    assert_eq!(data, [1u8; 1500]);
+    let meta = copy.metadata(vec!["docs", "a.txt"]).unwrap();
+    assert_eq!((meta.size, meta.created), (5, 5));
+    assert!(copy.remove(vec!["docs", "a.txt"]).is_err());
+    let root = copy.directory_at(Vec::<String>::new()).unwrap();
+    assert_eq!(root.entry_with_name("docs").unwrap().headers, headers);
+    let docs = copy.directory_at(vec!["docs"]).unwrap();
+    let old = docs.entry_with_name("old").unwrap();
+    assert_eq!((old.fallback, old.listing), (Fallback::Disabled, true));
+    assert_eq!(old.xattrs, xattrs);
+    assert_eq!(
+        root.entry_with_name("doc").unwrap().redirect,
+        Some(redirect)
+    );
+    assert_eq!(root.acl, Some(public));
+    assert_eq!(docs.entry_with_name("a.txt").unwrap().acl, Some(acl));
+
+    // A damaged image is refused.
+    image[20] ^= 1;
+    assert!(FileSystem::import(HeapMemory::default(), &image[..]).is_err());
+}
@@ -0,0 +1,490 @@
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, Write};
+
+use crate::bitmap::Bitmap;
+use crate::cluster::Cluster;
+use crate::error::Error;
+use crate::memory::Memory;
+use crate::serde::{Deserialize, Serialize};
+use crate::tail::Tail;
+
+/// Bytes taken by each inode in the table.
+pub const SLOT_SIZE: usize = 64;
+
+// Flags in the first byte of a slot. Free slots have none set.
+const USED: u8 = 1;
+const SEALED: u8 = 2;
+const INLINE: u8 = 4;
+const TAIL: u8 = 8;
+
+/// Metadata of a file or directory. Directory entries only refer to their
+/// inode by number, so the metadata can change without rewriting the parent
+/// directory.
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct Inode {
+    pub size: u64,
+    /// Creation time in nanoseconds since the epoch, as told by the clock of
+    /// the filesystem.
+    pub created: u64,
+    /// Time of the last change of the contents, like `created`. For
+    /// directories, the last time entries were added, removed or renamed.
+    pub modified: u64,
+    /// Number of entries of a directory, 0 for files.
+    pub entry_count: u64,
+    /// Sealed entries can't be written, truncated or removed.
+    pub sealed: bool,
+    /// Where the contents of a small file are packed into a block shared
+    /// with other files, like a tail. The cluster is then empty. Empty files
+    /// have an empty fragment outside of any block.
+    pub inline: Option<Tail>,
+    /// Last partial block of a file, packed into a block shared with other
+    /// tails. The cluster then only holds the full blocks.
+    pub tail: Option<Tail>,
+    /// Time after which `FileSystem::purge_expired` removes the entry.
+    pub expires: Option<u64>,
+    /// Only the handle of the cluster is kept.
+    pub cluster: Cluster,
+}
+
+/// Writes the inode as a slot of the table, `SLOT_SIZE` bytes.
+impl Serialize for Inode {
+    fn serialize(&self, mut w: impl io::Write) -> io::Result<usize> {
+        let mut flags = USED;
+        if self.sealed {
+            flags |= SEALED;
+        }
+        // Inline files have no tail, so both share the same field.
+        let fragment = match (self.inline, self.tail) {
+            (Some(inline), _) => {
+                flags |= INLINE;
+                inline
+            }
+            (None, Some(tail)) => {
+                flags |= TAIL;
+                tail
+            }
+            (None, None) => Tail::default(),
+        };
+        w.write_all(&[flags, 0, 0, 0, 0, 0, 0, 0])?;
+        Ok(8 + self.size.serialize(&mut w)?
+            + self.created.serialize(&mut w)?
+            + self.modified.serialize(&mut w)?
+            + self.entry_count.serialize(&mut w)?
+            // Entries which never expire store 0.
+            + self.expires.unwrap_or(0).serialize(&mut w)?
+            + self.cluster.serialize(&mut w)?
+            + fragment.serialize(w)?)
+    }
+}
+
+impl Deserialize for Inode {
+    fn deserialize(&mut self, mut r: impl io::Read) -> io::Result<usize> {
+        let mut head = [0u8; 8];
+        r.read_exact(&mut head)?;
+        let (mut expires, mut fragment) = (0u64, Tail::default());
+        let read = 8
+            + self.size.deserialize(&mut r)?
+            + self.created.deserialize(&mut r)?
+            + self.modified.deserialize(&mut r)?
+            + self.entry_count.deserialize(&mut r)?
+            + expires.deserialize(&mut r)?
+            + self.cluster.deserialize(&mut r)?
+            + fragment.deserialize(r)?;
+
+        let flags = head[0];
+        self.sealed = flags & SEALED != 0;
+        self.inline = Some(fragment).filter(|_| flags & INLINE != 0);
+        self.tail = Some(fragment).filter(|_| flags & TAIL != 0);
+        self.expires = Some(expires).filter(|&expires| expires != 0);
+        Ok(read)
+    }
+}
+
+/// All inodes by number, as slots of `SLOT_SIZE` bytes which are read and
+/// written one at a time. Slot 0 holds the header, so number 0 is never
+/// handed out and marks entries which don't have an inode yet. Free slots
+/// form a list through the header, so their numbers are reused without
+/// searching.
+///
+/// Each preamble copy has its own area of slots. Changes only go to the area
+/// of the copy the next persist writes, so the area of the last persisted
+/// copy stays as that copy refers to it until a newer one is complete.
+/// Areas are only ever extended, so none of their blocks is freed.
+#[derive(Default, Debug)]
+pub struct InodeTable {
+    areas: [Cluster; 2],
+    /// The copy the next persist writes, whose area takes the changes.
+    next: usize,
+    /// Slots in the table, the header and the free ones included.
+    slots: u64,
+    /// The most recently freed slot, which holds the number of the one freed
+    /// before it, or 0 if no slot is free.
+    free: u64,
+    /// Slots changed since the last persist, which the other area lacks.
+    changed: BTreeSet<u64>,
+    /// Slots the area of `next` lacks, as they changed in the other one
+    /// before the last persist. They're read from the other area until
+    /// `prepare` copies them.
+    stale: BTreeSet<u64>,
+    /// Whether `stale` isn't known yet, as after opening the table. All
+    /// slots but the changed ones are then read from the other area.
+    unsynced: bool,
+    dirty: bool,
+}
+
+impl InodeTable {
+    /// Creates an empty table, writing the header into both areas. `next`
+    /// is the copy the next persist writes.
+    pub fn create<M: Memory>(bitmap: &mut Bitmap, memory: &mut M, next: usize) -> io::Result<Self> {
+        let mut table = InodeTable {
+            next,
+            slots: 1,
+            ..Default::default()
+        };
+        for area in 0..2 {
+            table.write_header(bitmap, memory, area)?;
+        }
+        Ok(table)
+    }
+
+    /// Opens the table held by `areas`, of which the one of `next` is
+    /// outdated. Only the header is read, from the other area, which the
+    /// last persisted copy refers to. The slots the outdated area lacks are
+    /// found by the first `prepare`.
+    pub fn open(mut areas: [Cluster; 2], next: usize, memory: &impl Memory) -> io::Result<Self> {
+        for area in areas.iter_mut() {
+            area.load(memory.reader())?;
+        }
+        let mut table = InodeTable {
+            areas,
+            next,
+            unsynced: true,
+            ..Default::default()
+        };
+        let last = 1 - next;
+        if table.areas[last].block_count() == 0 {
+            return Ok(table);
+        }
+
+        let header = table.read_area(memory, last, 0)?;
+        let field = |i: usize| u64::from_be_bytes(header[8 * i..8 * i + 8].try_into().unwrap());
+        table.slots = field(1);
+        table.free = field(2);
+        if table.slots * SLOT_SIZE as u64 > table.areas[last].len()
+            || table.free >= table.slots.max(1)
+        {
+            return Err(Error::corrupted("inode table header is out of bounds").into());
+        }
+        Ok(table)
+    }
+
+    /// The clusters holding the area of each preamble copy.
+    pub fn areas(&self) -> &[Cluster; 2] {
+        &self.areas
+    }
+
+    /// Slots which fit into the blocks of an area.
+    fn capacity(&self, area: usize) -> u64 {
+        self.areas[area].len() / SLOT_SIZE as u64
+    }
+
+    fn read_area(
+        &self,
+        memory: &impl Memory,
+        area: usize,
+        number: u64,
+    ) -> io::Result<[u8; SLOT_SIZE]> {
+        let mut slot = [0u8; SLOT_SIZE];
+        let mut r = self.areas[area].reader(memory.reader());
+        r.seek(io::SeekFrom::Start(number * SLOT_SIZE as u64))?;
+        r.read_exact(&mut slot)?;
+        Ok(slot)
+    }
+
+    fn write_area<M: Memory>(
+        &mut self,
+        bitmap: &mut Bitmap,
+        memory: &mut M,
+        area: usize,
+        number: u64,
+        slot: &[u8; SLOT_SIZE],
+    ) -> io::Result<()> {
+        let mut w = self.areas[area].writer(bitmap, memory.writer());
+        w.seek(io::SeekFrom::Start(number * SLOT_SIZE as u64))?;
+        w.write_all(slot)
+    }
+
+    /// Whether the current contents of a slot are in the area of `next`.
+    fn is_current(&self, number: u64) -> bool {
+        match self.unsynced {
+            true => self.changed.contains(&number),
+            false => !self.stale.contains(&number),
+        }
+    }
+
+    /// Reads a slot from the area holding its current contents.
+    fn read_slot(&self, memory: &impl Memory, number: u64) -> io::Result<[u8; SLOT_SIZE]> {
+        let area = match self.is_current(number) {
+            true => self.next,
+            false => 1 - self.next,
+        };
+        self.read_area(memory, area, number)
+    }
+
+    fn write_slot<M: Memory>(
+        &mut self,
+        bitmap: &mut Bitmap,
+        memory: &mut M,
+        number: u64,
+        slot: &[u8; SLOT_SIZE],
+    ) -> io::Result<()> {
+        self.write_area(bitmap, memory, self.next, number, slot)?;
+        self.stale.remove(&number);
+        self.changed.insert(number);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn write_header<M: Memory>(
+        &mut self,
+        bitmap: &mut Bitmap,
+        memory: &mut M,
+        area: usize,
+    ) -> io::Result<()> {
+        let mut header = [0u8; SLOT_SIZE];
+        for (i, field) in [self.slots, self.free].iter().enumerate() {
+            header[8 * (i + 1)..8 * (i + 2)].copy_from_slice(&field.to_be_bytes());
+        }
+        self.write_area(bitmap, memory, area, 0, &header)
+    }
+
+    pub fn get(&self, memory: &impl Memory, number: u64) -> io::Result<Option<Inode>> {
+        if number == 0 || number >= self.slots {
+            return Ok(None);
+        }
+        let slot = self.read_slot(memory, number)?;
+        if slot[0] & USED == 0 {
+            return Ok(None);
+        }
+        Inode::deserialize_into_default(&slot[..]).map(Some)
+    }
+
+    pub fn allocate<M: Memory>(
+        &mut self,
+        bitmap: &mut Bitmap,
+        memory: &mut M,
+        inode: &Inode,
+    ) -> io::Result<u64> {
+        let (number, next_free) = match self.free {
+            0 => (self.slots.max(1), 0),
+            free => (free, next_free(&self.read_slot(memory, free)?)),
+        };
+        self.write_slot(bitmap, memory, number, &encode(inode)?)?;
+        self.slots = self.slots.max(number + 1);
+        self.free = next_free;
+        Ok(number)
+    }
+
+    /// Replaces the inode with the given number, which must be allocated.
+    /// The slot is only written if the inode changed.
+    pub fn set<M: Memory>(
+        &mut self,
+        bitmap: &mut Bitmap,
+        memory: &mut M,
+        number: u64,
+        inode: &Inode,
+    ) -> io::Result<()> {
+        let stored = self
+            .get(memory, number)?
+            .ok_or_else(|| Error::corrupted(format!("inode {} is not allocated", number)))?;
+        if stored != *inode {
+            self.write_slot(bitmap, memory, number, &encode(inode)?)?;
+        }
+        Ok(())
+    }
+
+    /// Frees the inode with the given number, returning what it held. The
+    /// slot is overwritten, so nothing of the inode is left behind.
+    pub fn free<M: Memory>(
+        &mut self,
+        bitmap: &mut Bitmap,
+        memory: &mut M,
+        number: u64,
+    ) -> io::Result<Option<Inode>> {
+        let inode = match self.get(memory, number)? {
+            Some(inode) => inode,
+            None => return Ok(None),
+        };
+        let mut slot = [0u8; SLOT_SIZE];
+        slot[8..16].copy_from_slice(&self.free.to_be_bytes());
+        self.write_slot(bitmap, memory, number, &slot)?;
+        self.free = number;
+        Ok(Some(inode))
+    }
+
+    /// The allocated inodes with their numbers, read from the table in one
+    /// pass over the area holding most of them. Only the other slots are
+    /// read one at a time from the other area.
+    pub fn iter<'a, M: Memory>(
+        &'a self,
+        memory: &'a M,
+    ) -> impl 'a + Iterator<Item = io::Result<(u64, Inode)>> {
+        let area = match self.unsynced {
+            true => 1 - self.next,
+            false => self.next,
+        };
+        let mut r = self.areas[area].reader(memory.reader()).buffered();
+        let capacity = self.capacity(area);
+        (0..self.slots).filter_map(move |number| {
+            let slot = if number >= capacity {
+                self.read_area(memory, 1 - area, number)
+            } else if self.is_current(number) != (area == self.next) {
+                r.seek_relative(SLOT_SIZE as i64)
+                    .and_then(|_| self.read_area(memory, 1 - area, number))
+            } else {
+                let mut slot = [0u8; SLOT_SIZE];
+                r.read_exact(&mut slot).map(|_| slot)
+            };
+            let slot = match slot {
+                Ok(slot) => slot,
+                Err(e) => return Some(Err(e)),
+            };
+            if number == 0 || slot[0] & USED == 0 {
+                return None;
+            }
+            Some(Inode::deserialize_into_default(&slot[..]).map(|inode| (number, inode)))
+        })
+    }
+
+    /// Whether the table changed since the last persist.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Completes the area of the copy the next persist writes: copies the
+    /// slots it lacks from the other area and writes the header. It may
+    /// need more blocks, so it comes before the bitmap is persisted.
+    pub fn prepare<M: Memory>(&mut self, bitmap: &mut Bitmap, memory: &mut M) -> io::Result<()> {
+        if self.unsynced {
+            self.find_stale(memory)?;
+        }
+        let (next, last) = (self.next, 1 - self.next);
+        for number in std::mem::take(&mut self.stale) {
+            let slot = self.read_area(memory, last, number)?;
+            self.write_area(bitmap, memory, next, number, &slot)?;
+        }
+        self.write_header(bitmap, memory, next)
+    }
+
+    /// Compares both areas in one pass to find the slots the area of `next`
+    /// lacks, as after opening the table, leaving out the slots changed
+    /// since.
+    fn find_stale(&mut self, memory: &impl Memory) -> io::Result<()> {
+        let last = 1 - self.next;
+        let mut latest = self.areas[last].reader(memory.reader()).buffered();
+        let mut outdated = self.areas[self.next].reader(memory.reader()).buffered();
+        let capacity = self.capacity(self.next);
+        let (mut a, mut b) = ([0u8; SLOT_SIZE], [0u8; SLOT_SIZE]);
+        // Slots past the end of the last persisted area were added since.
+        for number in 0..self.slots.min(self.capacity(last)) {
+            latest.read_exact(&mut a)?;
+            let differs = number >= capacity || {
+                outdated.read_exact(&mut b)?;
+                a != b
+            };
+            if number > 0 && differs && !self.changed.contains(&number) {
+                self.stale.insert(number);
+            }
+        }
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Switches to the other area once the preamble copy referring to this
+    /// one is written. The other area lacks the slots changed since the
+    /// last persist.
+    pub fn commit(&mut self) {
+        self.stale = std::mem::take(&mut self.changed);
+        self.next = 1 - self.next;
+        self.dirty = false;
+    }
+}
+
+fn encode(inode: &Inode) -> io::Result<[u8; SLOT_SIZE]> {
+    let mut slot = [0u8; SLOT_SIZE];
+    inode.serialize(&mut slot[..])?;
+    Ok(slot)
+}
+
+/// The number a free slot links to.
+fn next_free(slot: &[u8; SLOT_SIZE]) -> u64 {
+    u64::from_be_bytes(slot[8..16].try_into().unwrap())
+}
+
+#[test]
+fn inode_table() {
+    use crate::block::Block;
+    use crate::heap_memory::HeapMemory;
+
+    let mut memory = HeapMemory::default();
+    let mut bitmap = Bitmap::new(&memory);
+    // Block 0 can't be the head of a cluster.
+    bitmap.occupy(0);
+
+    let mut table = InodeTable::create(&mut bitmap, &mut memory, 1).unwrap();
+    let inode = |size| Inode {
+        size,
+        ..Default::default()
+    };
+    let a = table.allocate(&mut bitmap, &mut memory, &inode(1)).unwrap();
+    let b = table.allocate(&mut bitmap, &mut memory, &inode(2)).unwrap();
+    assert_eq!((a, b), (1, 2));
+    assert!(table.get(&memory, 0).unwrap().is_none());
+
+    let freed = table.free(&mut bitmap, &mut memory, a).unwrap();
+    assert_eq!(freed, Some(inode(1)));
+    assert!(table.get(&memory, a).unwrap().is_none());
+    assert!(table.set(&mut bitmap, &mut memory, a, &inode(1)).is_err());
+    let c = table.allocate(&mut bitmap, &mut memory, &inode(4)).unwrap();
+    assert_eq!(c, a);
+
+    let full = Inode {
+        size: 3,
+        created: 1,
+        modified: 2,
+        sealed: true,
+        inline: Some(Tail {
+            block: Block::at(9),
+            offset: 20,
+            len: 30,
+        }),
+        ..Default::default()
+    };
+    table.set(&mut bitmap, &mut memory, b, &full).unwrap();
+    table.prepare(&mut bitmap, &mut memory).unwrap();
+    table.commit();
+    assert!(!table.is_dirty());
+
+    // Changes go to the other area, which lacks the slots changed before.
+    let areas = table.areas().clone().map(|area| area.handle());
+    table.set(&mut bitmap, &mut memory, c, &inode(5)).unwrap();
+    assert_eq!(table.get(&memory, b).unwrap(), Some(full.clone()));
+
+    // Opening the table only reads the header. Slots are read from the
+    // persisted area until the next persist finds those which differ.
+    let mut restored = InodeTable::open(areas.clone(), 0, &memory).unwrap();
+    assert_eq!(restored.get(&memory, c).unwrap(), Some(inode(4)));
+    let inodes = restored.iter(&memory).collect::<io::Result<Vec<_>>>();
+    assert_eq!(inodes.unwrap(), [(c, inode(4)), (b, full)]);
+    assert!(!restored.is_dirty());
+
+    restored
+        .set(&mut bitmap, &mut memory, b, &inode(6))
+        .unwrap();
+    restored.prepare(&mut bitmap, &mut memory).unwrap();
+    restored.commit();
+    let persisted = InodeTable::open(areas, 1, &memory).unwrap();
+    let inodes = persisted.iter(&memory).collect::<io::Result<Vec<_>>>();
+    assert_eq!(inodes.unwrap(), [(c, inode(4)), (b, inode(6))]);
+}
@@ -0,0 +1,327 @@
+use std::io;
+
+use candid::types::{Serializer as CandidSerializer, Type};
+use candid::CandidType;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::directory::{Directory, Entry, EntryKind};
+use crate::error::Error;
+use crate::file_system::FileSystem;
+use crate::memory::Memory;
+
+/// Size and content type of a file, as the canister returns them.
+#[derive(Debug, PartialEq, Clone, CandidType, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub size: u64,
+    #[serde(rename = "contentType")]
+    pub content_type: String,
+}
+
+impl From<&Entry> for FileInfo {
+    fn from(entry: &Entry) -> Self {
+        FileInfo {
+            size: entry.size,
+            content_type: entry.content_type.clone(),
+        }
+    }
+}
+
+/// All the metadata of an entry, as the canister returns it in one call.
+#[derive(Debug, PartialEq, Clone, CandidType, Serialize, Deserialize)]
+pub struct EntryStat {
+    pub kind: EntryKind,
+    pub size: u64,
+    /// Empty for directories.
+    #[serde(rename = "contentType")]
+    pub content_type: String,
+    pub created: u64,
+    pub modified: u64,
+    /// Checksum of the contents of a file, which its ETag is made of.
+    pub hash: Option<u64>,
+    /// Data blocks of the entry, without its index blocks.
+    #[serde(rename = "blockCount")]
+    pub block_count: u64,
+}
+
+impl EntryStat {
+    /// Gathers the metadata of the entry at `path`. The hash of a file
+    /// takes reading all of it.
+    pub fn of<M: Memory>(fs: &FileSystem<M>, path: &[String]) -> io::Result<Self> {
+        let meta = fs.metadata(path)?;
+        let (content_type, hash) = match fs.resolve(path)? {
+            Some(file) if file.kind == EntryKind::File => {
+                (file.content_type.clone(), Some(file.content_checksum(fs)?))
+            }
+            _ => (String::new(), None),
+        };
+        Ok(EntryStat {
+            kind: meta.kind,
+            size: meta.size,
+            content_type,
+            created: meta.created,
+            modified: meta.modified,
+            hash,
+            block_count: meta.block_count as u64,
+        })
+    }
+}
+
+/// A failed call, as the canister returns it instead of trapping. Errors
+/// without a variant of their own carry their message.
+#[derive(Debug, PartialEq, Clone, CandidType, Serialize, Deserialize)]
+pub enum ApiError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    OutOfSpace,
+    Corrupted(String),
+    NameInvalid,
+    QuotaExceeded,
+    InvalidPath,
+    Sealed,
+    MountPoint,
+    AccessDenied,
+    /// An argument out of range, or a request which doesn't make sense in
+    /// the state the filesystem is in.
+    InvalidInput(String),
+    Other(String),
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        match Error::from(e) {
+            Error::NotFound => ApiError::NotFound,
+            Error::NotADirectory => ApiError::NotADirectory,
+            Error::IsADirectory => ApiError::IsADirectory,
+            Error::AlreadyExists => ApiError::AlreadyExists,
+            Error::OutOfSpace => ApiError::OutOfSpace,
+            Error::Corrupted { detail } => ApiError::Corrupted(detail),
+            Error::NameInvalid => ApiError::NameInvalid,
+            Error::QuotaExceeded => ApiError::QuotaExceeded,
+            Error::InvalidPath => ApiError::InvalidPath,
+            Error::Sealed => ApiError::Sealed,
+            Error::MountPoint => ApiError::MountPoint,
+            Error::AccessDenied => ApiError::AccessDenied,
+            Error::Io(e) if e.kind() == io::ErrorKind::NotFound => ApiError::NotFound,
+            Error::Io(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                ApiError::InvalidInput(e.to_string())
+            }
+            Error::Io(e) => ApiError::Other(e.to_string()),
+        }
+    }
+}
+
+/// Entries are exchanged as their name and kind, with the size and content
+/// type of files. Everything else stays inside the filesystem, and is left
+/// at its default when an entry is deserialized.
+#[derive(CandidType, Serialize, Deserialize)]
+struct EntryRecord {
+    name: String,
+    kind: KindRecord,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+enum KindRecord {
+    Directory,
+    File(FileInfo),
+}
+
+impl From<&Entry> for EntryRecord {
+    fn from(entry: &Entry) -> Self {
+        EntryRecord {
+            name: entry.name.clone(),
+            kind: match entry.kind {
+                EntryKind::Directory => KindRecord::Directory,
+                EntryKind::File => KindRecord::File(entry.into()),
+            },
+        }
+    }
+}
+
+impl From<EntryRecord> for Entry {
+    fn from(record: EntryRecord) -> Self {
+        let mut entry = Entry {
+            name: record.name,
+            ..Default::default()
+        };
+        match record.kind {
+            KindRecord::Directory => entry.kind = EntryKind::Directory,
+            KindRecord::File(file) => {
+                entry.size = file.size;
+                entry.content_type = file.content_type;
+            }
+        }
+        entry
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct DirectoryRecord {
+    entries: Vec<EntryRecord>,
+}
+
+impl CandidType for Entry {
+    fn _ty() -> Type {
+        EntryRecord::ty()
+    }
+
+    fn idl_serialize<S: CandidSerializer>(&self, serializer: S) -> Result<(), S::Error> {
+        EntryRecord::from(self).idl_serialize(serializer)
+    }
+}
+
+impl Serialize for Entry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        EntryRecord::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Entry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        EntryRecord::deserialize(deserializer).map(Entry::from)
+    }
+}
+
+impl From<&Directory> for DirectoryRecord {
+    fn from(dir: &Directory) -> Self {
+        DirectoryRecord {
+            entries: dir.entries.iter().map(EntryRecord::from).collect(),
+        }
+    }
+}
+
+impl CandidType for Directory {
+    fn _ty() -> Type {
+        DirectoryRecord::ty()
+    }
+
+    fn idl_serialize<S: CandidSerializer>(&self, serializer: S) -> Result<(), S::Error> {
+        DirectoryRecord::from(self).idl_serialize(serializer)
+    }
+}
+
+impl Serialize for Directory {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DirectoryRecord::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Directory {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let record = DirectoryRecord::deserialize(deserializer)?;
+        Ok(Directory {
+            entries: record.entries.into_iter().map(Entry::from).collect(),
+            ..Default::default()
+        })
+    }
+}
+
+#[test]
+fn candid() {
+    use crate::file_system::Metadata;
+    use candid::{Decode, Encode};
+
+    let mut dir = Directory::default();
+    dir.add_file("a.txt", "text/plain").unwrap().size = 5;
+    dir.add_directory("b").unwrap().inode = 7;
+
+    let bytes = Encode!(&dir).unwrap();
+    let read = Decode!(&bytes, Directory).unwrap();
+    let entries = read
+        .entries
+        .iter()
+        .map(|e| {
+            (
+                e.name.as_str(),
+                e.kind,
+                e.size,
+                e.content_type.as_str(),
+                e.inode,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        entries,
+        [
+            ("a.txt", EntryKind::File, 5, "text/plain", 0),
+            ("b", EntryKind::Directory, 0, "", 0)
+        ]
+    );
+
+    let meta = Metadata {
+        kind: EntryKind::File,
+        size: 5,
+        created: 1,
+        modified: 2,
+        block_count: 1,
+        entry_count: 0,
+    };
+    let bytes = Encode!(&meta).unwrap();
+    assert_eq!(Decode!(&bytes, Metadata).unwrap(), meta);
+
+    let e = ApiError::from(io::Error::from(Error::corrupted("bad index")));
+    assert_eq!(e, ApiError::Corrupted("bad index".to_owned()));
+    let e = io::Error::new(io::ErrorKind::InvalidInput, "chunk too large");
+    assert_eq!(
+        ApiError::from(e),
+        ApiError::InvalidInput("chunk too large".to_owned())
+    );
+    let result: Result<(), ApiError> = Err(ApiError::Sealed);
+    let bytes = Encode!(&result).unwrap();
+    assert_eq!(Decode!(&bytes, Result<(), ApiError>).unwrap(), result);
+}
+
+#[test]
+fn entry_stat() {
+    use crate::block::Block;
+    use crate::heap_memory::HeapMemory;
+    use candid::{Decode, Encode};
+
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_clock(|| 3);
+    fs.make_directory_recursive(vec!["d"]).unwrap();
+    fs.with_directory_mut(vec!["d"], |dir, _| {
+        dir.add_file("a.txt", "text/plain").map(drop)
+    })
+    .unwrap();
+    fs.write_atomic(vec!["d", "a.txt"], &b"hello"[..]).unwrap();
+    let path = vec!["d".to_owned(), "a.txt".to_owned()];
+    let stat = EntryStat::of(&fs, &path).unwrap();
+    let checksum = fs.resolve(&path).unwrap().unwrap().content_checksum(&fs);
+    assert_eq!(
+        (
+            stat.kind,
+            stat.size,
+            stat.content_type.as_str(),
+            stat.created,
+            stat.block_count
+        ),
+        (EntryKind::File, 5, "text/plain", 3, 0)
+    );
+    assert_eq!(stat.hash, Some(checksum.unwrap()));
+    let bytes = Encode!(&stat).unwrap();
+    assert_eq!(Decode!(&bytes, EntryStat).unwrap(), stat);
+
+    // The hash follows the contents, and files too large to be inlined
+    // take blocks.
+    fs.write_atomic(vec!["d", "a.txt"], &b"world"[..]).unwrap();
+    assert_ne!(EntryStat::of(&fs, &path).unwrap().hash, stat.hash);
+    let data = vec![1; Block::SIZE + 1];
+    fs.write_atomic(vec!["d", "a.txt"], &data[..]).unwrap();
+    let large = EntryStat::of(&fs, &path).unwrap();
+    assert_eq!((large.size, large.block_count), (data.len() as u64, 2));
+    fs.write_atomic(vec!["d", "a.txt"], &b"hello"[..]).unwrap();
+    assert_eq!(EntryStat::of(&fs, &path).unwrap().hash, stat.hash);
+
+    // Directories, the root among them, have no content type or hash.
+    let dir = EntryStat::of(&fs, &path[..1]).unwrap();
+    assert_eq!(
+        (dir.kind, dir.content_type.as_str(), dir.hash),
+        (EntryKind::Directory, "", None)
+    );
+    let root = EntryStat::of(&fs, &[]).unwrap();
+    assert_eq!((root.kind, root.hash), (EntryKind::Directory, None));
+    assert!(EntryStat::of(&fs, &["e".to_owned()]).is_err());
+}
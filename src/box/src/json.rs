@@ -0,0 +1,169 @@
+use std::io::{self, Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::directory::{Entry, EntryKind};
+use crate::error::Error;
+use crate::file_system::{DropPolicy, FileSystem};
+use crate::memory::Memory;
+
+/// An entry of a JSON listing, with its path relative to where the listing
+/// was taken.
+#[derive(Serialize, Deserialize)]
+struct JsonEntry {
+    path: Vec<String>,
+    kind: EntryKind,
+    #[serde(
+        rename = "contentType",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    content_type: String,
+    size: u64,
+    created: u64,
+    modified: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    sealed: bool,
+    /// The contents of a file in base64, if they were asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    contents: Option<String>,
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+/// A listing is an array of entries, each directory before its entries:
+///
+/// ```text
+/// [{"path": ["docs"], "kind": "Directory", "size": 0, "created": 5, ...},
+///  {"path": ["docs", "a.txt"], "kind": "File", "contentType": "text/plain",
+///   "size": 5, ..., "contents": "YWxwaGE="}]
+/// ```
+impl<M: Memory> FileSystem<M> {
+    /// Lists the tree below the directory at `path` as JSON, with the
+    /// contents of files if `contents` is set. Meant for debugging,
+    /// fixtures and tools off the chain rather than for backups, which are
+    /// better served by `export`.
+    pub fn to_json(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+        contents: bool,
+    ) -> io::Result<String> {
+        let mut listing = vec![];
+        let mut pending = vec![(vec![], self.directory_at(path)?)];
+        while let Some((path, dir)) = pending.pop() {
+            for entry in dir.entries.into_iter().rev() {
+                let mut entry_path: Vec<String> = path.clone();
+                entry_path.push(entry.name.clone());
+                let contents = match entry.kind {
+                    EntryKind::File if contents => {
+                        let mut data = vec![];
+                        io::copy(&mut entry.read_from_file_system(self)?, &mut data)?;
+                        Some(base64::encode(data))
+                    }
+                    _ => None,
+                };
+                if entry.kind == EntryKind::Directory {
+                    pending.push((entry_path.clone(), self.read_directory(&entry)?));
+                }
+                listing.push(JsonEntry {
+                    path: entry_path,
+                    kind: entry.kind,
+                    content_type: entry.content_type,
+                    size: entry.size,
+                    created: entry.created,
+                    modified: entry.modified,
+                    expires: entry.expires,
+                    sealed: entry.sealed,
+                    contents,
+                });
+            }
+        }
+        Ok(serde_json::to_string_pretty(&listing)?)
+    }
+
+    /// Builds a filesystem on `memory` from a listing written by `to_json`.
+    /// Files listed without contents come back empty. Nothing is persisted
+    /// to `memory` if the listing can't be read.
+    pub fn from_json(memory: M, r: impl Read) -> io::Result<Self> {
+        let listing: Vec<JsonEntry> = serde_json::from_reader(r)?;
+        let mut fs = Self::new(memory)?;
+        match fs.import_listing(listing) {
+            Ok(()) => Ok(fs),
+            Err(e) => {
+                fs.set_drop_policy(DropPolicy::Ignore);
+                Err(e)
+            }
+        }
+    }
+
+    fn import_listing(&mut self, listing: Vec<JsonEntry>) -> io::Result<()> {
+        // As with images, metadata is set once all entries are in place.
+        let mut imported = vec![];
+        for record in listing {
+            let entry = Entry {
+                kind: record.kind,
+                name: record.path.last().cloned().ok_or(Error::InvalidPath)?,
+                content_type: record.content_type,
+                created: record.created,
+                modified: record.modified,
+                expires: record.expires,
+                sealed: record.sealed,
+                ..Default::default()
+            };
+            self.create_entry(&record.path, &entry)?;
+            if let Some(contents) = record.contents {
+                let data = base64::decode(contents)
+                    .map_err(|_| Error::corrupted("bad base64 contents in listing"))?;
+                self.with_entry_mut(record.path.clone(), |file, fs| {
+                    io::copy(&mut &data[..], &mut file.write_to_file_system(fs)?)
+                })?;
+            }
+            imported.push((record.path, entry));
+        }
+        for (path, entry) in imported {
+            self.copy_metadata(path, &entry)?;
+        }
+        self.persist()
+    }
+}
+
+#[test]
+fn json() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default())
+        .unwrap()
+        .with_clock(|| 5);
+    fs.make_directory_recursive(vec!["docs", "old"]).unwrap();
+    fs.write_atomic(vec!["docs", "a.txt"], &b"alpha"[..])
+        .unwrap();
+    fs.write_atomic(vec!["docs", "old", "b.bin"], &[1u8; 1500][..])
+        .unwrap();
+    fs.set_sealed(vec!["docs", "a.txt"], true).unwrap();
+
+    let listing = fs.to_json(Vec::<String>::new(), false).unwrap();
+    assert!(!listing.contains("contents"));
+    let mut paths = serde_json::from_str::<Vec<JsonEntry>>(&listing)
+        .unwrap()
+        .into_iter()
+        .map(|e| e.path.join("/"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, ["docs", "docs/a.txt", "docs/old", "docs/old/b.bin"]);
+
+    let listing = fs.to_json(vec!["docs"], true).unwrap();
+    let copy = FileSystem::from_json(HeapMemory::default(), listing.as_bytes()).unwrap();
+    let mut data = vec![];
+    copy.read_file(vec!["old", "b.bin"], &mut data).unwrap();
+    assert_eq!(data, [1u8; 1500]);
+    let meta = copy.metadata(vec!["a.txt"]).unwrap();
+    assert_eq!((meta.size, meta.created), (5, 5));
+
+    let bad = r#"[{"path": ["a"], "kind": "File", "size": 1, "created": 0,
+        "modified": 0, "contents": "not base64!"}]"#;
+    assert!(FileSystem::from_json(HeapMemory::default(), bad.as_bytes()).is_err());
+}
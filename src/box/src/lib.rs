@@ -1,10 +1,90 @@
+//! A filesystem kept in the memory of an Internet Computer canister. Build
+//! with the `canister` feature (on by default) for the canister itself, or
+//! without it to use the filesystem as a library on any `Memory`.
+
+mod backup;
 mod bitmap;
 mod block;
-mod memory;
-mod heap_memory;
-mod stable_memory;
+mod checksum;
 mod cluster;
+mod directory;
+mod error;
+mod faulty_memory;
+mod file_memory;
 mod file_system;
+mod heap_memory;
+mod image;
+mod inode;
+#[cfg(feature = "interop")]
+mod interop;
+#[cfg(feature = "json")]
+mod json;
+mod memory;
+mod metered_memory;
+#[cfg(feature = "mmap")]
+mod mmap_memory;
+mod mount;
+mod observer;
+mod overlay;
+mod region_memory;
 mod serde;
-mod directory;
+mod shared;
+mod stable_memory;
+mod sync;
+mod tail;
+mod tar;
+mod tree;
+#[cfg(feature = "wasm-web")]
+mod web_memory;
+// The endpoints are only exported from wasm, as native linkers refuse their
+// export names. Tests still build them, to check them.
+#[cfg(all(feature = "canister", any(target_arch = "wasm32", test)))]
+mod access;
+#[cfg(all(feature = "canister", any(target_arch = "wasm32", test)))]
+mod assets;
+#[cfg(all(feature = "canister", any(target_arch = "wasm32", test)))]
 mod canister;
+#[cfg(all(feature = "canister", any(target_arch = "wasm32", test)))]
+mod http;
+#[cfg(all(feature = "canister", any(target_arch = "wasm32", test)))]
+mod maintenance;
+#[cfg(all(feature = "canister", any(target_arch = "wasm32", test)))]
+mod tenants;
+#[cfg(all(feature = "canister", any(target_arch = "wasm32", test)))]
+mod tokens;
+#[cfg(all(feature = "canister", any(target_arch = "wasm32", test)))]
+mod uploads;
+
+pub use crate::backup::TrackedMemory;
+pub use crate::bitmap::AllocationPolicy;
+pub use crate::directory::{
+    Acl, ContentReader, Directory, Entry, EntryKind, EntryReader, EntryWriter, Fallback,
+    NamePolicy, Redirect, FORMAT_VERSION, MAX_NAME_LEN,
+};
+pub use crate::error::Error;
+pub use crate::faulty_memory::{Fault, FaultyMemory, Operation};
+pub use crate::file_memory::FileMemory;
+pub use crate::file_system::{
+    DefragmentProgress, DirSize, DropPolicy, Durability, FileSystem, GarbageProgress,
+    GarbageReport, Metadata, MetadataChange, PurgeProgress, SortOrder, Stats, Usage, WriteFileMode,
+    DEFAULT_INLINE_LIMIT,
+};
+pub use crate::heap_memory::{HeapMemory, UnboundedHeapMemory};
+#[cfg(feature = "interop")]
+pub use crate::interop::{ApiError, EntryStat, FileInfo};
+pub use crate::memory::{Memory, MemoryReader, MemoryWriter};
+pub use crate::metered_memory::{MemoryStats, MeteredMemory};
+#[cfg(feature = "mmap")]
+pub use crate::mmap_memory::MmapMemory;
+pub use crate::mount::Mount;
+pub use crate::observer::FsObserver;
+pub use crate::overlay::OverlayFs;
+pub use crate::region_memory::RegionMemory;
+pub use crate::serde::{Deserialize, Serialize, MAX_STRING_LEN};
+pub use crate::shared::SharedFileSystem;
+pub use crate::stable_memory::StableMemory;
+pub use crate::sync::SyncReport;
+pub use crate::tar::TarImport;
+pub use crate::tree::{FindCursor, Found, Tree};
+#[cfg(feature = "wasm-web")]
+pub use crate::web_memory::WebMemory;
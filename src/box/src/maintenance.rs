@@ -0,0 +1,288 @@
+use std::io;
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use crate::file_system::FileSystem;
+use crate::memory::Memory;
+use crate::uploads::Uploads;
+
+const SECOND_NANOS: u64 = 1_000_000_000;
+
+/// Expired entries removed per call of `FileSystem::purge_expired`.
+const PURGE_SLICE: usize = 100;
+
+/// Entries visited per call of `FileSystem::collect_garbage_slice`.
+const GARBAGE_SLICE: usize = 500;
+
+/// Entries removed per call of `FileSystem::remove_tree`.
+const REMOVE_SLICE: usize = 100;
+
+/// The setting with the paths `Job::Remove` has yet to remove, one per
+/// line, with the names percent-encoded and joined by `/`.
+const REMOVALS: &str = "pendingRemovals";
+
+const NAME_CHARS: &AsciiSet = &CONTROLS.add(b'%');
+
+/// Entries and blocks visited per call of `FileSystem::defragment`.
+const DEFRAGMENT_SLICE: usize = 500;
+
+/// How often the schedule is looked at. Heartbeats come with every round,
+/// about once a second, and each one costs cycles even if nothing is due.
+pub const TICK: u64 = 5 * SECOND_NANOS;
+
+/// Work the canister does in the background, from its heartbeat.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Job {
+    /// Persists the preamble, if a call which failed halfway left it behind.
+    Persist,
+    /// Drops uploads which stopped receiving chunks.
+    ExpireUploads,
+    /// Removes entries past their expiry.
+    PurgeExpired,
+    /// Carries on with the removals queued by `remove_tree`.
+    Remove,
+    /// Frees blocks which nothing refers to.
+    CollectGarbage,
+    /// Lays out the blocks of files contiguously.
+    Defragment,
+}
+
+impl Job {
+    /// In the order they're run in when due at once.
+    pub const ALL: [Job; 6] = [
+        Job::Persist,
+        Job::ExpireUploads,
+        Job::PurgeExpired,
+        Job::Remove,
+        Job::CollectGarbage,
+        Job::Defragment,
+    ];
+
+    /// How long after finishing the job is due again, in nanoseconds.
+    pub fn interval(self) -> u64 {
+        match self {
+            Job::Remove => 10 * SECOND_NANOS,
+            Job::Persist | Job::ExpireUploads | Job::PurgeExpired => 60 * SECOND_NANOS,
+            Job::Defragment => 60 * 60 * SECOND_NANOS,
+            Job::CollectGarbage => 24 * 60 * 60 * SECOND_NANOS,
+        }
+    }
+}
+
+/// When each job is due. Every job is due when the schedule starts, as it
+/// does again after an upgrade.
+#[derive(Default)]
+pub struct Schedule {
+    next: [u64; Job::ALL.len()],
+    /// When the schedule was last looked at.
+    checked: Option<u64>,
+}
+
+impl Schedule {
+    /// Whether a tick has passed at `now` since the schedule was last looked
+    /// at, in which case `now` counts as looking at it.
+    pub fn wake(&mut self, now: u64) -> bool {
+        match self.checked {
+            Some(checked) if now < checked.saturating_add(TICK) => false,
+            _ => {
+                self.checked = Some(now);
+                true
+            }
+        }
+    }
+
+    /// The jobs due at `now`, in the order of `Job::ALL`.
+    pub fn due(&self, now: u64) -> Vec<Job> {
+        Job::ALL
+            .iter()
+            .zip(self.next.iter())
+            .filter(|(_, &next)| next <= now)
+            .map(|(&job, _)| job)
+            .collect()
+    }
+
+    /// Makes `job` due again an interval after `now`. A job which isn't
+    /// finished stays due, to carry on with the next tick.
+    pub fn finished(&mut self, job: Job, now: u64) {
+        let i = Job::ALL.iter().position(|&j| j == job).unwrap();
+        self.next[i] = now.saturating_add(job.interval());
+    }
+}
+
+/// The paths queued for removal, oldest first.
+pub fn pending_removals<M: Memory>(fs: &FileSystem<M>) -> io::Result<Vec<Vec<String>>> {
+    let removals = fs.setting(REMOVALS)?.unwrap_or_default();
+    Ok(removals
+        .lines()
+        .map(|line| {
+            line.split('/')
+                .map(|name| percent_decode_str(name).decode_utf8_lossy().into_owned())
+                .collect()
+        })
+        .collect())
+}
+
+fn set_pending_removals<M: Memory>(
+    fs: &mut FileSystem<M>,
+    paths: &[Vec<String>],
+) -> io::Result<()> {
+    let lines = paths
+        .iter()
+        .map(|path| {
+            let names = path
+                .iter()
+                .map(|name| utf8_percent_encode(name, NAME_CHARS).to_string());
+            names.collect::<Vec<_>>().join("/")
+        })
+        .collect::<Vec<_>>();
+    let value = if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    };
+    fs.set_setting(REMOVALS, value)
+}
+
+/// Removes the entry at `path` with everything inside, as much of it as a
+/// slice takes right away, and queues the rest for `Job::Remove`. Entries
+/// written below `path` before the job gets to them are removed as well.
+pub fn remove_tree<M: Memory>(fs: &mut FileSystem<M>, path: Vec<String>) -> io::Result<()> {
+    if !fs.remove_tree(path.clone(), REMOVE_SLICE)?.done {
+        let mut paths = pending_removals(fs)?;
+        if !paths.contains(&path) {
+            paths.push(path);
+            set_pending_removals(fs, &paths)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs `job` at `now` in slices until it's done or `out_of_budget` says
+/// to stop for this message. Returns whether it's done. Collecting garbage
+/// waits for uploads to finish, as their staged files aren't in the tree,
+/// and starts over if anything changed between slices.
+pub fn run<M: Memory>(
+    fs: &mut FileSystem<M>,
+    uploads: &mut Uploads,
+    job: Job,
+    now: u64,
+    out_of_budget: impl Fn() -> bool,
+) -> io::Result<bool> {
+    match job {
+        Job::Persist if fs.is_persisted() => Ok(true),
+        Job::Persist => fs.persist().map(|()| true),
+        Job::ExpireUploads => uploads.abort_expired(fs, now).map(|()| true),
+        Job::PurgeExpired => loop {
+            if fs.purge_expired(now, PURGE_SLICE)?.done {
+                return Ok(true);
+            }
+            if out_of_budget() {
+                return Ok(false);
+            }
+        },
+        Job::Remove => loop {
+            let mut paths = pending_removals(fs)?;
+            if paths.is_empty() {
+                return Ok(true);
+            }
+            // Paths which are gone or can't be removed leave the queue.
+            let result = fs.remove_tree(paths[0].clone(), REMOVE_SLICE);
+            if !matches!(result, Ok(progress) if !progress.done) {
+                paths.remove(0);
+                set_pending_removals(fs, &paths)?;
+            }
+            match result {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ if out_of_budget() => return Ok(false),
+                _ => {}
+            }
+        },
+        Job::CollectGarbage if !uploads.is_empty() => Ok(false),
+        Job::CollectGarbage => loop {
+            if fs.collect_garbage_slice(GARBAGE_SLICE)?.done {
+                return Ok(true);
+            }
+            if out_of_budget() {
+                return Ok(false);
+            }
+        },
+        Job::Defragment => loop {
+            if fs.defragment(DEFRAGMENT_SLICE)?.done {
+                return Ok(true);
+            }
+            if out_of_budget() {
+                return Ok(false);
+            }
+        },
+    }
+}
+
+#[test]
+fn maintenance() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut schedule = Schedule::default();
+    assert_eq!(schedule.due(0), Job::ALL);
+    schedule.finished(Job::Persist, 10);
+    schedule.finished(Job::CollectGarbage, 10);
+    let later = 10 + Job::Persist.interval();
+    assert_eq!(schedule.due(later - 1)[0], Job::ExpireUploads);
+    assert_eq!(schedule.due(later).len(), 5);
+    assert!(!schedule.due(later).contains(&Job::CollectGarbage));
+
+    // Heartbeats in between ticks don't look at the schedule.
+    assert!(schedule.wake(later));
+    assert!(!schedule.wake(later + TICK - 1));
+    assert!(schedule.wake(later + TICK));
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    let mut uploads = Uploads::default();
+    fs.write_atomic(["a.txt"], &b"alpha"[..]).unwrap();
+    fs.set_expiry(["a.txt"], Some(5)).unwrap();
+    for job in Job::ALL {
+        assert!(run(&mut fs, &mut uploads, job, 10, || false).unwrap());
+    }
+    assert!(!fs.exists(["a.txt"]));
+
+    // Garbage collection would take the blocks of uploads for leaked.
+    let path = vec!["b.bin".to_owned()];
+    let id = uploads.begin(&mut fs, path, String::new(), 2000, "alice".to_owned(), 10);
+    let job = Job::CollectGarbage;
+    assert!(!run(&mut fs, &mut uploads, job, 10, || false).unwrap());
+    uploads.abort(&mut fs, id.unwrap()).unwrap();
+    assert!(run(&mut fs, &mut uploads, job, 10, || false).unwrap());
+
+    // Out of budget, the job stops after a slice and carries on later.
+    for i in 0..=PURGE_SLICE {
+        let name = format!("{}.txt", i);
+        fs.write_atomic([name.as_str()], &b"alpha"[..]).unwrap();
+        fs.set_expiry([name.as_str()], Some(5)).unwrap();
+    }
+    let job = Job::PurgeExpired;
+    assert!(!run(&mut fs, &mut uploads, job, 10, || true).unwrap());
+    let left =
+        |fs: &FileSystem<HeapMemory>| fs.directory_at(Vec::<String>::new()).unwrap().entries.len();
+    assert_eq!(left(&fs), 1);
+    assert!(run(&mut fs, &mut uploads, job, 10, || true).unwrap());
+    assert_eq!(left(&fs), 0);
+
+    // Removals which take more than a slice are queued.
+    fs.make_directory_recursive(["dir"]).unwrap();
+    for i in 0..=REMOVE_SLICE {
+        let name = format!("{}\n%.txt", i);
+        fs.write_atomic(["dir", name.as_str()], &b"alpha"[..])
+            .unwrap();
+    }
+    let dir = vec!["dir".to_owned()];
+    remove_tree(&mut fs, dir.clone()).unwrap();
+    assert_eq!(pending_removals(&fs).unwrap(), std::slice::from_ref(&dir));
+    assert_eq!(fs.directory_at(dir.clone()).unwrap().entries.len(), 1);
+    fs.write_atomic(["a\n%.txt"], &b"alpha"[..]).unwrap();
+    remove_tree(&mut fs, vec!["a\n%.txt".to_owned()]).unwrap();
+    let path = vec!["missing".to_owned(), "a\n%.txt".to_owned()];
+    set_pending_removals(&mut fs, &[dir, path.clone()]).unwrap();
+    assert_eq!(pending_removals(&fs).unwrap()[1], path);
+    assert!(run(&mut fs, &mut uploads, Job::Remove, 10, || false).unwrap());
+    assert!(pending_removals(&fs).unwrap().is_empty());
+    assert_eq!(left(&fs), 0);
+}
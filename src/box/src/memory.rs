@@ -1,18 +1,44 @@
+use std::convert::TryFrom;
 use std::io;
 
+/// Linear memory addressed with 64-bit offsets, so memories over 4 GiB work
+/// on wasm32 too. The trait is object safe, so the backend can be picked at
+/// runtime with a `Box<dyn Memory>`.
 pub trait Memory {
-    const PAGE_SIZE: usize;
-    const MAX_PAGES: usize;
-    const MAX_SIZE: usize = Self::PAGE_SIZE * Self::MAX_PAGES;
+    fn page_size(&self) -> u64;
+    fn max_pages(&self) -> u64;
 
-    fn page_count(&self) -> io::Result<usize>;
-    fn grow(&mut self, num_pages: usize) -> io::Result<()>;
+    fn max_size(&self) -> u64 {
+        self.page_size() * self.max_pages()
+    }
+
+    fn page_count(&self) -> io::Result<u64>;
+    fn grow(&mut self, num_pages: u64) -> io::Result<()>;
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize>;
 
-    fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<usize>;
-    fn write(&mut self, offset: usize, buf: &[u8]) -> io::Result<usize>;
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.page_count()? * self.page_size())
+    }
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.page_count()? == 0)
+    }
 
-    fn len(&self) -> io::Result<usize> {
-        Ok(self.page_count()? * Self::PAGE_SIZE)
+    /// Overwrites `len` bytes at `offset` with zeros. Backends with a cheaper
+    /// way to clear memory should override this.
+    fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let zeros = [0u8; 4096];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(zeros.len() as u64) as usize;
+            match self.write(offset + done, &zeros[..n])? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => done += written as u64,
+            }
+        }
+        Ok(())
     }
 
     fn reader(&self) -> MemoryReader<'_, Self>
@@ -36,30 +62,130 @@ pub trait Memory {
     }
 }
 
-impl<'a, M: Memory> Memory for &'a mut M {
-    const PAGE_SIZE: usize = M::PAGE_SIZE;
-    const MAX_PAGES: usize = M::MAX_PAGES;
+impl<M: Memory + ?Sized> Memory for &mut M {
+    fn page_size(&self) -> u64 {
+        M::page_size(self)
+    }
+
+    fn max_pages(&self) -> u64 {
+        M::max_pages(self)
+    }
 
-    fn page_count(&self) -> io::Result<usize> {
+    fn page_count(&self) -> io::Result<u64> {
         M::page_count(self)
     }
 
-    fn grow(&mut self, num_pages: usize) -> io::Result<()> {
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
         M::grow(self, num_pages)
     }
 
-    fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<usize> {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
         M::read(self, offset, buf)
     }
 
-    fn write(&mut self, offset: usize, buf: &[u8]) -> io::Result<usize> {
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
         M::write(self, offset, buf)
     }
+
+    fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        M::zero(self, offset, len)
+    }
+}
+
+impl<M: Memory + ?Sized> Memory for Box<M> {
+    fn page_size(&self) -> u64 {
+        M::page_size(self)
+    }
+
+    fn max_pages(&self) -> u64 {
+        M::max_pages(self)
+    }
+
+    fn page_count(&self) -> io::Result<u64> {
+        M::page_count(self)
+    }
+
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        M::grow(self, num_pages)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        M::read(self, offset, buf)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        M::write(self, offset, buf)
+    }
+
+    fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        M::zero(self, offset, len)
+    }
+}
+
+/// Converts a 64-bit size or offset for indexing, failing where it doesn't
+/// fit the platform's `usize`.
+pub fn to_usize(n: u64) -> io::Result<usize> {
+    usize::try_from(n).map_err(|_| io::ErrorKind::InvalidInput.into())
+}
+
+/// Resolves `pos` against the current `offset` and the `len` of a stream,
+/// rejecting targets before its start.
+pub fn seek_target(pos: io::SeekFrom, offset: u64, len: u64) -> io::Result<u64> {
+    let (base, delta) = match pos {
+        io::SeekFrom::Start(offset) => return Ok(offset),
+        io::SeekFrom::Current(delta) => (offset, delta),
+        io::SeekFrom::End(delta) => (len, delta),
+    };
+    base.checked_add_signed(delta)
+        .ok_or_else(|| io::ErrorKind::InvalidInput.into())
+}
+
+/// Fills `bufs` in order through `read`, stopping at the first short read.
+/// Used to implement `read_vectored` for readers which can't do better than
+/// one read per buffer.
+pub fn read_slices<R: io::Read + ?Sized>(
+    r: &mut R,
+    bufs: &mut [io::IoSliceMut<'_>],
+) -> io::Result<usize> {
+    let mut total = 0;
+    for buf in bufs.iter_mut().filter(|buf| !buf.is_empty()) {
+        let n = match r.read(buf) {
+            Ok(n) => n,
+            Err(_) if total > 0 => break,
+            Err(e) => return Err(e),
+        };
+        total += n;
+        if n < buf.len() {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+/// Writes `bufs` in order through `write`, stopping at the first short
+/// write. The counterpart of `read_slices`.
+pub fn write_slices<W: io::Write + ?Sized>(
+    w: &mut W,
+    bufs: &[io::IoSlice<'_>],
+) -> io::Result<usize> {
+    let mut total = 0;
+    for buf in bufs.iter().filter(|buf| !buf.is_empty()) {
+        let n = match w.write(buf) {
+            Ok(n) => n,
+            Err(_) if total > 0 => break,
+            Err(e) => return Err(e),
+        };
+        total += n;
+        if n < buf.len() {
+            break;
+        }
+    }
+    Ok(total)
 }
 
 pub struct MemoryReader<'a, M: Sized> {
     pub memory: &'a M,
-    offset: usize,
+    offset: u64,
 }
 
 impl<'a, M> io::Seek for MemoryReader<'a, M>
@@ -67,13 +193,8 @@ where
     M: Memory,
 {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        let new_offset = match pos {
-            io::SeekFrom::Start(offset) => offset,
-            io::SeekFrom::Current(offset) => (self.offset as i64 + offset) as u64,
-            io::SeekFrom::End(offset) => (self.memory.len()? as i64 + offset) as u64,
-        };
-        self.offset = new_offset as _;
-        Ok(new_offset)
+        self.offset = seek_target(pos, self.offset, self.memory.len()?)?;
+        Ok(self.offset)
     }
 }
 
@@ -82,29 +203,25 @@ where
     M: Memory,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let required_len = self.offset + buf.len();
-        let current_len = self.memory.len()?;
-
-        let read_buf = if required_len > current_len {
-            let missing_len = required_len - current_len;
-            if missing_len > buf.len() {
-                return Ok(0);
-            }
-            let acceptable_len = buf.len() - missing_len;
-            &mut buf[..acceptable_len]
-        } else {
-            buf
-        };
+        let available = self.memory.len()?.saturating_sub(self.offset);
+        let read_len = buf.len().min(to_usize(available).unwrap_or(usize::MAX));
+        if read_len == 0 {
+            return Ok(0);
+        }
 
-        let read = self.memory.read(self.offset, read_buf)?;
-        self.offset += read;
+        let read = self.memory.read(self.offset, &mut buf[..read_len])?;
+        self.offset += read as u64;
         Ok(read)
     }
+
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        read_slices(self, bufs)
+    }
 }
 
 pub struct MemoryWriter<'a, M: Sized> {
     pub memory: &'a mut M,
-    offset: usize,
+    offset: u64,
 }
 
 impl<'a, M> io::Seek for MemoryWriter<'a, M>
@@ -112,36 +229,44 @@ where
     M: Memory,
 {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        let new_offset = match pos {
-            io::SeekFrom::Start(offset) => offset,
-            io::SeekFrom::Current(offset) => (self.offset as i64 + offset) as u64,
-            io::SeekFrom::End(offset) => (self.memory.len()? as i64 + offset) as u64,
-        };
-        self.offset = new_offset as _;
-        Ok(new_offset)
+        self.offset = seek_target(pos, self.offset, self.memory.len()?)?;
+        Ok(self.offset)
     }
 }
 
-impl<'a, M> io::Write for MemoryWriter<'a, M>
+impl<'a, M> MemoryWriter<'a, M>
 where
     M: Memory,
 {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let required_len = self.offset + buf.len();
+    fn grow_to(&mut self, required_len: u64) -> io::Result<()> {
         let current_len = self.memory.len()?;
         if required_len > current_len {
             let missing_len = required_len - current_len;
-            let mut missing_pages = missing_len / M::PAGE_SIZE;
-            if missing_len % M::PAGE_SIZE > 0 {
-                missing_pages += 1;
-            }
-            self.memory.grow(missing_pages)?;
+            self.memory
+                .grow(missing_len.div_ceil(self.memory.page_size()))?;
         }
+        Ok(())
+    }
+}
+
+impl<'a, M> io::Write for MemoryWriter<'a, M>
+where
+    M: Memory,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.grow_to(self.offset + buf.len() as u64)?;
         let written = self.memory.write(self.offset, buf)?;
-        self.offset += written;
+        self.offset += written as u64;
         Ok(written)
     }
 
+    /// Grows the memory once for all buffers before writing them.
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len() as u64).sum::<u64>();
+        self.grow_to(self.offset + len)?;
+        write_slices(self, bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
@@ -153,10 +278,11 @@ fn io() {
     use super::heap_memory::HeapMemory;
 
     let mut memory = HeapMemory::default();
+    let page_size = memory.page_size();
 
     {
         let mut w = memory.writer();
-        w.seek(io::SeekFrom::Start((HeapMemory::PAGE_SIZE - 13) as _)).unwrap();
+        w.seek(io::SeekFrom::Start((page_size - 13) as _)).unwrap();
         w.write_all(b"Hello, World!").unwrap();
     }
 
@@ -168,3 +294,44 @@ fn io() {
         assert_eq!(&buf, b"World!");
     }
 }
+
+#[test]
+fn vectored() {
+    use super::heap_memory::HeapMemory;
+    use std::io::{Read, Seek, Write};
+
+    let mut memory = HeapMemory::default();
+
+    {
+        let mut w = memory.writer();
+        let bufs = [io::IoSlice::new(b"Hello, "), io::IoSlice::new(b"World!")];
+        assert_eq!(w.write_vectored(&bufs).unwrap(), 13);
+    }
+    assert_eq!(memory.page_count().unwrap(), 1);
+
+    let mut r = memory.reader();
+    let (mut a, mut b) = ([0u8; 5], [0u8; 8]);
+    let mut bufs = [io::IoSliceMut::new(&mut a), io::IoSliceMut::new(&mut b)];
+    assert_eq!(r.read_vectored(&mut bufs).unwrap(), 13);
+    assert_eq!(&a, b"Hello");
+    assert_eq!(&b, b", World!");
+
+    // Reads stop at the end of the memory.
+    r.seek(io::SeekFrom::End(-4)).unwrap();
+    let mut bufs = [io::IoSliceMut::new(&mut a), io::IoSliceMut::new(&mut b)];
+    assert_eq!(r.read_vectored(&mut bufs).unwrap(), 4);
+}
+
+#[test]
+fn dyn_memory() {
+    use super::file_system::FileSystem;
+    use super::heap_memory::HeapMemory;
+
+    let memory: Box<dyn Memory> = Box::new(HeapMemory::default());
+    let mut fs = FileSystem::new(memory).unwrap();
+    fs.write_atomic(vec!["a"], &b"boxed"[..]).unwrap();
+
+    let mut data = vec![];
+    fs.read_file(vec!["a"], &mut data).unwrap();
+    assert_eq!(data, b"boxed");
+}
@@ -0,0 +1,159 @@
+use std::cell::Cell;
+use std::io;
+
+use crate::memory::Memory;
+
+/// What a `MeteredMemory` counted. Instructions are only counted on the
+/// IC, and include those spent in the wrapped memory and system calls.
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct MemoryStats {
+    pub reads: u64,
+    pub bytes_read: u64,
+    pub read_instructions: u64,
+    /// Writes and zeroing.
+    pub writes: u64,
+    pub bytes_written: u64,
+    pub write_instructions: u64,
+    pub grows: u64,
+    pub pages_grown: u64,
+    pub grow_instructions: u64,
+}
+
+/// Wraps a memory to count the operations on it, to find out where the
+/// time goes.
+pub struct MeteredMemory<M> {
+    memory: M,
+    stats: Cell<MemoryStats>,
+}
+
+impl<M: Memory> MeteredMemory<M> {
+    pub fn new(memory: M) -> Self {
+        MeteredMemory {
+            memory,
+            stats: Cell::default(),
+        }
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        self.stats.get()
+    }
+
+    /// Starts counting from 0, e.g. at the start of a call.
+    pub fn reset(&self) {
+        self.stats.take();
+    }
+
+    /// Adds to the stats what an operation which started with the
+    /// instruction counter at `start` did.
+    fn record(&self, start: u64, update: impl FnOnce(&mut MemoryStats, u64)) {
+        let mut stats = self.stats.get();
+        update(&mut stats, instruction_counter().wrapping_sub(start));
+        self.stats.set(stats);
+    }
+}
+
+impl<M: Memory> Memory for MeteredMemory<M> {
+    fn page_size(&self) -> u64 {
+        self.memory.page_size()
+    }
+
+    fn max_pages(&self) -> u64 {
+        self.memory.max_pages()
+    }
+
+    fn page_count(&self) -> io::Result<u64> {
+        self.memory.page_count()
+    }
+
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        let start = instruction_counter();
+        let r = self.memory.grow(num_pages);
+        self.record(start, |stats, instructions| {
+            stats.grows += 1;
+            stats.pages_grown += if r.is_ok() { num_pages } else { 0 };
+            stats.grow_instructions += instructions;
+        });
+        r
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = instruction_counter();
+        let r = self.memory.read(offset, buf);
+        self.record(start, |stats, instructions| {
+            stats.reads += 1;
+            stats.bytes_read += *r.as_ref().unwrap_or(&0) as u64;
+            stats.read_instructions += instructions;
+        });
+        r
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let start = instruction_counter();
+        let r = self.memory.write(offset, buf);
+        self.record(start, |stats, instructions| {
+            stats.writes += 1;
+            stats.bytes_written += *r.as_ref().unwrap_or(&0) as u64;
+            stats.write_instructions += instructions;
+        });
+        r
+    }
+
+    fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let start = instruction_counter();
+        let r = self.memory.zero(offset, len);
+        self.record(start, |stats, instructions| {
+            stats.writes += 1;
+            stats.bytes_written += if r.is_ok() { len } else { 0 };
+            stats.write_instructions += instructions;
+        });
+        r
+    }
+}
+
+/// Instructions executed in the current message so far.
+#[cfg(target_arch = "wasm32")]
+fn instruction_counter() -> u64 {
+    #[link(wasm_import_module = "ic0")]
+    extern "C" {
+        fn performance_counter(counter_type: u32) -> u64;
+    }
+    // Safety: a system call without pointer arguments.
+    unsafe { performance_counter(0) }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn instruction_counter() -> u64 {
+    0
+}
+
+#[test]
+fn metered_memory() {
+    use crate::file_system::FileSystem;
+    use crate::heap_memory::HeapMemory;
+
+    let mut memory = MeteredMemory::new(HeapMemory::default());
+    memory.grow(2).unwrap();
+    memory.write(10, &[1u8; 100]).unwrap();
+    memory.read(0, &mut [0u8; 50]).unwrap();
+    memory.zero(0, 20).unwrap();
+    assert_eq!(
+        memory.stats(),
+        MemoryStats {
+            reads: 1,
+            bytes_read: 50,
+            writes: 2,
+            bytes_written: 120,
+            grows: 1,
+            pages_grown: 2,
+            ..Default::default()
+        }
+    );
+    memory.reset();
+    assert_eq!(memory.stats(), MemoryStats::default());
+
+    // Works underneath a filesystem.
+    let mut fs = FileSystem::new(&mut memory).unwrap();
+    fs.write_atomic(vec!["a"], &[1u8; 3000][..]).unwrap();
+    drop(fs);
+    assert!(memory.stats().bytes_written >= 3000);
+}
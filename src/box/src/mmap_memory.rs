@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io;
+
+use memmap2::MmapMut;
+
+use crate::error::Error;
+use crate::memory::{to_usize, Memory};
+
+/// Memory backed by a file mapped into the address space, which avoids a
+/// system call per read and write for large native workloads. Like
+/// `FileMemory`, pages are the size of stable memory pages. The file is
+/// remapped whenever it grows.
+pub struct MmapMemory {
+    file: File,
+    map: MmapMut,
+    max_pages: u64,
+}
+
+impl MmapMemory {
+    const PAGE_SIZE: u64 = 65536;
+
+    /// Maps `file`, which has to be open for reading and writing. Nothing
+    /// else may change the length of the file while it's mapped.
+    pub fn new(file: File) -> io::Result<Self> {
+        // Safety: the mapping lives no longer than `self`, and only `grow`
+        // changes the length of the file, after which it's remapped.
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(MmapMemory {
+            file,
+            map,
+            max_pages: 65535,
+        })
+    }
+
+    pub fn with_max_pages(mut self, max_pages: u64) -> Self {
+        self.max_pages = max_pages;
+        self
+    }
+
+    /// Writes changes back to the file. They reach it eventually anyway,
+    /// but only this waits for them to be durable.
+    pub fn flush(&self) -> io::Result<()> {
+        self.map.flush()
+    }
+
+    /// The `len` bytes at `offset`, as far as they're mapped.
+    fn range(&self, offset: u64, len: usize) -> io::Result<std::ops::Range<usize>> {
+        let start = to_usize(offset)?.min(self.map.len());
+        Ok(start..start + len.min(self.map.len() - start))
+    }
+}
+
+impl Memory for MmapMemory {
+    fn page_size(&self) -> u64 {
+        Self::PAGE_SIZE
+    }
+
+    fn max_pages(&self) -> u64 {
+        self.max_pages
+    }
+
+    fn page_count(&self) -> io::Result<u64> {
+        Ok(self.map.len() as u64 / Self::PAGE_SIZE)
+    }
+
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        let pages = self.page_count()? + num_pages;
+        if pages > self.max_pages {
+            return Err(Error::OutOfSpace.into());
+        }
+        self.map.flush()?;
+        self.file.set_len(pages * Self::PAGE_SIZE)?;
+        // Safety: as in `new`.
+        self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        Ok(())
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let range = self.range(offset, buf.len())?;
+        let len = range.len();
+        buf[..len].copy_from_slice(&self.map[range]);
+        Ok(len)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let range = self.range(offset, buf.len())?;
+        let len = range.len();
+        self.map[range].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        let range = self.range(offset, to_usize(len)?)?;
+        if range.len() as u64 != len {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        self.map[range].fill(0);
+        Ok(())
+    }
+}
+
+#[test]
+fn mmap_memory() {
+    use crate::file_system::FileSystem;
+
+    let path = std::env::temp_dir().join(format!("box-mmap-memory-{}", std::process::id()));
+    let open = || {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .unwrap();
+        MmapMemory::new(file).unwrap().with_max_pages(64)
+    };
+
+    {
+        let mut fs = FileSystem::new(open()).unwrap();
+        fs.write_atomic(vec!["a"], &[5u8; 3000][..]).unwrap();
+        fs.remove(vec!["a"]).unwrap();
+        fs.write_atomic(vec!["b"], &[6u8; 300_000][..]).unwrap();
+    }
+    let mut memory = open();
+    memory.flush().unwrap();
+    assert!(memory.grow(64).is_err());
+    assert!(memory.zero(memory.len().unwrap() - 10, 20).is_err());
+
+    let fs = FileSystem::open(memory).unwrap();
+    let mut data = vec![];
+    fs.read_file(vec!["b"], &mut data).unwrap();
+    assert_eq!(data, [6u8; 300_000]);
+    drop(fs);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,64 @@
+use std::io;
+
+use crate::directory::EntryKind;
+use crate::file_system::{FileSystem, Metadata};
+use crate::memory::Memory;
+
+/// A filesystem which can be mounted into another with
+/// `FileSystem::mount`, whatever memory it's backed by. Paths are relative
+/// to its root. Mounts are `Send + Sync` so that a filesystem can be
+/// shared between threads.
+pub trait Mount: Send + Sync {
+    fn exists(&self, path: &[String]) -> bool;
+    fn metadata(&self, path: &[String]) -> io::Result<Metadata>;
+    fn read_file(&self, path: &[String], w: &mut dyn io::Write) -> io::Result<u64>;
+    fn list_directory(&self, path: &[String]) -> io::Result<Vec<(String, EntryKind)>>;
+    fn write_atomic(&mut self, path: &[String], r: &mut dyn io::Read) -> io::Result<u64>;
+    fn remove(&mut self, path: &[String]) -> io::Result<()>;
+    fn make_directory_recursive(&mut self, path: &[String]) -> io::Result<()>;
+    fn set_sealed(&mut self, path: &[String], sealed: bool) -> io::Result<()>;
+    fn set_expiry(&mut self, path: &[String], expires: Option<u64>) -> io::Result<()>;
+    fn persist(&mut self) -> io::Result<()>;
+}
+
+impl<M: Memory + Send + Sync> Mount for FileSystem<M> {
+    fn exists(&self, path: &[String]) -> bool {
+        FileSystem::exists(self, path)
+    }
+
+    fn metadata(&self, path: &[String]) -> io::Result<Metadata> {
+        FileSystem::metadata(self, path)
+    }
+
+    fn read_file(&self, path: &[String], w: &mut dyn io::Write) -> io::Result<u64> {
+        FileSystem::read_file(self, path, w)
+    }
+
+    fn list_directory(&self, path: &[String]) -> io::Result<Vec<(String, EntryKind)>> {
+        FileSystem::list_directory(self, path)
+    }
+
+    fn write_atomic(&mut self, path: &[String], r: &mut dyn io::Read) -> io::Result<u64> {
+        FileSystem::write_atomic(self, path, r)
+    }
+
+    fn remove(&mut self, path: &[String]) -> io::Result<()> {
+        FileSystem::remove(self, path)
+    }
+
+    fn make_directory_recursive(&mut self, path: &[String]) -> io::Result<()> {
+        FileSystem::make_directory_recursive(self, path)
+    }
+
+    fn set_sealed(&mut self, path: &[String], sealed: bool) -> io::Result<()> {
+        FileSystem::set_sealed(self, path, sealed)
+    }
+
+    fn set_expiry(&mut self, path: &[String], expires: Option<u64>) -> io::Result<()> {
+        FileSystem::set_expiry(self, path, expires)
+    }
+
+    fn persist(&mut self) -> io::Result<()> {
+        FileSystem::persist(self)
+    }
+}
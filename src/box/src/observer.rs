@@ -0,0 +1,94 @@
+use std::collections::HashSet;
+
+use crate::directory::{Entry, EntryKind};
+
+/// Told about changes to a `FileSystem` once they're written, so indexes,
+/// certification trees or audit logs can be kept up to date. Paths are
+/// relative to the root. Changes made by a closure which returns an error
+/// are not reported. Observers are `Send + Sync` so that a filesystem can be
+/// shared between threads.
+pub trait FsObserver: Send + Sync {
+    /// An entry was added to a directory.
+    fn on_create(&mut self, _path: &[String], _kind: EntryKind) {}
+
+    /// A file was changed through `with_file_mut` or replaced by
+    /// `write_atomic`, and is now `size` bytes long.
+    fn on_write_complete(&mut self, _path: &[String], _size: u64) {}
+
+    /// An entry was removed from a directory. For a directory, its contents
+    /// are not reported one by one.
+    fn on_delete(&mut self, _path: &[String], _kind: EntryKind) {}
+
+    /// An entry was renamed within its directory.
+    fn on_rename(&mut self, _from: &[String], _to: &[String]) {}
+}
+
+/// A change to report to the observer.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Event {
+    Create(Vec<String>, EntryKind),
+    WriteComplete(Vec<String>, u64),
+    Delete(Vec<String>, EntryKind),
+    Rename(Vec<String>, Vec<String>),
+}
+
+impl Event {
+    pub(crate) fn notify(&self, observer: &mut dyn FsObserver) {
+        match self {
+            Event::Create(path, kind) => observer.on_create(path, *kind),
+            Event::WriteComplete(path, size) => observer.on_write_complete(path, *size),
+            Event::Delete(path, kind) => observer.on_delete(path, *kind),
+            Event::Rename(from, to) => observer.on_rename(from, to),
+        }
+    }
+}
+
+/// Inode, name and kind of the entries of a directory before a change.
+pub(crate) type Listing = Vec<(u64, String, EntryKind)>;
+
+pub(crate) fn listing(entries: &[Entry]) -> Listing {
+    entries
+        .iter()
+        .map(|entry| (entry.inode, entry.name.clone(), entry.kind))
+        .collect()
+}
+
+/// Compares the entries of the directory at `path` with the listing taken
+/// before they were changed. Entries are told apart by inode. Those without
+/// one are new, unless an entry of the same name had none either.
+pub(crate) fn changes(path: &[String], before: &Listing, after: &[Entry]) -> Vec<Event> {
+    let child = |name: &str| {
+        let mut child = path.to_vec();
+        child.push(name.to_owned());
+        child
+    };
+
+    let mut kept = HashSet::new();
+    let mut added = vec![];
+    for entry in after {
+        let old = before
+            .iter()
+            .position(|(inode, name, _)| match entry.inode {
+                0 => *inode == 0 && *name == entry.name,
+                number => *inode == number,
+            });
+        match old {
+            Some(i) => {
+                kept.insert(i);
+                if before[i].1 != entry.name {
+                    added.push(Event::Rename(child(&before[i].1), child(&entry.name)));
+                }
+            }
+            None => added.push(Event::Create(child(&entry.name), entry.kind)),
+        }
+    }
+
+    let mut events = before
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !kept.contains(i))
+        .map(|(_, (_, name, kind))| Event::Delete(child(name), *kind))
+        .collect::<Vec<_>>();
+    events.extend(added);
+    events
+}
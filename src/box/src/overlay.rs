@@ -0,0 +1,280 @@
+use std::io;
+
+use crate::directory::EntryKind;
+use crate::error::Error;
+use crate::file_system::Metadata;
+use crate::mount::Mount;
+
+/// Names starting with this mark entries of the base which were removed.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Marks a directory of the upper layer which hides the base directory it
+/// replaced, after the base directory was removed and created again.
+const OPAQUE: &str = ".wh..wh..opq";
+
+/// Layers a writable filesystem over a read-only one, like a default asset
+/// bundle with user changes on top. Reads fall through to the base where
+/// the upper layer has no entry. Everything written goes to the upper
+/// layer, which also records removed base entries as whiteouts: empty
+/// files named `.wh.` followed by the name of the entry. Names with that
+/// prefix can't be used through the overlay.
+pub struct OverlayFs<B, U> {
+    base: B,
+    upper: U,
+}
+
+impl<B: Mount, U: Mount> OverlayFs<B, U> {
+    pub fn new(base: B, upper: U) -> Self {
+        OverlayFs { base, upper }
+    }
+
+    pub fn base(&self) -> &B {
+        &self.base
+    }
+
+    pub fn upper(&self) -> &U {
+        &self.upper
+    }
+
+    fn whiteout(path: &[String]) -> Vec<String> {
+        let (name, parent) = path.split_last().unwrap();
+        let mut whiteout = parent.to_vec();
+        whiteout.push(format!("{}{}", WHITEOUT_PREFIX, name));
+        whiteout
+    }
+
+    fn opaque(path: &[String]) -> Vec<String> {
+        let mut opaque = path.to_vec();
+        opaque.push(OPAQUE.to_owned());
+        opaque
+    }
+
+    /// Whether the base entry at `path` shows through the upper layer,
+    /// i.e. neither it nor a directory on the way was removed.
+    fn in_base(&self, path: &[String]) -> bool {
+        for i in 1..=path.len() {
+            let prefix = &path[..i];
+            if self.upper.exists(&Self::whiteout(prefix))
+                || (i < path.len() && self.upper.exists(&Self::opaque(prefix)))
+            {
+                return false;
+            }
+        }
+        self.base.exists(path)
+    }
+
+    fn check_name(path: &[String]) -> io::Result<()> {
+        match path.last() {
+            None => Err(Error::InvalidPath.into()),
+            Some(name) if name.starts_with(WHITEOUT_PREFIX) => Err(Error::NameInvalid.into()),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Makes sure the directories leading to `path` exist in the upper
+    /// layer, so an entry can be written there.
+    fn copy_up_parent(&mut self, path: &[String]) -> io::Result<()> {
+        let parent = &path[..path.len() - 1];
+        if self.metadata(parent)?.kind != EntryKind::Directory {
+            return Err(Error::NotADirectory.into());
+        }
+        self.make_directory_recursive(parent)
+    }
+
+    /// Copies the entry at `path` from the base into the upper layer,
+    /// unless it's there already.
+    fn copy_up(&mut self, path: &[String]) -> io::Result<()> {
+        if self.upper.exists(path) {
+            return Ok(());
+        }
+        match self.metadata(path)?.kind {
+            EntryKind::Directory => self.make_directory_recursive(path),
+            EntryKind::File => {
+                let mut data = vec![];
+                self.base.read_file(path, &mut data)?;
+                self.copy_up_parent(path)?;
+                self.upper.write_atomic(path, &mut &data[..]).map(drop)
+            }
+        }
+    }
+}
+
+impl<B: Mount, U: Mount> Mount for OverlayFs<B, U> {
+    fn exists(&self, path: &[String]) -> bool {
+        path.is_empty() || self.upper.exists(path) || self.in_base(path)
+    }
+
+    fn metadata(&self, path: &[String]) -> io::Result<Metadata> {
+        if path.is_empty() || self.upper.exists(path) {
+            self.upper.metadata(path)
+        } else if self.in_base(path) {
+            self.base.metadata(path)
+        } else {
+            Err(Error::NotFound.into())
+        }
+    }
+
+    fn read_file(&self, path: &[String], w: &mut dyn io::Write) -> io::Result<u64> {
+        if self.upper.exists(path) {
+            self.upper.read_file(path, w)
+        } else if self.in_base(path) {
+            self.base.read_file(path, w)
+        } else {
+            Err(Error::NotFound.into())
+        }
+    }
+
+    /// Merges the entries of both layers, those of the upper layer first.
+    fn list_directory(&self, path: &[String]) -> io::Result<Vec<(String, EntryKind)>> {
+        if !self.exists(path) {
+            return Err(Error::NotFound.into());
+        }
+
+        let (mut entries, opaque) = if self.upper.exists(path) {
+            (
+                self.upper.list_directory(path)?,
+                self.upper.exists(&Self::opaque(path)),
+            )
+        } else {
+            (vec![], false)
+        };
+        let hidden = entries
+            .iter()
+            .filter_map(|(name, _)| name.strip_prefix(WHITEOUT_PREFIX))
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        entries.retain(|(name, _)| !name.starts_with(WHITEOUT_PREFIX));
+
+        if !opaque && (path.is_empty() || self.in_base(path)) {
+            for (name, kind) in self.base.list_directory(path)? {
+                if !hidden.contains(&name) && !entries.iter().any(|(n, _)| *n == name) {
+                    entries.push((name, kind));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write_atomic(&mut self, path: &[String], r: &mut dyn io::Read) -> io::Result<u64> {
+        Self::check_name(path)?;
+        if self.exists(path) && self.metadata(path)?.kind == EntryKind::Directory {
+            return Err(Error::IsADirectory.into());
+        }
+        self.copy_up_parent(path)?;
+
+        let whiteout = Self::whiteout(path);
+        if self.upper.exists(&whiteout) {
+            self.upper.remove(&whiteout)?;
+        }
+        self.upper.write_atomic(path, r)
+    }
+
+    /// Entries of the base are hidden behind a whiteout.
+    fn remove(&mut self, path: &[String]) -> io::Result<()> {
+        Self::check_name(path)?;
+        let in_base = self.in_base(path);
+        if self.upper.exists(path) {
+            self.upper.remove(path)?;
+        } else if !in_base {
+            return Err(Error::NotFound.into());
+        }
+
+        if in_base {
+            self.copy_up_parent(path)?;
+            self.upper
+                .write_atomic(&Self::whiteout(path), &mut io::empty())?;
+        }
+        Ok(())
+    }
+
+    /// Directories which replace a removed base directory are made opaque,
+    /// so the base contents stay hidden.
+    fn make_directory_recursive(&mut self, path: &[String]) -> io::Result<()> {
+        for i in 1..=path.len() {
+            let prefix = &path[..i];
+            Self::check_name(prefix)?;
+            if self.exists(prefix) {
+                if self.metadata(prefix)?.kind != EntryKind::Directory {
+                    return Err(Error::NotADirectory.into());
+                }
+                self.upper.make_directory_recursive(prefix)?;
+                continue;
+            }
+
+            let whiteout = Self::whiteout(prefix);
+            let replaces_base = self.upper.exists(&whiteout);
+            self.upper.make_directory_recursive(prefix)?;
+            if replaces_base {
+                self.upper.remove(&whiteout)?;
+                self.upper
+                    .write_atomic(&Self::opaque(prefix), &mut io::empty())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Base entries are copied up first.
+    fn set_sealed(&mut self, path: &[String], sealed: bool) -> io::Result<()> {
+        self.copy_up(path)?;
+        self.upper.set_sealed(path, sealed)
+    }
+
+    /// Base entries are copied up first.
+    fn set_expiry(&mut self, path: &[String], expires: Option<u64>) -> io::Result<()> {
+        self.copy_up(path)?;
+        self.upper.set_expiry(path, expires)
+    }
+
+    fn persist(&mut self) -> io::Result<()> {
+        self.upper.persist()
+    }
+}
+
+#[test]
+fn overlay() {
+    use crate::file_system::FileSystem;
+    use crate::heap_memory::HeapMemory;
+
+    let path = |path: &str| path.split('/').map(str::to_owned).collect::<Vec<_>>();
+    let read = |fs: &dyn Mount, p: &str| {
+        let mut data = vec![];
+        fs.read_file(&path(p), &mut data).map(|_| data)
+    };
+
+    let mut base = FileSystem::new(HeapMemory::default()).unwrap();
+    base.make_directory_recursive(vec!["assets", "img"])
+        .unwrap();
+    base.write_atomic(vec!["assets", "index.html"], &b"default"[..])
+        .unwrap();
+    base.write_atomic(vec!["assets", "img", "logo.png"], &b"logo"[..])
+        .unwrap();
+    let upper = FileSystem::new(HeapMemory::default()).unwrap();
+    let mut fs = OverlayFs::new(base, upper);
+
+    // Writes go to the upper layer and shadow the base.
+    fs.write_atomic(&path("assets/index.html"), &mut &b"custom"[..])
+        .unwrap();
+    assert_eq!(read(&fs, "assets/index.html").unwrap(), b"custom");
+    assert_eq!(read(fs.base(), "assets/index.html").unwrap(), b"default");
+    assert_eq!(read(&fs, "assets/img/logo.png").unwrap(), b"logo");
+
+    // Removed base entries are hidden by whiteouts.
+    fs.remove(&path("assets/img")).unwrap();
+    assert!(!fs.exists(&path("assets/img/logo.png")));
+    assert_eq!(
+        fs.list_directory(&path("assets")).unwrap(),
+        [("index.html".to_owned(), EntryKind::File)]
+    );
+    assert!(fs.base().exists(path("assets/img/logo.png")));
+    assert!(fs.upper().exists(path("assets/.wh.img")));
+
+    // A directory created again doesn't bring back the base contents.
+    fs.make_directory_recursive(&path("assets/img")).unwrap();
+    assert!(fs.exists(&path("assets/img")));
+    assert!(!fs.exists(&path("assets/img/logo.png")));
+    assert_eq!(fs.list_directory(&path("assets/img")).unwrap(), []);
+
+    assert!(fs
+        .write_atomic(&path("assets/.wh.index.html"), &mut io::empty())
+        .is_err());
+}
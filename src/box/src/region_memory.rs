@@ -0,0 +1,118 @@
+use std::io;
+
+use crate::error::Error;
+use crate::memory::{to_usize, Memory};
+
+/// A window of `max_pages` pages of another memory, starting at
+/// `start_page`, which looks like a memory of its own starting at offset 0.
+/// Lets the filesystem share a memory with other state, e.g. stable memory
+/// whose first pages are used by the rest of the canister. Pages of the
+/// window already in the other memory count as part of it.
+pub struct RegionMemory<M> {
+    memory: M,
+    start_page: u64,
+    max_pages: u64,
+}
+
+impl<M: Memory> RegionMemory<M> {
+    pub fn new(memory: M, start_page: u64, max_pages: u64) -> Self {
+        let max_pages = max_pages.min(memory.max_pages().saturating_sub(start_page));
+        RegionMemory {
+            memory,
+            start_page,
+            max_pages,
+        }
+    }
+
+    pub fn into_inner(self) -> M {
+        self.memory
+    }
+
+    fn start(&self) -> u64 {
+        self.start_page * self.memory.page_size()
+    }
+
+    /// How much of `len` bytes at `offset` lies within the window.
+    fn clamp(&self, offset: u64, len: usize) -> usize {
+        let available = self.max_size().saturating_sub(offset);
+        len.min(to_usize(available).unwrap_or(usize::MAX))
+    }
+}
+
+impl<M: Memory> Memory for RegionMemory<M> {
+    fn page_size(&self) -> u64 {
+        self.memory.page_size()
+    }
+
+    fn max_pages(&self) -> u64 {
+        self.max_pages
+    }
+
+    fn page_count(&self) -> io::Result<u64> {
+        let pages = self.memory.page_count()?.saturating_sub(self.start_page);
+        Ok(pages.min(self.max_pages))
+    }
+
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        let required = self.page_count()? + num_pages;
+        if required > self.max_pages {
+            return Err(Error::OutOfSpace.into());
+        }
+        let missing = (self.start_page + required).saturating_sub(self.memory.page_count()?);
+        self.memory.grow(missing)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        self.memory.read(self.start() + offset, &mut buf[..len])
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        let start = self.start();
+        self.memory.write(start + offset, &buf[..len])
+    }
+
+    fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if offset + len > self.max_size() {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        let start = self.start();
+        self.memory.zero(start + offset, len)
+    }
+}
+
+#[test]
+fn region_memory() {
+    use crate::file_system::FileSystem;
+    use crate::heap_memory::HeapMemory;
+
+    use std::io::{Read, Write};
+
+    let mut heap = HeapMemory::default();
+    heap.writer().write_all(&[7u8; 2048]).unwrap();
+
+    let mut region = RegionMemory::new(&mut heap, 2, 100);
+    assert_eq!(region.page_count().unwrap(), 0);
+    {
+        let mut fs = FileSystem::new(&mut region).unwrap();
+        fs.write_atomic(vec!["a"], &[1u8; 5000][..]).unwrap();
+    }
+    // Nothing past the window can be reached.
+    let pages = region.page_count().unwrap();
+    assert!(region.grow(101 - pages).is_err());
+    region.grow(100 - pages).unwrap();
+    assert_eq!(region.write(100 * 1024 - 1, &[1, 2]).unwrap(), 1);
+
+    let fs = FileSystem::open(&mut region).unwrap();
+    let mut data = vec![];
+    fs.read_file(vec!["a"], &mut data).unwrap();
+    assert_eq!(data, [1u8; 5000]);
+    drop(fs);
+
+    // The pages before the region are left alone.
+    let heap = region.into_inner();
+    let mut before = [0u8; 2048];
+    heap.reader().read_exact(&mut before).unwrap();
+    assert!(before.iter().all(|&b| b == 7));
+}
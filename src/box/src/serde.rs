@@ -1,6 +1,10 @@
+use std::cell::Cell;
 use std::io::{self, Read, Write};
 use std::mem::size_of;
 
+use crate::error::Error;
+use crate::memory::to_usize;
+
 pub trait Serialize {
     fn serialize(&self, w: impl Write) -> io::Result<usize>;
 }
@@ -90,21 +94,21 @@ impl<T: Deserialize + Default> Deserialize for Vec<T> {
     }
 }
 
-impl<'a> Serialize for &'a [u8] {
+impl Serialize for &[u8] {
     fn serialize(&self, mut w: impl Write) -> io::Result<usize> {
         w.write_all(self)?;
         Ok(self.len())
     }
 }
 
-impl<'a> Deserialize for &'a mut [u8] {
+impl Deserialize for &mut [u8] {
     fn deserialize(&mut self, mut r: impl Read) -> io::Result<usize> {
         r.read_exact(self)?;
         Ok(self.len())
     }
 }
 
-impl<'a> Serialize for &'a str {
+impl Serialize for &str {
     fn serialize(&self, mut w: impl Write) -> io::Result<usize> {
         Ok(self.len().serialize(&mut w)? + self.as_bytes().serialize(&mut w)?)
     }
@@ -114,9 +118,121 @@ impl Deserialize for String {
     fn deserialize(&mut self, mut r: impl Read) -> io::Result<usize> {
         let mut len = 0usize;
         let n = len.deserialize(&mut r)?;
-        let mut bytes = vec![0u8; len];
-        r.read_exact(&mut bytes)?;
-        *self = String::from_utf8_lossy(&bytes).to_string();
+        *self = read_string(r, len)?;
+        Ok(n + len)
+    }
+}
+
+/// Longest string which is read by default, so a corrupted length is refused
+/// instead of trusted. Names and content types are far shorter.
+pub const MAX_STRING_LEN: usize = 64 * 1024;
+
+thread_local! {
+    static MAX_LEN: Cell<usize> = const { Cell::new(MAX_STRING_LEN) };
+}
+
+/// Runs `f` with strings up to `len` bytes long being read, rather than
+/// `MAX_STRING_LEN`. The limit can't be passed through `Deserialize`.
+pub(crate) fn with_max_string_len<R>(len: usize, f: impl FnOnce() -> R) -> R {
+    struct Restore(usize);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            MAX_LEN.with(|max| max.set(self.0));
+        }
+    }
+
+    let _restore = Restore(MAX_LEN.with(|max| max.replace(len)));
+    f()
+}
+
+/// Reads a string of `len` bytes, failing if it's too long or not UTF-8.
+pub(crate) fn read_string(r: impl Read, len: usize) -> io::Result<String> {
+    if len > MAX_LEN.with(Cell::get) {
+        return Err(Error::corrupted(format!("string of {} bytes is too long", len)).into());
+    }
+    String::from_utf8(read_bytes(r, len)?)
+        .map_err(|_| Error::corrupted("string is not valid UTF-8").into())
+}
+
+pub(crate) fn read_bytes(r: impl Read, len: usize) -> io::Result<Vec<u8>> {
+    // A corrupted length runs into the end of the data rather than
+    // allocating all of it up front.
+    let mut bytes = Vec::new();
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+/// Numbers as unsigned LEB128 varints, seven bits per byte with the high bit
+/// set on all but the last byte, and strings with such a length. Numbers
+/// below 128 take a single byte.
+pub struct Compact<T>(pub T);
+
+impl Serialize for Compact<u64> {
+    fn serialize(&self, mut w: impl Write) -> io::Result<usize> {
+        let mut buf = [0u8; 10];
+        let (mut n, mut len) = (self.0, 0);
+        loop {
+            buf[len] = (n & 0x7f) as u8;
+            n >>= 7;
+            len += 1;
+            if n == 0 {
+                break;
+            }
+            buf[len - 1] |= 0x80;
+        }
+        w.write_all(&buf[..len])?;
+        Ok(len)
+    }
+}
+
+impl Deserialize for Compact<&mut u64> {
+    fn deserialize(&mut self, mut r: impl Read) -> io::Result<usize> {
+        let mut n = 0u64;
+        for i in 0..10 {
+            let byte = u8::deserialize_into_default(&mut r)?;
+            if i == 9 && byte > 1 {
+                break;
+            }
+            n |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                *self.0 = n;
+                return Ok(i + 1);
+            }
+        }
+        Err(Error::corrupted("varint out of range").into())
+    }
+}
+
+impl Serialize for Compact<usize> {
+    fn serialize(&self, w: impl Write) -> io::Result<usize> {
+        Compact(self.0 as u64).serialize(w)
+    }
+}
+
+impl Deserialize for Compact<&mut usize> {
+    fn deserialize(&mut self, r: impl Read) -> io::Result<usize> {
+        let mut n = 0u64;
+        let len = Compact(&mut n).deserialize(r)?;
+        *self.0 = to_usize(n)?;
+        Ok(len)
+    }
+}
+
+impl Serialize for Compact<&str> {
+    fn serialize(&self, mut w: impl Write) -> io::Result<usize> {
+        Ok(Compact(self.0.len()).serialize(&mut w)? + self.0.as_bytes().serialize(&mut w)?)
+    }
+}
+
+impl Deserialize for Compact<&mut String> {
+    fn deserialize(&mut self, mut r: impl Read) -> io::Result<usize> {
+        let mut len = 0usize;
+        let n = Compact(&mut len).deserialize(&mut r)?;
+        *self.0 = read_string(r, len)?;
         Ok(n + len)
     }
 }
@@ -130,4 +246,35 @@ fn serde() {
     let mut actual = String::new();
     actual.deserialize(&*buf).unwrap();
     assert_eq!(string, actual);
+
+    // Corrupted strings are refused.
+    buf.clear();
+    (&[0xffu8, 0xfe][..]).serialize(&mut buf).unwrap();
+    assert!(read_string(&*buf, 2).is_err());
+    buf.clear();
+    (MAX_STRING_LEN + 1).serialize(&mut buf).unwrap();
+    buf.resize(buf.len() + MAX_STRING_LEN + 1, b'a');
+    assert!(String::deserialize_into_default(&*buf).is_err());
+
+    // The limit can be raised and lowered.
+    let long = with_max_string_len(MAX_STRING_LEN + 1, || {
+        String::deserialize_into_default(&*buf)
+    });
+    assert_eq!(long.unwrap().len(), MAX_STRING_LEN + 1);
+    buf.clear();
+    "short".serialize(&mut buf).unwrap();
+    assert!(with_max_string_len(4, || String::deserialize_into_default(&*buf)).is_err());
+    assert!(String::deserialize_into_default(&*buf).is_ok());
+
+    for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+        buf.clear();
+        let len = Compact(n).serialize(&mut buf).unwrap();
+        assert_eq!(len, buf.len());
+        let mut actual = 0u64;
+        assert_eq!(Compact(&mut actual).deserialize(&*buf).unwrap(), len);
+        assert_eq!(actual, n);
+    }
+    assert_eq!(buf.len(), 10);
+    buf[9] = 2;
+    assert!(Compact(&mut 0u64).deserialize(&*buf).is_err());
 }
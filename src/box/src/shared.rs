@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::error::Error;
+use crate::file_system::FileSystem;
+use crate::memory::Memory;
+
+/// How much of a file `read_file` and `write_file` move while holding the
+/// filesystem.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// A filesystem which can be shared between threads, e.g. by a native
+/// server. Any number of readers go through at once, while a writer has the
+/// filesystem to itself.
+///
+/// File contents are best moved with `read_file` and `write_file`, which
+/// hold the filesystem a chunk at a time and lock the file alone in between.
+pub struct SharedFileSystem<M: Memory> {
+    fs: RwLock<FileSystem<M>>,
+    files: Mutex<HashMap<Vec<String>, Arc<RwLock<()>>>>,
+}
+
+impl<M: Memory> SharedFileSystem<M> {
+    pub fn new(fs: FileSystem<M>) -> Self {
+        SharedFileSystem {
+            fs: RwLock::new(fs),
+            files: Mutex::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> io::Result<FileSystem<M>> {
+        self.fs.into_inner().map_err(|_| Self::poisoned())
+    }
+
+    /// Runs `f` on the filesystem alongside other readers.
+    pub fn read<R>(&self, f: impl FnOnce(&FileSystem<M>) -> io::Result<R>) -> io::Result<R> {
+        f(&*self.fs.read().map_err(|_| Self::poisoned())?)
+    }
+
+    /// Runs `f` on the filesystem once all readers and writers before it are
+    /// done.
+    pub fn write<R>(&self, f: impl FnOnce(&mut FileSystem<M>) -> io::Result<R>) -> io::Result<R> {
+        f(&mut *self.fs.write().map_err(|_| Self::poisoned())?)
+    }
+
+    /// Copies the contents of the file at `path` into `w`. Writers to other
+    /// files get in between the chunks, and a slow `w` holds up no one but
+    /// writers to the same file.
+    pub fn read_file(&self, path: &[String], mut w: impl io::Write) -> io::Result<u64> {
+        let lock = self.file_lock(path)?;
+        let _file = lock.read().map_err(|_| Self::poisoned())?;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let n = self.read(|fs| fs.read_at(path, offset, &mut buf))?;
+            if n == 0 {
+                return Ok(offset);
+            }
+            w.write_all(&buf[..n])?;
+            offset += n as u64;
+        }
+    }
+
+    /// Writes `data` at `offset` of the file at `path`. Readers of the file
+    /// through `read_file` see all of it or none of it, while everything
+    /// else goes on in between the chunks.
+    pub fn write_file(&self, path: &[String], offset: u64, data: &[u8]) -> io::Result<()> {
+        let lock = self.file_lock(path)?;
+        let _file = lock.write().map_err(|_| Self::poisoned())?;
+        for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+            let at = offset + (i * CHUNK_SIZE) as u64;
+            self.write(|fs| fs.write_at(path, at, chunk))?;
+        }
+        Ok(())
+    }
+
+    /// The lock on the contents of the file at `path`. Locks nobody holds
+    /// any more are dropped along the way.
+    fn file_lock(&self, path: &[String]) -> io::Result<Arc<RwLock<()>>> {
+        let mut files = self.files.lock().map_err(|_| Self::poisoned())?;
+        files.retain(|_, lock| Arc::strong_count(lock) > 1);
+        Ok(files.entry(path.to_vec()).or_default().clone())
+    }
+
+    /// A writer panicked, so the filesystem may be half changed.
+    fn poisoned() -> io::Error {
+        Error::corrupted("a writer panicked while changing the filesystem").into()
+    }
+}
+
+#[test]
+fn shared_file_system() {
+    use crate::heap_memory::HeapMemory;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Waits to be resumed on its first write.
+    struct Sink {
+        data: Vec<u8>,
+        started: mpsc::Sender<()>,
+        resume: mpsc::Receiver<()>,
+    }
+
+    impl io::Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                self.started.send(()).unwrap();
+                self.resume.recv().unwrap();
+            }
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let fs = FileSystem::new(HeapMemory::default()).unwrap();
+    let shared = Arc::new(SharedFileSystem::new(fs));
+    shared
+        .write(|fs| fs.write_atomic(vec!["a"], &[1u8; 3000][..]))
+        .unwrap();
+
+    let readers = (0..4)
+        .map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let mut data = vec![];
+                shared.read(|fs| fs.read_file(vec!["a"], &mut data))?;
+                assert_eq!(data, [1u8; 3000]);
+                Ok::<_, io::Error>(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for i in 0..4 {
+        let name = format!("f{}", i);
+        shared
+            .write(|fs| fs.write_atomic(vec![name], &b"data"[..]))
+            .unwrap();
+    }
+    for reader in readers {
+        reader.join().unwrap().unwrap();
+    }
+
+    // A slow reader of one file doesn't hold up writers of others.
+    let (started, wait) = mpsc::channel();
+    let (go, resume) = mpsc::channel::<()>();
+    let reader = {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let mut sink = Sink {
+                data: vec![],
+                started,
+                resume,
+            };
+            shared.read_file(&["a".to_owned()], &mut sink).unwrap();
+            sink.data
+        })
+    };
+    wait.recv().unwrap();
+    shared.write_file(&["f0".to_owned()], 4, b"more").unwrap();
+    go.send(()).unwrap();
+    assert_eq!(reader.join().unwrap(), [1u8; 3000]);
+    let mut data = vec![];
+    shared.read_file(&["f0".to_owned()], &mut data).unwrap();
+    assert_eq!(data, b"datamore");
+
+    // A panicking writer leaves the filesystem unusable.
+    let poisoner = shared.clone();
+    thread::spawn(move || poisoner.write(|_| -> io::Result<()> { panic!() }))
+        .join()
+        .unwrap_err();
+    assert!(shared.read(|fs| Ok(fs.exists(vec!["a"]))).is_err());
+    assert!(Arc::try_unwrap(shared).ok().unwrap().into_inner().is_err());
+}
@@ -1,60 +1,161 @@
 use std::io;
 
+#[cfg(not(test))]
 use ic_cdk::api::stable;
 
-use crate::memory::Memory;
+use crate::error::Error;
+use crate::memory::{to_usize, Memory};
 
-pub struct StableMemory;
+/// The canister's stable memory, always addressed through the 64-bit API so
+/// that it may grow past 4 GiB regardless of the pointer width.
+///
+/// Reads and writes move at most `chunk_size` bytes per system call and
+/// return after one, so large transfers are made of bounded calls and
+/// whoever loops over them knows how far they got.
+pub struct StableMemory {
+    chunk_size: usize,
+}
+
+impl Default for StableMemory {
+    fn default() -> Self {
+        StableMemory {
+            chunk_size: Self::DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+impl StableMemory {
+    pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// How much of `len` bytes at `offset` lies within the stable memory
+    /// and the next chunk. The system API traps on anything past the end,
+    /// so reads and writes are cut short there instead, as with
+    /// `HeapMemory`, and those with nothing left aren't made at all.
+    fn clamp(&self, offset: u64, len: usize) -> io::Result<usize> {
+        let available = self.len()?.saturating_sub(offset);
+        Ok(len
+            .min(self.chunk_size)
+            .min(to_usize(available).unwrap_or(usize::MAX)))
+    }
+}
 
 impl Memory for StableMemory {
-    const PAGE_SIZE: usize = 65536;
-    const MAX_PAGES: usize = 65535;
+    fn page_size(&self) -> u64 {
+        65536
+    }
 
-    #[cfg(target_pointer_width = "32")]
-    fn page_count(&self) -> io::Result<usize> {
-        Ok(stable::stable_size() as _)
+    fn max_pages(&self) -> u64 {
+        65535
     }
 
-    #[cfg(target_pointer_width = "64")]
-    fn page_count(&self) -> io::Result<usize> {
-        Ok(stable::stable64_size() as _)
+    fn page_count(&self) -> io::Result<u64> {
+        Ok(stable::stable64_size())
     }
 
-    #[cfg(target_pointer_width = "32")]
-    fn grow(&mut self, num_pages: usize) -> io::Result<()> {
-        stable::stable_grow(num_pages as _)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "out of memory"))?;
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        stable::stable64_grow(num_pages).map_err(|_| Error::OutOfSpace)?;
         Ok(())
     }
 
-    #[cfg(target_pointer_width = "64")]
-    fn grow(&mut self, num_pages: usize) -> io::Result<()> {
-        stable::stable64_grow(num_pages as _)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "out of memory"))?;
-        Ok(())
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.clamp(offset, buf.len())?;
+        if len > 0 {
+            stable::stable64_read(offset, &mut buf[..len]);
+        }
+        Ok(len)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let len = self.clamp(offset, buf.len())?;
+        if len > 0 {
+            stable::stable64_write(offset, &buf[..len]);
+        }
+        Ok(len)
     }
+}
+
+/// Stands in for the system API in tests, and traps like it on accesses past
+/// the end. Keeps the length of every read and write.
+#[cfg(test)]
+mod stable {
+    use std::cell::RefCell;
+
+    const PAGE_SIZE: usize = 65536;
 
-    #[cfg(target_pointer_width = "32")]
-    fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<usize> {
-        stable::stable_read(offset as _, buf);
-        Ok(buf.len())
+    thread_local! {
+        static MEMORY: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        pub static ACCESSES: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
     }
 
-    #[cfg(target_pointer_width = "64")]
-    fn read(&self, offset: usize, buf: &mut [u8]) -> io::Result<usize> {
-        stable::stable64_read(offset as _, buf);
-        Ok(buf.len())
+    pub fn stable64_size() -> u64 {
+        MEMORY.with(|m| (m.borrow().len() / PAGE_SIZE) as u64)
     }
 
-    #[cfg(target_pointer_width = "32")]
-    fn write(&mut self, offset: usize, buf: &[u8]) -> io::Result<usize> {
-        stable::stable_write(offset as _, buf);
-        Ok(buf.len())
+    pub fn stable64_grow(new_pages: u64) -> Result<u64, ()> {
+        let size = stable64_size();
+        MEMORY.with(|m| {
+            m.borrow_mut()
+                .resize((size + new_pages) as usize * PAGE_SIZE, 0)
+        });
+        Ok(size)
     }
 
-    #[cfg(target_pointer_width = "64")]
-    fn write(&mut self, offset: usize, buf: &[u8]) -> io::Result<usize> {
-        stable::stable64_write(offset as _, buf);
-        Ok(buf.len())
+    pub fn stable64_read(offset: u64, buf: &mut [u8]) {
+        ACCESSES.with(|a| a.borrow_mut().push(buf.len()));
+        let offset = offset as usize;
+        MEMORY.with(|m| buf.copy_from_slice(&m.borrow()[offset..offset + buf.len()]));
     }
+
+    pub fn stable64_write(offset: u64, buf: &[u8]) {
+        ACCESSES.with(|a| a.borrow_mut().push(buf.len()));
+        let offset = offset as usize;
+        MEMORY.with(|m| m.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf));
+    }
+}
+
+#[test]
+fn bounds() {
+    let mut memory = StableMemory::default();
+    memory.grow(1).unwrap();
+    let end = memory.len().unwrap();
+
+    // Accesses across the end are cut short, and those past it do nothing.
+    assert_eq!(memory.write(end - 8, &[7u8; 16]).unwrap(), 8);
+    let mut buf = [0u8; 16];
+    assert_eq!(memory.read(end - 8, &mut buf).unwrap(), 8);
+    assert_eq!(&buf[..8], &[7u8; 8]);
+    assert_eq!(memory.read(end + 100, &mut buf).unwrap(), 0);
+    assert_eq!(memory.write(end, &buf).unwrap(), 0);
+
+    // So a corrupted offset is an error rather than a trap.
+    let mut r = memory.reader();
+    io::Seek::seek(&mut r, io::SeekFrom::Start(end - 8)).unwrap();
+    let err = io::Read::read_exact(&mut r, &mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn chunks() {
+    use std::io::{Read, Write};
+
+    let mut memory = StableMemory::default().with_chunk_size(1000);
+    let data = (0..4096).map(|i| i as u8).collect::<Vec<_>>();
+    memory.writer().write_all(&data).unwrap();
+
+    // Each call moves one chunk at most, and says how far it got.
+    let mut buf = vec![0u8; 4096];
+    assert_eq!(memory.read(0, &mut buf).unwrap(), 1000);
+    memory.reader().read_exact(&mut buf).unwrap();
+    assert_eq!(buf, data);
+
+    let accesses = stable::ACCESSES.with(|a| a.take());
+    assert_eq!(
+        accesses,
+        [1000, 1000, 1000, 1000, 96, 1000, 1000, 1000, 1000, 1000, 96]
+    );
 }
@@ -0,0 +1,188 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::checksum::Checksum;
+use crate::directory::EntryKind;
+use crate::file_system::{names, FileSystem};
+use crate::memory::Memory;
+
+/// What `sync_from` changed, or would change in a dry run. Paths are
+/// separated by `/`.
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct SyncReport {
+    /// Files which were new or differed, in the order they were written.
+    pub uploaded: Vec<String>,
+    /// Entries without a local counterpart, directories with everything
+    /// inside them.
+    pub removed: Vec<String>,
+    pub unchanged: usize,
+}
+
+/// Checksum of everything `r` yields.
+fn checksum(mut r: impl io::Read) -> io::Result<u64> {
+    let mut checksum = Checksum::default();
+    io::copy(&mut r, &mut checksum)?;
+    Ok(checksum.value())
+}
+
+impl<M: Memory> FileSystem<M> {
+    /// Makes the directory at `target` a copy of the local directory
+    /// `local`, like `rsync --delete`. Files are compared by size, then by
+    /// checksum, and only written if they differ. Entries without a local
+    /// counterpart are removed. Symbolic links are followed, and other
+    /// special files are left out. Files which are written keep their
+    /// content type, and new ones have none. With `dry_run` nothing is
+    /// changed, and the report tells what would be.
+    pub fn sync_from(
+        &mut self,
+        local: impl AsRef<Path>,
+        target: impl IntoIterator<Item = impl AsRef<str>>,
+        dry_run: bool,
+    ) -> io::Result<SyncReport> {
+        let mut path = names(target);
+        if !dry_run && !path.is_empty() {
+            self.make_directory_recursive(path.clone())?;
+        }
+        let mut report = SyncReport::default();
+        self.sync_directory(local.as_ref(), &mut path, dry_run, &mut report)?;
+        Ok(report)
+    }
+
+    fn sync_directory(
+        &mut self,
+        local: &Path,
+        path: &mut Vec<String>,
+        dry_run: bool,
+        report: &mut SyncReport,
+    ) -> io::Result<()> {
+        // Only missing in a dry run, for directories which would be created.
+        let existing = if self.exists(&*path) {
+            self.directory_at(&*path)?.entries
+        } else {
+            vec![]
+        };
+        let mut local_entries = std::fs::read_dir(local)?.collect::<io::Result<Vec<_>>>()?;
+        local_entries.sort_by_key(|e| e.file_name());
+
+        let mut synced = HashSet::new();
+        for local_entry in local_entries {
+            let name = local_entry.file_name().into_string().map_err(|name| {
+                let message = format!("file name {:?} isn't UTF-8", name);
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })?;
+            let metadata = std::fs::metadata(local_entry.path())?;
+            let file_type = metadata.file_type();
+            if !file_type.is_dir() && !file_type.is_file() {
+                continue;
+            }
+            let existing = existing.iter().find(|e| e.name == name);
+            let existing_kind = existing.map(|e| e.kind);
+            path.push(name.clone());
+
+            if file_type.is_dir() {
+                if existing_kind != Some(EntryKind::Directory) {
+                    if existing_kind.is_some() {
+                        self.remove_synced(path, dry_run, report)?;
+                    }
+                    if !dry_run {
+                        self.make_directory_recursive(path.clone())?;
+                    }
+                }
+                self.sync_directory(&local_entry.path(), path, dry_run, report)?;
+            } else {
+                let unchanged = match existing {
+                    Some(entry)
+                        if entry.kind == EntryKind::File && entry.size == metadata.len() =>
+                    {
+                        entry.content_checksum(self)? == checksum(File::open(local_entry.path())?)?
+                    }
+                    _ => false,
+                };
+                if unchanged {
+                    report.unchanged += 1;
+                } else {
+                    if existing_kind == Some(EntryKind::Directory) {
+                        self.remove_synced(path, dry_run, report)?;
+                    }
+                    if !dry_run {
+                        self.write_atomic(path.clone(), File::open(local_entry.path())?)?;
+                    }
+                    report.uploaded.push(path.join("/"));
+                }
+            }
+
+            path.pop();
+            synced.insert(name);
+        }
+
+        for entry in existing.iter().filter(|e| !synced.contains(&e.name)) {
+            path.push(entry.name.clone());
+            self.remove_synced(path, dry_run, report)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    fn remove_synced(
+        &mut self,
+        path: &[String],
+        dry_run: bool,
+        report: &mut SyncReport,
+    ) -> io::Result<()> {
+        if !dry_run {
+            self.remove(path.to_vec())?;
+        }
+        report.removed.push(path.join("/"));
+        Ok(())
+    }
+}
+
+#[test]
+fn sync_from() {
+    use crate::heap_memory::HeapMemory;
+
+    let local = std::env::temp_dir().join(format!("box-sync-{}", std::process::id()));
+    std::fs::create_dir_all(local.join("img")).unwrap();
+    std::fs::write(local.join("index.html"), b"<h1>hi</h1>").unwrap();
+    std::fs::write(local.join("img").join("a.png"), [1u8; 3000]).unwrap();
+    std::fs::write(local.join("old.txt"), b"old").unwrap();
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    let report = fs.sync_from(&local, vec!["www"], false).unwrap();
+    assert_eq!(
+        report.uploaded,
+        ["www/img/a.png", "www/index.html", "www/old.txt"]
+    );
+
+    // Same size, different contents.
+    std::fs::write(local.join("index.html"), b"<h1>yo</h1>").unwrap();
+    std::fs::remove_file(local.join("old.txt")).unwrap();
+    std::fs::create_dir(local.join("docs")).unwrap();
+    std::fs::write(local.join("docs").join("b.txt"), b"b").unwrap();
+    let expected = SyncReport {
+        uploaded: vec!["www/docs/b.txt".to_owned(), "www/index.html".to_owned()],
+        removed: vec!["www/old.txt".to_owned()],
+        unchanged: 1,
+    };
+    assert_eq!(fs.sync_from(&local, vec!["www"], true).unwrap(), expected);
+    assert!(fs.exists(vec!["www", "old.txt"]));
+    assert_eq!(fs.sync_from(&local, vec!["www"], false).unwrap(), expected);
+    assert!(!fs.exists(vec!["www", "old.txt"]));
+    let mut data = vec![];
+    fs.read_file(vec!["www", "index.html"], &mut data).unwrap();
+    assert_eq!(data, b"<h1>yo</h1>");
+
+    // A file turning into a directory.
+    std::fs::remove_file(local.join("index.html")).unwrap();
+    std::fs::create_dir(local.join("index.html")).unwrap();
+    let report = fs.sync_from(&local, vec!["www"], false).unwrap();
+    assert_eq!(report.removed, ["www/index.html"]);
+    assert_eq!(report.unchanged, 2);
+    assert!(fs
+        .list_directory(vec!["www", "index.html"])
+        .unwrap()
+        .is_empty());
+    std::fs::remove_dir_all(&local).unwrap();
+}
@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::block::Block;
+use crate::serde::{Deserialize, Serialize};
+
+/// Contents packed as `len` bytes at `offset` into a block shared with
+/// other files, rather than into a block of their own. Small files are kept
+/// entirely in such a tail.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Tail {
+    pub block: Block,
+    pub offset: u16,
+    pub len: u16,
+}
+
+impl Tail {
+    /// The offset of the tail in memory.
+    pub fn offset(&self) -> u64 {
+        self.block.offset() + self.offset as u64
+    }
+}
+
+impl Serialize for Tail {
+    fn serialize(&self, mut w: impl io::Write) -> io::Result<usize> {
+        w.write_all(&(self.block.index as u32).to_be_bytes())?;
+        w.write_all(&self.offset.to_be_bytes())?;
+        w.write_all(&self.len.to_be_bytes())?;
+        Ok(8)
+    }
+}
+
+impl Deserialize for Tail {
+    fn deserialize(&mut self, mut r: impl io::Read) -> io::Result<usize> {
+        let mut buf = [0u8; 8];
+        r.read_exact(&mut buf)?;
+        self.block = Block::at(u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as _);
+        self.offset = u16::from_be_bytes([buf[4], buf[5]]);
+        self.len = u16::from_be_bytes([buf[6], buf[7]]);
+        Ok(8)
+    }
+}
+
+impl Default for Tail {
+    fn default() -> Self {
+        Tail {
+            block: Block::at(0),
+            offset: 0,
+            len: 0,
+        }
+    }
+}
+
+/// Hands out space for tails within shared blocks, first fit. Which parts
+/// of the blocks are in use is derived from the inodes and never persisted.
+#[derive(Default, Debug)]
+pub struct TailAllocator {
+    /// The fragments in use in each tail block, as `(offset, len)` sorted by
+    /// offset.
+    blocks: BTreeMap<usize, Vec<(u16, u16)>>,
+}
+
+impl TailAllocator {
+    /// Records a tail which is in use, as found in an inode.
+    pub fn insert(&mut self, tail: Tail) {
+        let fragments = self.blocks.entry(tail.block.index).or_default();
+        let i = fragments.partition_point(|&(offset, _)| offset < tail.offset);
+        fragments.insert(i, (tail.offset, tail.len));
+    }
+
+    /// Finds room for `len` bytes in one of the tail blocks. Returns `None`
+    /// if none of them has enough, and a new block has to be added.
+    pub fn allocate(&mut self, len: u16) -> Option<Tail> {
+        let (&index, fragments) = self
+            .blocks
+            .iter()
+            .find(|(_, fragments)| Self::gap(fragments, len).is_some())?;
+        let tail = Tail {
+            block: Block::at(index),
+            offset: Self::gap(fragments, len).unwrap(),
+            len,
+        };
+        self.insert(tail);
+        Some(tail)
+    }
+
+    /// The offset of the first gap of at least `len` bytes.
+    fn gap(fragments: &[(u16, u16)], len: u16) -> Option<u16> {
+        let mut start = 0;
+        for &(offset, fragment_len) in fragments {
+            if offset - start >= len {
+                return Some(start);
+            }
+            start = offset + fragment_len;
+        }
+        (Block::SIZE as u16 - start >= len).then_some(start)
+    }
+
+    /// Adds an empty block to hand out tails from.
+    pub fn add_block(&mut self, block: Block) {
+        self.blocks.insert(block.index, vec![]);
+    }
+
+    /// Gives back the space of `tail`. Returns its block if no other tail is
+    /// left in it, so it can be released.
+    pub fn free(&mut self, tail: Tail) -> Option<Block> {
+        let fragments = self.blocks.get_mut(&tail.block.index)?;
+        fragments.retain(|&(offset, _)| offset != tail.offset);
+        if !fragments.is_empty() {
+            return None;
+        }
+        self.blocks.remove(&tail.block.index);
+        Some(tail.block)
+    }
+
+    pub fn blocks(&self) -> impl '_ + Iterator<Item = Block> {
+        self.blocks.keys().map(|&index| Block::at(index))
+    }
+}
+
+#[test]
+fn tail_allocator() {
+    let mut tails = TailAllocator::default();
+    assert_eq!(tails.allocate(100), None);
+
+    tails.add_block(Block::at(7));
+    let a = tails.allocate(300).unwrap();
+    let b = tails.allocate(200).unwrap();
+    assert_eq!((a.block, a.offset, b.offset), (Block::at(7), 0, 300));
+    assert_eq!(tails.allocate(100), None);
+
+    // Freed space is reused, and an empty block is handed back.
+    assert_eq!(tails.free(a), None);
+    let c = tails.allocate(250).unwrap();
+    assert_eq!(c.offset, 0);
+    assert_eq!(tails.free(b), None);
+    assert_eq!(tails.free(c), Some(Block::at(7)));
+    assert_eq!(tails.blocks().count(), 0);
+}
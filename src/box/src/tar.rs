@@ -0,0 +1,509 @@
+use std::io::{self, Read, Write};
+
+use crate::directory::EntryKind;
+use crate::error::Error;
+use crate::file_system::{names, FileSystem};
+use crate::memory::Memory;
+
+const BLOCK: usize = 512;
+
+/// Length of the name field of a header, and of the prefix in front of it.
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+
+/// What `import_tar` unpacked.
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct TarImport {
+    pub files: usize,
+    pub directories: usize,
+    /// Paths of links, devices and other entries which have no counterpart
+    /// in the filesystem.
+    pub skipped: Vec<String>,
+}
+
+/// A header field, up to its first NUL.
+fn field(header: &[u8]) -> &[u8] {
+    let len = header.iter().position(|&b| b == 0).unwrap_or(header.len());
+    &header[..len]
+}
+
+/// A number in a header field: octal digits, or big-endian base-256 if the
+/// high bit of the first byte is set.
+fn number(header: &[u8]) -> io::Result<u64> {
+    if header[0] & 0x80 != 0 {
+        return Ok(header[1..]
+            .iter()
+            .fold(u64::from(header[0] & 0x7f), |n, &b| n << 8 | u64::from(b)));
+    }
+    let digits = std::str::from_utf8(field(header))
+        .map_err(|_| Error::corrupted("bad number in tar header"))?
+        .trim();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| Error::corrupted("bad number in tar header").into())
+}
+
+/// Reads `len` bytes of an entry, and the padding after them.
+fn read_data(mut r: impl Read, len: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    (&mut r).take(len).read_to_end(&mut data)?;
+    if data.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    skip_padding(r, len)?;
+    Ok(data)
+}
+
+fn skip_padding(r: impl Read, len: u64) -> io::Result<()> {
+    let padding = (BLOCK as u64 - len % BLOCK as u64) % BLOCK as u64;
+    io::copy(&mut r.take(padding), &mut io::sink())?;
+    Ok(())
+}
+
+/// Stores `n` in a header field as octal digits, or in base-256 if it
+/// doesn't fit.
+fn put_number(field: &mut [u8], n: u64) {
+    let digits = format!("{:0width$o}", n, width = field.len() - 1);
+    if digits.len() < field.len() {
+        field[..digits.len()].copy_from_slice(digits.as_bytes());
+    } else {
+        let len = field.len();
+        field.iter_mut().for_each(|b| *b = 0);
+        field[len - 8..].copy_from_slice(&n.to_be_bytes());
+        field[0] |= 0x80;
+    }
+}
+
+/// Builds a ustar header. Names which don't fit are cut short, and have to
+/// come with a pax header holding them in full.
+fn header(path: &str, kind: u8, size: u64, mtime: u64) -> [u8; BLOCK] {
+    let mut header = [0u8; BLOCK];
+    let (prefix, name) = match split_path(path) {
+        Some(split) => split,
+        None => {
+            let len = (0..=NAME_LEN).rev().find(|&i| path.is_char_boundary(i));
+            ("", &path[..len.unwrap_or(0)])
+        }
+    };
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    put_number(
+        &mut header[100..108],
+        if kind == b'5' { 0o755 } else { 0o644 },
+    );
+    put_number(&mut header[108..116], 0);
+    put_number(&mut header[116..124], 0);
+    put_number(&mut header[124..136], size);
+    put_number(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    let sum = header.iter().map(|&b| u64::from(b)).sum::<u64>() + 8 * u64::from(b' ');
+    header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    header[155] = b' ';
+    header
+}
+
+/// Splits `path` into the prefix and name fields of a ustar header, if it
+/// fits into them.
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= NAME_LEN {
+        return Some(("", path));
+    }
+    path.char_indices()
+        .filter(|&(i, c)| c == '/' && i <= PREFIX_LEN && path.len() - i - 1 <= NAME_LEN)
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(_, name)| !name.is_empty())
+}
+
+/// A pax record, which holds its own length.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value)
+}
+
+/// Values of a pax extended header, which are records of the form
+/// `"<length> <key>=<value>\n"`.
+fn pax_records(data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let bad = || Error::corrupted("bad pax record in tar archive");
+    let mut records = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|&b| b == b' ').ok_or_else(bad)?;
+        let len = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| space < len && len <= rest.len())
+            .ok_or_else(bad)?;
+        let record = std::str::from_utf8(&rest[space + 1..len - 1]).map_err(|_| bad())?;
+        let (key, value) = record.split_once('=').ok_or_else(bad)?;
+        records.push((key.to_owned(), value.to_owned()));
+        rest = &rest[len..];
+    }
+    Ok(records)
+}
+
+impl<M: Memory> FileSystem<M> {
+    /// Unpacks a tar archive into the directory at `target`, which is
+    /// created if it doesn't exist. Supports ustar, GNU long names and pax
+    /// paths, sizes and modification times. Existing files are replaced.
+    /// Paths with `..` are refused. Links and other special entries are
+    /// skipped, and reported.
+    pub fn import_tar(
+        &mut self,
+        target: impl IntoIterator<Item = impl AsRef<str>>,
+        mut r: impl Read,
+    ) -> io::Result<TarImport> {
+        let target = names(target);
+        if !target.is_empty() {
+            self.make_directory_recursive(target.clone())?;
+        }
+
+        let mut imported = TarImport::default();
+        let (mut long_name, mut pax_path, mut pax_size, mut pax_mtime) = (None, None, None, None);
+        // Adding entries moves the modification time of directories, so
+        // times are set once everything is in place.
+        let mut times = vec![];
+        let mut header = [0u8; BLOCK];
+        loop {
+            r.read_exact(&mut header)?;
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+
+            let stored = number(&header[148..156])?;
+            let sum = header[..148]
+                .iter()
+                .chain([b' '; 8].iter())
+                .chain(header[156..].iter())
+                .map(|&b| u64::from(b))
+                .sum::<u64>();
+            if sum != stored {
+                return Err(Error::corrupted("tar header doesn't match its checksum").into());
+            }
+
+            let kind = header[156];
+            let size = match pax_size.take() {
+                Some(size) => size,
+                None => number(&header[124..136])?,
+            };
+            let mtime = match pax_mtime.take() {
+                Some(mtime) => mtime,
+                None => number(&header[136..148])?,
+            };
+            match kind {
+                b'x' => {
+                    for (key, value) in pax_records(&read_data(&mut r, size)?)? {
+                        match key.as_str() {
+                            "path" => pax_path = Some(value),
+                            "size" => {
+                                let size = value
+                                    .parse()
+                                    .map_err(|_| Error::corrupted("bad pax size in tar archive"))?;
+                                pax_size = Some(size);
+                            }
+                            // Seconds, possibly with a fraction.
+                            "mtime" => {
+                                let seconds = value.split('.').next().unwrap_or_default();
+                                let mtime = seconds.parse().map_err(|_| {
+                                    Error::corrupted("bad pax mtime in tar archive")
+                                })?;
+                                pax_mtime = Some(mtime);
+                            }
+                            _ => {}
+                        }
+                    }
+                    continue;
+                }
+                b'L' => {
+                    let name = read_data(&mut r, size)?;
+                    long_name = Some(String::from_utf8_lossy(field(&name)).into_owned());
+                    continue;
+                }
+                b'g' => {
+                    read_data(&mut r, size)?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let name = match (pax_path.take(), long_name.take()) {
+                (Some(path), _) | (None, Some(path)) => path,
+                (None, None) => {
+                    let (name, prefix) = (field(&header[..100]), field(&header[345..500]));
+                    let ustar = &header[257..262] == b"ustar";
+                    if ustar && !prefix.is_empty() {
+                        format!(
+                            "{}/{}",
+                            String::from_utf8_lossy(prefix),
+                            String::from_utf8_lossy(name)
+                        )
+                    } else {
+                        String::from_utf8_lossy(name).into_owned()
+                    }
+                }
+            };
+            let mut path = target.clone();
+            for segment in name.split('/').filter(|s| !s.is_empty() && *s != ".") {
+                if segment == ".." {
+                    return Err(Error::InvalidPath.into());
+                }
+                path.push(segment.to_owned());
+            }
+
+            match kind {
+                b'0' | b'\0' | b'7' if path.len() > target.len() => {
+                    let parent = &path[..path.len() - 1];
+                    if parent.len() > target.len() {
+                        self.make_directory_recursive(parent.to_vec())?;
+                    }
+                    let written = self.write_atomic(path.clone(), (&mut r).take(size))?;
+                    if written != size {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    skip_padding(&mut r, size)?;
+                    times.push((path, mtime));
+                    imported.files += 1;
+                }
+                b'5' => {
+                    self.make_directory_recursive(path.clone())?;
+                    read_data(&mut r, size)?;
+                    times.push((path, mtime));
+                    imported.directories += 1;
+                }
+                _ => {
+                    io::copy(&mut (&mut r).take(size), &mut io::sink())?;
+                    skip_padding(&mut r, size)?;
+                    imported.skipped.push(name);
+                }
+            }
+        }
+
+        for (path, mtime) in times
+            .into_iter()
+            .filter(|(path, _)| path.len() > target.len())
+        {
+            self.with_entry_mut(path, |entry, _| {
+                entry.modified = mtime.saturating_mul(1_000_000_000);
+                Ok(())
+            })?;
+        }
+        Ok(imported)
+    }
+}
+
+/// Archives are written as ustar, with a pax header in front of entries
+/// whose path doesn't fit into one. Files are `0644` and directories `0755`,
+/// owned by user and group 0. Content types have no place in tar, so they
+/// are lost on the way.
+impl<M: Memory> FileSystem<M> {
+    /// Streams the directory at `path` and everything below it as a tar
+    /// archive to `w`, with paths relative to it. Files are copied straight
+    /// from their blocks rather than read into the heap first. Returns the
+    /// length of the archive.
+    pub fn export_tar(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+        w: impl Write,
+    ) -> io::Result<u64> {
+        let mut w = io::BufWriter::new(w);
+        let mut written = 0;
+        let mut pending = vec![(String::new(), self.directory_at(path)?)];
+        while let Some((path, dir)) = pending.pop() {
+            for entry in dir.entries.into_iter().rev() {
+                let mut entry_path = format!("{}{}", path, entry.name);
+                let mtime = entry.modified / 1_000_000_000;
+                if entry.kind == EntryKind::Directory {
+                    entry_path.push('/');
+                    written += Self::write_tar_header(&mut w, &entry_path, b'5', 0, mtime)?;
+                    pending.push((entry_path, self.read_directory(&entry)?));
+                    continue;
+                }
+                written += Self::write_tar_header(&mut w, &entry_path, b'0', entry.size, mtime)?;
+                let copied = io::copy(&mut entry.read_from_file_system(self)?, &mut w)?;
+                if copied != entry.size {
+                    return Err(Error::corrupted("file is shorter than its size").into());
+                }
+                written += copied + Self::write_padding(&mut w, copied)?;
+            }
+        }
+        w.write_all(&[0u8; 2 * BLOCK])?;
+        w.flush()?;
+        Ok(written + 2 * BLOCK as u64)
+    }
+
+    fn write_tar_header(
+        mut w: impl Write,
+        path: &str,
+        kind: u8,
+        size: u64,
+        mtime: u64,
+    ) -> io::Result<u64> {
+        let mut written = 0;
+        if split_path(path).is_none() {
+            let record = pax_record("path", path);
+            let len = record.len() as u64;
+            w.write_all(&header("././@PaxHeader", b'x', len, mtime))?;
+            w.write_all(record.as_bytes())?;
+            written += BLOCK as u64 + len + Self::write_padding(&mut w, len)?;
+        }
+        w.write_all(&header(path, kind, size, mtime))?;
+        Ok(written + BLOCK as u64)
+    }
+
+    fn write_padding(mut w: impl Write, len: u64) -> io::Result<u64> {
+        let padding = (BLOCK as u64 - len % BLOCK as u64) % BLOCK as u64;
+        w.write_all(&[0u8; BLOCK][..padding as usize])?;
+        Ok(padding)
+    }
+}
+
+/// Writes a ustar header for `name`, as `tar` would.
+#[cfg(test)]
+fn tar_header(name: &str, kind: u8, size: usize) -> Vec<u8> {
+    let mut header = vec![0u8; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..107].copy_from_slice(b"0000644");
+    header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+    header[136..147].copy_from_slice(b"14000000000");
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[148..156].copy_from_slice(b"        ");
+    let sum = header.iter().map(|&b| b as usize).sum::<usize>();
+    header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    header
+}
+
+#[test]
+fn import_tar() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut archive = vec![];
+    let mut add = |name: &str, kind: u8, data: &[u8]| {
+        archive.extend(tar_header(name, kind, data.len()));
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().div_ceil(BLOCK) * BLOCK, 0);
+    };
+    add("site/", b'5', b"");
+    add("site/index.html", b'0', b"<h1>hi</h1>");
+    add("./site/img/logo.png", b'0', &[7u8; 1000]);
+    add("site/latest", b'2', b"");
+    let long = format!("site/{}", "n".repeat(120));
+    add("././@LongLink", b'L', long.as_bytes());
+    add("ignored", b'0', b"long");
+    archive.extend_from_slice(&[0u8; 2 * BLOCK]);
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    let imported = fs.import_tar(vec!["www"], &archive[..]).unwrap();
+    assert_eq!(
+        imported,
+        TarImport {
+            files: 3,
+            directories: 1,
+            skipped: vec!["site/latest".to_owned()],
+        }
+    );
+    let mut data = vec![];
+    fs.read_file(vec!["www", "site", "img", "logo.png"], &mut data)
+        .unwrap();
+    assert_eq!(data, [7u8; 1000]);
+    let modified = fs.metadata(vec!["www", "site"]).unwrap().modified;
+    assert_eq!(modified, 0o14000000000 * 1_000_000_000);
+    data.clear();
+    fs.read_file(vec!["www".to_owned(), long.replace("site/", "")], &mut data)
+        .unwrap_err();
+    fs.read_file(vec!["www", "site", &"n".repeat(120)], &mut data)
+        .unwrap();
+    assert_eq!(data, b"long");
+
+    // Exported trees come back as they were, long names included.
+    let mut archive = vec![];
+    let len = fs.export_tar(vec!["www"], &mut archive).unwrap();
+    assert_eq!(len, archive.len() as u64);
+    assert_eq!(len % BLOCK as u64, 0);
+    let mut copy = FileSystem::new(HeapMemory::default()).unwrap();
+    let imported = copy.import_tar(Vec::<String>::new(), &archive[..]).unwrap();
+    assert_eq!((imported.files, imported.directories), (3, 2));
+    for path in [
+        vec!["site", "index.html"],
+        vec!["site", "img", "logo.png"],
+        vec!["site", &"n".repeat(120)],
+    ] {
+        let (mut original, mut copied) = (vec![], vec![]);
+        let mut original_path = vec!["www"];
+        original_path.extend(&path);
+        fs.read_file(original_path, &mut original).unwrap();
+        copy.read_file(path, &mut copied).unwrap();
+        assert_eq!(original, copied);
+    }
+    let modified = copy.metadata(vec!["site"]).unwrap().modified;
+    assert_eq!(modified, 0o14000000000 * 1_000_000_000);
+
+    // Paths leaving the target are refused.
+    let mut archive = tar_header("../escape", b'0', 0);
+    archive.extend_from_slice(&[0u8; 2 * BLOCK]);
+    assert!(fs.import_tar(vec!["www"], &archive[..]).is_err());
+}
+
+#[test]
+fn export_tar() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(vec!["www", "docs"]).unwrap();
+    fs.write_atomic(vec!["www", "docs", "a.txt"], &b"hello"[..])
+        .unwrap();
+    let long = "n".repeat(120);
+    fs.write_atomic(vec!["www", &long], &b"x"[..]).unwrap();
+    let mut archive = vec![];
+    fs.export_tar(vec!["www"], &mut archive).unwrap();
+
+    // Headers are ustar with valid checksums, each followed by its data
+    // padded to a block, and two empty blocks end the archive.
+    let mut entries = vec![];
+    let mut offset = 0;
+    loop {
+        let header = &archive[offset..offset + BLOCK];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        assert_eq!(&header[257..265], b"ustar\x0000");
+        let sum = header[..148]
+            .iter()
+            .chain(&[b' '; 8])
+            .chain(&header[156..])
+            .map(|&b| u64::from(b))
+            .sum::<u64>();
+        assert_eq!(number(&header[148..156]).unwrap(), sum);
+        let size = number(&header[124..136]).unwrap() as usize;
+        let data = &archive[offset + BLOCK..offset + BLOCK + size];
+        entries.push((header[156], field(&header[..NAME_LEN]).to_vec(), data));
+        offset += BLOCK + size.div_ceil(BLOCK) * BLOCK;
+    }
+    assert_eq!(archive.len(), offset + 2 * BLOCK);
+    assert!(archive[offset..].iter().all(|&b| b == 0));
+
+    assert_eq!(entries.len(), 4);
+    let find = |name: &[u8]| entries.iter().find(|(_, n, _)| n == name).unwrap();
+    assert_eq!(find(b"docs/").0, b'5');
+    assert_eq!(
+        (find(b"docs/a.txt").0, find(b"docs/a.txt").2),
+        (b'0', &b"hello"[..])
+    );
+
+    // The long name comes in a pax header in front of its entry.
+    let pax = entries
+        .iter()
+        .position(|(kind, _, _)| *kind == b'x')
+        .unwrap();
+    let record = std::str::from_utf8(entries[pax].2).unwrap();
+    assert!(record.ends_with(&format!(" path={}\n", long)));
+    assert_eq!((entries[pax + 1].0, entries[pax + 1].2), (b'0', &b"x"[..]));
+}
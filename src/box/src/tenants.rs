@@ -0,0 +1,151 @@
+use std::io;
+
+use ic_cdk::export::candid::{CandidType, Deserialize};
+
+use crate::directory::Acl;
+use crate::error::Error;
+use crate::file_system::FileSystem;
+use crate::memory::Memory;
+
+/// The directory holding the home directories of tenants, by principal.
+pub const HOMES: &str = "users";
+
+/// Whether principals are kept apart in home directories of their own, and
+/// how much they may store there. Admins aren't tenants, and see the whole
+/// filesystem.
+#[derive(Debug, Default, PartialEq, Clone, CandidType, Deserialize)]
+pub struct Tenancy {
+    pub enabled: bool,
+    /// Bytes of files each tenant may keep, unless it has a quota of its
+    /// own. No limit if `None`.
+    pub quota: Option<u64>,
+}
+
+const TENANCY: &str = "tenancy";
+const QUOTA: &str = "tenantQuota";
+
+/// The setting with the quota of `principal` alone.
+fn own_quota(principal: &str) -> String {
+    format!("{}:{}", QUOTA, principal)
+}
+
+fn parse_quota(value: Option<String>) -> io::Result<Option<u64>> {
+    value
+        .map(|quota| {
+            quota
+                .parse()
+                .map_err(|_| Error::corrupted(format!("bad quota {}", quota)).into())
+        })
+        .transpose()
+}
+
+impl Tenancy {
+    /// The tenancy kept in the settings of `fs`, disabled if there's none.
+    pub fn read<M: Memory>(fs: &FileSystem<M>) -> io::Result<Self> {
+        Ok(Tenancy {
+            enabled: fs.setting(TENANCY)?.as_deref() == Some("on"),
+            quota: parse_quota(fs.setting(QUOTA)?)?,
+        })
+    }
+
+    /// Keeps the tenancy in the settings of `fs`. Quotas of single tenants
+    /// stay.
+    pub fn write<M: Memory>(&self, fs: &mut FileSystem<M>) -> io::Result<()> {
+        let enabled = if self.enabled {
+            Some("on".to_owned())
+        } else {
+            None
+        };
+        fs.set_setting(TENANCY, enabled)?;
+        fs.set_setting(QUOTA, self.quota.map(|quota| quota.to_string()))
+    }
+}
+
+/// The path of the home directory of `principal`.
+pub fn home(principal: &str) -> Vec<String> {
+    vec![HOMES.to_owned(), principal.to_owned()]
+}
+
+/// Makes the home directory of `principal`, owned by it, unless it exists.
+pub fn make_home<M: Memory>(fs: &mut FileSystem<M>, principal: &str) -> io::Result<()> {
+    let home = home(principal);
+    if !fs.exists(&home) {
+        fs.make_directory_recursive(home.clone())?;
+        fs.set_acl(home, Some(Acl::owned_by(principal)))?;
+    }
+    Ok(())
+}
+
+/// How many bytes `principal` may keep, its own quota if it has one.
+pub fn quota<M: Memory>(fs: &FileSystem<M>, principal: &str) -> io::Result<Option<u64>> {
+    match parse_quota(fs.setting(&own_quota(principal))?)? {
+        Some(quota) => Ok(Some(quota)),
+        None => Tenancy::read(fs).map(|tenancy| tenancy.quota),
+    }
+}
+
+/// Gives `principal` a quota of its own, or with `None` the one of every
+/// tenant.
+pub fn set_quota<M: Memory>(
+    fs: &mut FileSystem<M>,
+    principal: &str,
+    quota: Option<u64>,
+) -> io::Result<()> {
+    fs.set_setting(&own_quota(principal), quota.map(|quota| quota.to_string()))
+}
+
+/// Bytes of files in the home directory of `principal`.
+pub fn usage<M: Memory>(fs: &FileSystem<M>, principal: &str) -> io::Result<u64> {
+    match fs.dir_size(home(principal)) {
+        Ok(size) => Ok(size.bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Fails unless the files of `principal` may grow by `growth` bytes.
+pub fn check_quota<M: Memory>(fs: &FileSystem<M>, principal: &str, growth: u64) -> io::Result<()> {
+    if let Some(quota) = quota(fs, principal)? {
+        if usage(fs, principal)?.saturating_add(growth) > quota {
+            return Err(Error::QuotaExceeded.into());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn tenants() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    assert_eq!(Tenancy::read(&fs).unwrap(), Tenancy::default());
+    let tenancy = Tenancy {
+        enabled: true,
+        quota: Some(100),
+    };
+    tenancy.write(&mut fs).unwrap();
+    assert_eq!(Tenancy::read(&fs).unwrap(), tenancy);
+
+    make_home(&mut fs, "alice").unwrap();
+    make_home(&mut fs, "alice").unwrap();
+    assert_eq!(
+        fs.resolve(home("alice")).unwrap().unwrap().acl,
+        Some(Acl::owned_by("alice"))
+    );
+    assert_eq!(fs.resolve([HOMES]).unwrap().unwrap().acl, None);
+    let mut file = home("alice");
+    file.push("a.txt".to_owned());
+    fs.write_atomic(file, &[1; 60][..]).unwrap();
+    assert_eq!(usage(&fs, "alice").unwrap(), 60);
+    assert_eq!(usage(&fs, "bob").unwrap(), 0);
+    assert!(check_quota(&fs, "alice", 40).is_ok());
+    let e = check_quota(&fs, "alice", 41).unwrap_err();
+    assert!(matches!(Error::from(e), Error::QuotaExceeded));
+
+    // A quota of its own outlives changes to the tenancy.
+    set_quota(&mut fs, "alice", Some(1000)).unwrap();
+    Tenancy::default().write(&mut fs).unwrap();
+    assert_eq!(quota(&fs, "alice").unwrap(), Some(1000));
+    assert_eq!(quota(&fs, "bob").unwrap(), None);
+    assert!(check_quota(&fs, "alice", 500).is_ok());
+}
@@ -0,0 +1,134 @@
+use std::io;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::file_system::FileSystem;
+use crate::memory::Memory;
+
+/// The secret tokens are signed with, in hex. Clearing it revokes every
+/// token signed so far.
+const SECRET: &str = "tokenSecret";
+
+/// SHA-256 works on blocks of this many bytes.
+const BLOCK_LEN: usize = 64;
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<_>>();
+    let inner = Sha256::new().chain(pad(0x36)).chain(message).finalize();
+    Sha256::new()
+        .chain(pad(0x5c))
+        .chain(inner)
+        .finalize()
+        .into()
+}
+
+/// What a token signs: when it expires and the path it grants.
+fn signed(path: &[String], expires: u64) -> Vec<u8> {
+    let mut message = expires.to_be_bytes().to_vec();
+    for name in path {
+        message.extend_from_slice(&(name.len() as u64).to_be_bytes());
+        message.extend_from_slice(name.as_bytes());
+    }
+    message
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match std::str::from_utf8(pair) {
+            Ok(pair) if pair.len() == 2 => u8::from_str_radix(pair, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The secret kept in the settings of `fs`, if one was set.
+pub fn secret<M: Memory>(fs: &FileSystem<M>) -> io::Result<Option<Vec<u8>>> {
+    fs.setting(SECRET)?
+        .map(|secret| from_hex(&secret).ok_or_else(|| Error::corrupted("bad token secret").into()))
+        .transpose()
+}
+
+/// Keeps `secret` in the settings of `fs`, or with `None` revokes every
+/// token signed with the one before.
+pub fn set_secret<M: Memory>(fs: &mut FileSystem<M>, secret: Option<&[u8]>) -> io::Result<()> {
+    fs.set_setting(SECRET, secret.map(to_hex))
+}
+
+/// A token which lets its bearer read `path`, and what's below it, until
+/// `expires`, in nanoseconds since the epoch like the filesystem's clock.
+pub fn sign(secret: &[u8], path: &[String], expires: u64) -> String {
+    let mac = hmac(secret, &signed(path, expires));
+    format!("{}.{}", expires, to_hex(&mac))
+}
+
+/// Whether `token`, signed with the secret of `fs`, lets its bearer read
+/// `path` at `now`.
+pub fn grants<M: Memory>(
+    fs: &FileSystem<M>,
+    path: &[String],
+    token: &str,
+    now: u64,
+) -> io::Result<bool> {
+    let secret = match secret(fs)? {
+        Some(secret) => secret,
+        None => return Ok(false),
+    };
+    let (expires, mac) = match token.split_once('.') {
+        Some((expires, mac)) => (expires.parse::<u64>().ok(), from_hex(mac)),
+        None => (None, None),
+    };
+    let (expires, mac) = match (expires, mac) {
+        (Some(expires), Some(mac)) if expires > now => (expires, mac),
+        _ => return Ok(false),
+    };
+    Ok((0..=path.len()).any(|len| {
+        let expected = hmac(&secret, &signed(&path[..len], expires));
+        // Compared in full, so the time taken gives nothing away.
+        mac.len() == expected.len()
+            && mac.iter().zip(expected).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+    }))
+}
+
+#[test]
+fn tokens() {
+    use crate::heap_memory::HeapMemory;
+
+    // From RFC 4231.
+    assert_eq!(
+        to_hex(&hmac(&[0x0b; 20], b"Hi There")),
+        "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+    );
+    assert_eq!(from_hex("00ff"), Some(vec![0, 255]));
+    assert_eq!(from_hex("0"), None);
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    let path = |names: &[&str]| names.iter().map(|&n| n.to_owned()).collect::<Vec<_>>();
+    let token = sign(b"secret", &path(&["private"]), 100);
+    assert!(!grants(&fs, &path(&["private"]), &token, 0).unwrap());
+
+    set_secret(&mut fs, Some(b"secret")).unwrap();
+    assert_eq!(secret(&fs).unwrap(), Some(b"secret".to_vec()));
+    assert!(grants(&fs, &path(&["private"]), &token, 99).unwrap());
+    assert!(grants(&fs, &path(&["private", "a.txt"]), &token, 99).unwrap());
+    assert!(!grants(&fs, &path(&["private"]), &token, 100).unwrap());
+    assert!(!grants(&fs, &path(&["other"]), &token, 0).unwrap());
+    assert!(!grants(&fs, &[], &token, 0).unwrap());
+    let longer = token.replacen("100.", "1000.", 1);
+    assert!(!grants(&fs, &path(&["private"]), &longer, 0).unwrap());
+    assert!(!grants(&fs, &path(&["private"]), "garbage", 0).unwrap());
+
+    set_secret(&mut fs, Some(b"rotated")).unwrap();
+    assert!(!grants(&fs, &path(&["private"]), &token, 0).unwrap());
+}
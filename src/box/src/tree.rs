@@ -0,0 +1,283 @@
+use std::io;
+
+use crate::directory::{Entry, EntryKind};
+use crate::error::Error;
+use crate::file_system::{names, FileSystem};
+use crate::memory::Memory;
+
+/// Walks a directory tree depth first, each directory followed by its
+/// entries, reading one directory at a time. Yields the depth, name, kind
+/// and size of each entry, where the entries of the directory the walk
+/// started at have depth 0.
+pub struct Tree<'a, M: Memory> {
+    fs: &'a FileSystem<M>,
+    max_depth: Option<usize>,
+    /// The entries left to visit in each directory on the way down.
+    pending: Vec<std::vec::IntoIter<Entry>>,
+}
+
+impl<M: Memory> FileSystem<M> {
+    /// Walks the tree below the directory at `path`, down to entries of
+    /// depth `max_depth` if given. The walk doesn't descend into mounted
+    /// filesystems.
+    pub fn tree(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+        max_depth: Option<usize>,
+    ) -> io::Result<Tree<'_, M>> {
+        let dir = self.directory_at(path)?;
+        Ok(Tree {
+            fs: self,
+            max_depth,
+            pending: vec![dir.entries.into_iter()],
+        })
+    }
+}
+
+/// Where a `find` stopped: the path of the last entry it looked at.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FindCursor(Vec<String>);
+
+impl FindCursor {
+    /// The cursor which resumes after the entry at `path`, e.g. one which
+    /// was handed out as its path.
+    pub fn after(path: Vec<String>) -> Self {
+        FindCursor(path)
+    }
+
+    pub fn path(&self) -> &[String] {
+        &self.0
+    }
+}
+
+/// The entries found by `FileSystem::find`, with their paths.
+#[derive(Default, Debug)]
+pub struct Found {
+    pub matches: Vec<(Vec<String>, Entry)>,
+    /// Where to resume the search, or `None` if it's complete.
+    pub cursor: Option<FindCursor>,
+}
+
+impl<M: Memory> FileSystem<M> {
+    /// Searches the tree below the directory at `path` in the order of
+    /// `tree` for entries `predicate` accepts. Stops after `limit` matches
+    /// or looking at `budget` entries, whichever comes first, and returns a
+    /// cursor to pass back in to carry on after the last entry looked at.
+    /// If that entry was removed in between, the search starts over in its
+    /// directory.
+    pub fn find(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+        predicate: impl FnMut(&Entry) -> bool,
+        limit: usize,
+        budget: usize,
+        cursor: Option<FindCursor>,
+    ) -> io::Result<Found> {
+        self.find_to_depth(path, None, predicate, limit, budget, cursor)
+    }
+
+    /// Like `find`, but only down to entries of depth `max_depth` if given,
+    /// where the entries of the directory at `path` have depth 0.
+    pub fn find_to_depth(
+        &self,
+        path: impl IntoIterator<Item = impl AsRef<str>>,
+        max_depth: Option<usize>,
+        mut predicate: impl FnMut(&Entry) -> bool,
+        limit: usize,
+        budget: usize,
+        cursor: Option<FindCursor>,
+    ) -> io::Result<Found> {
+        let path = names(path);
+        let resume = match &cursor {
+            Some(FindCursor(last)) => last.strip_prefix(&path[..]).ok_or(Error::InvalidPath)?,
+            None => &[],
+        };
+
+        // Directories on the way to the cursor, with the entries after it.
+        let mut pending = vec![];
+        let mut dir_path = path.clone();
+        let mut entries = self.directory_at(&path)?.entries.into_iter();
+        for (depth, name) in resume.iter().enumerate() {
+            let entry = match entries.as_slice().iter().position(|e| e.name == *name) {
+                Some(i) => entries.nth(i).unwrap(),
+                None => break,
+            };
+            pending.push((dir_path.clone(), entries));
+            if entry.kind != EntryKind::Directory || matches!(max_depth, Some(max) if depth >= max)
+            {
+                entries = vec![].into_iter();
+                break;
+            }
+            dir_path.push(entry.name.clone());
+            entries = self.read_directory(&entry)?.entries.into_iter();
+        }
+        pending.push((dir_path, entries));
+
+        let mut found = Found::default();
+        let mut visited = 0;
+        let mut last = None;
+        while let Some((dir_path, entries)) = pending.last_mut() {
+            let entry = match entries.next() {
+                Some(entry) => entry,
+                None => {
+                    pending.pop();
+                    continue;
+                }
+            };
+            if visited == budget || found.matches.len() == limit {
+                found.cursor = last.map(FindCursor);
+                break;
+            }
+            visited += 1;
+
+            let mut entry_path = dir_path.clone();
+            entry_path.push(entry.name.clone());
+            let depth = entry_path.len() - path.len() - 1;
+            if entry.kind == EntryKind::Directory && !matches!(max_depth, Some(max) if depth >= max)
+            {
+                let dir = self.read_directory(&entry)?;
+                pending.push((entry_path.clone(), dir.entries.into_iter()));
+            }
+            if predicate(&entry) {
+                found.matches.push((entry_path.clone(), entry));
+            }
+            last = Some(entry_path);
+        }
+        Ok(found)
+    }
+}
+
+impl<M: Memory> Iterator for Tree<'_, M> {
+    type Item = io::Result<(usize, String, EntryKind, u64)>;
+
+    /// Stops after the first error.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let depth = self.pending.len().checked_sub(1)?;
+            let entry = match self.pending[depth].next() {
+                Some(entry) => entry,
+                None => {
+                    self.pending.pop();
+                    continue;
+                }
+            };
+
+            if entry.kind == EntryKind::Directory && self.max_depth.is_none_or(|max| depth < max) {
+                match self.fs.read_directory(&entry) {
+                    Ok(dir) => self.pending.push(dir.entries.into_iter()),
+                    Err(e) => {
+                        self.pending.clear();
+                        return Some(Err(e));
+                    }
+                }
+            }
+            return Some(Ok((depth, entry.name, entry.kind, entry.size)));
+        }
+    }
+}
+
+#[test]
+fn tree() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(vec!["a", "b"]).unwrap();
+    fs.write_atomic(vec!["a", "b", "c.txt"], &b"hello"[..])
+        .unwrap();
+    fs.write_atomic(vec!["d.txt"], &b"hi"[..]).unwrap();
+
+    let entries = fs
+        .tree(vec!["a"], None)
+        .unwrap()
+        .map(|entry| entry.map(|(depth, name, kind, _)| (depth, name, kind)))
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(
+        entries,
+        [
+            (0, "b".to_owned(), EntryKind::Directory),
+            (1, "c.txt".to_owned(), EntryKind::File),
+        ]
+    );
+    assert!(fs.tree(vec!["d.txt"], None).is_err());
+
+    assert_eq!(
+        format!("{:#}", fs),
+        "/
+| a/
+    | b/
+        | c.txt (5)
+| d.txt (2)"
+    );
+    assert_eq!(format!("{:.0}", fs), "/\n| a/\n| d.txt");
+    // Displaying leaves the tree as it was.
+    assert_eq!(format!("{}", fs).lines().count(), 5);
+}
+
+#[test]
+fn find() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::default()).unwrap();
+    fs.make_directory_recursive(vec!["docs", "old"]).unwrap();
+    for (i, name) in ["a.txt", "b.bin", "c.txt"].iter().enumerate() {
+        fs.write_atomic(vec!["docs", name], &vec![0u8; i * 10][..])
+            .unwrap();
+        fs.write_atomic(vec!["docs", "old", name], &b"old"[..])
+            .unwrap();
+    }
+    let is_text = |entry: &Entry| entry.name.ends_with(".txt");
+
+    let found = fs.find(vec!["docs"], is_text, 10, 100, None).unwrap();
+    assert_eq!(found.matches.len(), 4);
+    assert_eq!(found.cursor, None);
+
+    // Resuming finds every match once, however the search is split up.
+    for (limit, budget) in [(1, 100), (10, 1), (2, 3)].iter() {
+        let mut paths = vec![];
+        let mut cursor = None;
+        loop {
+            let found = fs
+                .find(vec!["docs"], is_text, *limit, *budget, cursor)
+                .unwrap();
+            assert!(found.matches.len() <= *limit);
+            paths.extend(found.matches.into_iter().map(|(path, _)| path));
+            cursor = found.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        paths.sort();
+        paths.dedup();
+        assert_eq!(paths.len(), 4);
+        assert!(paths.iter().all(|path| path[0] == "docs"));
+    }
+
+    let small = fs
+        .find(Vec::<String>::new(), |e| e.size == 10, 10, 100, None)
+        .unwrap();
+    assert_eq!(small.matches[0].0, ["docs", "b.bin"]);
+
+    let shallow = fs
+        .find_to_depth(vec!["docs"], Some(0), |_| true, 10, 100, None)
+        .unwrap();
+    assert_eq!(shallow.matches.len(), 4);
+    assert!(shallow.matches.iter().all(|(path, _)| path.len() == 2));
+    let cursor = FindCursor::after(vec!["docs".to_owned(), "old".to_owned()]);
+    let rest = fs
+        .find_to_depth(
+            vec!["docs"],
+            Some(1),
+            |_| true,
+            10,
+            100,
+            Some(cursor.clone()),
+        )
+        .unwrap();
+    assert_eq!(rest.matches.len(), 6);
+    assert_eq!(rest.matches[0].0, ["docs", "old", "a.txt"]);
+    let rest = fs
+        .find_to_depth(vec!["docs"], Some(0), |_| true, 10, 100, Some(cursor))
+        .unwrap();
+    assert_eq!(rest.matches.len(), 3);
+}
@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::io;
+
+use candid::CandidType;
+
+use crate::cluster::Cluster;
+use crate::directory::{Entry, EntryKind};
+use crate::error::Error;
+use crate::file_system::FileSystem;
+use crate::memory::Memory;
+use crate::serde::{Compact, Deserialize, Serialize, MAX_STRING_LEN};
+use crate::tokens::{from_hex, to_hex};
+
+/// Uploads which see no chunks for this long are dropped, along with what
+/// they staged, by the next maintenance or the next upload to begin.
+const UPLOAD_EXPIRY_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+/// The setting uploads are kept in, as their staged files are referenced
+/// from nowhere else.
+const UPLOADS: &str = "uploads";
+
+/// The bytes from `start` up to `end`.
+#[derive(Debug, PartialEq, Clone, Copy, CandidType, serde::Deserialize)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// What an upload has received, so an interrupted one can be resumed by
+/// sending only the rest.
+#[derive(Debug, PartialEq, Clone, CandidType, serde::Deserialize)]
+pub struct UploadStatus {
+    #[serde(rename = "totalSize")]
+    pub total_size: u64,
+    /// Sorted, and apart from each other.
+    pub received: Vec<ByteRange>,
+}
+
+#[derive(Default)]
+struct Upload {
+    path: Vec<String>,
+    /// Whoever began the upload.
+    owner: String,
+    /// The file being uploaded, written outside of any directory.
+    staged: Entry,
+    total_size: u64,
+    /// The byte ranges received so far, sorted and merged.
+    received: Vec<(u64, u64)>,
+    expires: u64,
+}
+
+/// Uploads of files too large for a single message, in chunks which may
+/// come in any order. Chunks go straight into a staged file in the
+/// filesystem, with blocks for the whole size reserved up front, and the
+/// file only replaces the one at its path when the upload is committed.
+/// Uploads are kept in the settings of the filesystem after every change,
+/// so they survive upgrades and can be resumed.
+#[derive(Default)]
+pub struct Uploads {
+    next_id: u64,
+    uploads: HashMap<u64, Upload>,
+}
+
+impl Uploads {
+    /// Starts an upload of `total_size` bytes to `path` for `owner`. The
+    /// file there, if any, gets `content_type` when the upload is committed,
+    /// unless it's empty. Fails if there isn't room for the whole file.
+    pub fn begin<M: Memory>(
+        &mut self,
+        fs: &mut FileSystem<M>,
+        path: Vec<String>,
+        content_type: String,
+        total_size: u64,
+        owner: String,
+        now: u64,
+    ) -> io::Result<u64> {
+        self.abort_expired(fs, now)?;
+        let name = path.last().ok_or(Error::InvalidPath)?;
+        if matches!(fs.metadata(&path), Ok(meta) if meta.kind == EntryKind::Directory) {
+            return Err(Error::IsADirectory.into());
+        }
+        let mut staged = Entry {
+            content_type,
+            ..Entry::new(name.clone())
+        };
+        if let Err(e) = staged.preallocate(fs, total_size) {
+            fs.release_entry(staged)?;
+            return Err(e);
+        }
+        self.next_id += 1;
+        let upload = Upload {
+            path,
+            owner,
+            staged,
+            total_size,
+            received: vec![],
+            expires: now.saturating_add(UPLOAD_EXPIRY_NANOS),
+        };
+        self.uploads.insert(self.next_id, upload);
+        if let Err(e) = self.save(fs) {
+            let upload = self.uploads.remove(&self.next_id).unwrap();
+            fs.release_entry(upload.staged)?;
+            return Err(e);
+        }
+        Ok(self.next_id)
+    }
+
+    /// The uploads `save` kept in the settings of `fs`, to go on with after
+    /// an upgrade.
+    pub fn load<M: Memory>(fs: &FileSystem<M>) -> io::Result<Self> {
+        let mut uploads = Uploads::default();
+        let data = match fs.setting(UPLOADS)? {
+            Some(hex) => from_hex(&hex).ok_or_else(|| Error::corrupted("bad uploads setting"))?,
+            None => return Ok(uploads),
+        };
+        let mut r = &data[..];
+        let mut count = 0usize;
+        Compact(&mut uploads.next_id).deserialize(&mut r)?;
+        Compact(&mut count).deserialize(&mut r)?;
+        for _ in 0..count {
+            let (mut id, mut upload) = (0u64, Upload::default());
+            let (mut segments, mut ranges) = (0usize, 0usize);
+            let (mut content_type, mut size) = (String::new(), 0u64);
+            let mut cluster = Cluster::default();
+            Compact(&mut id).deserialize(&mut r)?;
+            Compact(&mut segments).deserialize(&mut r)?;
+            for _ in 0..segments {
+                let mut segment = String::new();
+                Compact(&mut segment).deserialize(&mut r)?;
+                upload.path.push(segment);
+            }
+            Compact(&mut upload.owner).deserialize(&mut r)?;
+            Compact(&mut content_type).deserialize(&mut r)?;
+            Compact(&mut upload.total_size).deserialize(&mut r)?;
+            Compact(&mut upload.expires).deserialize(&mut r)?;
+            Compact(&mut size).deserialize(&mut r)?;
+            cluster.deserialize(&mut r)?;
+            Compact(&mut ranges).deserialize(&mut r)?;
+            for _ in 0..ranges {
+                let (mut start, mut end) = (0u64, 0u64);
+                Compact(&mut start).deserialize(&mut r)?;
+                Compact(&mut end).deserialize(&mut r)?;
+                upload.received.push((start, end));
+            }
+            let name = upload.path.last().ok_or(Error::InvalidPath)?;
+            upload.staged = Entry {
+                content_type,
+                size,
+                cluster,
+                ..Entry::new(name.clone())
+            };
+            uploads.uploads.insert(id, upload);
+        }
+        Ok(uploads)
+    }
+
+    /// Keeps the uploads in the settings of `fs`. Fails without keeping
+    /// anything if there are more than the root directory can hold.
+    fn save<M: Memory>(&self, fs: &mut FileSystem<M>) -> io::Result<()> {
+        let mut data = vec![];
+        Compact(self.next_id).serialize(&mut data)?;
+        Compact(self.uploads.len()).serialize(&mut data)?;
+        for (&id, upload) in self.uploads.iter() {
+            Compact(id).serialize(&mut data)?;
+            Compact(upload.path.len()).serialize(&mut data)?;
+            for segment in upload.path.iter() {
+                Compact(segment.as_str()).serialize(&mut data)?;
+            }
+            Compact(upload.owner.as_str()).serialize(&mut data)?;
+            Compact(upload.staged.content_type.as_str()).serialize(&mut data)?;
+            Compact(upload.total_size).serialize(&mut data)?;
+            Compact(upload.expires).serialize(&mut data)?;
+            Compact(upload.staged.size).serialize(&mut data)?;
+            upload.staged.cluster.serialize(&mut data)?;
+            Compact(upload.received.len()).serialize(&mut data)?;
+            for &(start, end) in upload.received.iter() {
+                Compact(start).serialize(&mut data)?;
+                Compact(end).serialize(&mut data)?;
+            }
+        }
+        let hex = to_hex(&data);
+        if hex.len() > MAX_STRING_LEN {
+            let message = "too many uploads in progress";
+            return Err(io::Error::new(io::ErrorKind::OutOfMemory, message));
+        }
+        fs.set_setting(UPLOADS, Some(hex))
+    }
+
+    /// Whoever began the upload, if it exists.
+    pub fn owner(&self, id: u64) -> Option<&str> {
+        self.uploads.get(&id).map(|upload| upload.owner.as_str())
+    }
+
+    /// Where the upload goes, if it exists.
+    pub fn path(&self, id: u64) -> Option<&[String]> {
+        self.uploads.get(&id).map(|upload| upload.path.as_slice())
+    }
+
+    /// Writes `data` at `offset` of the staged file. Chunks may overlap, and
+    /// the last one written wins.
+    pub fn put_chunk<M: Memory>(
+        &mut self,
+        fs: &mut FileSystem<M>,
+        id: u64,
+        offset: u64,
+        data: &[u8],
+        now: u64,
+    ) -> io::Result<()> {
+        let upload = self.uploads.get_mut(&id).ok_or(Error::NotFound)?;
+        let end = offset.saturating_add(data.len() as u64);
+        if end > upload.total_size {
+            let message = format!("chunk ends past the size of {}", upload.total_size);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        fs.write_staged(&mut upload.staged, offset, data)?;
+        upload.expires = now.saturating_add(UPLOAD_EXPIRY_NANOS);
+        if offset < end {
+            let received = &mut upload.received;
+            let i = received.partition_point(|&(_, e)| e < offset);
+            let j = received.partition_point(|&(s, _)| s <= end);
+            let merged = match (received[i..j].first(), received[i..j].last()) {
+                (Some(first), Some(last)) => (first.0.min(offset), last.1.max(end)),
+                _ => (offset, end),
+            };
+            received.splice(i..j, [merged]);
+        }
+        self.save(fs)
+    }
+
+    /// The byte ranges received so far. Expired uploads are gone, even if
+    /// they haven't been dropped yet.
+    pub fn status(&self, id: u64, now: u64) -> io::Result<UploadStatus> {
+        let upload = self
+            .uploads
+            .get(&id)
+            .filter(|upload| upload.expires > now)
+            .ok_or(Error::NotFound)?;
+        let received = upload
+            .received
+            .iter()
+            .map(|&(start, end)| ByteRange { start, end })
+            .collect();
+        Ok(UploadStatus {
+            total_size: upload.total_size,
+            received,
+        })
+    }
+
+    /// Puts the staged file in place, creating the directories above it. An
+    /// upload with bytes missing stays open, so the rest can be sent.
+    pub fn commit<M: Memory>(&mut self, fs: &mut FileSystem<M>, id: u64) -> io::Result<()> {
+        let upload = self.uploads.get(&id).ok_or(Error::NotFound)?;
+        let complete = match upload.received[..] {
+            [] => upload.total_size == 0,
+            [(start, end)] => start == 0 && end == upload.total_size,
+            _ => false,
+        };
+        if !complete {
+            let message = "not all of the upload has been received";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+
+        let upload = self.uploads.remove(&id).unwrap();
+        self.save(fs)?;
+        let parent = &upload.path[..upload.path.len() - 1];
+        if !parent.is_empty() {
+            if let Err(e) = fs.make_directory_recursive(parent.to_vec()) {
+                fs.release_entry(upload.staged)?;
+                return Err(e);
+            }
+        }
+        fs.publish(upload.path, upload.staged).map(drop)
+    }
+
+    /// Drops the upload and releases what it staged.
+    pub fn abort<M: Memory>(&mut self, fs: &mut FileSystem<M>, id: u64) -> io::Result<()> {
+        let upload = self.uploads.remove(&id).ok_or(Error::NotFound)?;
+        self.save(fs)?;
+        fs.release_entry(upload.staged)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uploads.is_empty()
+    }
+
+    /// Drops the uploads which expired at `now`.
+    pub fn abort_expired<M: Memory>(&mut self, fs: &mut FileSystem<M>, now: u64) -> io::Result<()> {
+        let expired = self
+            .uploads
+            .iter()
+            .filter(|(_, upload)| upload.expires <= now)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            self.abort(fs, id)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn uploads() {
+    use crate::heap_memory::HeapMemory;
+
+    let mut fs = FileSystem::new(HeapMemory::with_max_size(16 << 20)).unwrap();
+    let mut uploads = Uploads::default();
+    let used = fs.usage().used_blocks;
+    let path = vec!["videos".to_owned(), "a.mp4".to_owned()];
+    let id = uploads
+        .begin(
+            &mut fs,
+            path.clone(),
+            "video/mp4".to_owned(),
+            300_000,
+            "alice".to_owned(),
+            0,
+        )
+        .unwrap();
+    let chunk = |i: u8| vec![i; 100_000];
+    uploads
+        .put_chunk(&mut fs, id, 200_000, &chunk(3), 1)
+        .unwrap();
+    uploads.put_chunk(&mut fs, id, 0, &chunk(1), 1).unwrap();
+    assert!(uploads
+        .put_chunk(&mut fs, id, 250_000, &chunk(4), 1)
+        .is_err());
+    assert!(uploads.commit(&mut fs, id).is_err());
+    assert!(!fs.exists(&path));
+    assert_eq!(uploads.owner(id), Some("alice"));
+    assert_eq!(uploads.path(id), Some(&path[..]));
+    let status = uploads.status(id, 1).unwrap();
+    assert_eq!(status.total_size, 300_000);
+    assert_eq!(
+        status.received,
+        [
+            ByteRange {
+                start: 0,
+                end: 100_000
+            },
+            ByteRange {
+                start: 200_000,
+                end: 300_000
+            }
+        ]
+    );
+    uploads
+        .put_chunk(&mut fs, id, 100_000, &chunk(2), 1)
+        .unwrap();
+    assert_eq!(uploads.uploads[&id].received, [(0, 300_000)]);
+    uploads.commit(&mut fs, id).unwrap();
+
+    let mut data = vec![];
+    fs.read_file(path.clone(), &mut data).unwrap();
+    assert_eq!(data, [chunk(1), chunk(2), chunk(3)].concat());
+    let content_type = fs.with_file(path.clone(), |file| Ok(file.content_type.clone()));
+    assert_eq!(content_type.unwrap(), "video/mp4");
+    assert!(uploads.commit(&mut fs, id).is_err());
+
+    // Aborted and expired uploads give their blocks back.
+    fs.remove(path.clone()).unwrap();
+    fs.remove(vec!["videos"]).unwrap();
+    let id = uploads
+        .begin(
+            &mut fs,
+            path.clone(),
+            String::new(),
+            300_000,
+            String::new(),
+            0,
+        )
+        .unwrap();
+    uploads.put_chunk(&mut fs, id, 0, &chunk(1), 1).unwrap();
+    uploads.abort(&mut fs, id).unwrap();
+    assert_eq!(fs.usage().used_blocks, used);
+    let expired = uploads
+        .begin(&mut fs, path.clone(), String::new(), 10, String::new(), 0)
+        .unwrap();
+    assert!(uploads.status(expired, UPLOAD_EXPIRY_NANOS).is_err());
+    let id = uploads
+        .begin(
+            &mut fs,
+            path,
+            String::new(),
+            0,
+            String::new(),
+            UPLOAD_EXPIRY_NANOS,
+        )
+        .unwrap();
+    assert!(uploads.put_chunk(&mut fs, expired, 0, b"x", 1).is_err());
+    uploads.commit(&mut fs, id).unwrap();
+    assert!(uploads.uploads.is_empty());
+}
+
+#[test]
+fn resume() {
+    use crate::file_system::Durability;
+    use crate::heap_memory::HeapMemory;
+
+    let mut mem = HeapMemory::with_max_size(16 << 20);
+    let path = vec!["a".to_owned(), "b.bin".to_owned()];
+    let chunk = |i: u8| vec![i; 100_000];
+    let mut fs = FileSystem::new(&mut mem)
+        .unwrap()
+        .with_durability(Durability::AfterEveryMutation);
+    let used = fs.usage().used_blocks;
+    let mut uploads = Uploads::default();
+    let id = uploads
+        .begin(
+            &mut fs,
+            path.clone(),
+            "x/y".to_owned(),
+            300_000,
+            "alice".to_owned(),
+            0,
+        )
+        .unwrap();
+    let expired = uploads
+        .begin(&mut fs, path.clone(), String::new(), 10, String::new(), 0)
+        .unwrap();
+    uploads.put_chunk(&mut fs, expired, 0, b"x", 0).unwrap();
+    uploads
+        .put_chunk(&mut fs, id, 200_000, &chunk(3), 1)
+        .unwrap();
+    assert!(fs.is_persisted());
+    fs.close().unwrap();
+
+    // A commit with ranges missing fails, and leaves the upload to resume.
+    let mut fs = FileSystem::open(&mut mem).unwrap();
+    let mut uploads = Uploads::load(&fs).unwrap();
+    assert!(uploads.commit(&mut fs, id).is_err());
+    assert_eq!(uploads.owner(id), Some("alice"));
+    assert_eq!(uploads.path(id), Some(&path[..]));
+    let status = uploads.status(id, 1).unwrap();
+    assert_eq!(status.total_size, 300_000);
+    assert_eq!(
+        status.received,
+        [ByteRange {
+            start: 200_000,
+            end: 300_000
+        }]
+    );
+    uploads.put_chunk(&mut fs, id, 0, &chunk(1), 1).unwrap();
+    fs.close().unwrap();
+
+    let mut fs = FileSystem::open(&mut mem).unwrap();
+    let mut uploads = Uploads::load(&fs).unwrap();
+    uploads
+        .put_chunk(&mut fs, id, 100_000, &chunk(2), 1)
+        .unwrap();
+    uploads.commit(&mut fs, id).unwrap();
+    let mut data = vec![];
+    fs.read_file(path.clone(), &mut data).unwrap();
+    assert_eq!(data, [chunk(1), chunk(2), chunk(3)].concat());
+    assert_eq!(
+        fs.with_file(path.clone(), |file| Ok(file.content_type.clone()))
+            .unwrap(),
+        "x/y"
+    );
+    fs.close().unwrap();
+
+    // Expired uploads stay expired, and give their blocks back.
+    let mut fs = FileSystem::open(&mut mem).unwrap();
+    let mut uploads = Uploads::load(&fs).unwrap();
+    assert!(uploads.status(expired, UPLOAD_EXPIRY_NANOS).is_err());
+    uploads.abort_expired(&mut fs, UPLOAD_EXPIRY_NANOS).unwrap();
+    assert!(uploads.is_empty());
+    fs.remove(path).unwrap();
+    fs.remove(vec!["a"]).unwrap();
+    assert_eq!(fs.usage().used_blocks, used);
+    fs.persist().unwrap();
+    drop(fs);
+    let fs = FileSystem::open(&mut mem).unwrap();
+    assert!(Uploads::load(&fs).unwrap().is_empty());
+}
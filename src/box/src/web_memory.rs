@@ -0,0 +1,98 @@
+use std::convert::TryFrom;
+use std::io;
+
+use js_sys::{ArrayBuffer, Uint8Array};
+
+use crate::error::Error;
+use crate::memory::{to_usize, Memory};
+
+/// Memory in a JS `ArrayBuffer`, so a web frontend can prepare a filesystem
+/// before uploading it, or look into an image it downloaded. Like
+/// `FileMemory`, pages are the size of stable memory pages, so images can be
+/// copied between the two as they are. Growing copies the memory into a
+/// larger buffer.
+pub struct WebMemory {
+    array: Uint8Array,
+    max_pages: u64,
+}
+
+impl Default for WebMemory {
+    fn default() -> Self {
+        Self::new(ArrayBuffer::new(0))
+    }
+}
+
+impl WebMemory {
+    const PAGE_SIZE: u64 = 65536;
+
+    /// Uses the bytes in `buffer`, e.g. an image read from IndexedDB. A part
+    /// of a page at the end is ignored. Defaults to the size limit of stable
+    /// memory, which also keeps the memory within the 4 GiB a typed array
+    /// can address.
+    pub fn new(buffer: ArrayBuffer) -> Self {
+        WebMemory {
+            array: Uint8Array::new(&buffer),
+            max_pages: 65535,
+        }
+    }
+
+    /// Caps the memory at `max_pages`, which can't be more than 65535.
+    pub fn with_max_pages(mut self, max_pages: u64) -> Self {
+        self.max_pages = max_pages.min(65535);
+        self
+    }
+
+    /// The buffer holding the memory, e.g. to store or upload it. It's
+    /// replaced whenever the memory grows.
+    pub fn buffer(&self) -> ArrayBuffer {
+        self.array.buffer()
+    }
+
+    /// The part of `len` bytes at `offset` which lies within the memory, as
+    /// the bounds of a subarray.
+    fn range(&self, offset: u64, len: usize) -> (u32, u32) {
+        let size = self.array.length();
+        let start = offset.min(u64::from(size)) as u32;
+        let len = u32::try_from(len).unwrap_or(u32::MAX).min(size - start);
+        (start, start + len)
+    }
+}
+
+impl Memory for WebMemory {
+    fn page_size(&self) -> u64 {
+        Self::PAGE_SIZE
+    }
+
+    fn max_pages(&self) -> u64 {
+        self.max_pages
+    }
+
+    fn page_count(&self) -> io::Result<u64> {
+        Ok(u64::from(self.array.length()) / Self::PAGE_SIZE)
+    }
+
+    fn grow(&mut self, num_pages: u64) -> io::Result<()> {
+        let pages = self.page_count()? + num_pages;
+        if pages > self.max_pages {
+            return Err(Error::OutOfSpace.into());
+        }
+        let array = Uint8Array::new_with_length((pages * Self::PAGE_SIZE) as u32);
+        array.set(&self.array, 0);
+        self.array = array;
+        Ok(())
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let (start, end) = self.range(offset, buf.len());
+        let len = to_usize(u64::from(end - start))?;
+        self.array.subarray(start, end).copy_to(&mut buf[..len]);
+        Ok(len)
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        let (start, end) = self.range(offset, buf.len());
+        let len = to_usize(u64::from(end - start))?;
+        self.array.subarray(start, end).copy_from(&buf[..len]);
+        Ok(len)
+    }
+}